@@ -1,18 +1,25 @@
 use argon2::{
     password_hash::{
-        rand_core::OsRng,
+        rand_core::{OsRng, RngCore},
         PasswordHash, PasswordHasher, PasswordVerifier, SaltString
     },
     Argon2
 };
-use rusqlite::{Error as SqliteError, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, TransactionBehavior, params};
 use serde::{Serialize, Deserialize};
 use rusqlite::OptionalExtension;
 use actix_session::Session;
 use actix_web::HttpRequest;
 use regex::Regex;
 use uuid::Uuid;
+use crate::rbac::{RbacError, role_exists};
+use crate::mfa::{MfaError, MfaState};
+use crate::login_guard::LoginGuardError;
+use crate::auth_session::client_ip;
+use crate::ldap_auth::{LDAP_PROVIDER, LdapProvider};
+use crate::oidc::OIDC_PROVIDER;
+use log::{error, warn};
+use std::env;
 use std::fmt;
 
 #[derive(Debug)]
@@ -23,6 +30,8 @@ pub enum AccountError {
     ExpectedField(String),
     SessionError(String),
     ValidationError(String),
+    // An external provider such as a directory server could not be reached or failed
+    ProviderError(String),
 }
 
 impl From<SqliteError> for AccountError {
@@ -31,6 +40,24 @@ impl From<SqliteError> for AccountError {
     }
 }
 
+impl From<MfaError> for AccountError {
+    fn from(error: MfaError) -> Self {
+        match error {
+            MfaError::DatabaseError(err) => AccountError::DatabaseError(err),
+            MfaError::ValidationError(msg) => AccountError::ValidationError(msg),
+        }
+    }
+}
+
+impl From<LoginGuardError> for AccountError {
+    fn from(error: LoginGuardError) -> Self {
+        match error {
+            LoginGuardError::DatabaseError(err) => AccountError::DatabaseError(err),
+            LoginGuardError::ValidationError(msg) => AccountError::ValidationError(msg),
+        }
+    }
+}
+
 impl fmt::Display for AccountError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -40,6 +67,7 @@ impl fmt::Display for AccountError {
             AccountError::ExpectedField(field) => write!(f, "Missing required field: {}", field),
             AccountError::SessionError(err) => write!(f, "Session Error: {}", err),
             AccountError::ValidationError(err) => write!(f, "Validation Error: {}", err),
+            AccountError::ProviderError(err) => write!(f, "Authentication provider error: {}", err),
         }
     }
 }
@@ -47,22 +75,29 @@ impl fmt::Display for AccountError {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Account {
     pub id: String,
+    #[serde(default)]
+    pub organization_id: String,
     pub name: String,
+    // Only read when creating an account or logging in; edits leave it empty
+    #[serde(default)]
     pub password: String,
     pub role: String,
 }
 
 impl Account {
-    fn verify_password(&self, password: &String) -> bool {
-        let parsed_hash = PasswordHash::new(&self.password).unwrap();
-        Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok()
+    fn verify_password(&self, password: &str) -> bool {
+        Self::password_matches(&self.password, password)
     }
 
-    pub fn is_valid_role(role: &str) -> bool {
-        matches!(role, "Admin" | "Analyst")
+    // Whether a password matches a stored hash. Anything that isn't a valid hash never matches
+    pub fn password_matches(hash: &str, password: &str) -> bool {
+        match PasswordHash::new(hash) {
+            Ok(parsed_hash) => Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok(),
+            Err(_) => false,
+        }
     }
 
-    fn hash_password(password: &str) -> Result<String, AccountError> {
+    pub fn hash_password(password: &str) -> Result<String, AccountError> {
         let salt = SaltString::generate(&mut OsRng);
         let argon2 = Argon2::default();
         argon2.hash_password(password.as_bytes(), &salt)
@@ -81,7 +116,7 @@ impl Account {
         Ok(())
     }
 
-    fn validate_password(password: &str) -> Result<(), AccountError> {
+    pub fn validate_password(password: &str) -> Result<(), AccountError> {
         if password.len() < 15 {
             return Err(AccountError::ValidationError("Password must be at least 15 characters long".to_string()));
         }
@@ -91,137 +126,501 @@ impl Account {
         if !password.chars().any(|c| c.is_lowercase()) {
             return Err(AccountError::ValidationError("Password must contain at least one lowercase letter".to_string()));
         }
-        if !password.chars().any(|c| c.is_digit(10)) {
+        if !password.chars().any(|c| c.is_ascii_digit()) {
             return Err(AccountError::ValidationError("Password must contain at least one number".to_string()));
         }
         Ok(())
     }
 }
 
-pub fn create_account(name: String, password: String, role: String) -> Result<usize, AccountError> {
-    let conn = establish_connection()?;
+pub fn create_account(conn: &Connection, organization_id: &str, name: String, password: String, role: String) -> Result<String, AccountError> {
     let id = Uuid::new_v4().to_string();
 
     Account::validate_name(&name)?;
     Account::validate_password(&password)?;
 
-    if !Account::is_valid_role(&role) {
+    if !is_valid_role(conn, organization_id, &role)? {
         return Err(AccountError::InvalidRole);
     }
 
-    if account_exists(&name)? {
+    if account_exists(conn, &name)? {
         return Err(AccountError::ValidationError(format!("Account name '{}' already exists.", name)));
     }
 
     let hashed_password = Account::hash_password(&password)?;
 
     conn.execute(
-        "INSERT INTO accounts (id, name, password, role) VALUES (?1, ?2, ?3, ?4)",
-        params![id, name, hashed_password, role],
-    ).map_err(AccountError::from)
+        "INSERT INTO accounts (id, organization_id, name, password, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id, organization_id, name, hashed_password, role],
+    )?;
+
+    Ok(id)
 }
 
-pub fn get_account(id: &String) -> Result<Option<Account>, AccountError> {
+pub fn get_account(conn: &Connection, id: &String) -> Result<Option<Account>, AccountError> {
     if id.is_empty() {
         return Err(AccountError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
-    let mut stmt = conn.prepare("SELECT id, name, password, role FROM accounts WHERE id = ?1")?;
+    let mut stmt = conn.prepare("SELECT id, organization_id, name, password, role FROM accounts WHERE id = ?1")?;
 
     let account = stmt.query_row(params![id], |row| {
         Ok(Account {
             id: row.get(0)?,
-            name: row.get(1)?,
-            password: row.get(2)?,
-            role: row.get(3)?,
+            organization_id: row.get(1)?,
+            name: row.get(2)?,
+            password: row.get(3)?,
+            role: row.get(4)?,
         })
     }).optional()?;
 
     Ok(account)
 }
 
-pub fn update_account(account: &Account) -> Result<bool, AccountError> {
+// Rename an account or change its role. Accounts never move between organizations, so
+// organization_id only scopes the update. Passwords are changed by their owner with the current
+// one, or replaced through an admin-issued reset token, never here
+pub fn update_account(conn: &Connection, account: &Account) -> Result<bool, AccountError> {
     if account.id.is_empty() {
         return Err(AccountError::ExpectedField("id".to_string()));
     }
     Account::validate_name(&account.name)?;
-    Account::validate_password(&account.password)?;
-    if !Account::is_valid_role(&account.role) {
+    if !is_valid_role(conn, &account.organization_id, &account.role)? {
         return Err(AccountError::InvalidRole);
     }
 
-    let conn = establish_connection()?;
-    let affected_rows = conn.execute(
-        "UPDATE accounts SET name = ?1, password = ?2, role = ?3 WHERE id = ?4",
-        params![account.name, account.password, account.role, account.id],
+    let provider: Option<String> = conn.query_row(
+        "SELECT auth_provider FROM accounts WHERE id = ?1 AND organization_id = ?2",
+        params![account.id, account.organization_id],
+        |row| row.get(0),
+    ).optional()?;
+    match provider.as_deref() {
+        None => return Ok(false),
+        Some(LOCAL_PROVIDER) => {}
+        // The provider sets the role on every login and owns the name and password
+        Some(_) => return Err(AccountError::ValidationError("This account is managed by its identity provider".to_string())),
+    }
+
+    conn.execute(
+        "UPDATE accounts SET name = ?1, role = ?2 WHERE id = ?3 AND organization_id = ?4",
+        params![account.name, account.role, account.id, account.organization_id],
     )?;
 
-    Ok(affected_rows > 0)
+    Ok(true)
 }
 
-pub fn delete_account(id: &String) -> Result<bool, AccountError> {
+pub fn delete_account(conn: &Connection, organization_id: &str, id: &String) -> Result<bool, AccountError> {
     if id.is_empty() {
         return Err(AccountError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let affected_rows = conn.execute(
-        "DELETE FROM accounts WHERE id = ?1",
-        params![id],
+        "DELETE FROM accounts WHERE id = ?1 AND organization_id = ?2",
+        params![id, organization_id],
     )?;
 
     Ok(affected_rows > 0)
 }
 
-pub fn verify_login(session: &Session, name: &String, password: &String, req: &HttpRequest) -> Result<Option<Account>, AccountError> {
-    Account::validate_name(name)?;
-    Account::validate_password(password)?;
+pub const LOCAL_PROVIDER: &str = "local";
+
+// The account a login name belongs to and the provider that owns it, read before the
+// credentials are checked
+pub struct LoginTarget {
+    pub provider: String,
+    pub account: Account,
+}
 
-    let conn = establish_connection()?;
-    let mut stmt = conn.prepare("SELECT id, name, password, role FROM accounts WHERE name = ?1")?;
+pub fn login_target(conn: &Connection, name: &str) -> Result<Option<LoginTarget>, AccountError> {
+    let target = conn.query_row(
+        "SELECT id, organization_id, name, password, role, auth_provider FROM accounts WHERE name = ?1",
+        params![name],
+        |row| Ok(LoginTarget {
+            account: Account {
+                id: row.get(0)?,
+                organization_id: row.get(1)?,
+                name: row.get(2)?,
+                password: row.get(3)?,
+                role: row.get(4)?,
+            },
+            provider: row.get(5)?,
+        }),
+    ).optional()?;
+    Ok(target)
+}
 
-    let account = stmt.query_row(params![name], |row| {
-        Ok(Account {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            password: row.get(2)?,
-            role: row.get(3)?,
-        })
-    }).optional()?;
+// What a provider vouches for once the credentials check out
+#[derive(Debug)]
+pub enum Grant {
+    // A stored account, and the hash its password was checked against
+    Existing { id: String, password: String },
+    // An account the provider manages, created or given this role in its organization
+    Provisioned { organization_id: String, role: String },
+}
+
+// Checks a user's credentials. Each account belongs to one provider, recorded in
+// `accounts.auth_provider`, and is only ever checked by that provider
+pub trait AuthProvider {
+    // Stored in `accounts.auth_provider` for the accounts this provider owns
+    fn name(&self) -> &'static str;
+
+    // Whether the provider creates accounts on first login
+    fn provisions_accounts(&self) -> bool {
+        false
+    }
+
+    // A grant on valid credentials, None otherwise. `account` is the stored account this
+    // provider owns, None for names without one. Runs without a database connection, since
+    // providers may wait on a remote server
+    fn verify(&self, account: Option<&Account>, name: &str, password: &str) -> Result<Option<Grant>, AccountError>;
+}
+
+// Argon2 hashes in the accounts table
+pub struct LocalProvider;
+
+impl AuthProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        LOCAL_PROVIDER
+    }
+
+    fn verify(&self, account: Option<&Account>, name: &str, password: &str) -> Result<Option<Grant>, AccountError> {
+        Account::validate_name(name)?;
+        Account::validate_password(password)?;
+
+        match account {
+            Some(account) if account.verify_password(password) => Ok(Some(Grant::Existing {
+                id: account.id.clone(),
+                password: account.password.clone(),
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+// A provider setting from the environment, None when unset or blank
+pub fn optional_env(name: &str) -> Option<String> {
+    env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+pub fn required_env(name: &str) -> Result<String, String> {
+    optional_env(name).ok_or_else(|| format!("{} must be set", name))
+}
+
+// `value=>Role` pairs separated by `;`, in order, for mapping directory groups or token claims to roles
+pub fn role_mappings(name: &str) -> Result<Vec<(String, String)>, String> {
+    let mut mappings = Vec::new();
+    for pair in optional_env(name).unwrap_or_default().split(';').filter(|p| !p.trim().is_empty()) {
+        let (value, role) = pair.split_once("=>")
+            .ok_or_else(|| format!("{} entry '{}' must look like 'value=>Role'", name, pair))?;
+        mappings.push((value.trim().to_string(), role.trim().to_string()));
+    }
+    Ok(mappings)
+}
+
+// Names in `AUTH_PROVIDERS` (comma separated, default `local`)
+pub fn provider_names() -> Vec<String> {
+    env::var("AUTH_PROVIDERS").unwrap_or_else(|_| LOCAL_PROVIDER.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
 
-    if let Some(account) = account {
-        if account.verify_password(password) {
-            // Store account ID
-            session.insert("account_id", account.id.clone())
-                .map_err(|e| AccountError::SessionError(e.to_string()))?;
+// The password providers named in `AUTH_PROVIDERS`, in that order
+pub fn auth_providers() -> Vec<Box<dyn AuthProvider>> {
+    let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+    for name in provider_names() {
+        match name.as_str() {
+            LOCAL_PROVIDER => providers.push(Box::new(LocalProvider)),
+            LDAP_PROVIDER => match LdapProvider::from_env() {
+                Ok(provider) => providers.push(Box::new(provider)),
+                Err(err) => error!("LDAP provider is misconfigured: {}", err),
+            },
+            // Signs in through a browser redirect rather than a password, see `oidc.rs`
+            OIDC_PROVIDER => {}
+            other => error!("Unknown authentication provider: {}", other),
+        }
+    }
+    providers
+}
+
+
+// Check a login against the provider that owns the account. Names without an account are
+// offered to the providers that create accounts on first login. Nothing here touches the
+// database; `grant_login` applies the result
+pub fn verify_credentials(target: Option<&LoginTarget>, name: &str, password: &str) -> Result<Option<(&'static str, Grant)>, AccountError> {
+    for provider in auth_providers() {
+        let account = match target {
+            Some(target) if target.provider == provider.name() => Some(&target.account),
+            Some(_) => continue,
+            None if provider.provisions_accounts() => None,
+            None => continue,
+        };
+        if let Some(grant) = provider.verify(account, name, password)? {
+            return Ok(Some((provider.name(), grant)));
+        }
+    }
+    Ok(None)
+}
+
+// The account a verified login signs in to. None when a stored account was deleted, renamed or
+// given a new password while its credentials were being checked
+pub fn grant_login(conn: &Connection, name: &str, provider: &str, grant: Grant) -> Result<Option<Account>, AccountError> {
+    match grant {
+        Grant::Existing { id, password } => {
+            let unchanged = conn.query_row(
+                "SELECT 1 FROM accounts WHERE id = ?1 AND name = ?2 AND password = ?3 AND auth_provider = ?4",
+                params![id, name, password, provider],
+                |_| Ok(()),
+            ).optional()?.is_some();
+            if !unchanged {
+                return Ok(None);
+            }
+            get_account(conn, &id)
+        }
+        Grant::Provisioned { organization_id, role } => {
+            provision_account(conn, &organization_id, name, &role, provider).map(Some)
+        }
+    }
+}
+
+// Create or update an account owned by an external provider, which decides its role. Its local
+// password is a random hash that is never checked
+pub fn provision_account(conn: &Connection, organization_id: &str, name: &str, role: &str, provider: &str) -> Result<Account, AccountError> {
+    Account::validate_name(name)?;
+    if !is_valid_role(conn, organization_id, role)? {
+        return Err(AccountError::InvalidRole);
+    }
 
-            let user_agent = req.headers().get(actix_web::http::header::USER_AGENT)
-                .and_then(|h| h.to_str().ok())
-                .unwrap_or("unknown")
-                .to_string();
-            session.insert("user_agent", user_agent)
-                .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    let existing: Option<(String, String)> = conn.query_row(
+        "SELECT id, auth_provider FROM accounts WHERE name = ?1",
+        params![name],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
 
-            // Store last activity time
-            session.insert("last_activity", std::time::SystemTime::now())
-                .map_err(|e| AccountError::SessionError(e.to_string()))?;
-            session.renew();
-            return Ok(Some(account)); // Login successful
-        } else {
-            return Ok(None); // Password incorrect
+    let id = match existing {
+        Some((id, owner)) if owner == provider => {
+            conn.execute("UPDATE accounts SET role = ?1 WHERE id = ?2", params![role, id])?;
+            id
+        }
+        Some(_) => return Err(AccountError::ValidationError(format!("Account name '{}' already exists.", name))),
+        None => {
+            let id = Uuid::new_v4().to_string();
+            conn.execute(
+                "INSERT INTO accounts (id, organization_id, name, password, role, auth_provider) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, organization_id, name, unusable_password()?, role, provider],
+            )?;
+            id
         }
+    };
+
+    get_account(conn, &id)?.ok_or(AccountError::ExpectedField("id".to_string()))
+}
+
+// Like `provision_account`, for providers that identify users by issuer and subject. The account
+// is found by that pair alone; the name follows the provider's display name while it is free,
+// but never decides which account signs in
+pub fn provision_external_account(
+    conn: &mut Connection,
+    organization_id: &str,
+    issuer: &str,
+    subject: &str,
+    name: &str,
+    role: &str,
+    provider: &str
+) -> Result<Account, AccountError> {
+    if !is_valid_role(conn, organization_id, role)? {
+        return Err(AccountError::InvalidRole);
     }
 
-    Ok(None) // No account found with the provided name
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let linked: Option<(String, String)> = tx.query_row(
+        "SELECT id, name FROM accounts WHERE external_issuer = ?1 AND external_subject = ?2",
+        params![issuer, subject],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    let id = match linked {
+        Some((id, current)) => {
+            if current != name {
+                let taken = tx.query_row("SELECT 1 FROM accounts WHERE name = ?1", params![name], |_| Ok(()))
+                    .optional()?
+                    .is_some();
+                if Account::validate_name(name).is_ok() && !taken {
+                    tx.execute("UPDATE accounts SET name = ?1 WHERE id = ?2", params![name, id])?;
+                } else {
+                    warn!("Account {} keeps its name, {} is taken or not a valid account name", current, name);
+                }
+            }
+            tx.execute("UPDATE accounts SET role = ?1 WHERE id = ?2", params![role, id])?;
+            id
+        }
+        None => {
+            Account::validate_name(name)?;
+            let existing: Option<(String, String, Option<String>)> = tx.query_row(
+                "SELECT id, auth_provider, external_subject FROM accounts WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).optional()?;
+
+            match existing {
+                // Accounts created before subjects were stored belong to the first subject that
+                // signs in with their name
+                Some((id, owner, None)) if owner == provider => {
+                    tx.execute(
+                        "UPDATE accounts SET external_issuer = ?1, external_subject = ?2, role = ?3 WHERE id = ?4",
+                        params![issuer, subject, role, id],
+                    )?;
+                    id
+                }
+                Some(_) => return Err(AccountError::ValidationError(format!("Account name '{}' already exists.", name))),
+                None => {
+                    let id = Uuid::new_v4().to_string();
+                    tx.execute(
+                        "INSERT INTO accounts (id, organization_id, name, password, role, auth_provider, external_issuer, external_subject)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                        params![id, organization_id, name, unusable_password()?, role, provider, issuer, subject],
+                    )?;
+                    id
+                }
+            }
+        }
+    };
+    tx.commit()?;
+
+    get_account(conn, &id)?.ok_or(AccountError::ExpectedField("id".to_string()))
+}
+
+// A random password hash for accounts that sign in through a provider, so no password matches it
+fn unusable_password() -> Result<String, AccountError> {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    Account::hash_password(&secret.iter().map(|b| format!("{:02x}", b)).collect::<String>())
 }
 
-fn account_exists(name: &String) -> Result<bool, AccountError> {
+pub fn start_session(session: &Session, account: &Account, mfa_state: MfaState, req: &HttpRequest) -> Result<(), AccountError> {
+    // Store account ID
+    session.insert("account_id", account.id.clone())
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    session.insert("organization_id", account.organization_id.clone())
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    // Anything but Verified keeps the session out of the API until the MFA step is done
+    session.insert("mfa_state", mfa_state)
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    // Identifies the sign-in in the session store, which lists and revokes by it
+    session.insert("session_id", Uuid::new_v4().to_string())
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    session.insert("ip_address", client_ip(req))
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+
+    let user_agent = req.headers().get(actix_web::http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+    session.insert("user_agent", user_agent)
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+
+    // Store last activity time
+    session.insert("last_activity", std::time::SystemTime::now())
+        .map_err(|e| AccountError::SessionError(e.to_string()))?;
+    session.renew();
+    Ok(())
+}
+
+fn account_exists(conn: &Connection, name: &String) -> Result<bool, AccountError> {
     Account::validate_name(name)?;
-    let conn = establish_connection()?;
 
     let mut stmt = conn.prepare("SELECT COUNT(*) FROM accounts WHERE name = ?1")?;
     let count: i64 = stmt.query_row(params![name], |row| row.get(0))?;
 
     Ok(count > 0)
-}
\ No newline at end of file
+}
+// A role is valid if it is built in or one of the organization's custom roles
+fn is_valid_role(conn: &Connection, organization_id: &str, role: &str) -> Result<bool, AccountError> {
+    role_exists(conn, organization_id, role).map_err(|e| match e {
+        RbacError::DatabaseError(err) => AccountError::DatabaseError(err),
+        RbacError::ValidationError(msg) => AccountError::ValidationError(msg),
+    })
+}
+
+pub fn count_accounts(conn: &Connection) -> Result<i64, AccountError> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM accounts", [], |row| row.get(0))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{organization, test_pool};
+
+    const ISSUER: &str = "https://idp.example.com";
+
+    fn sign_in(conn: &mut Connection, organization_id: &str, subject: &str, name: &str, role: &str) -> Result<Account, AccountError> {
+        provision_external_account(conn, organization_id, ISSUER, subject, name, role, "oidc")
+    }
+
+    fn account_names(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM accounts ORDER BY name").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn a_new_subject_gets_an_account_without_a_usable_password() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+
+        let account = sign_in(&mut conn, &organization_id, "sub-1", "alice", "Analyst").unwrap();
+        assert_eq!((account.name.as_str(), account.role.as_str()), ("alice", "Analyst"));
+        let (provider, issuer, subject): (String, String, String) = conn.query_row(
+            "SELECT auth_provider, external_issuer, external_subject FROM accounts WHERE id = ?1",
+            params![account.id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert_eq!((provider.as_str(), issuer.as_str(), subject.as_str()), ("oidc", ISSUER, "sub-1"));
+        assert!(!Account::password_matches(&account.password, ""));
+
+        assert!(matches!(sign_in(&mut conn, &organization_id, "sub-2", "bob", "Nonexistent"), Err(AccountError::InvalidRole)));
+    }
+
+    #[test]
+    fn the_subject_decides_the_account_not_the_name() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let alice = sign_in(&mut conn, &organization_id, "sub-1", "alice", "Analyst").unwrap();
+
+        // Renamed at the provider, and given another role
+        let renamed = sign_in(&mut conn, &organization_id, "sub-1", "alice-smith", "Admin").unwrap();
+        assert_eq!(renamed.id, alice.id);
+        assert_eq!((renamed.name.as_str(), renamed.role.as_str()), ("alice-smith", "Admin"));
+
+        // Another subject claiming a name in use gets nothing, whoever holds it
+        for name in ["alice-smith", "acme"] {
+            assert!(matches!(sign_in(&mut conn, &organization_id, "sub-2", name, "Analyst"), Err(AccountError::ValidationError(_))));
+        }
+
+        // A rename onto a taken name keeps the account's own
+        let kept = sign_in(&mut conn, &organization_id, "sub-1", "acme", "Analyst").unwrap();
+        assert_eq!((kept.id, kept.name.as_str()), (alice.id, "alice-smith"));
+        assert_eq!(account_names(&conn), vec!["acme", "alice-smith"]);
+    }
+
+    #[test]
+    fn accounts_from_before_subjects_were_stored_are_claimed_once() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        conn.execute(
+            "INSERT INTO accounts (id, organization_id, name, password, role, auth_provider) VALUES ('legacy', ?1, 'carol', 'x', 'Analyst', 'oidc')",
+            params![organization_id],
+        ).unwrap();
+
+        let claimed = sign_in(&mut conn, &organization_id, "sub-1", "carol", "Analyst").unwrap();
+        assert_eq!(claimed.id, "legacy");
+        assert!(matches!(sign_in(&mut conn, &organization_id, "sub-2", "carol", "Analyst"), Err(AccountError::ValidationError(_))));
+        assert_eq!(sign_in(&mut conn, &organization_id, "sub-1", "carol", "Analyst").unwrap().id, "legacy");
+    }
+}
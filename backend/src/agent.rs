@@ -1,9 +1,28 @@
-use rusqlite::{Error as SqliteError, OptionalExtension, params};
-use crate::database::establish_connection;
-use std::{fmt, net::IpAddr, str::FromStr};
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use std::{collections::HashMap, fmt, net::IpAddr, str::FromStr};
 use serde::{Serialize, Deserialize};
-use chrono::{DateTime, Utc};
+use sha2::{Sha256, Digest};
+use chrono::{DateTime, Duration, Utc};
+use rand::RngCore;
 use uuid::Uuid;
+use actix_web::rt;
+use log::{error, info, warn};
+use crate::security_event::{SecurityEventError, record_security_event};
+use crate::rules::{Detection, Levels, LogSource, Rule, RuleError, create_rule};
+use crate::audit::{AuditActor, AuditError, AuditTarget, record_audit, snapshot};
+use crate::database::{DbPool, run};
+use std::env;
+
+const KEY_PREFIX: &str = "agent_";
+// Leading characters of a key kept in clear, to find its agent without storing the key
+const KEY_LOOKUP_LEN: usize = KEY_PREFIX.len() + 8;
+// How long the key replaced by a rotation keeps working, so agents can pick up the new one
+const DEFAULT_GRACE_HOURS: i64 = 24;
+const MAX_GRACE_HOURS: i64 = 168;
+// Longest version or OS string an agent can report
+const MAX_REPORTED_LEN: usize = 100;
+// Security event raised for an agent that stopped sending heartbeats
+pub const AGENT_OFFLINE_EVENT: &str = "agent_offline";
 
 #[derive(Debug)]
 pub enum AgentError {
@@ -26,25 +45,146 @@ impl From<SqliteError> for AgentError {
     }
 }
 
+impl From<RuleError> for AgentError {
+    fn from(err: RuleError) -> Self {
+        match err {
+            RuleError::DatabaseError(err) => AgentError::DatabaseError(err),
+            err => AgentError::ValidationError(err.to_string()),
+        }
+    }
+}
+
+impl From<AuditError> for AgentError {
+    fn from(err: AuditError) -> Self {
+        match err {
+            AuditError::DatabaseError(err) => AgentError::DatabaseError(err),
+            AuditError::ValidationError(msg) => AgentError::ValidationError(msg),
+        }
+    }
+}
+
+impl From<SecurityEventError> for AgentError {
+    fn from(err: SecurityEventError) -> Self {
+        match err {
+            SecurityEventError::DatabaseError(err) => AgentError::DatabaseError(err),
+            SecurityEventError::ValidationError(msg) => AgentError::ValidationError(msg),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Agent {
     pub id: String,
-    pub api_key: String,
+    // The start of the agent's key. The key itself is only shown when issued
+    #[serde(default)]
+    pub key_prefix: String,
     pub host_id: String,
     pub account_id: String,
     pub ip_address: Option<String>,
     pub hostname: Option<String>,
     pub status: AgentStatus,
     pub last_seen: Option<DateTime<Utc>>,
+    // As reported by the agent's last heartbeat
+    pub version: Option<String>,
+    pub os: Option<String>,
+    pub queue_depth: Option<i64>,
+    pub dropped_events: Option<i64>,
+    pub revoked_at: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// What an agent reports about itself with each heartbeat
+#[derive(Debug, Default, Deserialize)]
+pub struct AgentHeartbeat {
+    pub version: Option<String>,
+    pub os: Option<String>,
+    // Events waiting in the agent's local buffer
+    pub queue_depth: Option<i64>,
+    // Events the agent has discarded since it started, e.g. because its buffer was full
+    pub dropped_events: Option<i64>,
+}
+
+impl AgentHeartbeat {
+    fn validate(&self) -> Result<(), AgentError> {
+        for (field, value) in [("version", &self.version), ("os", &self.os)] {
+            if value.as_ref().is_some_and(|v| v.len() > MAX_REPORTED_LEN) {
+                return Err(AgentError::ValidationError(format!("{} cannot be longer than {} characters", field, MAX_REPORTED_LEN)));
+            }
+        }
+        for (field, value) in [("queue_depth", self.queue_depth), ("dropped_events", self.dropped_events)] {
+            if value.is_some_and(|v| v < 0) {
+                return Err(AgentError::ValidationError(format!("{} cannot be negative", field)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub enum AgentStatus {
     Active,
     Inactive,
     Error,
 }
 
+impl From<String> for AgentStatus {
+    fn from(s: String) -> Self {
+        match s.to_lowercase().as_str() {
+            "active" => AgentStatus::Active,
+            "inactive" => AgentStatus::Inactive,
+            _ => AgentStatus::Error,
+        }
+    }
+}
+
+// A newly issued key, returned once
+#[derive(Debug, Serialize)]
+pub struct AgentKey {
+    pub agent_id: String,
+    pub api_key: String,
+    // When the key this one replaced stops working, if it still does
+    pub previous_key_expires_at: Option<String>,
+}
+
+pub fn hash_key(api_key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(api_key.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn key_prefix(api_key: &str) -> String {
+    api_key.chars().take(KEY_LOOKUP_LEN).collect()
+}
+
+fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", KEY_PREFIX, secret)
+}
+
+const AGENT_COLUMNS: &str = "id, key_prefix, host_id, account_id, ip_address, hostname, status, last_seen,
+    version, os, queue_depth, dropped_events, revoked_at";
+
+fn row_to_agent(row: &rusqlite::Row) -> Result<Agent, SqliteError> {
+    Ok(Agent {
+        id: row.get(0)?,
+        key_prefix: row.get(1)?,
+        host_id: row.get(2)?,
+        account_id: row.get(3)?,
+        ip_address: row.get(4)?,
+        hostname: row.get(5)?,
+        status: AgentStatus::from(row.get::<_, String>(6)?),
+        last_seen: row.get::<_, Option<String>>(7)?
+            .and_then(|ts| DateTime::parse_from_rfc3339(&ts).ok())
+            .map(|ts| ts.with_timezone(&Utc)),
+        version: row.get(8)?,
+        os: row.get(9)?,
+        queue_depth: row.get(10)?,
+        dropped_events: row.get(11)?,
+        revoked_at: row.get(12)?,
+    })
+}
+
 impl Agent {
     fn validate(&self) -> Result<(), AgentError> {
         if self.account_id.is_empty() {
@@ -65,14 +205,22 @@ impl Agent {
     }
 }
 
-pub fn register_agent(agent: &Agent) -> Result<(String, String), AgentError> {
+pub fn register_agent(conn: &Connection, agent: &Agent) -> Result<(String, String), AgentError> {
     agent.validate()?;
 
-    let conn = establish_connection()?;
     let id = Uuid::new_v4().to_string();
-    let api_key = Uuid::new_v4().to_string();
+    let api_key = generate_key();
+
+    let host_in_account: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM hosts WHERE id = ?1 AND account_id = ?2",
+        params![agent.host_id, agent.account_id],
+        |row| row.get(0),
+    )?;
+    if !host_in_account {
+        return Err(AgentError::ValidationError("Host not found for this account".to_string()));
+    }
 
-    if agent_exists(&agent.host_id)? {
+    if agent_exists(conn, &agent.host_id)? {
         return Err(AgentError::ValidationError(
             format!("An agent for host '{}' already exists.", agent.host_id)
         ));
@@ -80,21 +228,27 @@ pub fn register_agent(agent: &Agent) -> Result<(String, String), AgentError> {
 
     let new_agent = Agent {
         id: id.clone(),
-        api_key: api_key.clone(),
+        key_prefix: key_prefix(&api_key),
         host_id: agent.host_id.clone(),
         account_id: agent.account_id.clone(),
         ip_address: agent.ip_address.clone(),
         hostname: agent.hostname.clone(),
         status: AgentStatus::Active,
         last_seen: Some(Utc::now()),
+        version: None,
+        os: None,
+        queue_depth: None,
+        dropped_events: None,
+        revoked_at: None,
     };
 
     conn.execute(
-        "INSERT INTO agents (id, api_key, host_id, account_id, ip_address, hostname, status, last_seen) 
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        "INSERT INTO agents (id, key_prefix, key_hash, host_id, account_id, ip_address, hostname, status, last_seen) 
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
         params![
             new_agent.id,
-            new_agent.api_key,
+            new_agent.key_prefix,
+            hash_key(&api_key),
             new_agent.host_id,
             new_agent.account_id,
             new_agent.ip_address,
@@ -107,26 +261,122 @@ pub fn register_agent(agent: &Agent) -> Result<(String, String), AgentError> {
     Ok((id, api_key))
 }
 
-pub fn verify_agent_api_key(api_key: &str) -> Result<bool, AgentError> {
-    let conn = establish_connection()?;
-    let mut stmt = conn.prepare(
-        "SELECT status FROM agents WHERE api_key = ?1 AND status = 'Active'"
+// The agent a presented key belongs to, unless it was revoked: its current key, or the one it
+// replaced while the rotation's grace period lasts. Agents marked inactive for going silent
+// still authenticate so they can report back. Its account_id is the tenant for everything the
+// agent sends.
+// Failures are logged, and recorded as security events when the key names a known agent
+pub fn authenticate_agent(conn: &Connection, api_key: &str, ip: &str) -> Result<Option<Agent>, AgentError> {
+    if api_key.is_empty() {
+        return Ok(None);
+    }
+
+    let hash = hash_key(api_key);
+    let now = Utc::now().to_rfc3339();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, key_hash = ?2, previous_key_hash = ?2 AND previous_key_expires_at > ?3
+         FROM agents WHERE key_prefix = ?1 OR previous_key_prefix = ?1",
+        AGENT_COLUMNS
+    ))?;
+    let candidates = stmt.query_map(params![key_prefix(api_key), hash, now], |row| {
+        Ok((row_to_agent(row)?, row.get::<_, bool>(13)?, row.get::<_, Option<bool>>(14)?.unwrap_or(false)))
+    })?.collect::<Result<Vec<_>, _>>()?;
+
+    let matched = candidates.iter().position(|(_, current, previous)| *current || *previous);
+    let (agent, reason) = match matched {
+        Some(i) if candidates[i].0.revoked_at.is_none() => {
+            return Ok(candidates.into_iter().nth(i).map(|(agent, _, _)| agent));
+        }
+        Some(i) => (&candidates[i].0, "agent_revoked"),
+        None => match candidates.first() {
+            Some((agent, _, _)) => (agent, "key_mismatch"),
+            None => {
+                warn!("Agent authentication with an unknown key from {}", ip);
+                return Ok(None);
+            }
+        },
+    };
+
+    warn!("Agent authentication failed for agent {} from {} ({})", agent.id, ip, reason);
+    record_security_event(
+        conn,
+        &agent.account_id,
+        "agent_auth_failure",
+        Some(ip),
+        format!("Agent authentication failed for agent {} from {}", agent.id, ip),
+        HashMap::from([
+            ("agent_id".to_string(), agent.id.clone()),
+            ("host_id".to_string(), agent.host_id.clone()),
+            ("reason".to_string(), reason.to_string()),
+        ]),
     )?;
+    Ok(None)
+}
+
+// Issue a new key for an agent of the organization. The old key keeps working for the grace
+// period; a revoked agent gets a fresh key without one and becomes active again.
+// None when the agent isn't found
+pub fn rotate_agent_key(conn: &Connection, organization_id: &str, agent_id: &str, grace_hours: Option<i64>) -> Result<Option<AgentKey>, AgentError> {
+    if agent_id.is_empty() {
+        return Err(AgentError::ValidationError("Agent ID cannot be empty".to_string()));
+    }
+    let grace_hours = grace_hours.unwrap_or(DEFAULT_GRACE_HOURS);
+    if !(0..=MAX_GRACE_HOURS).contains(&grace_hours) {
+        return Err(AgentError::ValidationError(format!("Grace period must be between 0 and {} hours", MAX_GRACE_HOURS)));
+    }
 
-    let result = stmt.query_row(
-        params![api_key],
-        |_| Ok(true)
+    let current: Option<(String, String, Option<String>)> = conn.query_row(
+        "SELECT key_prefix, key_hash, revoked_at FROM agents WHERE id = ?1 AND account_id = ?2",
+        params![agent_id, organization_id],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
     ).optional()?;
+    let Some((old_prefix, old_hash, revoked_at)) = current else {
+        return Ok(None);
+    };
+
+    let api_key = generate_key();
+    let previous = (revoked_at.is_none() && grace_hours > 0)
+        .then(|| (old_prefix, old_hash, (Utc::now() + Duration::hours(grace_hours)).to_rfc3339()));
+    let (previous_prefix, previous_hash, previous_expires_at) = match previous {
+        Some((prefix, hash, expires_at)) => (Some(prefix), Some(hash), Some(expires_at)),
+        None => (None, None, None),
+    };
+
+    conn.execute(
+        "UPDATE agents SET key_prefix = ?1, key_hash = ?2, previous_key_prefix = ?3, previous_key_hash = ?4,
+            previous_key_expires_at = ?5, status = 'Active', revoked_at = NULL
+         WHERE id = ?6 AND account_id = ?7",
+        params![key_prefix(&api_key), hash_key(&api_key), previous_prefix, previous_hash, previous_expires_at, agent_id, organization_id],
+    )?;
+
+    Ok(Some(AgentKey {
+        agent_id: agent_id.to_string(),
+        api_key,
+        previous_key_expires_at: previous_expires_at,
+    }))
+}
 
-    Ok(result.unwrap_or(false))
+// Stop an agent of the organization from authenticating with any of its keys. The agent is
+// kept, marked inactive and revoked, until it gets a new key
+pub fn revoke_agent(conn: &Connection, organization_id: &str, agent_id: &str) -> Result<bool, AgentError> {
+    if agent_id.is_empty() {
+        return Err(AgentError::ValidationError("Agent ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "UPDATE agents SET status = 'Inactive', revoked_at = ?1, previous_key_prefix = NULL, previous_key_hash = NULL,
+            previous_key_expires_at = NULL
+         WHERE id = ?2 AND account_id = ?3",
+        params![Utc::now().to_rfc3339(), agent_id, organization_id],
+    )?;
+    Ok(affected_rows > 0)
 }
 
-fn agent_exists(host_id: &String) -> Result<bool, AgentError> {
+fn agent_exists(conn: &Connection, host_id: &String) -> Result<bool, AgentError> {
     if host_id.is_empty() {
         return Err(AgentError::ValidationError("Host ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
         "SELECT COUNT(*) FROM agents WHERE host_id = ?1"
     )?;
@@ -135,11 +385,307 @@ fn agent_exists(host_id: &String) -> Result<bool, AgentError> {
     Ok(count > 0)
 }
 
-pub fn update_agent_last_seen(agent_id: &str) -> Result<(), AgentError> {
-    let conn = establish_connection()?;
+// Store a heartbeat: the agent was seen now, and is active again if it had gone silent. Details
+// the heartbeat leaves out keep their last reported value
+pub fn record_heartbeat(conn: &Connection, agent_id: &str, heartbeat: &AgentHeartbeat) -> Result<(), AgentError> {
+    heartbeat.validate()?;
+
+    let was_offline: bool = conn.query_row(
+        "SELECT status = 'Inactive' FROM agents WHERE id = ?1",
+        params![agent_id],
+        |row| row.get(0),
+    )?;
+    if was_offline {
+        info!("Agent {} is reporting again", agent_id);
+    }
+
     conn.execute(
-        "UPDATE agents SET last_seen = ?1 WHERE id = ?2",
-        params![Utc::now().to_rfc3339(), agent_id],
+        "UPDATE agents SET last_seen = ?1, status = 'Active', version = COALESCE(?2, version), os = COALESCE(?3, os),
+            queue_depth = COALESCE(?4, queue_depth), dropped_events = COALESCE(?5, dropped_events)
+         WHERE id = ?6",
+        params![
+            Utc::now().to_rfc3339(),
+            heartbeat.version,
+            heartbeat.os,
+            heartbeat.queue_depth,
+            heartbeat.dropped_events,
+            agent_id,
+        ],
     )?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// The organization's agents with their last reported state, most recently seen first
+pub fn list_agents(conn: &Connection, organization_id: &str) -> Result<Vec<Agent>, AgentError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM agents WHERE account_id = ?1 ORDER BY last_seen DESC",
+        AGENT_COLUMNS
+    ))?;
+    let agents = stmt.query_map(params![organization_id], row_to_agent)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(agents)
+}
+
+// The rule that turns `agent_offline` events into alerts. Every organization gets it once, when
+// it is created or from migration 22, and can disable, tune or delete it like any other rule
+pub fn seed_offline_rule(conn: &Connection, organization_id: &str) -> Result<String, AgentError> {
+    let now = Utc::now();
+    let rule_id = create_rule(conn, &Rule {
+        id: String::new(),
+        account_id: organization_id.to_string(),
+        title: "Agent offline".to_string(),
+        status: "stable".to_string(),
+        description: "An agent stopped sending heartbeats, so its host may no longer be monitored".to_string(),
+        ref_list: Vec::new(),
+        tags: vec!["siem.agent".to_string()],
+        author: "SIEM".to_string(),
+        date: now.format("%Y-%m-%d %H:%M:%S").to_string(),
+        logsource: LogSource { category: "agent".to_string(), product: "siem".to_string() },
+        detection: Detection {
+            selection: HashMap::from([("event_type".to_string(), serde_json::Value::from(AGENT_OFFLINE_EVENT))]),
+            condition: "selection".to_string(),
+        },
+        fields: vec!["agent_id".to_string(), "host_id".to_string(), "last_seen".to_string()],
+        falsepositives: vec!["Host shut down or agent stopped on purpose".to_string()],
+        level: Levels::High,
+        enabled: true,
+        created_at: now.to_rfc3339(),
+        updated_at: now.to_rfc3339(),
+    })?;
+
+    let after = snapshot(conn, organization_id, AuditTarget::Rule, &rule_id)?;
+    record_audit(conn, &AuditActor::system(organization_id), "rule.create", AuditTarget::Rule, &rule_id, None, after)?;
+    info!("Added the agent offline rule for organization {}", organization_id);
+    Ok(rule_id)
+}
+
+// Mark active agents that haven't sent a heartbeat within `silence` as inactive, and raise an
+// `agent_offline` security event for each, which the organization's rules turn into an alert
+pub fn mark_offline_agents(conn: &Connection, silence: Duration) -> Result<Vec<Agent>, AgentError> {
+    let cutoff = (Utc::now() - silence).to_rfc3339();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM agents WHERE status = 'Active' AND revoked_at IS NULL AND (last_seen IS NULL OR last_seen < ?1)",
+        AGENT_COLUMNS
+    ))?;
+    let silent = stmt.query_map(params![cutoff], row_to_agent)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for agent in &silent {
+        conn.execute("UPDATE agents SET status = 'Inactive' WHERE id = ?1", params![agent.id])?;
+
+        let last_seen = agent.last_seen.map(|ts| ts.to_rfc3339()).unwrap_or_else(|| "never".to_string());
+        warn!("Agent {} for host {} has been silent since {}", agent.id, agent.host_id, last_seen);
+        record_security_event(
+            conn,
+            &agent.account_id,
+            AGENT_OFFLINE_EVENT,
+            agent.ip_address.as_deref(),
+            format!("Agent {} for host {} has sent no heartbeat since {}", agent.id, agent.host_id, last_seen),
+            HashMap::from([
+                ("agent_id".to_string(), agent.id.clone()),
+                ("host_id".to_string(), agent.host_id.clone()),
+                ("hostname".to_string(), agent.hostname.clone().unwrap_or_default()),
+                ("last_seen".to_string(), last_seen),
+            ]),
+        )?;
+    }
+    Ok(silent)
+}
+
+fn env_secs(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
+}
+
+// Look for silent agents every `AGENT_CHECK_INTERVAL_SECS` (default a minute). An agent is
+// offline after `AGENT_OFFLINE_SECS` (default five minutes) without a heartbeat
+pub fn spawn_offline_check(pool: DbPool) {
+    let interval = std::time::Duration::from_secs(env_secs("AGENT_CHECK_INTERVAL_SECS", 60) as u64);
+    let silence = Duration::seconds(env_secs("AGENT_OFFLINE_SECS", 300));
+
+    rt::spawn(async move {
+        loop {
+            rt::time::sleep(interval).await;
+            match run(&pool, move |conn| mark_offline_agents(conn, silence)).await {
+                Ok(silent) if silent.is_empty() => {},
+                Ok(silent) => info!("Marked {} silent agents offline", silent.len()),
+                Err(e) => error!("Agent offline check failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{agent, host, organization, test_pool};
+
+    // A new organization with one host and its agent: the organization and agent IDs and the key
+    fn organization_with_agent(conn: &Connection) -> (String, String, String) {
+        let (organization_id, _) = organization(conn, "bob");
+        let host_id = host(conn, &organization_id, "web");
+        let (agent_id, api_key) = agent(conn, &organization_id, &host_id);
+        (organization_id, agent_id, api_key)
+    }
+
+    fn offline_rules(conn: &Connection, organization_id: &str) -> i64 {
+        conn.query_row(
+            "SELECT COUNT(*) FROM rules WHERE account_id = ?1 AND json_extract(detection, '$.selection.event_type') = ?2",
+            params![organization_id, AGENT_OFFLINE_EVENT],
+            |row| row.get(0),
+        ).unwrap()
+    }
+
+    fn go_silent(conn: &Connection, agent_id: &str) {
+        let last_seen = (Utc::now() - Duration::hours(1)).to_rfc3339();
+        conn.execute("UPDATE agents SET last_seen = ?1 WHERE id = ?2", params![last_seen, agent_id]).unwrap();
+    }
+
+    #[test]
+    fn new_organizations_get_the_offline_rule_with_an_audit_entry() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, _, _) = organization_with_agent(&conn);
+
+        assert_eq!(offline_rules(&conn, &organization_id), 1);
+        let (actor, target_type): (Option<String>, String) = conn.query_row(
+            "SELECT actor_id, target_type FROM audit_log WHERE organization_id = ?1 AND action = 'rule.create'",
+            params![organization_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).unwrap();
+        assert_eq!(actor, None);
+        assert_eq!(target_type, "rule");
+    }
+
+    #[test]
+    fn offline_check_raises_an_event_without_restoring_a_deleted_rule() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, agent_id, _) = organization_with_agent(&conn);
+        conn.execute("DELETE FROM rules WHERE account_id = ?1", params![organization_id]).unwrap();
+        go_silent(&conn, &agent_id);
+
+        let silent = mark_offline_agents(&conn, Duration::minutes(5)).unwrap();
+        assert_eq!(silent.iter().map(|a| a.id.as_str()).collect::<Vec<_>>(), vec![agent_id.as_str()]);
+        assert_eq!(list_agents(&conn, &organization_id).unwrap()[0].status, AgentStatus::Inactive);
+        let events: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM security_events WHERE account_id = ?1 AND event_type = ?2",
+            params![organization_id, AGENT_OFFLINE_EVENT],
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(events, 1);
+        assert_eq!(offline_rules(&conn, &organization_id), 0);
+
+        // Already inactive, so the next check leaves it alone
+        assert!(mark_offline_agents(&conn, Duration::minutes(5)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn offline_check_skips_recent_and_revoked_agents() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, agent_id, _) = organization_with_agent(&conn);
+        assert!(mark_offline_agents(&conn, Duration::minutes(5)).unwrap().is_empty());
+
+        go_silent(&conn, &agent_id);
+        assert!(revoke_agent(&conn, &organization_id, &agent_id).unwrap());
+        assert!(mark_offline_agents(&conn, Duration::minutes(5)).unwrap().is_empty());
+    }
+
+    // Failed agent logins recorded for the organization, by reason
+    fn auth_failures(conn: &Connection, organization_id: &str, reason: &str) -> i64 {
+        conn.query_row(
+            "SELECT COUNT(*) FROM security_events WHERE account_id = ?1 AND event_type = 'agent_auth_failure'
+               AND json_extract(log_data, '$.extensions.reason') = ?2",
+            params![organization_id, reason],
+            |row| row.get(0),
+        ).unwrap()
+    }
+
+    fn authenticates(conn: &Connection, api_key: &str) -> bool {
+        authenticate_agent(conn, api_key, "10.0.0.1").unwrap().is_some()
+    }
+
+    #[test]
+    fn keys_are_stored_hashed_and_checked_in_full() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, agent_id, api_key) = organization_with_agent(&conn);
+
+        let agent = authenticate_agent(&conn, &api_key, "10.0.0.1").unwrap().unwrap();
+        assert_eq!((agent.id.as_str(), agent.account_id.as_str()), (agent_id.as_str(), organization_id.as_str()));
+        let stored: String = conn.query_row("SELECT key_hash FROM agents WHERE id = ?1", params![agent_id], |row| row.get(0)).unwrap();
+        assert_eq!(stored, hash_key(&api_key));
+
+        // Same lookup prefix, wrong secret
+        let forged = format!("{}{}", key_prefix(&api_key), "0".repeat(api_key.len() - KEY_LOOKUP_LEN));
+        assert!(!authenticates(&conn, &forged));
+        assert_eq!(auth_failures(&conn, &organization_id, "key_mismatch"), 1);
+        assert!(!authenticates(&conn, "agent_unknown"));
+        assert!(!authenticates(&conn, ""));
+        assert_eq!(auth_failures(&conn, &organization_id, "key_mismatch"), 1);
+    }
+
+    #[test]
+    fn the_old_key_works_until_the_grace_period_ends() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, agent_id, old_key) = organization_with_agent(&conn);
+
+        let rotated = rotate_agent_key(&conn, &organization_id, &agent_id, None).unwrap().unwrap();
+        assert!(rotated.previous_key_expires_at.is_some());
+        assert!(authenticates(&conn, &rotated.api_key));
+        assert!(authenticates(&conn, &old_key));
+
+        let expired = (Utc::now() - Duration::seconds(1)).to_rfc3339();
+        conn.execute("UPDATE agents SET previous_key_expires_at = ?1 WHERE id = ?2", params![expired, agent_id]).unwrap();
+        assert!(!authenticates(&conn, &old_key));
+        assert!(authenticates(&conn, &rotated.api_key));
+        assert_eq!(auth_failures(&conn, &organization_id, "key_mismatch"), 1);
+
+        // Without a grace period the replaced key stops at once
+        let again = rotate_agent_key(&conn, &organization_id, &agent_id, Some(0)).unwrap().unwrap();
+        assert!(again.previous_key_expires_at.is_none());
+        assert!(!authenticates(&conn, &rotated.api_key));
+        assert!(authenticates(&conn, &again.api_key));
+    }
+
+    #[test]
+    fn rotation_is_limited_to_the_organization_and_the_maximum_grace() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (_, agent_id, api_key) = organization_with_agent(&conn);
+        let (other_organization, _) = organization(&conn, "globex");
+
+        assert!(rotate_agent_key(&conn, &other_organization, &agent_id, None).unwrap().is_none());
+        assert!(!revoke_agent(&conn, &other_organization, &agent_id).unwrap());
+        let result = rotate_agent_key(&conn, &other_organization, &agent_id, Some(MAX_GRACE_HOURS + 1));
+        assert!(matches!(result, Err(AgentError::ValidationError(_))));
+        assert!(authenticates(&conn, &api_key));
+    }
+
+    #[test]
+    fn a_revoked_agent_is_refused_until_it_gets_a_new_key() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, agent_id, old_key) = organization_with_agent(&conn);
+        let rotated = rotate_agent_key(&conn, &organization_id, &agent_id, None).unwrap().unwrap();
+
+        assert!(revoke_agent(&conn, &organization_id, &agent_id).unwrap());
+        assert!(!authenticates(&conn, &rotated.api_key));
+        assert!(!authenticates(&conn, &old_key));
+        // Revoking drops the replaced key altogether, so it is just unknown
+        assert_eq!(auth_failures(&conn, &organization_id, "agent_revoked"), 1);
+        assert_eq!(auth_failures(&conn, &organization_id, "key_mismatch"), 0);
+
+        // A revoked agent's new key comes without a grace period for the old ones
+        let fresh = rotate_agent_key(&conn, &organization_id, &agent_id, None).unwrap().unwrap();
+        assert!(fresh.previous_key_expires_at.is_none());
+        assert!(authenticates(&conn, &fresh.api_key));
+        assert!(!authenticates(&conn, &rotated.api_key));
+        assert_eq!(list_agents(&conn, &organization_id).unwrap()[0].status, AgentStatus::Active);
+    }
+}
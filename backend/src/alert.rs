@@ -1,5 +1,4 @@
-use rusqlite::{Error as SqliteError, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, params};
 use chrono::{Utc, DateTime, ParseError};
 use serde::{Deserialize, Serialize};
 use rusqlite::OptionalExtension;
@@ -35,6 +34,7 @@ impl From<String> for AlertSeverity {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
 pub enum AlertError {
     DatabaseError(SqliteError),
     ValidationError(String),
@@ -92,7 +92,7 @@ impl Alert {
     }
 }
 
-pub fn create_alert(alert: &Alert) -> Result<Alert, AlertError> {
+pub fn create_alert(conn: &Connection, alert: &Alert) -> Result<Alert, AlertError> {
     let new_alert = Alert {
         id: Uuid::new_v4().to_string(),
         rule_id: alert.rule_id.clone(),
@@ -105,7 +105,6 @@ pub fn create_alert(alert: &Alert) -> Result<Alert, AlertError> {
     };
     new_alert.validate(&new_alert)?;
 
-    let conn = establish_connection()?;
     conn.execute(
         "INSERT INTO alerts (id, rule_id, account_id, severity, message, acknowledged, created_at) 
          VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
@@ -123,18 +122,20 @@ pub fn create_alert(alert: &Alert) -> Result<Alert, AlertError> {
     Ok(new_alert)
 }
 
-pub fn get_alert(alert_id: &String) -> Result<Option<Alert>, AlertError> {
+pub fn get_alert(conn: &Connection, account_id: &str, alert_id: &String) -> Result<Option<Alert>, AlertError> {
+    if account_id.is_empty() {
+        return Err(AlertError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if alert_id.is_empty() {
         return Err(AlertError::ValidationError("Alert ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
         "SELECT id, rule_id, account_id, severity, message, acknowledged, case_id, created_at 
-         FROM alerts WHERE id = ?1"
+         FROM alerts WHERE id = ?1 AND account_id = ?2"
     )?;
 
-    let alert = stmt.query_row(params![alert_id], |row| {
+    let alert = stmt.query_row(params![alert_id, account_id], |row| {
         Ok(Alert {
             id: row.get(0)?,
             rule_id: row.get(1)?,
@@ -150,12 +151,11 @@ pub fn get_alert(alert_id: &String) -> Result<Option<Alert>, AlertError> {
     Ok(alert)
 }
 
-pub fn list_alerts(acct_id: &String) -> Result<Vec<Alert>, AlertError> {
+pub fn list_alerts(conn: &Connection, acct_id: &String) -> Result<Vec<Alert>, AlertError> {
     if acct_id.is_empty() {
         return Err(AlertError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
         "SELECT id, rule_id, account_id, severity, message, acknowledged, created_at, case_id 
          FROM alerts WHERE account_id = ?1 
@@ -179,43 +179,49 @@ pub fn list_alerts(acct_id: &String) -> Result<Vec<Alert>, AlertError> {
     Ok(alerts?)
 }
 
-pub fn delete_alert(alert_id: &String) -> Result<bool, AlertError> {
+pub fn delete_alert(conn: &Connection, account_id: &str, alert_id: &String) -> Result<bool, AlertError> {
+    if account_id.is_empty() {
+        return Err(AlertError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if alert_id.is_empty() {
         return Err(AlertError::ValidationError("Alert ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let affected_rows = conn.execute(
-        "DELETE FROM alerts WHERE id = ?1",
-        params![alert_id],
+        "DELETE FROM alerts WHERE id = ?1 AND account_id = ?2",
+        params![alert_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
 }
 
-pub fn acknowledge_alert(alert_id: &String) -> Result<bool, AlertError> {
+pub fn acknowledge_alert(conn: &Connection, account_id: &str, alert_id: &String) -> Result<bool, AlertError> {
+    if account_id.is_empty() {
+        return Err(AlertError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if alert_id.is_empty() {
         return Err(AlertError::ValidationError("Alert ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let affected_rows = conn.execute(
-        "UPDATE alerts SET acknowledged = TRUE WHERE id = ?1",
-        params![alert_id],
+        "UPDATE alerts SET acknowledged = TRUE WHERE id = ?1 AND account_id = ?2",
+        params![alert_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
 }
 
-pub fn update_alert_case_id(alert_id: &str, case_id: &str) -> Result<bool, AlertError> {
+pub fn update_alert_case_id(conn: &Connection, account_id: &str, alert_id: &str, case_id: &str) -> Result<bool, AlertError> {
+    if account_id.is_empty() {
+        return Err(AlertError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if alert_id.is_empty() {
         return Err(AlertError::ValidationError("Alert ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let affected_rows = conn.execute(
-        "UPDATE alerts SET case_id = ?2 WHERE id = ?1",
-        params![alert_id, case_id],
+        "UPDATE alerts SET case_id = ?2 WHERE id = ?1 AND account_id = ?3",
+        params![alert_id, case_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
@@ -0,0 +1,347 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use uuid::Uuid;
+use crate::rbac::{Permission, RbacError, account_permissions};
+use std::fmt;
+
+const TOKEN_PREFIX: &str = "siem_";
+const DEFAULT_TOKEN_DAYS: i64 = 90;
+const MAX_TOKEN_DAYS: i64 = 365;
+
+#[derive(Debug)]
+pub enum ApiTokenError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for ApiTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiTokenError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            ApiTokenError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for ApiTokenError {
+    fn from(err: SqliteError) -> Self {
+        ApiTokenError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for ApiTokenError {
+    fn from(err: serde_json::Error) -> Self {
+        ApiTokenError::ValidationError(err.to_string())
+    }
+}
+
+impl From<RbacError> for ApiTokenError {
+    fn from(err: RbacError) -> Self {
+        match err {
+            RbacError::DatabaseError(err) => ApiTokenError::DatabaseError(err),
+            RbacError::ValidationError(msg) => ApiTokenError::ValidationError(msg),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub account_id: String,
+    pub organization_id: String,
+    pub name: String,
+    // The first characters of the token, so users can tell their tokens apart
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+    pub expires_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<Permission>,
+    pub expires_in_days: Option<i64>,
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}{}", TOKEN_PREFIX, secret)
+}
+
+fn row_to_token(row: &rusqlite::Row) -> Result<(ApiToken, String), SqliteError> {
+    Ok((
+        ApiToken {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            organization_id: row.get(2)?,
+            name: row.get(3)?,
+            prefix: row.get(4)?,
+            scopes: Vec::new(),
+            expires_at: row.get(6)?,
+            last_used_at: row.get(7)?,
+            revoked_at: row.get(8)?,
+            created_at: row.get(9)?,
+        },
+        row.get(5)?,
+    ))
+}
+
+// Returns the stored token and its secret. Only a hash is kept, so the secret can't be shown again.
+// Scopes are limited to what the account's role grants at creation time
+pub fn create_api_token(
+    conn: &Connection,
+    account_id: &str,
+    organization_id: &str,
+    new_token: &NewApiToken,
+) -> Result<(ApiToken, String), ApiTokenError> {
+    let name = new_token.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Err(ApiTokenError::ValidationError("Token name must be between 1 and 100 characters".to_string()));
+    }
+    if new_token.scopes.is_empty() {
+        return Err(ApiTokenError::ValidationError("A token needs at least one scope".to_string()));
+    }
+    let days = new_token.expires_in_days.unwrap_or(DEFAULT_TOKEN_DAYS);
+    if !(1..=MAX_TOKEN_DAYS).contains(&days) {
+        return Err(ApiTokenError::ValidationError(format!("Tokens must expire within 1 to {} days", MAX_TOKEN_DAYS)));
+    }
+
+    let granted = account_permissions(conn, account_id)?;
+    if let Some(scope) = new_token.scopes.iter().find(|scope| !granted.contains(scope)) {
+        return Err(ApiTokenError::ValidationError(format!(
+            "Scope {} is not granted by your role",
+            serde_json::to_string(scope)?
+        )));
+    }
+
+    let mut scopes: Vec<Permission> = Vec::new();
+    for scope in &new_token.scopes {
+        if !scopes.contains(scope) {
+            scopes.push(*scope);
+        }
+    }
+
+    let secret = generate_token();
+    let now = Utc::now();
+    let token = ApiToken {
+        id: Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        organization_id: organization_id.to_string(),
+        name: name.to_string(),
+        prefix: secret[..TOKEN_PREFIX.len() + 8].to_string(),
+        scopes,
+        expires_at: (now + Duration::days(days)).to_rfc3339(),
+        last_used_at: None,
+        revoked_at: None,
+        created_at: now.to_rfc3339(),
+    };
+
+    conn.execute(
+        "INSERT INTO api_tokens (id, account_id, organization_id, name, prefix, token_hash, scopes, expires_at, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            token.id,
+            token.account_id,
+            token.organization_id,
+            token.name,
+            token.prefix,
+            hash_token(&secret),
+            serde_json::to_string(&token.scopes)?,
+            token.expires_at,
+            token.created_at,
+        ],
+    )?;
+
+    Ok((token, secret))
+}
+
+pub fn list_api_tokens(conn: &Connection, account_id: &str) -> Result<Vec<ApiToken>, ApiTokenError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, organization_id, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+        FROM api_tokens WHERE account_id = ?1 ORDER BY created_at DESC"
+    )?;
+    let rows = stmt.query_map(params![account_id], row_to_token)?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut tokens = Vec::new();
+    for (mut token, scopes) in rows {
+        token.scopes = serde_json::from_str(&scopes)?;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Revoked tokens are kept so the listing shows when they stopped working
+pub fn revoke_api_token(conn: &Connection, account_id: &str, id: &str) -> Result<bool, ApiTokenError> {
+    if id.is_empty() {
+        return Err(ApiTokenError::ValidationError("Token ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "UPDATE api_tokens SET revoked_at = ?1 WHERE id = ?2 AND account_id = ?3 AND revoked_at IS NULL",
+        params![Utc::now().to_rfc3339(), id, account_id],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+// Look up a presented token. Unknown, revoked and expired tokens all return None
+pub fn verify_api_token(conn: &Connection, secret: &str) -> Result<Option<ApiToken>, ApiTokenError> {
+    if !secret.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let row = conn.query_row(
+        "SELECT id, account_id, organization_id, name, prefix, scopes, expires_at, last_used_at, revoked_at, created_at
+        FROM api_tokens WHERE token_hash = ?1 AND revoked_at IS NULL AND expires_at > ?2",
+        params![hash_token(secret), now],
+        row_to_token,
+    ).optional()?;
+
+    match row {
+        Some((mut token, scopes)) => {
+            token.scopes = serde_json::from_str(&scopes)?;
+            conn.execute("UPDATE api_tokens SET last_used_at = ?1 WHERE id = ?2", params![now, token.id])?;
+            token.last_used_at = Some(now);
+            Ok(Some(token))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test::{TestRequest, init_service, try_call_service};
+    use actix_web::http::StatusCode;
+    use crate::rbac::{Role, RequirePermission, create_role};
+    use crate::test_support::{api_token, organization, test_pool};
+
+    fn new_token(scopes: &[Permission], expires_in_days: Option<i64>) -> NewApiToken {
+        NewApiToken { name: "ci".to_string(), scopes: scopes.to_vec(), expires_in_days }
+    }
+
+    // Middleware refusals come back as errors; the server would answer with their status
+    async fn status<S, R, B>(app: &S, req: R) -> StatusCode
+    where
+        S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    {
+        match try_call_service(app, req).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    fn analyst(conn: &Connection) -> (String, String) {
+        let (organization_id, account_id) = organization(conn, "acme");
+        conn.execute("UPDATE accounts SET role = 'Analyst' WHERE id = ?1", params![account_id]).unwrap();
+        (organization_id, account_id)
+    }
+
+    #[test]
+    fn scopes_are_limited_to_the_role() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = analyst(&conn);
+
+        let result = create_api_token(&conn, &account_id, &organization_id, &new_token(&[Permission::ManageUsers], None));
+        assert!(matches!(result, Err(ApiTokenError::ValidationError(_))));
+        for days in [0, MAX_TOKEN_DAYS + 1] {
+            let result = create_api_token(&conn, &account_id, &organization_id, &new_token(&[Permission::ReadLogs], Some(days)));
+            assert!(matches!(result, Err(ApiTokenError::ValidationError(_))));
+        }
+
+        let (token, secret) = create_api_token(&conn, &account_id, &organization_id, &new_token(&[Permission::ReadLogs, Permission::ReadLogs], None)).unwrap();
+        assert_eq!(token.scopes, vec![Permission::ReadLogs]);
+        assert!(secret.starts_with(&token.prefix));
+        let stored: String = conn.query_row("SELECT token_hash FROM api_tokens WHERE id = ?1", params![token.id], |row| row.get(0)).unwrap();
+        assert_ne!(stored, secret);
+    }
+
+    #[test]
+    fn only_live_tokens_verify() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = organization(&conn, "acme");
+        let (_, other_account) = organization(&conn, "globex");
+        let live = api_token(&conn, &account_id, &organization_id, &[Permission::ReadLogs]);
+        let expired = api_token(&conn, &account_id, &organization_id, &[Permission::ReadLogs]);
+        let revoked = api_token(&conn, &account_id, &organization_id, &[Permission::ReadLogs]);
+
+        let token = verify_api_token(&conn, &live).unwrap().unwrap();
+        assert_eq!((token.account_id.as_str(), token.organization_id.as_str()), (account_id.as_str(), organization_id.as_str()));
+        assert!(token.last_used_at.is_some());
+
+        conn.execute("UPDATE api_tokens SET expires_at = '2000-01-01T00:00:00+00:00' WHERE token_hash = ?1", params![hash_token(&expired)]).unwrap();
+        assert!(verify_api_token(&conn, &expired).unwrap().is_none());
+
+        let revoked_id = verify_api_token(&conn, &revoked).unwrap().unwrap().id;
+        assert!(!revoke_api_token(&conn, &other_account, &revoked_id).unwrap());
+        assert!(revoke_api_token(&conn, &account_id, &revoked_id).unwrap());
+        assert!(verify_api_token(&conn, &revoked).unwrap().is_none());
+
+        assert!(verify_api_token(&conn, &format!("{}{}", TOKEN_PREFIX, "0".repeat(64))).unwrap().is_none());
+        assert!(verify_api_token(&conn, "not-a-token").unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn bearer_requests_need_both_the_scope_and_the_role() {
+        let pool = test_pool();
+        let (organization_id, account_id, secret) = {
+            let conn = pool.get().unwrap();
+            let (organization_id, account_id) = analyst(&conn);
+            let secret = api_token(&conn, &account_id, &organization_id, &[Permission::ReadHosts]);
+            (organization_id, account_id, secret)
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .service(
+                    web::resource("/hosts")
+                        .wrap(RequirePermission::new(Permission::ReadHosts, Permission::ManageHosts))
+                        .route(web::get().to(HttpResponse::Ok))
+                        .route(web::post().to(HttpResponse::Ok))
+                )
+        ).await;
+        let bearer = format!("Bearer {}", secret);
+        let request = |method: fn() -> TestRequest, bearer: &str| {
+            method().uri("/hosts").insert_header(("Authorization", bearer.to_string())).to_request()
+        };
+
+        assert_eq!(status(&app, request(TestRequest::get, &bearer)).await, StatusCode::OK);
+        // Analysts may not manage hosts, and the token couldn't carry that scope anyway
+        assert_eq!(status(&app, request(TestRequest::post, &bearer)).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&app, request(TestRequest::get, "Bearer siem_unknown")).await, StatusCode::UNAUTHORIZED);
+
+        // Losing the permission from the role takes it from the token too
+        {
+            let conn = pool.get().unwrap();
+            let role = Role {
+                name: "Case Clerk".to_string(),
+                description: String::new(),
+                permissions: vec![Permission::ReadCases],
+                builtin: false,
+                created_at: None,
+                updated_at: None,
+            };
+            create_role(&conn, &organization_id, &role).unwrap();
+            conn.execute("UPDATE accounts SET role = 'Case Clerk' WHERE id = ?1", params![account_id]).unwrap();
+        }
+        assert_eq!(status(&app, request(TestRequest::get, &bearer)).await, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,469 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use chrono::{NaiveDate, Utc};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+use log::{info, warn};
+use crate::integrity::mark_purged;
+use std::env;
+use std::fmt;
+
+// Logs are read from the hot table in chunks so a large archive run stays bounded in memory
+const ARCHIVE_CHUNK: usize = 500;
+const ZSTD_LEVEL: i32 = 9;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
+pub enum ArchiveError {
+    DatabaseError(SqliteError),
+    IoError(io::Error),
+    SerializationError(String),
+    ValidationError(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            ArchiveError::IoError(err) => write!(f, "IO error: {}", err),
+            ArchiveError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
+            ArchiveError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for ArchiveError {
+    fn from(err: SqliteError) -> Self {
+        ArchiveError::DatabaseError(err)
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        ArchiveError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(err: serde_json::Error) -> Self {
+        ArchiveError::SerializationError(err.to_string())
+    }
+}
+
+// One line of an archive file: the full `logs` row
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchivedLog {
+    id: String,
+    hash: String,
+    account_id: String,
+    host_id: String,
+    timestamp: Option<String>,
+    log_data: String,
+    created_at: String,
+    #[serde(default = "default_occurrence_count")]
+    occurrence_count: i64,
+    #[serde(default)]
+    last_seen: Option<String>,
+}
+
+// Archives written before occurrence counts existed hold one occurrence per line
+fn default_occurrence_count() -> i64 {
+    1
+}
+
+// Manifest entry for one archive file: the logs of one account and day moved by one archive run
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub account_id: String,
+    pub day: String,
+    pub path: String,
+    pub log_count: i64,
+    pub size_bytes: i64,
+    pub sha256: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct RestoreReport {
+    pub days: i64,
+    pub restored: i64,
+    pub duplicates: i64,
+}
+
+fn archive_root() -> PathBuf {
+    PathBuf::from(env::var("ARCHIVE_DIR").unwrap_or_else(|_| "./archive".to_string()))
+}
+
+fn validate_account_id(account_id: &str) -> Result<(), ArchiveError> {
+    if account_id.is_empty() {
+        return Err(ArchiveError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    // The account ID becomes a directory name
+    if account_id.contains(['/', '\\']) || account_id.contains("..") {
+        return Err(ArchiveError::ValidationError("Invalid account ID".to_string()));
+    }
+    Ok(())
+}
+
+// Archives are time-partitioned as <ARCHIVE_DIR>/<account_id>/<YYYY-MM-DD>/<segment>.ndjson.zst,
+// with a new segment for every run that archives logs from that day
+fn segment_path(account_id: &str, day: &str) -> PathBuf {
+    archive_root().join(account_id).join(day).join(format!("{}.ndjson.zst", Uuid::new_v4()))
+}
+
+fn file_sha256(path: &PathBuf) -> Result<String, ArchiveError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Write logs to a new segment file. Existing files are never touched, so a manifest entry
+// always matches the file it names
+fn write_segment(path: &PathBuf, logs: &[ArchivedLog]) -> Result<(), ArchiveError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let file = OpenOptions::new().create_new(true).write(true).open(path)?;
+    let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+    for log in logs {
+        serde_json::to_writer(&mut encoder, log)?;
+        encoder.write_all(b"\n")?;
+    }
+    let file = encoder.finish()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Move the given logs into cold storage. Each chunk's segments are written and synced, then
+// recorded in the manifest and the rows deleted in one transaction. A segment whose
+// transaction doesn't commit is removed, or at worst left behind unlisted; its logs are still
+// in `logs`, so there is never a gap and never a manifest entry that doesn't match its file
+pub fn archive_logs(conn: &mut Connection, account_id: &str, log_ids: &[String]) -> Result<i64, ArchiveError> {
+    validate_account_id(account_id)?;
+
+    let mut archived = 0;
+    for chunk in log_ids.chunks(ARCHIVE_CHUNK) {
+        let mut written = Vec::new();
+        match archive_chunk(conn, account_id, chunk, &mut written) {
+            Ok(count) => archived += count,
+            Err(err) => {
+                for path in written {
+                    if let Err(e) = fs::remove_file(&path) {
+                        warn!("Failed to remove uncommitted archive segment {}: {}", path.display(), e);
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    if archived > 0 {
+        info!("Archived {} logs for account {}", archived, account_id);
+    }
+    Ok(archived)
+}
+
+// Archive one chunk of logs, adding every segment file it creates to `written`
+fn archive_chunk(conn: &mut Connection, account_id: &str, chunk: &[String], written: &mut Vec<PathBuf>) -> Result<i64, ArchiveError> {
+    let tx = conn.transaction()?;
+    let mut archived = 0;
+    let mut by_day: BTreeMap<String, Vec<ArchivedLog>> = BTreeMap::new();
+    {
+        let mut stmt = tx.prepare(
+            "SELECT id, hash, account_id, host_id, timestamp, log_data, created_at, date(created_at),
+                    occurrence_count, last_seen
+             FROM logs WHERE id = ?1 AND account_id = ?2"
+        )?;
+        for id in chunk {
+            let row = stmt.query_row(params![id, account_id], |row| {
+                Ok((row.get::<_, String>(7)?, ArchivedLog {
+                    id: row.get(0)?,
+                    hash: row.get(1)?,
+                    account_id: row.get(2)?,
+                    host_id: row.get(3)?,
+                    timestamp: row.get(4)?,
+                    log_data: row.get(5)?,
+                    created_at: row.get(6)?,
+                    occurrence_count: row.get(8)?,
+                    last_seen: row.get(9)?,
+                }))
+            }).optional()?;
+            if let Some((day, log)) = row {
+                by_day.entry(day).or_default().push(log);
+            }
+        }
+    }
+
+    for (day, logs) in &by_day {
+        let path = segment_path(account_id, day);
+        written.push(path.clone());
+        write_segment(&path, logs)?;
+
+        let size_bytes = fs::metadata(&path)?.len() as i64;
+        tx.execute(
+            "INSERT INTO archive_manifest (account_id, day, path, log_count, size_bytes, sha256, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                account_id,
+                day,
+                path.to_string_lossy(),
+                logs.len() as i64,
+                size_bytes,
+                file_sha256(&path)?,
+                Utc::now().to_rfc3339(),
+            ],
+        )?;
+
+        let mut delete = tx.prepare("DELETE FROM logs WHERE id = ?1")?;
+        for log in logs {
+            archived += delete.execute(params![log.id])? as i64;
+        }
+        let ids: Vec<String> = logs.iter().map(|log| log.id.clone()).collect();
+        mark_purged(&tx, &ids)?;
+    }
+    tx.commit()?;
+    Ok(archived)
+}
+
+pub fn list_archives(conn: &Connection, account_id: &str) -> Result<Vec<ArchiveManifest>, ArchiveError> {
+    validate_account_id(account_id)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT account_id, day, path, log_count, size_bytes, sha256, updated_at
+         FROM archive_manifest WHERE account_id = ?1
+         ORDER BY day, updated_at"
+    )?;
+
+    let archives_iter = stmt.query_map(params![account_id], |row| {
+        Ok(ArchiveManifest {
+            account_id: row.get(0)?,
+            day: row.get(1)?,
+            path: row.get(2)?,
+            log_count: row.get(3)?,
+            size_bytes: row.get(4)?,
+            sha256: row.get(5)?,
+            updated_at: row.get(6)?,
+        })
+    })?;
+
+    let archives: Result<Vec<ArchiveManifest>, SqliteError> = archives_iter.collect();
+    Ok(archives?)
+}
+
+// Reload archived days back into `logs`. Restored rows keep their original IDs and
+// ingestion time, and are marked so retention leaves them alone for a while
+pub fn restore_range(conn: &mut Connection, account_id: &str, start_date: &str, end_date: &str) -> Result<RestoreReport, ArchiveError> {
+    validate_account_id(account_id)?;
+    let start = NaiveDate::parse_from_str(start_date, "%Y-%m-%d")
+        .map_err(|_| ArchiveError::ValidationError("Start date must be YYYY-MM-DD".to_string()))?;
+    let end = NaiveDate::parse_from_str(end_date, "%Y-%m-%d")
+        .map_err(|_| ArchiveError::ValidationError("End date must be YYYY-MM-DD".to_string()))?;
+    if end < start {
+        return Err(ArchiveError::ValidationError("End date is before start date".to_string()));
+    }
+
+    let archives: Vec<ArchiveManifest> = list_archives(conn, account_id)?
+        .into_iter()
+        .filter(|archive| archive.day.as_str() >= start_date && archive.day.as_str() <= end_date)
+        .collect();
+
+    let mut report = RestoreReport::default();
+    let mut days = BTreeSet::new();
+    let restored_at = Utc::now().to_rfc3339();
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR IGNORE INTO logs (id, hash, account_id, host_id, timestamp, log_data, created_at, restored_at,
+                                         occurrence_count, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)"
+        )?;
+
+        for archive in &archives {
+            let path = PathBuf::from(&archive.path);
+            if file_sha256(&path)? != archive.sha256 {
+                return Err(ArchiveError::ValidationError(format!("Archive {} for {} does not match its manifest checksum", archive.path, archive.day)));
+            }
+
+            let reader = BufReader::new(zstd::Decoder::new(File::open(&path)?)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let log: ArchivedLog = serde_json::from_str(&line)?;
+                if log.account_id != account_id {
+                    continue;
+                }
+                let inserted = stmt.execute(params![
+                    log.id,
+                    log.hash,
+                    log.account_id,
+                    log.host_id,
+                    log.timestamp,
+                    log.log_data,
+                    log.created_at,
+                    restored_at,
+                    log.occurrence_count,
+                    log.last_seen,
+                ])?;
+                if inserted > 0 {
+                    report.restored += 1;
+                } else {
+                    report.duplicates += 1;
+                }
+            }
+            days.insert(archive.day.clone());
+        }
+    }
+    report.days = days.len() as i64;
+    tx.commit()?;
+
+    info!("Restored {} archived logs for account {} ({} to {})", report.restored, account_id, start, end);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Log;
+    use crate::test_support::{archive_dir, host, organization, store_logs, test_pool};
+
+    fn ids(logs: &[Log]) -> Vec<String> {
+        logs.iter().map(|log| log.id.clone()).collect()
+    }
+
+    fn today() -> String {
+        Utc::now().format("%Y-%m-%d").to_string()
+    }
+
+    // How many of the logs are in the table, and how many of those are marked restored
+    fn in_table(conn: &Connection, logs: &[Log]) -> (i64, i64) {
+        let mut found = (0, 0);
+        for log in logs {
+            let row = conn.query_row(
+                "SELECT hash, restored_at IS NOT NULL FROM logs WHERE id = ?1",
+                params![log.id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+            ).optional().unwrap();
+            if let Some((hash, restored)) = row {
+                assert_eq!(hash, log.hash);
+                found.0 += 1;
+                found.1 += restored as i64;
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn archived_logs_come_back_unchanged() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let logs = store_logs(&mut conn, &organization_id, &host_id, &["one", "two"]);
+
+        assert_eq!(archive_logs(&mut conn, &organization_id, &ids(&logs)).unwrap(), 2);
+        assert_eq!(in_table(&conn, &logs), (0, 0));
+        let archives = list_archives(&conn, &organization_id).unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!((archives[0].day.as_str(), archives[0].log_count), (today().as_str(), 2));
+
+        let report = restore_range(&mut conn, &organization_id, &today(), &today()).unwrap();
+        assert_eq!((report.days, report.restored, report.duplicates), (1, 2, 0));
+        assert_eq!(in_table(&conn, &logs), (2, 2));
+
+        // Restoring again finds every log already there
+        let report = restore_range(&mut conn, &organization_id, &today(), &today()).unwrap();
+        assert_eq!((report.restored, report.duplicates), (0, 2));
+    }
+
+    #[test]
+    fn each_run_writes_its_own_segment() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let first = store_logs(&mut conn, &organization_id, &host_id, &["first"]);
+        let second = store_logs(&mut conn, &organization_id, &host_id, &["second"]);
+
+        archive_logs(&mut conn, &organization_id, &ids(&first)).unwrap();
+        archive_logs(&mut conn, &organization_id, &ids(&second)).unwrap();
+        let archives = list_archives(&conn, &organization_id).unwrap();
+        assert_eq!(archives.len(), 2);
+        assert_ne!(archives[0].path, archives[1].path);
+        assert!(archives.iter().all(|archive| archive.day == today() && archive.log_count == 1));
+
+        let report = restore_range(&mut conn, &organization_id, &today(), &today()).unwrap();
+        assert_eq!((report.days, report.restored), (1, 2));
+        assert_eq!(in_table(&conn, &[first, second].concat()), (2, 2));
+    }
+
+    #[test]
+    fn a_segment_that_no_longer_matches_its_checksum_is_refused() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let logs = store_logs(&mut conn, &organization_id, &host_id, &["one"]);
+        archive_logs(&mut conn, &organization_id, &ids(&logs)).unwrap();
+
+        let archive = &list_archives(&conn, &organization_id).unwrap()[0];
+        OpenOptions::new().append(true).open(&archive.path).unwrap().write_all(b"tampered").unwrap();
+
+        let result = restore_range(&mut conn, &organization_id, &today(), &today());
+        assert!(matches!(result, Err(ArchiveError::ValidationError(_))));
+        assert_eq!(in_table(&conn, &logs), (0, 0));
+    }
+
+    #[test]
+    fn a_segment_whose_transaction_fails_is_removed() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let logs = store_logs(&mut conn, &organization_id, &host_id, &["one"]);
+        conn.execute_batch(
+            "CREATE TRIGGER manifest_full BEFORE INSERT ON archive_manifest
+             BEGIN SELECT RAISE(ABORT, 'manifest full'); END;"
+        ).unwrap();
+
+        assert!(archive_logs(&mut conn, &organization_id, &ids(&logs)).is_err());
+        assert_eq!(in_table(&conn, &logs), (1, 0));
+        assert!(list_archives(&conn, &organization_id).unwrap().is_empty());
+        let day_dir = archive_root().join(&organization_id).join(today());
+        assert_eq!(fs::read_dir(day_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn restore_range_is_validated() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        for (account_id, start, end) in [
+            ("", "2024-01-01", "2024-01-02"),
+            ("../etc", "2024-01-01", "2024-01-02"),
+            ("acme", "01/01/2024", "2024-01-02"),
+            ("acme", "2024-01-02", "2024-01-01"),
+        ] {
+            let result = restore_range(&mut conn, account_id, start, end);
+            assert!(matches!(result, Err(ArchiveError::ValidationError(_))), "{} {} {}", account_id, start, end);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params, types::ValueRef};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use actix_web::HttpRequest;
+use chrono::Utc;
+use uuid::Uuid;
+use log::error;
+use crate::auth_session::{Tenant, client_ip};
+use crate::database::{DbPool, run};
+use std::fmt;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 1000;
+// Upper bound on one export, which is built in memory
+pub const MAX_EXPORT_ROWS: i64 = 100_000;
+// Columns whose values never reach the audit log. A change to them still shows up, redacted
+const REDACTED_COLUMNS: &[&str] = &["password", "token_hash", "api_key", "secret", "key_hash", "previous_key_hash", "code_hash"];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Debug)]
+pub enum AuditError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            AuditError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for AuditError {
+    fn from(err: SqliteError) -> Self {
+        AuditError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for AuditError {
+    fn from(err: serde_json::Error) -> Self {
+        AuditError::ValidationError(err.to_string())
+    }
+}
+
+// What an audited action was done to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditTarget {
+    Account,
+    Agent,
+    Alert,
+    ApiToken,
+    Case,
+    CaseComment,
+    DeadLetter,
+    Host,
+    IngestJob,
+    Invitation,
+    Organization,
+    RetentionPolicy,
+    Role,
+    Rule,
+    Session,
+}
+
+impl AuditTarget {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditTarget::Account => "account",
+            AuditTarget::Agent => "agent",
+            AuditTarget::Alert => "alert",
+            AuditTarget::ApiToken => "api_token",
+            AuditTarget::Case => "case",
+            AuditTarget::CaseComment => "case_comment",
+            AuditTarget::DeadLetter => "dead_letter",
+            AuditTarget::Host => "host",
+            AuditTarget::IngestJob => "ingest_job",
+            AuditTarget::Invitation => "invitation",
+            AuditTarget::Organization => "organization",
+            AuditTarget::RetentionPolicy => "retention_policy",
+            AuditTarget::Role => "role",
+            AuditTarget::Rule => "rule",
+            AuditTarget::Session => "session",
+        }
+    }
+
+    // Where a snapshot of the target is read from: the table, its key column and the condition
+    // that keeps the row within the organization (bound as ?2)
+    fn source(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            AuditTarget::Account => ("accounts", "id", "organization_id = ?2"),
+            AuditTarget::Agent => ("agents", "id", "account_id = ?2"),
+            AuditTarget::Alert => ("alerts", "id", "account_id = ?2"),
+            AuditTarget::ApiToken => ("api_tokens", "id", "organization_id = ?2"),
+            AuditTarget::Case => ("cases", "id", "account_id = ?2"),
+            AuditTarget::CaseComment => ("case_comments", "id", "case_id IN (SELECT id FROM cases WHERE account_id = ?2)"),
+            AuditTarget::DeadLetter => ("dead_letters", "id", "account_id = ?2"),
+            AuditTarget::Host => ("hosts", "id", "account_id = ?2"),
+            AuditTarget::IngestJob => ("ingest_jobs", "id", "account_id = ?2"),
+            AuditTarget::Invitation => ("invitations", "id", "organization_id = ?2"),
+            AuditTarget::Organization => ("organizations", "id", "id = ?2"),
+            AuditTarget::RetentionPolicy => ("retention_policies", "id", "account_id = ?2"),
+            AuditTarget::Role => ("roles", "name", "organization_id = ?2"),
+            AuditTarget::Rule => ("rules", "id", "account_id = ?2"),
+            AuditTarget::Session => ("sessions", "id", "account_id IN (SELECT id FROM accounts WHERE organization_id = ?2)"),
+        }
+    }
+}
+
+// Who did it, from where
+#[derive(Debug, Clone)]
+pub struct AuditActor {
+    pub organization_id: String,
+    pub account_id: Option<String>,
+    pub token_id: Option<String>,
+    pub ip_address: String,
+}
+
+impl AuditActor {
+    pub fn new(tenant: &Tenant, req: &HttpRequest) -> Self {
+        AuditActor {
+            organization_id: tenant.organization_id.clone(),
+            account_id: Some(tenant.account_id.clone()),
+            token_id: tenant.token_id.clone(),
+            ip_address: client_ip(req),
+        }
+    }
+
+    // The server acting on its own, like seeding an organization's built-in rules
+    pub fn system(organization_id: &str) -> Self {
+        AuditActor {
+            organization_id: organization_id.to_string(),
+            account_id: None,
+            token_id: None,
+            ip_address: String::new(),
+        }
+    }
+
+    // An account acting for itself before it has a session, like accepting an invitation
+    pub fn account(organization_id: &str, account_id: &str, req: &HttpRequest) -> Self {
+        AuditActor {
+            organization_id: organization_id.to_string(),
+            account_id: Some(account_id.to_string()),
+            token_id: None,
+            ip_address: client_ip(req),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub organization_id: String,
+    pub actor_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub token_id: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: String,
+    // Only the fields that changed, or the whole record when it was created or deleted
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub ip_address: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    // Account ID or name of the actor
+    pub actor: Option<String>,
+    // An exact action, or a prefix ending in `.` such as `rule.`
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+fn column_value(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(t) => Value::from(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => Value::from(format!("<{} bytes>", b.len())),
+    }
+}
+
+// The current row of a target as a JSON object, None when it doesn't exist in the organization
+pub fn snapshot(conn: &Connection, organization_id: &str, target: AuditTarget, target_id: &str) -> Result<Option<Value>, AuditError> {
+    let (table, key, scope) = target.source();
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {} WHERE {} = ?1 AND {}", table, key, scope))?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let row = stmt.query_row(params![target_id, organization_id], |row| {
+        let mut object = Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            object.insert(column.clone(), column_value(row.get_ref(i)?));
+        }
+        Ok(Value::Object(object))
+    }).optional()?;
+    Ok(row)
+}
+
+fn redact(value: Option<Value>) -> Option<Value> {
+    value.map(|mut value| {
+        if let Value::Object(object) = &mut value {
+            for column in REDACTED_COLUMNS {
+                match object.get_mut(*column) {
+                    Some(field) if !field.is_null() => *field = Value::from(REDACTED),
+                    _ => {}
+                }
+            }
+        }
+        value
+    })
+}
+
+// Narrow an update down to the fields that changed. Creates and deletes keep the whole record
+fn diff(before: Option<Value>, after: Option<Value>) -> (Option<Value>, Option<Value>) {
+    match (before, after) {
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            let mut old = Map::new();
+            let mut new = Map::new();
+            for key in before.keys().chain(after.keys().filter(|k| !before.contains_key(*k))) {
+                let (a, b) = (before.get(key), after.get(key));
+                if a != b {
+                    old.insert(key.clone(), a.cloned().unwrap_or(Value::Null));
+                    new.insert(key.clone(), b.cloned().unwrap_or(Value::Null));
+                }
+            }
+            (Some(Value::Object(old)), Some(Value::Object(new)))
+        }
+        (before, after) => (before, after),
+    }
+}
+
+// Append an entry. The table refuses updates and deletes, so entries can't be rewritten later
+pub fn record_audit(
+    conn: &Connection,
+    actor: &AuditActor,
+    action: &str,
+    target: AuditTarget,
+    target_id: &str,
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<AuditEntry, AuditError> {
+    if action.is_empty() {
+        return Err(AuditError::ValidationError("Action cannot be empty".to_string()));
+    }
+
+    let actor_name: Option<String> = match &actor.account_id {
+        Some(account_id) => conn.query_row(
+            "SELECT name FROM accounts WHERE id = ?1",
+            params![account_id],
+            |row| row.get(0),
+        ).optional()?,
+        None => None,
+    };
+    let (before, after) = diff(before, after);
+    let entry = AuditEntry {
+        id: Uuid::new_v4().to_string(),
+        organization_id: actor.organization_id.clone(),
+        actor_id: actor.account_id.clone(),
+        actor_name,
+        token_id: actor.token_id.clone(),
+        action: action.to_string(),
+        target_type: target.as_str().to_string(),
+        target_id: target_id.to_string(),
+        before: redact(before),
+        after: redact(after),
+        ip_address: Some(actor.ip_address.clone()).filter(|ip| !ip.is_empty()),
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    conn.execute(
+        "INSERT INTO audit_log (id, organization_id, actor_id, actor_name, token_id, action, target_type, target_id,
+            before_state, after_state, ip_address, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            entry.id,
+            entry.organization_id,
+            entry.actor_id,
+            entry.actor_name,
+            entry.token_id,
+            entry.action,
+            entry.target_type,
+            entry.target_id,
+            entry.before.as_ref().map(Value::to_string),
+            entry.after.as_ref().map(Value::to_string),
+            entry.ip_address,
+            entry.created_at,
+        ],
+    )?;
+    Ok(entry)
+}
+
+// One audited action in a handler. Take a snapshot before changing the target, then record once
+// the change went through; the after state is read back from the database unless given
+pub struct Audit {
+    actor: AuditActor,
+    action: &'static str,
+    target: AuditTarget,
+    target_id: String,
+}
+
+impl Audit {
+    pub fn new(actor: AuditActor, action: &'static str, target: AuditTarget, target_id: impl Into<String>) -> Self {
+        Audit { actor, action, target, target_id: target_id.into() }
+    }
+
+    pub async fn snapshot(&self, pool: &DbPool) -> Option<Value> {
+        let (organization_id, target, target_id) = (self.actor.organization_id.clone(), self.target, self.target_id.clone());
+        match run(pool, move |conn| snapshot(conn, &organization_id, target, &target_id)).await {
+            Ok(state) => state,
+            Err(err) => {
+                error!("Failed to read {} {} for the audit log: {}", self.target.as_str(), self.target_id, err);
+                None
+            }
+        }
+    }
+
+    pub async fn record(self, pool: &DbPool, before: Option<Value>) {
+        let after = self.snapshot(pool).await;
+        self.record_with(pool, before, after).await
+    }
+
+    // The change already happened, so a failure to record it is logged rather than returned
+    pub async fn record_with(self, pool: &DbPool, before: Option<Value>, after: Option<Value>) {
+        let Audit { actor, action, target, target_id } = self;
+        let result = run(pool, {
+            let target_id = target_id.clone();
+            move |conn| record_audit(conn, &actor, action, target, &target_id, before, after)
+        }).await;
+        if let Err(err) = result {
+            error!("Failed to write audit entry {} for {} {}: {}", action, target.as_str(), target_id, err);
+        }
+    }
+}
+
+fn parse_state(state: Option<String>) -> Option<Value> {
+    state.and_then(|s| serde_json::from_str(&s).ok())
+}
+
+// Entries of an organization matching the filter, newest first
+pub fn search_audit_log(conn: &Connection, organization_id: &str, filter: &AuditFilter, max_rows: i64) -> Result<Vec<AuditEntry>, AuditError> {
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, max_rows);
+    let offset = filter.offset.unwrap_or(0).max(0);
+    let (action, action_prefix) = match filter.action.as_deref() {
+        Some(action) if action.ends_with('.') => (None, Some(format!("{}%", action.replace('%', "").replace('_', "\\_")))),
+        Some(action) => (Some(action.to_string()), None),
+        None => (None, None),
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT id, organization_id, actor_id, actor_name, token_id, action, target_type, target_id,
+            before_state, after_state, ip_address, created_at
+        FROM audit_log
+        WHERE organization_id = ?1
+          AND (?2 IS NULL OR actor_id = ?2 OR actor_name = ?2)
+          AND (?3 IS NULL OR action = ?3)
+          AND (?4 IS NULL OR action LIKE ?4 ESCAPE '\\')
+          AND (?5 IS NULL OR target_type = ?5)
+          AND (?6 IS NULL OR target_id = ?6)
+          AND (?7 IS NULL OR created_at >= ?7)
+          AND (?8 IS NULL OR created_at <= ?8)
+        ORDER BY created_at DESC
+        LIMIT ?9 OFFSET ?10"
+    )?;
+    let entries = stmt.query_map(params![
+        organization_id,
+        filter.actor,
+        action,
+        action_prefix,
+        filter.target_type,
+        filter.target_id,
+        filter.since,
+        filter.until,
+        limit,
+        offset,
+    ], |row| {
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            organization_id: row.get(1)?,
+            actor_id: row.get(2)?,
+            actor_name: row.get(3)?,
+            token_id: row.get(4)?,
+            action: row.get(5)?,
+            target_type: row.get(6)?,
+            target_id: row.get(7)?,
+            before: parse_state(row.get(8)?),
+            after: parse_state(row.get(9)?),
+            ip_address: row.get(10)?,
+            created_at: row.get(11)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(entries)
+}
+
+fn csv_field(value: &str) -> String {
+    // Spreadsheets run cells that start like a formula, and names and IDs come from users
+    let value = if value.starts_with(['=', '+', '-', '@']) { format!("'{}", value) } else { value.to_string() };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+// CSV with one row per entry; the states are JSON in their own columns
+pub fn audit_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("created_at,actor_id,actor_name,token_id,action,target_type,target_id,before,after,ip_address,id\n");
+    for entry in entries {
+        let fields = [
+            entry.created_at.clone(),
+            entry.actor_id.clone().unwrap_or_default(),
+            entry.actor_name.clone().unwrap_or_default(),
+            entry.token_id.clone().unwrap_or_default(),
+            entry.action.clone(),
+            entry.target_type.clone(),
+            entry.target_id.clone(),
+            entry.before.as_ref().map(Value::to_string).unwrap_or_default(),
+            entry.after.as_ref().map(Value::to_string).unwrap_or_default(),
+            entry.ip_address.clone().unwrap_or_default(),
+            entry.id.clone(),
+        ];
+        csv.push_str(&fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{organization, test_pool};
+
+    fn admin(organization_id: &str, account_id: &str) -> AuditActor {
+        AuditActor {
+            organization_id: organization_id.to_string(),
+            account_id: Some(account_id.to_string()),
+            token_id: None,
+            ip_address: "10.1.1.1".to_string(),
+        }
+    }
+
+    fn actions(entries: &[AuditEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.action.as_str()).collect()
+    }
+
+    #[test]
+    fn entries_cannot_be_rewritten_or_removed() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = organization(&conn, "acme");
+        let entry = record_audit(&conn, &admin(&organization_id, &account_id), "host.delete", AuditTarget::Host, "h1", None, None).unwrap();
+
+        for statement in ["UPDATE audit_log SET action = 'host.create' WHERE id = ?1", "DELETE FROM audit_log WHERE id = ?1"] {
+            let err = conn.execute(statement, params![entry.id]).unwrap_err();
+            assert!(err.to_string().contains("append-only"), "{}", err);
+        }
+        let stored: String = conn.query_row("SELECT action FROM audit_log WHERE id = ?1", params![entry.id], |row| row.get(0)).unwrap();
+        assert_eq!(stored, "host.delete");
+    }
+
+    #[test]
+    fn updates_keep_the_changed_fields_with_secrets_redacted() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = organization(&conn, "acme");
+        let actor = admin(&organization_id, &account_id);
+
+        let created = snapshot(&conn, &organization_id, AuditTarget::Account, &account_id).unwrap();
+        let entry = record_audit(&conn, &actor, "account.create", AuditTarget::Account, &account_id, None, created.clone()).unwrap();
+        let after = entry.after.unwrap();
+        assert_eq!((after["name"].as_str(), after["password"].as_str()), (Some("acme"), Some(REDACTED)));
+        assert_eq!(entry.actor_name.as_deref(), Some("acme"));
+
+        conn.execute("UPDATE accounts SET role = 'Analyst', password = 'y' WHERE id = ?1", params![account_id]).unwrap();
+        let updated = snapshot(&conn, &organization_id, AuditTarget::Account, &account_id).unwrap();
+        let entry = record_audit(&conn, &actor, "account.update", AuditTarget::Account, &account_id, created, updated).unwrap();
+        assert_eq!(entry.before.unwrap(), serde_json::json!({ "role": "Admin", "password": REDACTED }));
+        assert_eq!(entry.after.unwrap(), serde_json::json!({ "role": "Analyst", "password": REDACTED }));
+    }
+
+    #[test]
+    fn snapshots_and_searches_stay_within_the_organization() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (acme, acme_admin) = organization(&conn, "acme");
+        let (globex, globex_admin) = organization(&conn, "globex");
+        assert!(snapshot(&conn, &acme, AuditTarget::Account, &globex_admin).unwrap().is_none());
+
+        record_audit(&conn, &admin(&acme, &acme_admin), "rule.update", AuditTarget::Rule, "r1", None, None).unwrap();
+        record_audit(&conn, &admin(&acme, &acme_admin), "rules_exported", AuditTarget::Rule, "r1", None, None).unwrap();
+        record_audit(&conn, &admin(&globex, &globex_admin), "rule.delete", AuditTarget::Rule, "r2", None, None).unwrap();
+
+        // Creating an organization already recorded its seeded rule, by the system
+        let all = search_audit_log(&conn, &acme, &AuditFilter::default(), MAX_PAGE_SIZE).unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(all.iter().all(|entry| entry.organization_id == acme));
+
+        let filter = AuditFilter { action: Some("rule.".to_string()), actor: Some("acme".to_string()), ..Default::default() };
+        assert_eq!(actions(&search_audit_log(&conn, &acme, &filter, MAX_PAGE_SIZE).unwrap()), vec!["rule.update"]);
+        let filter = AuditFilter { action: Some("rule.".to_string()), ..Default::default() };
+        let entries = search_audit_log(&conn, &acme, &filter, MAX_PAGE_SIZE).unwrap();
+        let mut seen = actions(&entries);
+        seen.sort();
+        assert_eq!(seen, vec!["rule.create", "rule.update"]);
+    }
+
+    #[test]
+    fn csv_cells_cannot_start_a_formula() {
+        let entry = AuditEntry {
+            id: "e1".to_string(),
+            organization_id: "o1".to_string(),
+            actor_id: None,
+            actor_name: Some("=HYPERLINK(\"x\")".to_string()),
+            token_id: None,
+            action: "account.update".to_string(),
+            target_type: "account".to_string(),
+            target_id: "-1".to_string(),
+            before: None,
+            after: None,
+            ip_address: None,
+            created_at: "2024-01-01T00:00:00+00:00".to_string(),
+        };
+        let row = audit_csv(&[entry]).lines().nth(1).unwrap().to_string();
+        assert!(row.contains(",\"'=HYPERLINK(\"\"x\"\")\","), "{}", row);
+        assert!(row.contains(",'-1,"), "{}", row);
+    }
+}
@@ -1,8 +1,126 @@
-use actix_web::{error::ErrorUnauthorized, Error, HttpRequest};
+use actix_web::{web, error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized}, dev::Payload, Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::http::header::AUTHORIZATION;
+use std::future::Future;
+use std::pin::Pin;
 use std::time::{Duration, SystemTime};
-use actix_session::Session;
+use actix_session::{Session, SessionExt};
+use log::error;
+use crate::api_token::verify_api_token;
+use crate::database::{DbPool, run};
+use crate::rbac::Permission;
+use crate::mfa::MfaState;
 
+// The signed-in account and the organization it acts for, taken from the verified session
+// or an API token. Handlers use this instead of trusting an ID from the path, query string or body
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub account_id: String,
+    pub organization_id: String,
+    // Set when the request authenticated with an API token, whose scopes narrow the role
+    pub token_id: Option<String>,
+    pub scopes: Option<Vec<Permission>>,
+}
+
+impl Tenant {
+    // Reject requests that name an organization other than the caller's. Resources keep
+    // the owning organization in their account_id field
+    pub fn authorize(&self, organization_id: &str) -> Result<(), Error> {
+        if self.organization_id != organization_id {
+            return Err(ErrorForbidden("Access to this organization is not allowed"));
+        }
+        Ok(())
+    }
+}
+
+impl FromRequest for Tenant {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { request_tenant(&req).await })
+    }
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers().get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// The address of the connecting client. Forwarded headers are ignored since clients can set them
+pub fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Resolve the caller from an `Authorization: Bearer` token if one is sent, otherwise from the
+// session cookie. A request carrying a bearer token never falls back to the cookie
+pub async fn request_tenant(req: &HttpRequest) -> Result<Tenant, Error> {
+    // Routes behind `RequirePermission` have already resolved the tenant
+    if let Some(tenant) = req.extensions().get::<Tenant>() {
+        return Ok(tenant.clone());
+    }
+
+    let Some(secret) = bearer_token(req).map(str::to_string) else {
+        return session_tenant(&req.get_session(), req);
+    };
+    let pool = req.app_data::<web::Data<DbPool>>()
+        .ok_or_else(|| ErrorInternalServerError("An internal error occurred"))?;
+
+    match run(pool, move |conn| verify_api_token(conn, &secret)).await {
+        Ok(Some(token)) => {
+            let tenant = Tenant {
+                account_id: token.account_id,
+                organization_id: token.organization_id,
+                token_id: Some(token.id),
+                scopes: Some(token.scopes),
+            };
+            req.extensions_mut().insert(tenant.clone());
+            Ok(tenant)
+        }
+        Ok(None) => Err(ErrorUnauthorized("Invalid API token")),
+        Err(e) => {
+            error!("Failed to verify API token: {}", e);
+            Err(ErrorInternalServerError("An internal error occurred"))
+        }
+    }
+}
+
+pub fn session_tenant(session: &Session, req: &HttpRequest) -> Result<Tenant, Error> {
+    let account_id = verify_session(session, req)?;
+    match session.get::<String>("organization_id") {
+        Ok(Some(organization_id)) => Ok(Tenant { account_id, organization_id, token_id: None, scopes: None }),
+        _ => Err(ErrorUnauthorized("Unauthorized")),
+    }
+}
+
+// A valid session that still owes a second factor is refused everywhere except the MFA endpoints,
+// and one that has to replace its password everywhere except the password change
 pub fn verify_session(session: &Session, req: &HttpRequest) -> Result<String, Error> {
+    let account_id = verify_session_allowing_password_change(session, req)?;
+    match session.get::<bool>("password_change_required") {
+        Ok(Some(true)) => Err(ErrorForbidden("Password change required")),
+        Ok(_) => Ok(account_id),
+        Err(_) => Err(ErrorUnauthorized("Session error")),
+    }
+}
+
+// The MFA gate of `verify_session` only
+pub fn verify_session_allowing_password_change(session: &Session, req: &HttpRequest) -> Result<String, Error> {
+    let account_id = check_session(session, req)?;
+    match session.get::<MfaState>("mfa_state") {
+        // Sessions started before MFA existed carry no state
+        Ok(None) | Ok(Some(MfaState::Verified)) => Ok(account_id),
+        Ok(Some(_)) => Err(ErrorUnauthorized("MFA verification required")),
+        Err(_) => Err(ErrorUnauthorized("Session error")),
+    }
+}
+
+// Check the session's account, user agent and inactivity timeout, without the MFA gate
+pub fn check_session(session: &Session, req: &HttpRequest) -> Result<String, Error> {
     match session.get::<String>("account_id") {
         Ok(Some(account_id)) => {
             // Check session user agent
@@ -36,7 +154,8 @@ pub fn verify_session(session: &Session, req: &HttpRequest) -> Result<String, Er
                 }
             }
 
-            session.renew();
+            // The key is rotated at login and MFA verification, not per request, since concurrent
+            // requests still carry the old key
             session.insert("last_activity", SystemTime::now())
                 .map_err(|_| ErrorUnauthorized("Failed to update session"))?;
 
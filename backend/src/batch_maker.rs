@@ -1,17 +1,73 @@
 use std::io::{self, BufReader, BufRead};
-use crate::global::GLOBAL_MESSAGE_QUEUE;
+use crate::ingest_job::{IngestJob, JobError, create_job};
+use crate::message_queue::{MessageQueue, QueueError};
+use rusqlite::{Connection, Error as SqliteError, params};
+use crate::global::INGEST_SIGNAL;
 use std::fs::File;
-use serde_json;
+use std::fmt;
+use log::error;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
+pub enum BatchError {
+    DatabaseError(SqliteError),
+    IoError(io::Error),
+    QueueError(QueueError),
+    JobError(JobError),
+    ValidationError(String),
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            BatchError::IoError(err) => write!(f, "IO error: {}", err),
+            BatchError::QueueError(err) => write!(f, "Queue error: {}", err),
+            BatchError::JobError(err) => write!(f, "Job error: {}", err),
+            BatchError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for BatchError {
+    fn from(err: SqliteError) -> Self {
+        BatchError::DatabaseError(err)
+    }
+}
+
+impl From<io::Error> for BatchError {
+    fn from(err: io::Error) -> Self {
+        BatchError::IoError(err)
+    }
+}
+
+impl From<JobError> for BatchError {
+    fn from(err: JobError) -> Self {
+        BatchError::JobError(err)
+    }
+}
+
+impl From<QueueError> for BatchError {
+    fn from(err: QueueError) -> Self {
+        BatchError::QueueError(err)
+    }
+}
 
 // A Batch is <= 50 log entries long
 #[derive(Clone)]
 pub struct Batch {
+    pub job_id: String,
+    pub account_id: String,
+    pub host_id: String,
     pub lines: Vec<String>,
 }
 
 impl Batch {
-    pub fn new() -> Self {
+    pub fn new(account_id: &str, host_id: &str) -> Self {
         Batch {
+            job_id: String::new(),
+            account_id: account_id.to_string(),
+            host_id: host_id.to_string(),
             lines: Vec::new(),
         }
     }
@@ -29,10 +85,11 @@ impl Batch {
     }
 }
 
-pub async fn create_batches(file_path: &str) -> Result<(), io::Error> {
+// Split an uploaded file into batches and enqueue them under a new ingestion job
+pub fn create_batches(conn: &mut Connection, queue: &MessageQueue, file_path: &str, account_id: &str, host_id: &str) -> Result<IngestJob, BatchError> {
     let file = File::open(file_path)?;
     let reader = BufReader::new(file);
-    let mut current_batch = Batch::new();
+    let mut entries = Vec::new();
 
     // Read the first line to determine format
     let mut lines = reader.lines();
@@ -42,7 +99,7 @@ pub async fn create_batches(file_path: &str) -> Result<(), io::Error> {
             // JSON array detected
             let mut json_content = first_line;
             for line in lines {
-                json_content.push_str("\n");
+                json_content.push('\n');
                 json_content.push_str(&line?);
             }
 
@@ -52,54 +109,64 @@ pub async fn create_batches(file_path: &str) -> Result<(), io::Error> {
 
             for entry in log_entries {
                 let entry_str = serde_json::to_string(&entry)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Failed to serialize JSON entry: {}", e)))?;
-                current_batch.add_line(entry_str);
-
-                if current_batch.is_full() {
-                    let queue = GLOBAL_MESSAGE_QUEUE.lock().await;
-                    queue.enqueue(current_batch.clone()).await.map_err(|e| {
-                        eprintln!("Error enqueuing batch: {}", e);
-                        io::Error::new(io::ErrorKind::Other, e)
-                    })?;
-                    current_batch.clear();
-                }
+                    .map_err(|e| io::Error::other(format!("Failed to serialize JSON entry: {}", e)))?;
+                entries.push(entry_str);
             }
         } else {
             // Non-JSON, process line-by-line
-            current_batch.add_line(first_line);
-            if current_batch.is_full() {
-                let queue = GLOBAL_MESSAGE_QUEUE.lock().await;
-                queue.enqueue(current_batch.clone()).await.map_err(|e| {
-                    eprintln!("Error enqueuing batch: {}", e);
-                    io::Error::new(io::ErrorKind::Other, e)
-                })?;
-                current_batch.clear();
-            }
-
+            entries.push(first_line);
             for line in lines {
-                let line = line?;
-                current_batch.add_line(line);
-
-                if current_batch.is_full() {
-                    let queue = GLOBAL_MESSAGE_QUEUE.lock().await;
-                    queue.enqueue(current_batch.clone()).await.map_err(|e| {
-                        eprintln!("Error enqueuing batch: {}", e);
-                        io::Error::new(io::ErrorKind::Other, e)
-                    })?;
-                    current_batch.clear();
-                }
+                entries.push(line?);
             }
         }
     }
 
-    // Enqueue any remaining lines
+    enqueue_lines(conn, queue, account_id, host_id, entries)
+}
+
+// Batch raw log lines and enqueue them under a new ingestion job
+pub fn enqueue_lines(conn: &mut Connection, queue: &MessageQueue, account_id: &str, host_id: &str, lines: Vec<String>) -> Result<IngestJob, BatchError> {
+    // Logs can only be filed under one of the account's own hosts
+    let host_in_account: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM hosts WHERE id = ?1 AND account_id = ?2",
+        params![host_id, account_id],
+        |row| row.get(0),
+    )?;
+    if !host_in_account {
+        return Err(BatchError::ValidationError("Host not found for this account".to_string()));
+    }
+
+    let lines_read = lines.len() as i64;
+    let mut batches = Vec::new();
+    let mut current_batch = Batch::new(account_id, host_id);
+
+    for line in lines {
+        current_batch.add_line(line);
+
+        if current_batch.is_full() {
+            batches.push(current_batch.clone());
+            current_batch.clear();
+        }
+    }
+
+    // Keep any remaining lines
     if !current_batch.lines.is_empty() {
-        let queue = GLOBAL_MESSAGE_QUEUE.lock().await;
-        queue.enqueue(current_batch.clone()).await.map_err(|e| {
-            eprintln!("Error enqueuing batch: {}", e);
-            io::Error::new(io::ErrorKind::Other, e)
-        })?;
+        batches.push(current_batch);
+    }
+
+    // The job and all of its batches are created together, so a full queue rejects them cleanly
+    let tx = conn.transaction()?;
+    let job = create_job(&tx, account_id, host_id, lines_read, batches.len() as i64)?;
+    for batch in batches.iter_mut() {
+        batch.job_id = job.id.clone();
+    }
+
+    if let Err(e) = queue.enqueue_all(&tx, &batches) {
+        error!("Error enqueuing batches: {}", e);
+        return Err(e.into());
     }
+    tx.commit()?;
+    INGEST_SIGNAL.notify_waiters();
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(job)
+}
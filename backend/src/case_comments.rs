@@ -1,5 +1,4 @@
-use rusqlite::{Error as SqliteError, OptionalExtension, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
 use uuid::Uuid;
@@ -47,10 +46,27 @@ impl From<SqliteError> for CaseCommentError {
     }
 }
 
-pub fn create_comment(case_id: &str, comment_text: &str) -> Result<CaseComment, CaseCommentError> {
+// Comments have no account of their own; they belong to their case's account
+fn case_in_account(conn: &Connection, account_id: &str, case_id: &str) -> Result<bool, CaseCommentError> {
+    if account_id.is_empty() {
+        return Err(CaseCommentError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM cases WHERE id = ?1 AND account_id = ?2",
+        params![case_id, account_id],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+pub fn create_comment(conn: &Connection, account_id: &str, case_id: &str, comment_text: &str) -> Result<CaseComment, CaseCommentError> {
     if comment_text.is_empty() {
         return Err(CaseCommentError::ValidationError("Comment cannot be empty".to_string()));
     }
+    if !case_in_account(conn, account_id, case_id)? {
+        return Err(CaseCommentError::ValidationError("Case not found".to_string()));
+    }
 
     let comment = CaseComment {
         id: Uuid::new_v4().to_string(),
@@ -60,7 +76,6 @@ pub fn create_comment(case_id: &str, comment_text: &str) -> Result<CaseComment,
         updated_at: Utc::now().to_rfc3339(),
     };
 
-    let conn = establish_connection()?;
     conn.execute(
         "INSERT INTO case_comments (id, case_id, comment, created_at, updated_at) 
          VALUES (?1, ?2, ?3, ?4, ?5)",
@@ -76,14 +91,18 @@ pub fn create_comment(case_id: &str, comment_text: &str) -> Result<CaseComment,
     Ok(comment)
 }
 
-pub fn get_comment(comment_id: &str) -> Result<Option<CaseComment>, CaseCommentError> {
-    let conn = establish_connection()?;
+pub fn get_comment(conn: &Connection, account_id: &str, comment_id: &str) -> Result<Option<CaseComment>, CaseCommentError> {
+    if account_id.is_empty() {
+        return Err(CaseCommentError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
     let mut stmt = conn.prepare(
         "SELECT id, case_id, comment, created_at, updated_at 
-         FROM case_comments WHERE id = ?1"
+         FROM case_comments
+         WHERE id = ?1 AND case_id IN (SELECT id FROM cases WHERE account_id = ?2)"
     )?;
 
-    let comment = stmt.query_row(params![comment_id], |row| {
+    let comment = stmt.query_row(params![comment_id, account_id], |row| {
         Ok(CaseComment {
             id: row.get(0)?,
             case_id: row.get(1)?,
@@ -96,16 +115,19 @@ pub fn get_comment(comment_id: &str) -> Result<Option<CaseComment>, CaseCommentE
     Ok(comment)
 }
 
-pub fn get_comments_by_case(case_id: &str) -> Result<Vec<CaseComment>, CaseCommentError> {
-    let conn = establish_connection()?;
+pub fn get_comments_by_case(conn: &Connection, account_id: &str, case_id: &str) -> Result<Vec<CaseComment>, CaseCommentError> {
+    if account_id.is_empty() {
+        return Err(CaseCommentError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
     let mut stmt = conn.prepare(
         "SELECT id, case_id, comment, created_at, updated_at 
          FROM case_comments 
-         WHERE case_id = ?1 
+         WHERE case_id = ?1 AND case_id IN (SELECT id FROM cases WHERE account_id = ?2)
          ORDER BY created_at DESC"
     )?;
 
-    let comments_iter = stmt.query_map(params![case_id], |row| {
+    let comments_iter = stmt.query_map(params![case_id, account_id], |row| {
         Ok(CaseComment {
             id: row.get(0)?,
             case_id: row.get(1)?,
@@ -119,31 +141,38 @@ pub fn get_comments_by_case(case_id: &str) -> Result<Vec<CaseComment>, CaseComme
     Ok(comments?)
 }
 
-pub fn update_comment(comment: &CaseComment) -> Result<(), CaseCommentError> {
+pub fn update_comment(conn: &Connection, account_id: &str, comment: &CaseComment) -> Result<bool, CaseCommentError> {
+    if account_id.is_empty() {
+        return Err(CaseCommentError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if comment.comment.is_empty() {
         return Err(CaseCommentError::ValidationError("Comment cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
-    conn.execute(
+    let affected_rows = conn.execute(
         "UPDATE case_comments 
          SET comment = ?2, updated_at = ?3 
-         WHERE id = ?1",
+         WHERE id = ?1 AND case_id IN (SELECT id FROM cases WHERE account_id = ?4)",
         params![
             comment.id,
             comment.comment,
             Utc::now().to_rfc3339(),
+            account_id,
         ],
     )?;
 
-    Ok(())
+    Ok(affected_rows > 0)
 }
 
-pub fn delete_comment(comment_id: &str) -> Result<bool, CaseCommentError> {
-    let conn = establish_connection()?;
+pub fn delete_comment(conn: &Connection, account_id: &str, comment_id: &str) -> Result<bool, CaseCommentError> {
+    if account_id.is_empty() {
+        return Err(CaseCommentError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
     let affected_rows = conn.execute(
-        "DELETE FROM case_comments WHERE id = ?1",
-        params![comment_id],
+        "DELETE FROM case_comments
+         WHERE id = ?1 AND case_id IN (SELECT id FROM cases WHERE account_id = ?2)",
+        params![comment_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
@@ -1,9 +1,7 @@
-use rusqlite::{Error as SqliteError, OptionalExtension, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
 use crate::alert::update_alert_case_id;
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
-use serde_json;
 use uuid::Uuid;
 use std::fmt;
 
@@ -103,6 +101,7 @@ impl Default for Case {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
 pub enum CaseError {
     DatabaseError(SqliteError),
     ValidationError(String),
@@ -145,15 +144,16 @@ impl Case {
     }
 }
 
-pub fn create_case(account_id: &str) -> Result<Case, CaseError> {
-    let mut new_case = Case::default();
-    new_case.account_id = account_id.to_string();
-    new_case.analyst_assigned = "Unassigned".to_string();
+pub fn create_case(conn: &Connection, account_id: &str) -> Result<Case, CaseError> {
+    let new_case = Case {
+        account_id: account_id.to_string(),
+        analyst_assigned: "Unassigned".to_string(),
+        ..Default::default()
+    };
     new_case.validate()?;
 
     let observables_json = serde_json::to_string(&new_case.observables)?;
 
-    let conn = establish_connection()?;
     conn.execute(
         "INSERT INTO cases (id, account_id, title, description, severity, status, category, 
          analyst_assigned, observables, created_at, updated_at) 
@@ -176,15 +176,18 @@ pub fn create_case(account_id: &str) -> Result<Case, CaseError> {
     Ok(new_case)
 }
 
-pub fn get_case(case_id: &str) -> Result<Option<Case>, CaseError> {
-    let conn = establish_connection()?;
+pub fn get_case(conn: &Connection, account_id: &str, case_id: &str) -> Result<Option<Case>, CaseError> {
+    if account_id.is_empty() {
+        return Err(CaseError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
     let mut stmt = conn.prepare(
         "SELECT id, account_id, title, description, severity, status, category, 
          analyst_assigned, observables, created_at, updated_at 
-         FROM cases WHERE id = ?1"
+         FROM cases WHERE id = ?1 AND account_id = ?2"
     )?;
 
-    let case = stmt.query_row(params![case_id], |row| {
+    let case = stmt.query_row(params![case_id, account_id], |row| {
         let observables_json: String = row.get(8)?;
         Ok((
             row.get(0)?,
@@ -223,8 +226,7 @@ pub fn get_case(case_id: &str) -> Result<Option<Case>, CaseError> {
     }
 }
 
-pub fn get_cases_by_account(account_id: &str) -> Result<Vec<Case>, CaseError> {
-    let conn = establish_connection()?;
+pub fn get_cases_by_account(conn: &Connection, account_id: &str) -> Result<Vec<Case>, CaseError> {
     let mut stmt = conn.prepare(
         "SELECT id, account_id, title, description, severity, status, category, 
          analyst_assigned, observables, created_at, updated_at 
@@ -274,16 +276,16 @@ pub fn get_cases_by_account(account_id: &str) -> Result<Vec<Case>, CaseError> {
     Ok(cases)
 }
 
-pub fn update_case(case: &Case) -> Result<(), CaseError> {
+// The case's account_id scopes the update; alerts are only linked within that account
+pub fn update_case(conn: &Connection, case: &Case) -> Result<bool, CaseError> {
     case.validate()?;
     let observables_json = serde_json::to_string(&case.observables)?;
 
-    let conn = establish_connection()?;
-    conn.execute(
+    let affected_rows = conn.execute(
         "UPDATE cases 
          SET title = ?2, description = ?3, severity = ?4, status = ?5, 
          category = ?6, analyst_assigned = ?7, observables = ?8, updated_at = ?9 
-         WHERE id = ?1",
+         WHERE id = ?1 AND account_id = ?10",
         params![
             case.id,
             case.title,
@@ -294,15 +296,19 @@ pub fn update_case(case: &Case) -> Result<(), CaseError> {
             case.analyst_assigned,
             observables_json,
             Utc::now().to_rfc3339(),
+            case.account_id,
         ],
     )?;
+    if affected_rows == 0 {
+        return Ok(false);
+    }
 
     // Update alert case_ids
     for observable in &case.observables {
         if observable.observable_type == "alert" {
             if let Ok(alert_data) = serde_json::from_str::<serde_json::Value>(&observable.value) {
                 if let Some(alert_id) = alert_data.get("alert_id").and_then(|id| id.as_str()) {
-                    if let Err(e) = update_alert_case_id(alert_id, &case.id) {
+                    if let Err(e) = update_alert_case_id(conn, &case.account_id, alert_id, &case.id) {
                         eprintln!("Failed to update alert case_id: {}", e);
                     }
                 }
@@ -310,32 +316,35 @@ pub fn update_case(case: &Case) -> Result<(), CaseError> {
         }
     }
 
-    Ok(())
+    Ok(true)
 }
 
-pub fn delete_case(case_id: &str) -> Result<bool, CaseError> {
-    let conn = establish_connection()?;
+pub fn delete_case(conn: &Connection, account_id: &str, case_id: &str) -> Result<bool, CaseError> {
+    if account_id.is_empty() {
+        return Err(CaseError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
     let affected_rows = conn.execute(
-        "DELETE FROM cases WHERE id = ?1",
-        params![case_id],
+        "DELETE FROM cases WHERE id = ?1 AND account_id = ?2",
+        params![case_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
 }
 
-pub fn add_observable(case_id: &str, observable: Observable) -> Result<(), CaseError> {
-    let mut case = get_case(case_id)?.ok_or_else(|| {
+pub fn add_observable(conn: &Connection, account_id: &str, case_id: &str, observable: Observable) -> Result<(), CaseError> {
+    let mut case = get_case(conn, account_id, case_id)?.ok_or_else(|| {
         CaseError::ValidationError("Case not found".to_string())
     })?;
 
     case.observables.push(observable);
-    update_case(&case)?;
+    update_case(conn, &case)?;
 
     Ok(())
 }
 
-pub fn delete_observable(case_id: &str, observable: Observable) -> Result<(), CaseError> {
-    let mut case = get_case(case_id)?.ok_or_else(|| {
+pub fn delete_observable(conn: &Connection, account_id: &str, case_id: &str, observable: Observable) -> Result<(), CaseError> {
+    let mut case = get_case(conn, account_id, case_id)?.ok_or_else(|| {
         CaseError::ValidationError("Case not found".to_string())
     })?;
 
@@ -344,13 +353,12 @@ pub fn delete_observable(case_id: &str, observable: Observable) -> Result<(), Ca
         !(obs.observable_type == observable.observable_type && obs.value == observable.value)
     );
 
-    update_case(&case)?;
+    update_case(conn, &case)?;
 
     Ok(())
 }
 
-pub fn all_logs_with_cases(account_id: &str) -> Result<Vec<String>, CaseError> {
-    let conn = establish_connection()?;
+pub fn all_logs_with_cases(conn: &Connection, account_id: &str) -> Result<Vec<String>, CaseError> {
     let mut stmt = conn.prepare(
         "SELECT observables FROM cases WHERE account_id = ?1"
     )?;
@@ -1,74 +1,318 @@
 use crate::log_parser::{process_log, ParseLogError, NormalizedLog};
-use std::sync::{Mutex, atomic::{AtomicU16, Ordering}};
+use std::sync::atomic::{AtomicU16, Ordering};
+use crate::global::INGEST_SIGNAL;
+use crate::database::{DbPool, run};
+use crate::message_queue::{MessageQueue, QueuedBatch};
+use rusqlite::{Connection, TransactionBehavior};
 use crate::rules::evaluate_log_against_rules;
-use crate::global::GLOBAL_MESSAGE_QUEUE;
-use crate::log::{Log, create_log};
-use serde_json;
+use crate::ingest_job::{BatchStats, record_batch};
+use crate::log::{Log, create_logs};
+use crate::batch_maker::Batch;
+use crate::dead_letter::create_dead_letter;
+use actix_web::{rt, web};
+use std::time::Duration;
+use log::{error, info, warn};
 
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+// Numbers the logs stored by the workers. The logs themselves are only kept in the database
 pub struct LogCollector {
-    logs: Mutex<Vec<Log>>,
     next_id: AtomicU16,
 }
 
 impl LogCollector {
     pub fn new() -> Self {
         Self {
-            logs: Mutex::new(Vec::new()),
             next_id: AtomicU16::new(1),
         }
     }
 
-    pub fn add_log(&self, log: Log) -> u16 {
-        let line_number = self.next_id.fetch_add(1, Ordering::SeqCst);
-        let mut logs = self.logs.lock().unwrap();
-        logs.push(log);
-        line_number
+    pub fn add_log(&self) -> u16 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+// Start background workers that keep draining the message queue
+pub fn spawn_workers(pool: DbPool, queue: MessageQueue, collector: web::Data<LogCollector>, count: usize) {
+    info!("Starting {} ingestion workers", count);
+    for worker in 0..count {
+        let pool = pool.clone();
+        let queue = queue.clone();
+        let collector = collector.clone();
+        rt::spawn(async move {
+            loop {
+                match process_logs(&pool, &queue, &collector).await {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        // Queue is empty, wait for new batches or poll again shortly
+                        let _ = rt::time::timeout(IDLE_POLL, INGEST_SIGNAL.notified()).await;
+                    }
+                    Err(e) => {
+                        error!("Ingestion worker {} failed to process batch: {}", worker, e);
+                        rt::time::sleep(IDLE_POLL).await;
+                    }
+                }
+            }
+        });
     }
 }
 
-pub async fn process_logs(collector: &LogCollector, account_id: String, host_id: String) -> Result<(), ParseLogError> {
-    let queue = GLOBAL_MESSAGE_QUEUE.lock().await;
-    let batch = match queue.dequeue().await {
-        Ok(batch) => batch,
-        Err(_) => return Ok(()), // Queue empty
-    };
+// Process the next queued batch. Returns false when the queue is empty
+pub async fn process_logs(pool: &DbPool, queue: &MessageQueue, collector: &web::Data<LogCollector>) -> Result<bool, ParseLogError> {
+    let queue = queue.clone();
+    let collector = collector.clone();
+
+    run(pool, move |conn| {
+        let queued = match queue.dequeue(conn).map_err(|e| ParseLogError::DatabaseError(e.to_string()))? {
+            Some(queued) => queued,
+            None => return Ok(false),
+        };
+
+        // Dead letters, logs, alerts, the job's counters and the ack commit together, so a
+        // batch that fails part way leaves nothing behind and its retry starts from scratch
+        let result = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(ParseLogError::from)
+            .and_then(|tx| {
+                let mut stats = BatchStats::default();
+                let inserted = process_batch(&tx, &queued.batch, &mut stats)?;
+                settle(&tx, &queue, &queued, &stats)?;
+                tx.commit()?;
+                Ok(inserted)
+            });
+
+        match result {
+            Ok(inserted) => {
+                for _ in inserted {
+                    collector.add_log();
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                if let Err(settle_err) = fail_batch(conn, &queue, &queued, &e) {
+                    error!("Failed to settle batch {}: {}", queued.id, settle_err);
+                }
+                Err(e)
+            }
+        }
+    }).await
+}
 
-    if batch.lines.is_empty() {
-        return Ok(());
+// Count the batch towards its job and remove it from the queue
+fn settle(conn: &Connection, queue: &MessageQueue, queued: &QueuedBatch, stats: &BatchStats) -> Result<(), ParseLogError> {
+    record_batch(conn, &queued.batch.job_id, stats)
+        .map_err(|e| ParseLogError::DatabaseError(format!("Failed to update ingest job {}: {}", queued.batch.job_id, e)))?;
+    queue.ack(conn, queued.id)
+        .map_err(|e| ParseLogError::DatabaseError(e.to_string()))
+}
+
+// Storage failures are retried until the batch runs out of attempts. Anything else, or a batch
+// out of attempts, is given up on: every line is set aside as a dead letter so it can be re-parsed
+fn fail_batch(conn: &mut Connection, queue: &MessageQueue, queued: &QueuedBatch, err: &ParseLogError) -> Result<(), ParseLogError> {
+    if matches!(err, ParseLogError::DatabaseError(_)) && !queue.exhausted(queued) {
+        warn!("Batch {} failed on attempt {}, retrying: {}", queued.id, queued.attempts, err);
+        return queue.release(conn, queued.id)
+            .map_err(|e| ParseLogError::DatabaseError(e.to_string()));
+    }
+
+    error!("Giving up on batch {} after {} attempts: {}", queued.id, queued.attempts, err);
+    let batch = &queued.batch;
+    let reason = format!("Batch failed after {} attempts: {}", queued.attempts, err);
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for line in &batch.lines {
+        create_dead_letter(&tx, &batch.job_id, &batch.account_id, &batch.host_id, line, &reason)
+            .map_err(|e| ParseLogError::DatabaseError(format!("Failed to store dead letter: {}", e)))?;
     }
+    let stats = BatchStats { failures: batch.lines.len() as i64, ..BatchStats::default() };
+    settle(&tx, queue, queued, &stats)?;
+    tx.commit()?;
+    Ok(())
+}
 
-    for cef_log in batch.lines {
-        let id = format!("log{}", collector.next_id.fetch_add(1, Ordering::SeqCst));
-        let hash = "temp_hash".to_string();
+// Parse and store a batch, returning the logs that were newly inserted
+fn process_batch(conn: &Connection, batch: &Batch, stats: &mut BatchStats) -> Result<Vec<Log>, ParseLogError> {
+    let account_id = &batch.account_id;
+    let host_id = &batch.host_id;
+    let mut logs = Vec::with_capacity(batch.lines.len());
 
-        // Parse the log
-        let (log_json, timestamp) = process_log(&cef_log, &account_id, &host_id)?;
+    for cef_log in &batch.lines {
+        // Parse the log, setting aside lines that fail so the rest of the batch continues
+        let (log_json, timestamp) = match process_log(cef_log, account_id, host_id) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                stats.failures += 1;
+                create_dead_letter(conn, &batch.job_id, account_id, host_id, cef_log, &e.to_string())
+                    .map_err(|e| ParseLogError::DatabaseError(format!("Failed to store dead letter: {}", e)))?;
+                continue;
+            }
+        };
+        stats.lines_parsed += 1;
 
-        let log = Log {
-            id,
-            hash: hash.clone(),
+        logs.push(Log {
+            id: String::new(),
+            hash: String::new(),
             account_id: account_id.clone(),
             host_id: host_id.clone(),
             timestamp: Some(timestamp),
-            log_data: log_json.clone(),
-        };
+            log_data: log_json,
+            occurrence_count: 1,
+            last_seen: None,
+        });
+    }
 
-        // Store the log
-        match create_log(&log) {
-            Ok(Some(new_log)) => {
-                collector.add_log(new_log.clone());
+    let inserted = create_logs(conn, &logs)
+        .map_err(|e| ParseLogError::DatabaseError(e.to_string()))?;
+    stats.duplicates += inserted.duplicates as i64;
+    info!("Batch for job {}: {} inserted, {} duplicates", batch.job_id, inserted.inserted.len(), inserted.duplicates);
 
-                let normalized_log: NormalizedLog = serde_json::from_str(&log_json)
-                    .map_err(|e| ParseLogError::SerializationError(format!("Failed to deserialize log for rule evaluation: {}", e)))?;
-                match evaluate_log_against_rules(&normalized_log, &account_id).await {
-                    Ok(_alerts) => (),
-                    Err(err) => return Err(ParseLogError::DatabaseError(format!("Rule evaluation error: {}", err))),
-                }
-            }
-            Ok(None) => println!("Duplicate log skipped"),
-            Err(e) => return Err(ParseLogError::DatabaseError(e.to_string())),
+    for new_log in &inserted.inserted {
+        let normalized_log: NormalizedLog = serde_json::from_str(&new_log.log_data)
+            .map_err(|e| ParseLogError::SerializationError(format!("Failed to deserialize log for rule evaluation: {}", e)))?;
+        match evaluate_log_against_rules(conn, &normalized_log, account_id) {
+            Ok(alerts) => stats.alerts_raised += alerts.len() as i64,
+            Err(err) => return Err(ParseLogError::DatabaseError(format!("Rule evaluation error: {}", err))),
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(inserted.inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::ingest_job::{IngestJob, create_job, get_job};
+    use crate::test_support::{host, organization, test_pool};
+
+    const LINE: &str = "<34>Oct 11 22:14:15 mymachine sshd[0]: Failed password for invalid user root from 10.0.0.0 port 22";
+    const GARBAGE: &str = "not a log line";
+
+    // Queue one batch for a new organization's host: the organization and job IDs
+    fn queue_batch(pool: &DbPool, queue: &MessageQueue, lines: &[&str]) -> (String, String) {
+        let conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let job = create_job(&conn, &organization_id, &host_id, lines.len() as i64, 1).unwrap();
+        queue.enqueue_all(&conn, &[Batch {
+            job_id: job.id.clone(),
+            account_id: organization_id.clone(),
+            host_id,
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        }]).unwrap();
+        (organization_id, job.id)
+    }
+
+    // The pool has a single connection, so none may be held across `process_logs`
+    fn count(pool: &DbPool, table: &str) -> i64 {
+        pool.get().unwrap().query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    fn job(pool: &DbPool, organization_id: &str, job_id: &str) -> IngestJob {
+        get_job(&pool.get().unwrap(), organization_id, job_id).unwrap().unwrap()
+    }
+
+    // Every log insert fails until `restore_storage`, as on a full disk
+    fn break_storage(pool: &DbPool) {
+        pool.get().unwrap().execute_batch(
+            "CREATE TRIGGER fail_logs BEFORE INSERT ON logs BEGIN SELECT RAISE(ABORT, 'disk full'); END;"
+        ).unwrap();
+    }
+
+    fn restore_storage(pool: &DbPool) {
+        pool.get().unwrap().execute_batch("DROP TRIGGER fail_logs;").unwrap();
+    }
+
+    #[actix_web::test]
+    async fn a_batch_is_stored_and_acked_once() {
+        let pool = test_pool();
+        let queue = MessageQueue::with_limits(10, 3);
+        let collector = web::Data::new(LogCollector::new());
+        let (organization_id, job_id) = queue_batch(&pool, &queue, &[LINE, GARBAGE]);
+
+        assert!(process_logs(&pool, &queue, &collector).await.unwrap());
+        assert!(!process_logs(&pool, &queue, &collector).await.unwrap());
+
+        assert_eq!((count(&pool, "logs"), count(&pool, "dead_letters"), count(&pool, "message_queue")), (1, 1, 0));
+        let job = job(&pool, &organization_id, &job_id);
+        assert_eq!((job.lines_parsed, job.failures, job.batches_done), (1, 1, 1));
+        assert_eq!(job.status, "completed");
+    }
+
+    #[actix_web::test]
+    async fn a_storage_failure_is_retried_from_scratch() {
+        let pool = test_pool();
+        let queue = MessageQueue::with_limits(10, 3);
+        let collector = web::Data::new(LogCollector::new());
+        let (organization_id, job_id) = queue_batch(&pool, &queue, &[LINE, GARBAGE]);
+
+        break_storage(&pool);
+        assert!(process_logs(&pool, &queue, &collector).await.is_err());
+        // The failed attempt's dead letter and counters were rolled back with it
+        assert_eq!((count(&pool, "logs"), count(&pool, "dead_letters"), count(&pool, "message_queue")), (0, 0, 1));
+        assert_eq!(job(&pool, &organization_id, &job_id).batches_done, 0);
+
+        restore_storage(&pool);
+        assert!(process_logs(&pool, &queue, &collector).await.unwrap());
+        assert_eq!((count(&pool, "logs"), count(&pool, "dead_letters"), count(&pool, "message_queue")), (1, 1, 0));
+        let job = job(&pool, &organization_id, &job_id);
+        assert_eq!((job.lines_parsed, job.failures, job.batches_done), (1, 1, 1));
+    }
+
+    #[actix_web::test]
+    async fn a_batch_out_of_attempts_is_dead_lettered() {
+        let pool = test_pool();
+        let queue = MessageQueue::with_limits(10, 2);
+        let collector = web::Data::new(LogCollector::new());
+        let (organization_id, job_id) = queue_batch(&pool, &queue, &[LINE, GARBAGE]);
+
+        break_storage(&pool);
+        assert!(process_logs(&pool, &queue, &collector).await.is_err());
+        assert!(process_logs(&pool, &queue, &collector).await.is_err());
+        assert!(!process_logs(&pool, &queue, &collector).await.unwrap());
+
+        assert_eq!((count(&pool, "logs"), count(&pool, "message_queue")), (0, 0));
+        let reasons: Vec<String> = pool.get().unwrap()
+            .prepare("SELECT error FROM dead_letters ORDER BY raw").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons.iter().all(|reason| reason.starts_with("Batch failed after 2 attempts")));
+        let job = job(&pool, &organization_id, &job_id);
+        assert_eq!((job.lines_parsed, job.failures, job.batches_done), (0, 2, 1));
+        assert_eq!(job.status, "completed");
+    }
+
+    #[actix_web::test]
+    async fn workers_drain_the_queue_and_complete_the_job() {
+        let pool = test_pool();
+        let queue = MessageQueue::with_limits(10, 3);
+        let collector = web::Data::new(LogCollector::new());
+        let (organization_id, job_id) = {
+            let conn = pool.get().unwrap();
+            let (organization_id, _) = organization(&conn, "acme");
+            let host_id = host(&conn, &organization_id, "web");
+            let job = create_job(&conn, &organization_id, &host_id, 3, 2).unwrap();
+            let batch = |lines: &[&str]| Batch {
+                job_id: job.id.clone(),
+                account_id: organization_id.clone(),
+                host_id: host_id.clone(),
+                lines: lines.iter().map(|line| line.to_string()).collect(),
+            };
+            queue.enqueue_all(&conn, &[batch(&[LINE, GARBAGE]), batch(&[LINE])]).unwrap();
+            (organization_id, job.id)
+        };
+
+        spawn_workers(pool.clone(), queue.clone(), collector.clone(), 2);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while job(&pool, &organization_id, &job_id).status != "completed" {
+            assert!(Instant::now() < deadline, "workers did not finish the job");
+            rt::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let job = job(&pool, &organization_id, &job_id);
+        assert_eq!((job.lines_read, job.lines_parsed, job.duplicates, job.failures), (3, 2, 1, 1));
+        assert_eq!((job.alerts_raised, job.batches_done, job.batches_total), (0, 2, 2));
+        assert_eq!((count(&pool, "logs"), count(&pool, "dead_letters"), count(&pool, "message_queue")), (1, 1, 0));
+        // Only the one new log was numbered
+        assert_eq!(collector.add_log(), 2);
+    }
+}
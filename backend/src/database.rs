@@ -1,30 +1,88 @@
-use rusqlite::{Connection, Result, Error};
-use super::schema::Schema;
+use rusqlite::{ffi, Connection, Result, Error};
+use r2d2_sqlite::SqliteConnectionManager;
+use crate::migrations::apply_pending;
 use log::{info, error};
-use dotenvy::dotenv;
+use actix_web::web;
+use std::time::Duration;
 use std::env;
 
-pub fn establish_connection() -> Result<Connection> {
-    dotenv().ok();
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
 
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Open the pool without touching the schema
+pub fn build_pool() -> Result<DbPool> {
     let database_url = env::var("DATABASE_URL")
         .map_err(|e| {
             error!("DATABASE_URL environment variable not set: {}", e);
             Error::InvalidParameterName(format!("DATABASE_URL must be set: {}", e))
         })?;
 
-    info!("Attempting to connect to database");
-    let conn = Connection::open(&database_url)
+    let max_size = env::var("DATABASE_POOL_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(8);
+
+    // Every pooled connection enforces foreign keys and waits on locks instead of failing
+    let manager = SqliteConnectionManager::file(&database_url)
+        .with_init(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.execute_batch("PRAGMA foreign_keys = ON;")
+        });
+
+    info!("Creating database pool for {}", database_url);
+    let pool = r2d2::Pool::builder()
+        .max_size(max_size)
+        .build(manager)
         .map_err(|e| {
-            error!("Failed to connect to database: {}", e);
+            error!("Failed to create database pool: {}", e);
             Error::InvalidParameterName(format!("Error connecting to {}: {}", database_url, e))
         })?;
 
-    conn.execute("PRAGMA foreign_keys = ON", [])?;
-    
-    info!("Initializing database schemas");
-    Schema::create_all(&conn)?;
+    let conn = pool.get().map_err(pool_error)?;
+
+    // WAL is persistent, so it only needs to be set once per database file
+    conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get::<_, String>(0))?;
+
+    info!("Database pool created successfully");
+    Ok(pool)
+}
+
+// Open the pool and bring the schema up to date before serving requests
+pub fn create_pool() -> Result<DbPool> {
+    let pool = build_pool()?;
+    let mut conn = pool.get().map_err(pool_error)?;
+
+    match apply_pending(&mut conn) {
+        Ok(0) => {},
+        Ok(applied) => info!("Applied {} schema migrations", applied),
+        Err(e) => {
+            error!("Failed to migrate database: {}", e);
+            return Err(Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_ERROR), Some(e.to_string())));
+        }
+    }
+
+    Ok(pool)
+}
+
+// Run blocking SQLite work on actix's blocking thread pool with a pooled connection
+pub async fn run<F, T, E>(pool: &DbPool, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<Error> + Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let mut conn = pool.get().map_err(pool_error)?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| E::from(Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_ABORT), Some(e.to_string()))))?
+}
 
-    info!("Database connection established successfully");
-    Ok(conn)
-}
\ No newline at end of file
+fn pool_error(err: r2d2::Error) -> Error {
+    error!("Failed to get a database connection: {}", err);
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_CANTOPEN), Some(err.to_string()))
+}
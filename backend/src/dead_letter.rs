@@ -0,0 +1,201 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+use uuid::Uuid;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum DeadLetterError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for DeadLetterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeadLetterError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            DeadLetterError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for DeadLetterError {
+    fn from(err: SqliteError) -> Self {
+        DeadLetterError::DatabaseError(err)
+    }
+}
+
+// A raw log line that could not be parsed, kept so it can be re-parsed later
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetter {
+    pub id: String,
+    pub job_id: String,
+    pub account_id: String,
+    pub host_id: String,
+    pub raw: String,
+    pub error: String,
+    pub created_at: String,
+}
+
+pub fn create_dead_letter(conn: &Connection, job_id: &str, account_id: &str, host_id: &str, raw: &str, error: &str) -> Result<DeadLetter, DeadLetterError> {
+    if account_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if host_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Host ID cannot be empty".to_string()));
+    }
+
+    let letter = DeadLetter {
+        id: Uuid::new_v4().to_string(),
+        job_id: job_id.to_string(),
+        account_id: account_id.to_string(),
+        host_id: host_id.to_string(),
+        raw: raw.to_string(),
+        error: error.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    conn.execute(
+        "INSERT INTO dead_letters (id, job_id, account_id, host_id, raw, error, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            letter.id,
+            letter.job_id,
+            letter.account_id,
+            letter.host_id,
+            letter.raw,
+            letter.error,
+            letter.created_at,
+        ],
+    )?;
+
+    Ok(letter)
+}
+
+pub fn get_dead_letter(conn: &Connection, account_id: &str, id: &str) -> Result<Option<DeadLetter>, DeadLetterError> {
+    if account_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Dead letter ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, job_id, account_id, host_id, raw, error, created_at
+         FROM dead_letters WHERE id = ?1 AND account_id = ?2"
+    )?;
+
+    let letter = stmt.query_row(params![id, account_id], |row| {
+        Ok(DeadLetter {
+            id: row.get(0)?,
+            job_id: row.get(1)?,
+            account_id: row.get(2)?,
+            host_id: row.get(3)?,
+            raw: row.get(4)?,
+            error: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }).optional()?;
+
+    Ok(letter)
+}
+
+pub fn list_dead_letters(conn: &Connection, account_id: &str) -> Result<Vec<DeadLetter>, DeadLetterError> {
+    if account_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, job_id, account_id, host_id, raw, error, created_at
+         FROM dead_letters WHERE account_id = ?1
+         ORDER BY created_at DESC"
+    )?;
+
+    let letters_iter = stmt.query_map(params![account_id], |row| {
+        Ok(DeadLetter {
+            id: row.get(0)?,
+            job_id: row.get(1)?,
+            account_id: row.get(2)?,
+            host_id: row.get(3)?,
+            raw: row.get(4)?,
+            error: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    })?;
+
+    let letters: Result<Vec<DeadLetter>, SqliteError> = letters_iter.collect();
+    Ok(letters?)
+}
+
+pub fn delete_dead_letter(conn: &Connection, account_id: &str, id: &str) -> Result<bool, DeadLetterError> {
+    if account_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Dead letter ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "DELETE FROM dead_letters WHERE id = ?1 AND account_id = ?2",
+        params![id, account_id],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+pub fn purge_dead_letters(conn: &Connection, account_id: &str) -> Result<usize, DeadLetterError> {
+    if account_id.is_empty() {
+        return Err(DeadLetterError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute("DELETE FROM dead_letters WHERE account_id = ?1", params![account_id])?;
+    Ok(affected_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{host, organization, test_pool};
+
+    // A dead letter from a new host of the organization, named after the line
+    fn letter(conn: &Connection, organization_id: &str, raw: &str) -> DeadLetter {
+        let host_id = host(conn, organization_id, raw);
+        create_dead_letter(conn, "job", organization_id, &host_id, raw, "unparseable").unwrap()
+    }
+
+    #[test]
+    fn dead_letters_need_an_account_and_host() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+
+        assert!(matches!(create_dead_letter(&conn, "job", "", &host_id, "raw", "bad"), Err(DeadLetterError::ValidationError(_))));
+        assert!(matches!(create_dead_letter(&conn, "job", &organization_id, "", "raw", "bad"), Err(DeadLetterError::ValidationError(_))));
+        assert!(matches!(get_dead_letter(&conn, &organization_id, ""), Err(DeadLetterError::ValidationError(_))));
+        assert!(matches!(purge_dead_letters(&conn, ""), Err(DeadLetterError::ValidationError(_))));
+    }
+
+    #[test]
+    fn list_delete_and_purge_stay_within_the_organization() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (acme, _) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+        let first = letter(&conn, &acme, "first");
+        letter(&conn, &acme, "second");
+        let foreign = letter(&conn, &globex, "foreign");
+
+        assert_eq!(list_dead_letters(&conn, &acme).unwrap().len(), 2);
+        assert_eq!(get_dead_letter(&conn, &acme, &first.id).unwrap().unwrap().raw, "first");
+        assert!(get_dead_letter(&conn, &acme, &foreign.id).unwrap().is_none());
+
+        assert!(!delete_dead_letter(&conn, &acme, &foreign.id).unwrap());
+        assert!(delete_dead_letter(&conn, &acme, &first.id).unwrap());
+        assert!(!delete_dead_letter(&conn, &acme, &first.id).unwrap());
+
+        assert_eq!(purge_dead_letters(&conn, &acme).unwrap(), 1);
+        assert!(list_dead_letters(&conn, &acme).unwrap().is_empty());
+        let left: Vec<String> = list_dead_letters(&conn, &globex).unwrap().into_iter().map(|letter| letter.id).collect();
+        assert_eq!(left, vec![foreign.id]);
+    }
+}
@@ -1,8 +1,7 @@
-use crate::database::establish_connection;
-use rusqlite::{Error as SqliteError};
+use rusqlite::{Connection, Error as SqliteError};
 use serde_json::{Value, from_str};
 use chrono::NaiveDateTime;
-use rusqlite::params;
+use rusqlite::ToSql;
 use crate::log::Log;
 use std::fmt;
 
@@ -20,6 +19,7 @@ pub enum Token {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
 pub enum EqlError {
     ParseError(String),
     QueryBuildError(String),
@@ -138,7 +138,7 @@ impl EqlParser {
     // Lexer for quoted strings
     fn read_until_quote(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, EqlError> {
         let mut value = String::new();
-        while let Some(c) = chars.next() {
+        for c in chars.by_ref() {
             if c == '"' {
                 return Ok(value);
             }
@@ -172,6 +172,26 @@ struct Condition {
 #[derive(Debug)]
 struct EqlQuery {
     conditions: Vec<Condition>,
+    // Quoted strings on their own are full-text phrases, e.g. "Failed password"
+    text_terms: Vec<String>,
+}
+
+impl EqlQuery {
+    // FTS5 MATCH expression requiring every phrase. A trailing * makes it a prefix search
+    fn fts_match(&self) -> Option<String> {
+        if self.text_terms.is_empty() {
+            return None;
+        }
+
+        let phrases: Vec<String> = self.text_terms.iter().map(|term| {
+            let (phrase, prefix) = match term.strip_suffix('*') {
+                Some(stripped) => (stripped, " *"),
+                None => (term.as_str(), ""),
+            };
+            format!("\"{}\"{}", phrase.replace('"', "\"\""), prefix)
+        }).collect();
+        Some(phrases.join(" AND "))
+    }
 }
 
 pub struct QueryExecutor;
@@ -180,6 +200,7 @@ impl QueryExecutor {
     // Parse EQL query into a structured format
     fn parse_query(tokens: Vec<Token>) -> Result<EqlQuery, EqlError> {
         let mut conditions = Vec::new();
+        let mut text_terms = Vec::new();
         let mut current_field = None;
         let mut current_operator = None;
 
@@ -200,12 +221,17 @@ impl QueryExecutor {
                 Token::Value(val) => {
                     if let (Some(field), Some(operator)) = (current_field.take(), current_operator.take()) {
                         conditions.push(Condition { field, operator, value: val });
+                    } else if current_field.is_none() && current_operator.is_none() {
+                        if val.trim_end_matches('*').trim().is_empty() {
+                            return Err(EqlError::ParseError("Empty search text".to_string()));
+                        }
+                        text_terms.push(val);
                     } else {
                         return Err(EqlError::ParseError("Value token without field or operator".to_string()));
                     }
                 }
                 Token::TimeRange(range) => {
-                    let mut parts = range.split(|c| c == '>' || c == '<');
+                    let mut parts = range.split(['>', '<']);
                     let value = parts.next().ok_or_else(|| EqlError::QueryBuildError("Invalid time range".to_string()))?;
                     if NaiveDateTime::parse_from_str(value, "%Y-%m-%d").is_err() {
                         return Err(EqlError::QueryBuildError("Invalid datetime format".to_string()));
@@ -219,7 +245,7 @@ impl QueryExecutor {
             }
         }
 
-        Ok(EqlQuery { conditions })
+        Ok(EqlQuery { conditions, text_terms })
     }
 
     // Check if a log matches the EQL query
@@ -261,29 +287,36 @@ impl QueryExecutor {
     }
 
     // Execute EQL query and return matching logs one at a time
-    pub fn execute_query(
+    pub fn execute_query(conn: &Connection, 
         account_id: &str,
         start_time: &str,
         end_time: &str,
         eql_query: &str,
     ) -> Result<Vec<Log>, EqlError> {
-        let conn = establish_connection()
-            .map_err(|e| EqlError::DatabaseError(e.to_string()))?;
 
         // Parse the EQL query into a structured format
         let tokens = EqlParser::parse(eql_query)?;
         let query = Self::parse_query(tokens)?;
 
-        // Prepare the base SQL query for filtering by account_id and timestamp
-        let mut stmt = conn.prepare(
-            "SELECT id, hash, account_id, host_id, timestamp, log_data 
+        // Prepare the base SQL query for filtering by account_id and timestamp,
+        // narrowed by the full-text index when the query has free text
+        let fts_match = query.fts_match();
+        let mut sql = "SELECT id, hash, account_id, host_id, timestamp, log_data, occurrence_count, last_seen
              FROM logs 
-             WHERE account_id = ?1 AND timestamp BETWEEN ?2 AND ?3"
-        ).map_err(|e| EqlError::DatabaseError(e.to_string()))?;
+             WHERE account_id = ?1 AND timestamp BETWEEN ?2 AND ?3".to_string();
+        if fts_match.is_some() {
+            sql.push_str(" AND id IN (SELECT log_id FROM logs_fts WHERE logs_fts MATCH ?4)");
+        }
+        let mut stmt = conn.prepare(&sql).map_err(|e| EqlError::DatabaseError(e.to_string()))?;
+
+        let mut bindings: Vec<&dyn ToSql> = vec![&account_id, &start_time, &end_time];
+        if let Some(fts_match) = &fts_match {
+            bindings.push(fts_match);
+        }
 
         // Stream results one row at a time. We only load one log to memory at a time
         let rows = stmt.query_map(
-            params![account_id, start_time, end_time],
+            bindings.as_slice(),
             |row| {
                 Ok(Log {
                     id: row.get(0)?,
@@ -292,6 +325,8 @@ impl QueryExecutor {
                     host_id: row.get(3)?,
                     timestamp: row.get(4)?,
                     log_data: row.get(5)?,
+                    occurrence_count: row.get(6)?,
+                    last_seen: row.get(7)?,
                 })
             }
         )?;
@@ -306,3 +341,68 @@ impl QueryExecutor {
         Ok(matching_logs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{host, organization, store_logs, test_pool};
+
+    // The raw lines of the account's logs matching the query, sorted
+    fn search(conn: &Connection, account_id: &str, eql_query: &str) -> Vec<String> {
+        let logs = QueryExecutor::execute_query(conn, account_id, "2000-01-01", "2100-01-01", eql_query).unwrap();
+        let mut lines: Vec<String> = logs.iter()
+            .map(|log| from_str::<Value>(&log.log_data).unwrap()["raw"].as_str().unwrap().to_string())
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn phrases_match_whole_words_in_order() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        store_logs(&mut conn, &organization_id, &host_id, &[
+            "Failed password for root from 10.0.0.9",
+            "Accepted password for alice",
+            "password Failed twice",
+        ]);
+
+        assert_eq!(search(&conn, &organization_id, "\"failed password\""), vec!["Failed password for root from 10.0.0.9"]);
+        assert_eq!(search(&conn, &organization_id, "\"password\" \"alice\""), vec!["Accepted password for alice"]);
+        assert_eq!(search(&conn, &organization_id, "\"pass*\"").len(), 3);
+        assert!(search(&conn, &organization_id, "\"pass\"").is_empty());
+        assert_eq!(search(&conn, &organization_id, "\"alice\" AND event_type = \"test\"").len(), 1);
+        assert!(search(&conn, &organization_id, "\"alice\" AND event_type = \"other\"").is_empty());
+    }
+
+    #[test]
+    fn the_index_follows_the_account_and_deletes() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (acme, _) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+        let acme_host = host(&conn, &acme, "web");
+        let globex_host = host(&conn, &globex, "web");
+        let stored = store_logs(&mut conn, &acme, &acme_host, &["disk full on /var"]);
+        store_logs(&mut conn, &globex, &globex_host, &["disk full on /home"]);
+
+        assert_eq!(search(&conn, &acme, "\"disk full\""), vec!["disk full on /var"]);
+
+        conn.execute("DELETE FROM logs WHERE id = ?1", rusqlite::params![stored[0].id]).unwrap();
+        assert!(search(&conn, &acme, "\"disk full\"").is_empty());
+        let indexed: i64 = conn.query_row("SELECT COUNT(*) FROM logs_fts WHERE logs_fts MATCH '\"disk full\"'", [], |row| row.get(0)).unwrap();
+        assert_eq!(indexed, 1);
+    }
+
+    #[test]
+    fn search_text_is_quoted_for_fts() {
+        let query = QueryExecutor::parse_query(EqlParser::parse("\"Failed password\" \"adm*\"").unwrap()).unwrap();
+        assert_eq!(query.text_terms, vec!["Failed password", "adm*"]);
+        let query = EqlQuery { conditions: Vec::new(), text_terms: vec!["a \"b\" OR c".to_string(), "adm*".to_string()] };
+        assert_eq!(query.fts_match().unwrap(), "\"a \"\"b\"\" OR c\" AND \"adm\" *");
+
+        assert!(matches!(QueryExecutor::parse_query(EqlParser::parse("\" *\"").unwrap()), Err(EqlError::ParseError(_))));
+    }
+}
@@ -1,9 +1,8 @@
-use crate::message_queue::MessageQueue;
 use lazy_static::lazy_static;
-use tokio::sync::Mutex;
-use std::sync::{Arc};
+use tokio::sync::Notify;
+use std::sync::Arc;
 
-// Single global message queue instance
 lazy_static! {
-    pub static ref GLOBAL_MESSAGE_QUEUE: Arc<Mutex<MessageQueue>> = Arc::new(Mutex::new(MessageQueue::new()));
+    // Wakes idle ingestion workers when new batches are enqueued
+    pub static ref INGEST_SIGNAL: Arc<Notify> = Arc::new(Notify::new());
 }
\ No newline at end of file
@@ -1,22 +1,85 @@
-use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
+use actix_web::{web, error::ErrorForbidden, http::header::RETRY_AFTER, HttpResponse, HttpRequest, Error};
 use actix_session::Session;
 use serde_json::json;
 use log::error;
-use crate::account::{Account, AccountError, create_account, get_account, update_account, delete_account, verify_login};
+use crate::account::{Account, AccountError, count_accounts, create_account, get_account, update_account, delete_account, LoginTarget, login_target, verify_credentials, grant_login, start_session};
+use crate::auth_session::{Tenant, client_ip};
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::rbac::{Permission, authorize};
+use crate::organization::{OrganizationError, bootstrap_organization};
+use crate::mfa::{MfaState, login_state};
+use crate::password::{password_change_required, require_password_change};
+use crate::login_guard::{LoginBlock, reserve_login, release_attempt, record_failure, record_success, unlock_account};
+use crate::database::{DbPool, run};
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 
 pub async fn create_account_handler(
     req: HttpRequest,
+    pool: web::Data<DbPool>,
     account: web::Json<Account>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
+
+    // The first account bootstraps the installation as an Admin of a new organization,
+    // later ones need users:manage and join the creator's organization
+    let existing = match run(&pool, |conn| count_accounts(conn)).await {
+        Ok(count) => count,
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            return Ok(HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            })));
+        }
+    };
     let account = account.into_inner();
     let name = account.name;
     let password = account.password;
+    if existing == 0 {
+        let bootstrapped = run(&pool, move |conn| {
+            let account_id = bootstrap_organization(conn, name, password)?;
+            let organization_id: String = conn.query_row(
+                "SELECT organization_id FROM accounts WHERE id = ?1",
+                [&account_id],
+                |row| row.get(0),
+            )?;
+            Ok::<_, OrganizationError>((account_id, organization_id))
+        }).await;
+        return match bootstrapped {
+            Ok((account_id, organization_id)) => {
+                let actor = AuditActor::account(&organization_id, &account_id, &req);
+                Audit::new(actor, "account.create", AuditTarget::Account, &account_id).record(&pool, None).await;
+                Ok(HttpResponse::Ok().json(account_id))
+            }
+            Err(OrganizationError::ValidationError(error)) => Ok(HttpResponse::BadRequest().json(json!({
+                "status": "error",
+                "message": error
+            }))),
+            Err(err) => {
+                error!("Internal server error: {:?}", err);
+                Ok(HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "An internal error occurred"
+                })))
+            }
+        };
+    }
+
+    let tenant = authorize(&req, Permission::ManageUsers).await?;
     let role = account.role;
-    match create_account(name, password, role) {
-        Ok(account) => Ok(HttpResponse::Ok().json(account)),
+    let actor = AuditActor::new(&tenant, &req);
+    // The admin chose the password, so the new member replaces it at first login
+    let created = run(&pool, move |conn| {
+        let account_id = create_account(conn, &tenant.organization_id, name, password, role)?;
+        require_password_change(conn, &account_id)?;
+        Ok::<_, AccountError>(account_id)
+    }).await;
+    match created {
+        Ok(account_id) => {
+            Audit::new(actor, "account.create", AuditTarget::Account, &account_id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(account_id))
+        }
         Err(err) => match err {
             AccountError::InvalidRole => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -41,30 +104,68 @@ pub async fn create_account_handler(
     }
 }
 
-pub async fn get_account_handler(account_id: web::Path<String>) -> impl Responder {
-    match get_account(&account_id.to_string()) {
-        Ok(host) => HttpResponse::Ok().json(host),
+pub async fn get_account_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    if tenant.account_id != account_id {
+        return Err(ErrorForbidden("Access to this account is not allowed"));
+    }
+    match run(&pool, move |conn| get_account(conn, &account_id)).await {
+        Ok(host) => Ok(HttpResponse::Ok().json(host)),
         Err(err) => match err {
-            AccountError::ValidationError(err) => HttpResponse::BadRequest().json(json!({
+            AccountError::ValidationError(err) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
                 "message": err.to_string()
-            })),
-            _ => HttpResponse::InternalServerError().json(json!({
+            }))),
+            _ => Ok(HttpResponse::InternalServerError().json(json!({
                 "status": "error",
                 "message": err.to_string()
-            })),
+            }))),
         }
     }
 }
 
 pub async fn edit_account_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
     account: web::Json<Account>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match update_account(&account) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.update", AuditTarget::Account, account_id.as_str());
+    let before = audit.snapshot(&pool).await;
+    let account = Account {
+        id: account_id.into_inner(),
+        organization_id: tenant.organization_id,
+        ..account.into_inner()
+    };
+    // Passwords have their own endpoints, which check the current password and the history
+    if !account.password.is_empty() {
+        let message = if account.id == tenant.account_id {
+            "Change your password with /account/password"
+        } else {
+            "Issue a password reset for this account instead"
+        };
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": message
+        })));
+    }
+    let result = run(&pool, move |conn| update_account(conn, &account)).await;
+    match result {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(true))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Account not found"
+        }))),
         Err(err) => match err {
             AccountError::InvalidRole => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -91,12 +192,24 @@ pub async fn edit_account_handler(
 
 pub async fn delete_account_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     account_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match delete_account(&account_id.to_string()) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let account_id = account_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.delete", AuditTarget::Account, &account_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_account(conn, &tenant.organization_id, &account_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(true))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Account not found"
+        }))),
         Err(err) => match err {
             AccountError::ValidationError(error) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -113,9 +226,54 @@ pub async fn delete_account_handler(
     }
 }
 
+enum LoginOutcome {
+    // The MFA step still due, and whether the password has to be replaced first
+    Success(Account, MfaState, bool),
+    Failed,
+    Blocked(LoginBlock),
+}
+
+// Check a reserved login attempt and settle it. Directory binds wait on the network, so the
+// credentials are checked without holding a database connection
+async fn check_credentials(
+    pool: &DbPool,
+    name: String,
+    password: String,
+    ip: String,
+    target: Option<LoginTarget>
+) -> Result<LoginOutcome, AccountError> {
+    let verify_name = name.clone();
+    let verified = web::block(move || verify_credentials(target.as_ref(), &verify_name, &password)).await
+        .map_err(|e| AccountError::ProviderError(e.to_string()))
+        .and_then(|verified| verified);
+
+    run(pool, move |conn| {
+        let authenticated = verified.and_then(|verified| match verified {
+            Some((provider, grant)) => grant_login(conn, &name, provider, grant),
+            None => Ok(None),
+        });
+        if !matches!(authenticated, Ok(None)) {
+            release_attempt(conn, &name, &ip)?;
+        }
+        match authenticated? {
+            Some(account) => {
+                record_success(conn, &name)?;
+                let mfa_state = login_state(conn, &account.id, &account.organization_id)?;
+                let change_password = password_change_required(conn, &account.id)?;
+                Ok(LoginOutcome::Success(account, mfa_state, change_password))
+            }
+            None => {
+                record_failure(conn, &name, &ip)?;
+                Ok(LoginOutcome::Failed)
+            }
+        }
+    }).await
+}
+
 pub async fn login_account_handler(
     req: HttpRequest,
     session: Session,
+    pool: web::Data<DbPool>,
     account: web::Json<Account>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
@@ -124,16 +282,66 @@ pub async fn login_account_handler(
     let account_data = account.into_inner();
     let name = account_data.name;
     let password = account_data.password;
-    match verify_login(&session, &name, &password, &req) {
-        Ok(Some(account)) => Ok(HttpResponse::Ok().json(json!({
+    let ip = client_ip(&req);
+    // Refuse without checking the password while the name or address is backing off
+    let (target_name, target_ip) = (name.clone(), ip.clone());
+    let reserved = run(&pool, move |conn| {
+        if let Some(block) = reserve_login(conn, &target_name, &target_ip)? {
+            return Ok(Err(block));
+        }
+        match login_target(conn, &target_name) {
+            Ok(target) => Ok(Ok(target)),
+            Err(err) => {
+                release_attempt(conn, &target_name, &target_ip)?;
+                Err(err)
+            }
+        }
+    }).await;
+    let login = match reserved {
+        Ok(Ok(target)) => check_credentials(&pool, name, password, ip, target).await,
+        Ok(Err(block)) => Ok(LoginOutcome::Blocked(block)),
+        Err(err) => Err(err),
+    }
+        .and_then(|login| match login {
+            LoginOutcome::Success(account, mfa_state, change_password) => start_session(&session, &account, mfa_state, &req)
+                .and_then(|_| session.insert("password_change_required", change_password)
+                    .map_err(|e| AccountError::SessionError(e.to_string())))
+                .map(|_| LoginOutcome::Success(account, mfa_state, change_password)),
+            other => Ok(other),
+        });
+    match login {
+        Ok(LoginOutcome::Success(account, MfaState::Verified, false)) => Ok(HttpResponse::Ok().json(json!({
             "status": "success",
             "message": "Login successful!",
             "account": account
         }))),
-        Ok(None) => Ok(HttpResponse::Unauthorized().json(json!({
+        // The password was right but the session stays locked until the MFA step
+        Ok(LoginOutcome::Success(_, mfa_state, change_password)) if mfa_state != MfaState::Verified => Ok(HttpResponse::Ok().json(json!({
+            "status": "mfa_required",
+            "message": "Multi-factor authentication required",
+            "mfa": mfa_state,
+            "password_change_required": change_password
+        }))),
+        // Only `/account/password` works until a new password is set
+        Ok(LoginOutcome::Success(..)) => Ok(HttpResponse::Ok().json(json!({
+            "status": "password_change_required",
+            "message": "You must choose a new password"
+        }))),
+        Ok(LoginOutcome::Failed) => Ok(HttpResponse::Unauthorized().json(json!({
             "status": "error",
             "message": "Invalid username or password"
         }))),
+        Ok(LoginOutcome::Blocked(block)) => Ok(HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, block.retry_after.to_string()))
+            .json(json!({
+                "status": "error",
+                "message": if block.locked {
+                    "Too many failed login attempts, the account is temporarily locked"
+                } else {
+                    "Too many failed login attempts, try again later"
+                },
+                "retry_after": block.retry_after
+            }))),
         Err(err) => match err {
             AccountError::InvalidRole => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -147,10 +355,56 @@ pub async fn login_account_handler(
                 "status": "error",
                 "message": error.to_string()
             }))),
+            AccountError::ProviderError(error) => Ok(HttpResponse::ServiceUnavailable().json(json!({
+                "status": "error",
+                "message": error
+            }))),
             _ => Ok(HttpResponse::InternalServerError().json(json!({
                 "status": "error",
                 "message": "An internal error occurred"
             }))),
         },
     }
-}
\ No newline at end of file
+}
+
+// Admin unlock: clears failed logins, backoff and lockout for an account in the organization
+pub async fn unlock_account_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let account_id = account_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.unlock", AuditTarget::Account, &account_id);
+
+    let result = run(&pool, move |conn| {
+        match get_account(conn, &account_id)? {
+            Some(account) if account.organization_id == tenant.organization_id => {
+                unlock_account(conn, &account.name)?;
+                Ok(true)
+            }
+            _ => Ok::<_, AccountError>(false),
+        }
+    }).await;
+
+    match result {
+        Ok(true) => {
+            // The lockout lives outside the account row, so there is no state to compare
+            audit.record_with(&pool, None, None).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Account not found"
+        }))),
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            Ok(HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            })))
+        }
+    }
+}
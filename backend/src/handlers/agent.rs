@@ -1,29 +1,52 @@
 use actix_multipart::form::{tempfile::TempFile, MultipartForm, text::Text};
-use actix_web::{web, HttpResponse, Error};
+use actix_web::{web, HttpResponse, HttpRequest, Error};
 use serde::Deserialize;
 use serde_json::json;
 use chrono::Utc;
-use crate::agent::{Agent, register_agent, verify_agent_api_key, update_agent_last_seen};
-use crate::collector::{LogCollector, process_logs};
-use crate::batch_maker::create_batches;
+use log::{error, warn};
+use crate::agent::{Agent, AgentError, AgentHeartbeat, register_agent, authenticate_agent, rotate_agent_key, revoke_agent, record_heartbeat, list_agents};
+use crate::auth_session::{Tenant, client_ip};
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+use crate::batch_maker::{BatchError, create_batches};
+use crate::message_queue::{MessageQueue, QueueError};
+use crate::database::{DbPool, run};
+use super::queue_full_response;
 
 #[derive(Debug, MultipartForm)]
 pub struct AgentUploadForm {
     #[multipart(rename = "file")]
     log_file: TempFile,
     api_key: Text<String>,
-    account_id: Text<String>,
-    host_id: Text<String>,
+    // Optional, and refused unless they name the agent's own organization and host
+    account_id: Option<Text<String>>,
+    host_id: Option<Text<String>>,
 }
 
+// Agents are registered by a signed-in user, for one of that user's own hosts
 pub async fn register_agent_handler(
-    agent: web::Json<Agent>
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    agent: web::Json<Agent>,
+    csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
-    match register_agent(&agent) {
-        Ok((id, api_key)) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "agent_id": id,
-            "api_key": api_key
+    csrf_validator(&req, &csrf).await?;
+
+    let actor = AuditActor::new(&tenant, &req);
+    let agent = Agent { account_id: tenant.organization_id, ..agent.into_inner() };
+    match run(&pool, move |conn| register_agent(conn, &agent)).await {
+        Ok((id, api_key)) => {
+            Audit::new(actor, "agent.register", AuditTarget::Agent, &id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "agent_id": id,
+                "api_key": api_key
+            })))
+        }
+        Err(AgentError::ValidationError(msg)) => Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
         }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
@@ -32,67 +55,174 @@ pub async fn register_agent_handler(
     }
 }
 
+#[derive(Deserialize)]
+pub struct KeyRotation {
+    // How long the current key keeps working, 24 hours when not given
+    pub grace_hours: Option<i64>,
+}
+
+fn agent_error_response(err: AgentError) -> HttpResponse {
+    match err {
+        AgentError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// Issue a new key for an agent. The response carries the key, which is shown only once
+pub async fn rotate_agent_key_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    agent_id: web::Path<String>,
+    rotation: Option<web::Json<KeyRotation>>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let agent_id = agent_id.into_inner();
+    let grace_hours = rotation.and_then(|rotation| rotation.into_inner().grace_hours);
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "agent.rotate_key", AuditTarget::Agent, &agent_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| rotate_agent_key(conn, &tenant.organization_id, &agent_id, grace_hours)).await {
+        Ok(Some(key)) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(key))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Agent not found"
+        }))),
+        Err(err) => Ok(agent_error_response(err)),
+    }
+}
+
+// The organization's agents with what each last reported, for watching the fleet
+pub async fn get_agents_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    match run(&pool, move |conn| list_agents(conn, &tenant.organization_id)).await {
+        Ok(agents) => Ok(HttpResponse::Ok().json(agents)),
+        Err(err) => Ok(agent_error_response(err)),
+    }
+}
+
+// Mark an agent inactive so none of its keys are accepted
+pub async fn revoke_agent_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    agent_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let agent_id = agent_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "agent.revoke", AuditTarget::Agent, &agent_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| revoke_agent(conn, &tenant.organization_id, &agent_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Agent not found"
+        }))),
+        Err(err) => Ok(agent_error_response(err)),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct HeartbeatRequest {
     api_key: String,
+    #[serde(flatten)]
+    heartbeat: AgentHeartbeat,
 }
 
 pub async fn agent_heartbeat_handler(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
     payload: web::Json<HeartbeatRequest>,
 ) -> Result<HttpResponse, Error> {
-    match verify_agent_api_key(&payload.api_key) {
-        Ok(true) => {
-            match update_agent_last_seen(&payload.api_key) {
-                Ok(_) => Ok(HttpResponse::Ok().json(json!({
-                    "status": "success",
-                    "timestamp": Utc::now()
-                }))),
-                Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
-                    "status": "error",
-                    "message": err.to_string()
-                })))
-            }
-        },
+    let HeartbeatRequest { api_key, heartbeat } = payload.into_inner();
+    let ip = client_ip(&req);
+    let result = run(&pool, move |conn| {
+        let Some(agent) = authenticate_agent(conn, &api_key, &ip)? else {
+            return Ok(false);
+        };
+        record_heartbeat(conn, &agent.id, &heartbeat)?;
+        Ok::<bool, AgentError>(true)
+    }).await;
+
+    match result {
+        Ok(true) => Ok(HttpResponse::Ok().json(json!({
+            "status": "success",
+            "timestamp": Utc::now()
+        }))),
         Ok(false) => Ok(HttpResponse::Unauthorized().json(json!({
             "status": "error",
             "message": "Invalid API key"
         }))),
-        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
-            "status": "error",
-            "message": err.to_string()
-        })))
+        Err(err) => Ok(agent_error_response(err)),
     }
 }
 
 pub async fn agent_upload_handler(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    queue: web::Data<MessageQueue>,
     form: MultipartForm<AgentUploadForm>,
-    collector: web::Data<LogCollector>,
 ) -> Result<HttpResponse, Error> {
     let AgentUploadForm { log_file, api_key, account_id, host_id } = form.into_inner();
-    let log_file_path = log_file.file.path();
 
-    match verify_agent_api_key(&api_key) {
-        Ok(true) => {
-            match create_batches(log_file_path.to_str().unwrap()).await {
-                Ok(_) => {
-                    match process_logs(&collector, account_id.to_string(), host_id.to_string()).await {
-                        Ok(_) => Ok(HttpResponse::Ok().json(json!({ "status": "ok" }))),
-                        Err(err) => {
-                            Ok(HttpResponse::InternalServerError().json(json!({
-                                "status": "error",
-                                "message": "Failed to process logs",
-                                "details": err.to_string()
-                            })))
-                        }
-                    }
-                },
+    // The agent's key decides the tenant and the host; a form naming others is refused
+    let ip = client_ip(&req);
+    match run(&pool, move |conn| authenticate_agent(conn, &api_key, &ip)).await {
+        Ok(Some(agent)) if account_id.is_some_and(|id| agent.account_id != *id) => {
+            warn!("Agent {} tried to upload logs for another organization", agent.id);
+            Ok(HttpResponse::Forbidden().json(json!({
+                "status": "error",
+                "message": "Agent is not registered to this account"
+            })))
+        }
+        Ok(Some(agent)) if host_id.is_some_and(|id| agent.host_id != *id) => {
+            warn!("Agent {} tried to upload logs for another host", agent.id);
+            Ok(HttpResponse::Forbidden().json(json!({
+                "status": "error",
+                "message": "Agent is not registered to this host"
+            })))
+        }
+        Ok(Some(agent)) => {
+            let queue = queue.get_ref().clone();
+            let result = run(&pool, move |conn| {
+                let log_file_path = log_file.file.path();
+                create_batches(conn, &queue, log_file_path.to_str().unwrap(), &agent.account_id, &agent.host_id)
+            }).await;
+
+            match result {
+                Ok(job) => Ok(HttpResponse::Accepted().json(json!({
+                    "status": job.status,
+                    "job_id": job.id
+                }))),
+                Err(BatchError::QueueError(QueueError::QueueFull)) => Ok(queue_full_response()),
+                Err(BatchError::ValidationError(msg)) => Ok(HttpResponse::BadRequest().json(json!({
+                    "status": "error",
+                    "message": msg
+                }))),
                 Err(err) => Ok(HttpResponse::BadRequest().json(json!({
                     "status": "error",
                     "message": format!("Invalid log format: {:?}", err)
                 }))),
             }
         },
-        Ok(false) => Ok(HttpResponse::Unauthorized().json(json!({
+        Ok(None) => Ok(HttpResponse::Unauthorized().json(json!({
             "status": "error",
             "message": "Invalid API key"
         }))),
@@ -101,4 +231,99 @@ pub async fn agent_upload_handler(
             "message": err.to_string()
         })))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::App;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use crate::test_support::{agent, host, organization, test_pool};
+
+    const BOUNDARY: &str = "----upload-test";
+
+    // Post a two-line log file with the given form fields to the upload route
+    async fn upload(pool: &DbPool, queue: &MessageQueue, fields: &[(&str, &str)]) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(queue.clone()))
+                .route("/agent/upload", web::post().to(agent_upload_handler))
+        ).await;
+
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!("--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"));
+        }
+        body.push_str(&format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"agent.log\"\r\n\
+             Content-Type: text/plain\r\n\r\nfirst line\nsecond line\r\n--{BOUNDARY}--\r\n"
+        ));
+        let req = TestRequest::post()
+            .uri("/agent/upload")
+            .insert_header(("content-type", format!("multipart/form-data; boundary={BOUNDARY}")))
+            .set_payload(body)
+            .to_request();
+        call_service(&app, req).await.status()
+    }
+
+    fn queued_hosts(pool: &DbPool, queue: &MessageQueue) -> Vec<(String, String)> {
+        let conn = pool.get().unwrap();
+        let mut hosts = Vec::new();
+        while let Some(queued) = queue.dequeue(&conn).unwrap() {
+            hosts.push((queued.batch.account_id, queued.batch.host_id));
+            queue.ack(&conn, queued.id).unwrap();
+        }
+        hosts
+    }
+
+    #[actix_web::test]
+    async fn uploads_are_stored_for_the_agents_own_host() {
+        let pool = test_pool();
+        let queue = MessageQueue::new();
+        let (organization_id, host_id, api_key) = {
+            let conn = pool.get().unwrap();
+            let (organization_id, _) = organization(&conn, "acme");
+            let host_id = host(&conn, &organization_id, "web");
+            let (_, api_key) = agent(&conn, &organization_id, &host_id);
+            (organization_id, host_id, api_key)
+        };
+
+        assert_eq!(upload(&pool, &queue, &[("api_key", &api_key)]).await, StatusCode::ACCEPTED);
+        assert_eq!(
+            upload(&pool, &queue, &[("api_key", &api_key), ("account_id", &organization_id), ("host_id", &host_id)]).await,
+            StatusCode::ACCEPTED
+        );
+        let hosts = queued_hosts(&pool, &queue);
+        assert!(!hosts.is_empty());
+        assert!(hosts.iter().all(|queued| *queued == (organization_id.clone(), host_id.clone())));
+
+        assert_eq!(upload(&pool, &queue, &[("api_key", "not-a-key")]).await, StatusCode::UNAUTHORIZED);
+        assert!(queued_hosts(&pool, &queue).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn uploads_naming_another_host_or_organization_are_refused() {
+        let pool = test_pool();
+        let queue = MessageQueue::new();
+        let (api_key, sibling_host, other_organization, other_host) = {
+            let conn = pool.get().unwrap();
+            let (acme, _) = organization(&conn, "acme");
+            let web = host(&conn, &acme, "web");
+            let db = host(&conn, &acme, "db");
+            let (_, api_key) = agent(&conn, &acme, &web);
+            let (globex, _) = organization(&conn, "globex");
+            let other_host = host(&conn, &globex, "web");
+            (api_key, db, globex, other_host)
+        };
+
+        assert_eq!(upload(&pool, &queue, &[("api_key", &api_key), ("host_id", &sibling_host)]).await, StatusCode::FORBIDDEN);
+        assert_eq!(upload(&pool, &queue, &[("api_key", &api_key), ("account_id", &other_organization)]).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            upload(&pool, &queue, &[("api_key", &api_key), ("account_id", &other_organization), ("host_id", &other_host)]).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(queued_hosts(&pool, &queue).is_empty());
+    }
+}
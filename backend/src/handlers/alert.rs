@@ -1,11 +1,19 @@
 use actix_web::{web, HttpRequest, HttpResponse, Responder, Error};
 use serde_json::json;
 use crate::alert::{get_alert, list_alerts, delete_alert, acknowledge_alert};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 
-pub async fn get_alert_handler(alert_id: web::Path<String>) -> impl Responder {
-    match get_alert(&alert_id.to_string()) {
-        Ok(alert) => HttpResponse::Ok().json(alert),
+pub async fn get_alert_handler(tenant: Tenant, pool: web::Data<DbPool>, alert_id: web::Path<String>) -> impl Responder {
+    let alert_id = alert_id.into_inner();
+    match run(&pool, move |conn| get_alert(conn, &tenant.organization_id, &alert_id)).await {
+        Ok(Some(alert)) => HttpResponse::Ok().json(alert),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Alert not found"
+        })),
         Err(err) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -13,24 +21,42 @@ pub async fn get_alert_handler(alert_id: web::Path<String>) -> impl Responder {
     }
 }
 
-pub async fn get_all_alerts_handler(account_id: web::Path<String>) -> impl Responder {
-    match list_alerts(&account_id) {
-        Ok(alerts) => HttpResponse::Ok().json(alerts),
-        Err(err) => HttpResponse::InternalServerError().json(json!({
+pub async fn get_all_alerts_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_alerts(conn, &account_id)).await {
+        Ok(alerts) => Ok(HttpResponse::Ok().json(alerts)),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
-        }))
+        })))
     }
 }
 
 pub async fn delete_alert_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     alert_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match delete_alert(&alert_id.to_string()) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let alert_id = alert_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "alert.delete", AuditTarget::Alert, &alert_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_alert(conn, &tenant.organization_id, &alert_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(true))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Alert not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -40,12 +66,24 @@ pub async fn delete_alert_handler(
 
 pub async fn acknowledge_alert_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     alert_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match acknowledge_alert(&alert_id.to_string()) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let alert_id = alert_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "alert.acknowledge", AuditTarget::Alert, &alert_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| acknowledge_alert(conn, &tenant.organization_id, &alert_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(true))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Alert not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
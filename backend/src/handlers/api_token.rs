@@ -0,0 +1,91 @@
+use actix_web::{web, error::ErrorForbidden, HttpResponse, HttpRequest, Error};
+use serde_json::json;
+use log::error;
+use crate::api_token::{ApiTokenError, NewApiToken, create_api_token, list_api_tokens, revoke_api_token};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+fn api_token_error_response(err: ApiTokenError) -> HttpResponse {
+    match err {
+        ApiTokenError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// Tokens are managed from a signed-in session only, so a leaked token can't mint more
+fn require_session(tenant: &Tenant) -> Result<(), Error> {
+    if tenant.token_id.is_some() {
+        return Err(ErrorForbidden("API tokens cannot manage API tokens"));
+    }
+    Ok(())
+}
+
+pub async fn create_api_token_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    new_token: web::Json<NewApiToken>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    require_session(&tenant)?;
+
+    let new_token = new_token.into_inner();
+    let actor = AuditActor::new(&tenant, &req);
+    match run(&pool, move |conn| create_api_token(conn, &tenant.account_id, &tenant.organization_id, &new_token)).await {
+        Ok((token, secret)) => {
+            Audit::new(actor, "api_token.create", AuditTarget::ApiToken, &token.id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "api_token": token,
+                "token": secret
+            })))
+        }
+        Err(err) => Ok(api_token_error_response(err)),
+    }
+}
+
+pub async fn get_api_tokens_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    require_session(&tenant)?;
+    match run(&pool, move |conn| list_api_tokens(conn, &tenant.account_id)).await {
+        Ok(tokens) => Ok(HttpResponse::Ok().json(tokens)),
+        Err(err) => Ok(api_token_error_response(err)),
+    }
+}
+
+pub async fn revoke_api_token_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    token_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    require_session(&tenant)?;
+
+    let token_id = token_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "api_token.revoke", AuditTarget::ApiToken, &token_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| revoke_api_token(conn, &tenant.account_id, &token_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "API token not found"
+        }))),
+        Err(err) => Ok(api_token_error_response(err)),
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::{web, HttpResponse, HttpRequest, Error};
+use serde::Deserialize;
+use serde_json::json;
+use log::error;
+use crate::archive::{ArchiveError, list_archives, restore_range};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+#[derive(Deserialize)]
+pub struct RestoreRequest {
+    pub start_date: String,
+    pub end_date: String,
+}
+
+fn archive_error_response(err: ArchiveError) -> HttpResponse {
+    match err {
+        ArchiveError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+pub async fn get_archives_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_archives(conn, &account_id)).await {
+        Ok(archives) => Ok(HttpResponse::Ok().json(archives)),
+        Err(err) => Ok(archive_error_response(err)),
+    }
+}
+
+// Rehydrate a date range of archived logs into the hot table for investigation
+pub async fn restore_archive_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    range: web::Json<RestoreRequest>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let range = range.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "archive.restore", AuditTarget::Organization, &tenant.organization_id);
+    let (start_date, end_date) = (range.start_date.clone(), range.end_date.clone());
+    match run(&pool, move |conn| restore_range(conn, &account_id, &range.start_date, &range.end_date)).await {
+        Ok(report) => {
+            audit.record_with(&pool, None, Some(json!({
+                "start_date": start_date,
+                "end_date": end_date,
+                "days": report.days,
+                "restored": report.restored
+            }))).await;
+            Ok(HttpResponse::Ok().json(report))
+        }
+        Err(err) => Ok(archive_error_response(err)),
+    }
+}
@@ -0,0 +1,84 @@
+use actix_web::{web, http::header::{CONTENT_DISPOSITION, CONTENT_TYPE}, HttpResponse, HttpRequest, Error};
+use serde::Deserialize;
+use serde_json::json;
+use chrono::Utc;
+use log::error;
+use crate::audit::{Audit, AuditActor, AuditError, AuditFilter, AuditTarget, MAX_EXPORT_ROWS, MAX_PAGE_SIZE, audit_csv, search_audit_log};
+use crate::auth_session::Tenant;
+use crate::database::{DbPool, run};
+
+#[derive(Deserialize)]
+pub struct AuditExportParams {
+    // `csv` or `json` (the default)
+    pub format: Option<String>,
+}
+
+fn audit_error_response(err: AuditError) -> HttpResponse {
+    match err {
+        AuditError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// Search the organization's audit log, newest first
+pub async fn get_audit_log_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    filter: web::Query<AuditFilter>,
+) -> Result<HttpResponse, Error> {
+    let filter = filter.into_inner();
+    match run(&pool, move |conn| search_audit_log(conn, &tenant.organization_id, &filter, MAX_PAGE_SIZE)).await {
+        Ok(entries) => Ok(HttpResponse::Ok().json(entries)),
+        Err(err) => Ok(audit_error_response(err)),
+    }
+}
+
+// Download the matching entries as a file. Exports are themselves audited
+pub async fn export_audit_log_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    filter: web::Query<AuditFilter>,
+    params: web::Query<AuditExportParams>,
+) -> Result<HttpResponse, Error> {
+    let csv = match params.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => return Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "Format must be csv or json"
+        }))),
+    };
+    let mut filter = filter.into_inner();
+    filter.limit = Some(filter.limit.unwrap_or(MAX_EXPORT_ROWS));
+
+    let organization_id = tenant.organization_id.clone();
+    let entries = match run(&pool, move |conn| search_audit_log(conn, &organization_id, &filter, MAX_EXPORT_ROWS)).await {
+        Ok(entries) => entries,
+        Err(err) => return Ok(audit_error_response(err)),
+    };
+
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "audit.export", AuditTarget::Organization, &tenant.organization_id);
+    audit.record_with(&pool, None, Some(json!({
+        "format": if csv { "csv" } else { "json" },
+        "entries": entries.len()
+    }))).await;
+
+    let filename = format!("audit-{}.{}", Utc::now().format("%Y%m%dT%H%M%SZ"), if csv { "csv" } else { "json" });
+    let mut response = HttpResponse::Ok();
+    response.insert_header((CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)));
+    if csv {
+        Ok(response.insert_header((CONTENT_TYPE, "text/csv; charset=utf-8")).body(audit_csv(&entries)))
+    } else {
+        Ok(response.json(entries))
+    }
+}
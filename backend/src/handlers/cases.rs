@@ -6,17 +6,28 @@ use crate::cases::{Case, CaseError, Observable, create_case, get_case, get_cases
                    update_case, delete_case, add_observable, delete_observable, all_logs_with_cases};
 use crate::case_comments::{CaseCommentError, create_comment, get_comment,
     get_comments_by_case, update_comment, delete_comment};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 
 pub async fn create_case_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     account_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    match create_case(&account_id) {
-        Ok(case) => Ok(HttpResponse::Ok().json(case)),
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let actor = AuditActor::new(&tenant, &req);
+    match run(&pool, move |conn| create_case(conn, &account_id)).await {
+        Ok(case) => {
+            Audit::new(actor, "case.create", AuditTarget::Case, &case.id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(case))
+        }
         Err(err) => match err {
             CaseError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -34,9 +45,12 @@ pub async fn create_case_handler(
 }
 
 pub async fn get_case_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>
 ) -> Result<HttpResponse, Error> {
-    match get_case(&case_id) {
+    let case_id = case_id.into_inner();
+    match run(&pool, move |conn| get_case(conn, &tenant.organization_id, &case_id)).await {
         Ok(Some(case)) => Ok(HttpResponse::Ok().json(case)),
         Ok(None) => Ok(HttpResponse::NotFound().json(json!({
             "status": "error",
@@ -53,9 +67,13 @@ pub async fn get_case_handler(
 }
 
 pub async fn get_cases_by_account_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     account_id: web::Path<String>
 ) -> Result<HttpResponse, Error> {
-    match get_cases_by_account(&account_id) {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| get_cases_by_account(conn, &account_id)).await {
         Ok(cases) => Ok(HttpResponse::Ok().json(cases)),
         Err(err) => {
             error!("Internal server error: {:?}", err);
@@ -69,15 +87,27 @@ pub async fn get_cases_by_account_handler(
 
 pub async fn update_case_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_data: web::Json<Case>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
     
-    match update_case(&case_data) {
-        Ok(()) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Case updated successfully"
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case.update", AuditTarget::Case, &case_data.id);
+    let before = audit.snapshot(&pool).await;
+    let case_data = Case { account_id: tenant.organization_id, ..case_data.into_inner() };
+    match run(&pool, move |conn| update_case(conn, &case_data)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Case updated successfully"
+            })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Case not found"
         }))),
         Err(err) => match err {
             CaseError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
@@ -97,16 +127,24 @@ pub async fn update_case_handler(
 
 pub async fn delete_case_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    match delete_case(&case_id) {
-        Ok(true) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Case deleted successfully"
-        }))),
+    let case_id = case_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case.delete", AuditTarget::Case, &case_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_case(conn, &tenant.organization_id, &case_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Case deleted successfully"
+            })))
+        }
         Ok(false) => Ok(HttpResponse::NotFound().json(json!({
             "status": "error",
             "message": "Case not found"
@@ -123,17 +161,26 @@ pub async fn delete_case_handler(
 
 pub async fn add_observable_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>,
     observable: web::Json<Observable>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    match add_observable(&case_id, observable.into_inner()) {
-        Ok(()) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Observable added successfully"
-        }))),
+    let case_id = case_id.into_inner();
+    let observable = observable.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case.observable_add", AuditTarget::Case, &case_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| add_observable(conn, &tenant.organization_id, &case_id, observable)).await {
+        Ok(()) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Observable added successfully"
+            })))
+        }
         Err(err) => match err {
             CaseError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -152,17 +199,26 @@ pub async fn add_observable_handler(
 
 pub async fn delete_observable_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>,
     observable: web::Json<Observable>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    match delete_observable(&case_id, observable.into_inner()) {
-        Ok(()) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Observable deleted successfully"
-        }))),
+    let case_id = case_id.into_inner();
+    let observable = observable.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case.observable_delete", AuditTarget::Case, &case_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_observable(conn, &tenant.organization_id, &case_id, observable)).await {
+        Ok(()) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Observable deleted successfully"
+            })))
+        }
         Err(err) => match err {
             CaseError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -181,17 +237,25 @@ pub async fn delete_observable_handler(
 
 pub async fn add_comment_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>,
     comment: web::Json<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    match create_comment(&case_id, &comment.into_inner()) {
-        Ok(_) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Comment added successfully"
-        }))),
+    let case_id = case_id.into_inner();
+    let comment = comment.into_inner();
+    let actor = AuditActor::new(&tenant, &req);
+    match run(&pool, move |conn| create_comment(conn, &tenant.organization_id, &case_id, &comment)).await {
+        Ok(comment) => {
+            Audit::new(actor, "case_comment.create", AuditTarget::CaseComment, comment.id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Comment added successfully"
+            })))
+        }
         Err(err) => match err {
             CaseCommentError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
@@ -209,9 +273,12 @@ pub async fn add_comment_handler(
 }
 
 pub async fn get_case_comments_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     case_id: web::Path<String>
 ) -> Result<HttpResponse, Error> {
-    match get_comments_by_case(&case_id) {
+    let case_id = case_id.into_inner();
+    match run(&pool, move |conn| get_comments_by_case(conn, &tenant.organization_id, &case_id)).await {
         Ok(comments) => Ok(HttpResponse::Ok().json(comments)),
         Err(err) => {
             error!("Internal server error: {:?}", err);
@@ -225,66 +292,81 @@ pub async fn get_case_comments_handler(
 
 pub async fn update_comment_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     comment_id: web::Path<String>,
     comment_text: web::Json<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
-    // Get the existing comment
-    match get_comment(&comment_id) {
-        Ok(Some(mut comment)) => {
-            // Update the comment text
-            comment.comment = comment_text.into_inner();
-            comment.updated_at = Utc::now().to_rfc3339();
+    let comment_id = comment_id.into_inner();
+    let comment_text = comment_text.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case_comment.update", AuditTarget::CaseComment, &comment_id);
+    let before = audit.snapshot(&pool).await;
+    let result = run(&pool, move |conn| {
+        // Get the existing comment
+        match get_comment(conn, &tenant.organization_id, &comment_id)? {
+            Some(mut comment) => {
+                // Update the comment text
+                comment.comment = comment_text;
+                comment.updated_at = Utc::now().to_rfc3339();
 
-            // Save the updated comment
-            match update_comment(&comment) {
-                Ok(()) => Ok(HttpResponse::Ok().json(json!({
-                    "status": "success",
-                    "message": "Comment updated successfully"
-                }))),
-                Err(err) => match err {
-                    CaseCommentError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
-                        "status": "error",
-                        "message": msg
-                    }))),
-                    CaseCommentError::DatabaseError(e) => {
-                        error!("Database error while updating comment: {:?}", e);
-                        Ok(HttpResponse::InternalServerError().json(json!({
-                            "status": "error",
-                            "message": "An internal error occurred"
-                        })))
-                    }
-                }
+                // Save the updated comment
+                update_comment(conn, &tenant.organization_id, &comment)
             }
-        },
-        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            None => Ok(false),
+        }
+    }).await;
+
+    match result {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Comment updated successfully"
+            })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
             "status": "error",
             "message": "Comment not found"
         }))),
-        Err(err) => {
-            error!("Database error while fetching comment: {:?}", err);
-            Ok(HttpResponse::InternalServerError().json(json!({
+        Err(err) => match err {
+            CaseCommentError::ValidationError(msg) => Ok(HttpResponse::BadRequest().json(json!({
                 "status": "error",
-                "message": "An internal error occurred"
-            })))
+                "message": msg
+            }))),
+            CaseCommentError::DatabaseError(e) => {
+                error!("Database error while updating comment: {:?}", e);
+                Ok(HttpResponse::InternalServerError().json(json!({
+                    "status": "error",
+                    "message": "An internal error occurred"
+                })))
+            }
         }
     }
 }
 
 pub async fn delete_comment_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     comment_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
     
-    match delete_comment(&comment_id) {
-        Ok(true) => Ok(HttpResponse::Ok().json(json!({
-            "status": "success",
-            "message": "Comment deleted successfully"
-        }))),
+    let comment_id = comment_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "case_comment.delete", AuditTarget::CaseComment, &comment_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_comment(conn, &tenant.organization_id, &comment_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Comment deleted successfully"
+            })))
+        }
         Ok(false) => Ok(HttpResponse::NotFound().json(json!({
             "status": "error",
             "message": "Comment not found"
@@ -300,9 +382,13 @@ pub async fn delete_comment_handler(
 }
 
 pub async fn get_logs_wt_cases_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     account_id: web::Path<String>
 ) -> Result<HttpResponse, Error> {
-    match all_logs_with_cases(&account_id) {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| all_logs_with_cases(conn, &account_id)).await {
         Ok(logs) => Ok(HttpResponse::Ok().json(logs)),
         Err(err) => {
             error!("Internal server error: {:?}", err);
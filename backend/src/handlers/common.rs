@@ -1,5 +1,6 @@
-use crate::auth_session::{verify_session, invalidate_session};
+use crate::auth_session::{session_tenant, invalidate_session};
 use actix_web::{HttpResponse, HttpRequest, Responder};
+use actix_web::http::header::RETRY_AFTER;
 use actix_session::Session;
 
 pub async fn index() -> impl Responder {
@@ -7,8 +8,17 @@ pub async fn index() -> impl Responder {
 }
 
 pub async fn verify_session_handler(session: Session, req: HttpRequest) -> impl Responder {
-    match verify_session(&session, &req) {
-        Ok(account_id) => HttpResponse::Ok().json(serde_json::json!({ "authenticated": true, "account_id": account_id })),
+    match session_tenant(&session, &req) {
+        Ok(tenant) => HttpResponse::Ok().json(serde_json::json!({
+            "authenticated": true,
+            "account_id": tenant.account_id,
+            "organization_id": tenant.organization_id
+        })),
+        Err(_) if session.get::<bool>("password_change_required").ok().flatten() == Some(true) => HttpResponse::Forbidden().json(serde_json::json!({
+            "authenticated": false,
+            "password_change_required": true,
+            "message": "Password change required"
+        })),
         Err(_) => HttpResponse::Unauthorized().json(serde_json::json!({ "authenticated": false, "message": "Not authenticated" }))
     }
 }
@@ -16,4 +26,14 @@ pub async fn verify_session_handler(session: Session, req: HttpRequest) -> impl
 pub async fn logout_handler(session: Session) -> impl Responder {
     invalidate_session(&session);
     HttpResponse::Ok().json(serde_json::json!({ "message": "Logged out successfully" }))
-}
\ No newline at end of file
+}
+
+// Back-pressure response for uploads arriving while the ingestion queue is full
+pub fn queue_full_response() -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, "30"))
+        .json(serde_json::json!({
+            "status": "error",
+            "message": "Ingestion queue is full, retry later"
+        }))
+}
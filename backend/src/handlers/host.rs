@@ -1,17 +1,29 @@
 use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
 use serde_json::json;
 use crate::host::{Host, create_host, get_host, get_all_hosts, update_host, delete_host};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 
 pub async fn create_host_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     host: web::Json<Host>,
     account_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match create_host(&host, &account_id.to_string()) {
-        Ok(host) => Ok(HttpResponse::Ok().json(host)),
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let actor = AuditActor::new(&tenant, &req);
+    let host = Host { account_id: account_id.clone(), ..host.into_inner() };
+    match run(&pool, move |conn| create_host(conn, &host, &account_id)).await {
+        Ok(host_id) => {
+            Audit::new(actor, "host.create", AuditTarget::Host, host_id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -19,9 +31,14 @@ pub async fn create_host_handler(
     }
 }
 
-pub async fn get_host_handler(host_id: web::Path<String>) -> impl Responder {
-    match get_host(&host_id.to_string()) {
-        Ok(host) => HttpResponse::Ok().json(host),
+pub async fn get_host_handler(tenant: Tenant, pool: web::Data<DbPool>, host_id: web::Path<String>) -> impl Responder {
+    let host_id = host_id.into_inner();
+    match run(&pool, move |conn| get_host(conn, &tenant.organization_id, &host_id)).await {
+        Ok(Some(host)) => HttpResponse::Ok().json(host),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Host not found"
+        })),
         Err(err) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -29,24 +46,42 @@ pub async fn get_host_handler(host_id: web::Path<String>) -> impl Responder {
     }
 }
 
-pub async fn get_all_hosts_handler(account_id: web::Path<String>) -> impl Responder {
-    match get_all_hosts(&account_id.to_string()) {
-        Ok(host) => HttpResponse::Ok().json(host),
-        Err(err) => HttpResponse::InternalServerError().json(json!({
+pub async fn get_all_hosts_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| get_all_hosts(conn, &account_id)).await {
+        Ok(host) => Ok(HttpResponse::Ok().json(host)),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
-        }))
+        })))
     }
 }
 
 pub async fn edit_host_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     host: web::Json<Host>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match update_host(&host) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "host.update", AuditTarget::Host, &host.id);
+    let before = audit.snapshot(&pool).await;
+    let host = Host { account_id: tenant.organization_id, ..host.into_inner() };
+    match run(&pool, move |conn| update_host(conn, &host)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Host not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -56,15 +91,116 @@ pub async fn edit_host_handler(
 
 pub async fn delete_host_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     host_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match delete_host(&host_id.to_string()) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let host_id = host_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "host.delete", AuditTarget::Host, &host_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_host(conn, &tenant.organization_id, &host_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(true))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Host not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
         })))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use crate::host::get_host;
+    use crate::rbac::{Permission, RequirePermission};
+    use crate::test_support::{api_token, host, organization, test_pool};
+
+    macro_rules! app {
+        ($pool:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($pool.clone()))
+                    .app_data(web::Data::new(CsrfMiddleware::new()))
+                    .service(
+                        web::scope("/host")
+                            .wrap(RequirePermission::new(Permission::ReadHosts, Permission::ManageHosts))
+                            .route("/{account_id}", web::post().to(create_host_handler))
+                            .route("/{host_id}", web::get().to(get_host_handler))
+                            .route("/all/{account_id}", web::get().to(get_all_hosts_handler))
+                            .route("/{host_id}", web::put().to(edit_host_handler))
+                            .route("/{host_id}", web::delete().to(delete_host_handler))
+                    )
+            ).await
+        };
+    }
+
+    // Two organizations with a host each, and an Admin token for the first
+    fn tenants(pool: &DbPool) -> (String, String, String, String) {
+        let conn = pool.get().unwrap();
+        let (acme, admin) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+        host(&conn, &acme, "acme-web");
+        let globex_host = host(&conn, &globex, "globex-web");
+        let token = api_token(&conn, &admin, &acme, &[Permission::ReadHosts, Permission::ManageHosts]);
+        (format!("Bearer {}", token), acme, globex, globex_host)
+    }
+
+    #[actix_web::test]
+    async fn another_organizations_hosts_are_not_listed_or_created() {
+        let pool = test_pool();
+        let (bearer, acme, globex, _) = tenants(&pool);
+        let app = app!(pool);
+
+        let req = test::TestRequest::get().uri(&format!("/host/all/{}", acme))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        let hosts: Vec<Host> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].hostname.as_deref(), Some("acme-web"));
+
+        let req = test::TestRequest::get().uri(&format!("/host/all/{}", globex))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post().uri(&format!("/host/{}", globex))
+            .insert_header(("Authorization", bearer.as_str()))
+            .set_json(json!({ "id": "", "account_id": globex, "ip_address": "10.9.9.9", "hostname": "planted" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(get_all_hosts(&pool.get().unwrap(), &globex).unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn another_organizations_host_cannot_be_read_edited_or_deleted() {
+        let pool = test_pool();
+        let (bearer, _, globex, globex_host) = tenants(&pool);
+        let app = app!(pool);
+
+        let req = test::TestRequest::get().uri(&format!("/host/{}", globex_host))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        // The organization named in the body is ignored in favour of the caller's
+        let req = test::TestRequest::put().uri(&format!("/host/{}", globex_host))
+            .insert_header(("Authorization", bearer.as_str()))
+            .set_json(json!({ "id": globex_host, "account_id": globex, "ip_address": "10.9.9.9", "hostname": "renamed" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::delete().uri(&format!("/host/{}", globex_host))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+        let host = get_host(&pool.get().unwrap(), &globex, &globex_host).unwrap().unwrap();
+        assert_eq!(host.hostname.as_deref(), Some("globex-web"));
+    }
+}
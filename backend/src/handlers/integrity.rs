@@ -0,0 +1,49 @@
+use actix_web::{web, HttpResponse, Error};
+use serde_json::json;
+use log::error;
+use crate::integrity::{IntegrityError, verify_chain, list_digests};
+use crate::auth_session::Tenant;
+use crate::database::{DbPool, run};
+
+fn integrity_error_response(err: IntegrityError) -> HttpResponse {
+    match err {
+        IntegrityError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// Recompute the account's hash chain and digests and report any break
+pub async fn verify_log_chain_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| verify_chain(conn, &account_id)).await {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err) => Ok(integrity_error_response(err)),
+    }
+}
+
+pub async fn get_log_digests_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_digests(conn, &account_id)).await {
+        Ok(digests) => Ok(HttpResponse::Ok().json(digests)),
+        Err(err) => Ok(integrity_error_response(err)),
+    }
+}
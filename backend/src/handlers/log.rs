@@ -2,10 +2,19 @@ use actix_multipart::form::{tempfile::TempFile, MultipartForm, text::Text};
 use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
 use serde::Deserialize;
 use serde_json::json;
-use crate::collector::{LogCollector, process_logs};
+use std::collections::HashMap;
+use log::error;
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 use crate::log::{get_all_logs, get_query_logs};
-use crate::batch_maker::create_batches;
+use crate::security_event::list_security_events;
+use crate::ingest_job::get_job;
+use crate::batch_maker::{BatchError, create_batches, enqueue_lines};
+use crate::dead_letter::{DeadLetter, get_dead_letter, list_dead_letters, delete_dead_letter, purge_dead_letters};
+use crate::message_queue::{MessageQueue, QueueError};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use super::queue_full_response;
 
 #[derive(Debug, MultipartForm)]
 pub struct UploadForm {
@@ -15,9 +24,14 @@ pub struct UploadForm {
     host_id: Text<String>,
 }
 
+#[derive(Deserialize)]
+pub struct SecurityEventParams {
+    pub limit: Option<i64>,
+}
+
 #[derive(Deserialize)]
 pub struct QueryParams {
-    pub account_id: String,
+    pub account_id: Option<String>,
     pub query: String,
     pub start_time: Option<String>,
     pub end_time: Option<String>,
@@ -25,28 +39,38 @@ pub struct QueryParams {
 
 pub async fn import_log_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    queue: web::Data<MessageQueue>,
     csrf: web::Data<CsrfMiddleware>,
-    collector: web::Data<LogCollector>,
     form: MultipartForm<UploadForm>,
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
 
     let UploadForm { log_file, account_id, host_id } = form.into_inner();
-    let log_file_path = log_file.file.path();
-
-    match create_batches(log_file_path.to_str().unwrap()).await {
-        Ok(_) => {
-            match process_logs(&collector, account_id.to_string(), host_id.to_string()).await {
-                Ok(_) => Ok(HttpResponse::Ok().json(json!({ "status": "ok" }))),
-                Err(err) => {
-                    Ok(HttpResponse::InternalServerError().json(json!({
-                        "status": "error",
-                        "message": "Failed to process logs",
-                        "details": err.to_string()
-                    })))
-                }
-            }
-        },
+    tenant.authorize(&account_id)?;
+    let actor = AuditActor::new(&tenant, &req);
+    let queue = queue.get_ref().clone();
+
+    // The temp file is moved into the closure so it outlives the read
+    let result = run(&pool, move |conn| {
+        let log_file_path = log_file.file.path();
+        create_batches(conn, &queue, log_file_path.to_str().unwrap(), &account_id, &host_id)
+    }).await;
+
+    match result {
+        Ok(job) => {
+            Audit::new(actor, "log.import", AuditTarget::IngestJob, &job.id).record(&pool, None).await;
+            Ok(HttpResponse::Accepted().json(json!({
+                "status": job.status,
+                "job_id": job.id
+            })))
+        }
+        Err(BatchError::QueueError(QueueError::QueueFull)) => Ok(queue_full_response()),
+        Err(BatchError::ValidationError(msg)) => Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        }))),
         Err(err) => Ok(HttpResponse::BadRequest().json(json!({
             "status": "error",
             "message": format!("Invalid log format: {:?}", err)
@@ -54,9 +78,14 @@ pub async fn import_log_handler(
     }
 }
 
-pub async fn get_logs_handler(account_id: web::Path<String>) -> impl Responder {
-    match get_all_logs(&account_id) {
-        Ok(logs) => HttpResponse::Ok().json(logs),
+pub async fn get_ingest_job_handler(tenant: Tenant, pool: web::Data<DbPool>, job_id: web::Path<String>) -> impl Responder {
+    let job_id = job_id.into_inner();
+    match run(&pool, move |conn| get_job(conn, &tenant.organization_id, &job_id)).await {
+        Ok(Some(job)) => HttpResponse::Ok().json(job),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Job not found"
+        })),
         Err(err) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -64,20 +93,383 @@ pub async fn get_logs_handler(account_id: web::Path<String>) -> impl Responder {
     }
 }
 
+pub async fn get_logs_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| get_all_logs(conn, &account_id)).await {
+        Ok(logs) => Ok(HttpResponse::Ok().json(logs)),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+// Searches the caller's own account. An account_id in the query is optional and must match it
 pub async fn get_query_logs_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     query_params: web::Query<QueryParams>,
-    _req: actix_web::HttpRequest,
 ) -> Result<HttpResponse, Error> {
-    if query_params.account_id.is_empty() {
-        return Ok(HttpResponse::BadRequest().json(json!({
-            "error": "Account ID is required"
-        })));
+    let params = query_params.into_inner();
+    if let Some(account_id) = params.account_id.as_deref().filter(|id| !id.is_empty()) {
+        tenant.authorize(account_id)?;
     }
 
-    match get_query_logs(&query_params.account_id, &query_params.query, query_params.start_time.clone(), query_params.end_time.clone()) {
+    let account_id = tenant.organization_id;
+    match run(&pool, move |conn| get_query_logs(conn, &account_id, &params.query, params.start_time, params.end_time)).await {
         Ok(logs) => Ok(HttpResponse::Ok().json(logs)),
         Err(e) => Ok(HttpResponse::InternalServerError().json(json!({
             "error": e.to_string()
         })))
     }
-}
\ No newline at end of file
+}
+
+pub async fn get_dead_letters_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_dead_letters(conn, &account_id)).await {
+        Ok(letters) => Ok(HttpResponse::Ok().json(letters)),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+pub async fn reparse_dead_letter_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    queue: web::Data<MessageQueue>,
+    letter_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let letter_id = letter_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "dead_letter.reparse", AuditTarget::DeadLetter, &letter_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| get_dead_letter(conn, &tenant.organization_id, &letter_id)).await {
+        Ok(Some(letter)) => {
+            let response = requeue_dead_letters(&pool, &queue, vec![letter]).await;
+            if response.status().is_success() {
+                audit.record(&pool, before).await;
+            }
+            Ok(response)
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Dead letter not found"
+        }))),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+pub async fn reparse_all_dead_letters_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    queue: web::Data<MessageQueue>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "dead_letter.reparse_all", AuditTarget::Organization, &tenant.organization_id);
+    match run(&pool, move |conn| list_dead_letters(conn, &account_id)).await {
+        Ok(letters) => {
+            let count = letters.len();
+            let response = requeue_dead_letters(&pool, &queue, letters).await;
+            if response.status().is_success() {
+                audit.record_with(&pool, None, Some(json!({ "dead_letters": count }))).await;
+            }
+            Ok(response)
+        }
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+pub async fn delete_dead_letter_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    letter_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let letter_id = letter_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "dead_letter.delete", AuditTarget::DeadLetter, &letter_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_dead_letter(conn, &tenant.organization_id, &letter_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Dead letter deleted successfully"
+            })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Dead letter not found"
+        }))),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+pub async fn purge_dead_letters_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "dead_letter.purge", AuditTarget::Organization, &tenant.organization_id);
+    match run(&pool, move |conn| purge_dead_letters(conn, &account_id)).await {
+        Ok(purged) => {
+            audit.record_with(&pool, None, Some(json!({ "purged": purged }))).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "purged": purged
+            })))
+        }
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
+            "status": "error",
+            "message": err.to_string()
+        })))
+    }
+}
+
+// Send dead-lettered lines back through ingestion, one job per host.
+// Lines that still fail to parse are dead-lettered again by the workers
+async fn requeue_dead_letters(pool: &DbPool, queue: &MessageQueue, letters: Vec<DeadLetter>) -> HttpResponse {
+    let mut by_host: HashMap<(String, String), Vec<DeadLetter>> = HashMap::new();
+    for letter in letters {
+        by_host.entry((letter.account_id.clone(), letter.host_id.clone()))
+            .or_default()
+            .push(letter);
+    }
+
+    let mut job_ids = Vec::new();
+    for ((account_id, host_id), letters) in by_host {
+        let lines = letters.iter().map(|letter| letter.raw.clone()).collect();
+        let queue = queue.clone();
+        match run(pool, move |conn| enqueue_lines(conn, &queue, &account_id, &host_id, lines)).await {
+            Ok(job) => job_ids.push(job.id),
+            Err(BatchError::QueueError(QueueError::QueueFull)) => return queue_full_response(),
+            Err(err) => return HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": err.to_string()
+            })),
+        }
+
+        let removed = run(pool, move |conn| {
+            for letter in &letters {
+                if let Err(e) = delete_dead_letter(conn, &letter.account_id, &letter.id) {
+                    error!("Failed to remove requeued dead letter {}: {}", letter.id, e);
+                }
+            }
+            Ok::<(), rusqlite::Error>(())
+        }).await;
+        if let Err(e) = removed {
+            error!("Failed to remove requeued dead letters: {}", e);
+        }
+    }
+
+    HttpResponse::Accepted().json(json!({
+        "status": "queued",
+        "job_ids": job_ids
+    }))
+}
+
+// Events the SIEM raised about itself, like failed logins and lockouts, newest first
+pub async fn get_security_events_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    params: web::Query<SecurityEventParams>,
+) -> Result<HttpResponse, Error> {
+    let limit = params.limit.unwrap_or(100).clamp(1, 1000);
+    match run(&pool, move |conn| list_security_events(conn, &tenant.organization_id, limit)).await {
+        Ok(events) => Ok(HttpResponse::Ok().json(events)),
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            Ok(HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            })))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+    use actix_web::http::StatusCode;
+    use crate::collector::{LogCollector, process_logs};
+    use crate::dead_letter::create_dead_letter;
+    use crate::rbac::{Permission, RequirePermission};
+    use crate::test_support::{api_token, host, organization, test_pool};
+
+    const LINE: &str = "<34>Oct 11 22:14:15 mymachine sshd[0]: Failed password for invalid user root from 10.0.0.0 port 22";
+    const GARBAGE: &str = "not a log line";
+
+    macro_rules! app {
+        ($pool:expr, $queue:expr) => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new($pool.clone()))
+                    .app_data(web::Data::new($queue.clone()))
+                    .app_data(web::Data::new(CsrfMiddleware::new()))
+                    .service(
+                        web::scope("/log")
+                            .wrap(RequirePermission::new(Permission::ReadLogs, Permission::IngestLogs))
+                            .route("/dead-letter/all/{account_id}", web::get().to(get_dead_letters_handler))
+                            .route("/dead-letter/all/{account_id}", web::delete().to(purge_dead_letters_handler))
+                            .route("/dead-letter/reparse/{account_id}", web::post().to(reparse_all_dead_letters_handler))
+                            .route("/dead-letter/{letter_id}/reparse", web::post().to(reparse_dead_letter_handler))
+                            .route("/dead-letter/{letter_id}", web::delete().to(delete_dead_letter_handler))
+                    )
+            ).await
+        };
+    }
+
+    // An organization with a host and a token allowed to ingest: the organization and host IDs
+    // and the bearer header
+    fn tenant(pool: &DbPool, name: &str) -> (String, String, String) {
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = organization(&conn, name);
+        let host_id = host(&conn, &organization_id, "web");
+        let token = api_token(&conn, &account_id, &organization_id, &[Permission::ReadLogs, Permission::IngestLogs]);
+        (organization_id, host_id, format!("Bearer {}", token))
+    }
+
+    fn dead_letter(pool: &DbPool, organization_id: &str, host_id: &str, raw: &str) -> String {
+        create_dead_letter(&pool.get().unwrap(), "job", organization_id, host_id, raw, "unparseable").unwrap().id
+    }
+
+    fn count(pool: &DbPool, table: &str) -> i64 {
+        pool.get().unwrap().query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn a_bad_line_is_set_aside_and_reparsed_on_request() {
+        let pool = test_pool();
+        let queue = MessageQueue::new();
+        let collector = web::Data::new(LogCollector::new());
+        let (organization_id, host_id, bearer) = tenant(&pool, "acme");
+        let app = app!(pool, queue);
+
+        let lines = vec![LINE.to_string(), GARBAGE.to_string()];
+        let job = enqueue_lines(&mut pool.get().unwrap(), &queue, &organization_id, &host_id, lines).unwrap();
+        assert!(process_logs(&pool, &queue, &collector).await.unwrap());
+        // The rest of the batch is stored
+        assert_eq!(count(&pool, "logs"), 1);
+
+        let req = test::TestRequest::get().uri(&format!("/log/dead-letter/all/{}", organization_id))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        let letters: Vec<DeadLetter> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(letters.len(), 1);
+        assert_eq!((letters[0].raw.as_str(), letters[0].job_id.as_str()), (GARBAGE, job.id.as_str()));
+
+        let req = test::TestRequest::post().uri(&format!("/log/dead-letter/{}/reparse", letters[0].id))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+        assert_eq!(count(&pool, "dead_letters"), 0);
+
+        let requeued = queue.dequeue(&pool.get().unwrap()).unwrap().unwrap();
+        assert_eq!(requeued.batch.lines, vec![GARBAGE.to_string()]);
+        assert_eq!((requeued.batch.account_id.as_str(), requeued.batch.host_id.as_str()), (organization_id.as_str(), host_id.as_str()));
+        queue.release(&pool.get().unwrap(), requeued.id).unwrap();
+
+        // A line that still doesn't parse comes straight back
+        assert!(process_logs(&pool, &queue, &collector).await.unwrap());
+        assert_eq!((count(&pool, "logs"), count(&pool, "dead_letters"), count(&pool, "message_queue")), (1, 1, 0));
+    }
+
+    #[actix_web::test]
+    async fn reparse_all_requeues_one_job_per_host() {
+        let pool = test_pool();
+        let queue = MessageQueue::new();
+        let (acme, web_host, bearer) = tenant(&pool, "acme");
+        let (globex, globex_host, _) = tenant(&pool, "globex");
+        let db_host = host(&pool.get().unwrap(), &acme, "db");
+        dead_letter(&pool, &acme, &web_host, "first");
+        dead_letter(&pool, &acme, &web_host, "second");
+        dead_letter(&pool, &acme, &db_host, "third");
+        let foreign = dead_letter(&pool, &globex, &globex_host, "foreign");
+        let app = app!(pool, queue);
+
+        let req = test::TestRequest::post().uri(&format!("/log/dead-letter/reparse/{}", acme))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(response["job_ids"].as_array().unwrap().len(), 2);
+
+        let conn = pool.get().unwrap();
+        let mut requeued = Vec::new();
+        while let Some(queued) = queue.dequeue(&conn).unwrap() {
+            assert_eq!(queued.batch.account_id, acme);
+            requeued.extend(queued.batch.lines);
+        }
+        requeued.sort();
+        assert_eq!(requeued, vec!["first", "second", "third"]);
+        let left: Vec<String> = list_dead_letters(&conn, &globex).unwrap().into_iter().map(|letter| letter.id).collect();
+        assert_eq!(left, vec![foreign]);
+        assert!(list_dead_letters(&conn, &acme).unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn another_organizations_dead_letters_are_out_of_reach() {
+        let pool = test_pool();
+        let queue = MessageQueue::new();
+        let (acme, acme_host, bearer) = tenant(&pool, "acme");
+        let (globex, globex_host, _) = tenant(&pool, "globex");
+        let own = dead_letter(&pool, &acme, &acme_host, "own");
+        let foreign = dead_letter(&pool, &globex, &globex_host, "foreign");
+        let app = app!(pool, queue);
+
+        let requests = [
+            (test::TestRequest::get().uri(&format!("/log/dead-letter/all/{}", globex)), StatusCode::FORBIDDEN),
+            (test::TestRequest::delete().uri(&format!("/log/dead-letter/all/{}", globex)), StatusCode::FORBIDDEN),
+            (test::TestRequest::post().uri(&format!("/log/dead-letter/reparse/{}", globex)), StatusCode::FORBIDDEN),
+            (test::TestRequest::post().uri(&format!("/log/dead-letter/{}/reparse", foreign)), StatusCode::NOT_FOUND),
+            (test::TestRequest::delete().uri(&format!("/log/dead-letter/{}", foreign)), StatusCode::NOT_FOUND),
+        ];
+        for (req, status) in requests {
+            let req = req.insert_header(("Authorization", bearer.as_str())).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+        assert_eq!(count(&pool, "message_queue"), 0);
+        assert_eq!(list_dead_letters(&pool.get().unwrap(), &globex).unwrap().len(), 1);
+
+        let req = test::TestRequest::delete().uri(&format!("/log/dead-letter/all/{}", acme))
+            .insert_header(("Authorization", bearer.as_str())).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(get_dead_letter(&pool.get().unwrap(), &acme, &own).unwrap().is_none());
+        assert_eq!(list_dead_letters(&pool.get().unwrap(), &globex).unwrap().len(), 1);
+    }
+}
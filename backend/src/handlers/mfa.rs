@@ -0,0 +1,318 @@
+use actix_web::{web, error::{ErrorForbidden, ErrorUnauthorized}, http::header::RETRY_AFTER, HttpResponse, HttpRequest, Error};
+use actix_session::Session;
+use rusqlite::Connection;
+use serde::Deserialize;
+use serde_json::json;
+use log::error;
+use crate::mfa::{MfaError, MfaState, start_enrollment, confirm_enrollment, verify_code, disable,
+    is_enabled, is_required, set_required};
+use crate::account::get_account;
+use crate::auth_session::{Tenant, check_session, client_ip, invalidate_session};
+use crate::login_guard::{LoginBlock, reserve_mfa, release_mfa, record_mfa_failure, record_mfa_success};
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+#[derive(Deserialize)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct MfaRequirement {
+    pub required: bool,
+}
+
+fn mfa_error_response(err: MfaError) -> HttpResponse {
+    match err {
+        MfaError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+fn blocked_response(block: &LoginBlock) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, block.retry_after.to_string()))
+        .json(json!({
+            "status": "error",
+            "message": "Too many wrong codes, try again later",
+            "retry_after": block.retry_after
+        }))
+}
+
+// Outcome of checking a code under the second-factor attempt limit
+enum CodeCheck {
+    Valid,
+    // Wrong, and whether that locked the account out of verification
+    Invalid(bool),
+    Blocked(LoginBlock),
+}
+
+// Check a code, counting wrong ones against the account like failed logins
+fn check_code(conn: &mut Connection, organization_id: &str, account_id: &str, code: &str, ip: &str) -> Result<CodeCheck, MfaError> {
+    if let Some(block) = reserve_mfa(conn, account_id)? {
+        return Ok(CodeCheck::Blocked(block));
+    }
+    match verify_code(conn, account_id, code) {
+        Ok(true) => {
+            record_mfa_success(conn, account_id)?;
+            Ok(CodeCheck::Valid)
+        }
+        Ok(false) => Ok(CodeCheck::Invalid(record_mfa_failure(conn, organization_id, account_id, ip)?)),
+        Err(err) => {
+            release_mfa(conn, account_id)?;
+            Err(err)
+        }
+    }
+}
+
+fn internal_error() -> HttpResponse {
+    HttpResponse::InternalServerError().json(json!({
+        "status": "error",
+        "message": "An internal error occurred"
+    }))
+}
+
+// The MFA endpoints accept sessions that are still waiting on the second factor
+fn mfa_session(session: &Session, req: &HttpRequest) -> Result<(String, MfaState), Error> {
+    let account_id = check_session(session, req)?;
+    let state = session.get::<MfaState>("mfa_state")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .unwrap_or(MfaState::Verified);
+    Ok((account_id, state))
+}
+
+fn set_verified(session: &Session) -> Result<(), Error> {
+    session.renew();
+    session.insert("mfa_state", MfaState::Verified)
+        .map_err(|_| ErrorUnauthorized("Failed to update session"))
+}
+
+pub async fn get_mfa_status_handler(req: HttpRequest, session: Session, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    let (account_id, state) = mfa_session(&session, &req)?;
+    let organization_id = session.get::<String>("organization_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+
+    let status = run(&pool, move |conn| Ok::<_, MfaError>((is_enabled(conn, &account_id)?, is_required(conn, &organization_id)?))).await;
+    match status {
+        Ok((enabled, required)) => Ok(HttpResponse::Ok().json(json!({
+            "enabled": enabled,
+            "required": required,
+            "state": state
+        }))),
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+pub async fn enroll_mfa_handler(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let (account_id, state) = mfa_session(&session, &req)?;
+    if state == MfaState::Challenge {
+        return Err(ErrorUnauthorized("MFA verification required"));
+    }
+
+    let account = match run(&pool, {
+        let account_id = account_id.clone();
+        move |conn| get_account(conn, &account_id)
+    }).await {
+        Ok(Some(account)) => account,
+        Ok(None) => return Err(ErrorUnauthorized("Unauthorized")),
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            return Ok(internal_error());
+        }
+    };
+
+    match run(&pool, move |conn| start_enrollment(conn, &account_id, &account.name)).await {
+        Ok(enrollment) => Ok(HttpResponse::Ok().json(enrollment)),
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+pub async fn confirm_mfa_handler(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    mfa: web::Json<MfaCode>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let (account_id, state) = mfa_session(&session, &req)?;
+    if state == MfaState::Challenge {
+        return Err(ErrorUnauthorized("MFA verification required"));
+    }
+
+    let organization_id = session.get::<String>("organization_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+    let audit = Audit::new(AuditActor::account(&organization_id, &account_id, &req), "account.mfa_enable", AuditTarget::Account, &account_id);
+
+    let code = mfa.into_inner().code;
+    match run(&pool, move |conn| confirm_enrollment(conn, &account_id, &code)).await {
+        Ok(recovery_codes) => {
+            audit.record_with(&pool, Some(json!({ "mfa_enabled": false })), Some(json!({ "mfa_enabled": true }))).await;
+            set_verified(&session)?;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "recovery_codes": recovery_codes
+            })))
+        }
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+// Second step of a login: a TOTP code or one of the recovery codes
+pub async fn verify_mfa_handler(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    mfa: web::Json<MfaCode>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let (account_id, state) = mfa_session(&session, &req)?;
+    if state != MfaState::Challenge {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "No MFA verification pending"
+        })));
+    }
+
+    let organization_id = session.get::<String>("organization_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+    let code = mfa.into_inner().code;
+    let ip = client_ip(&req);
+    match run(&pool, move |conn| check_code(conn, &organization_id, &account_id, &code, &ip)).await {
+        Ok(CodeCheck::Valid) => {
+            set_verified(&session)?;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Login successful!"
+            })))
+        }
+        // Once locked out, the pending login is thrown away and has to start again from the password
+        Ok(CodeCheck::Invalid(true)) => {
+            invalidate_session(&session);
+            Ok(HttpResponse::Unauthorized().json(json!({
+                "status": "error",
+                "message": "Too many wrong codes, log in again later"
+            })))
+        }
+        Ok(CodeCheck::Invalid(false)) => Ok(HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": "Invalid code"
+        }))),
+        Ok(CodeCheck::Blocked(block)) => {
+            if block.locked {
+                invalidate_session(&session);
+            }
+            Ok(blocked_response(&block))
+        }
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+pub async fn disable_mfa_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    mfa: web::Json<MfaCode>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    if tenant.token_id.is_some() {
+        return Err(ErrorForbidden("API tokens cannot change MFA settings"));
+    }
+
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.mfa_disable", AuditTarget::Account, &tenant.account_id);
+    let code = mfa.into_inner().code;
+    let ip = client_ip(&req);
+    let result = run(&pool, move |conn| {
+        if is_required(conn, &tenant.organization_id)? {
+            return Err(MfaError::ValidationError("Your organization requires MFA".to_string()));
+        }
+        match check_code(conn, &tenant.organization_id, &tenant.account_id, &code, &ip)? {
+            CodeCheck::Valid => disable(conn, &tenant.account_id).map(|_| None),
+            CodeCheck::Invalid(_) => Err(MfaError::ValidationError("Invalid code".to_string())),
+            CodeCheck::Blocked(block) => Ok(Some(block)),
+        }
+    }).await;
+
+    match result {
+        Ok(Some(block)) => Ok(blocked_response(&block)),
+        Ok(None) => {
+            audit.record_with(&pool, Some(json!({ "mfa_enabled": true })), Some(json!({ "mfa_enabled": false }))).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+// Admin reset for a member who lost their authenticator; they enroll again at next login
+pub async fn reset_mfa_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let account_id = account_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.mfa_reset", AuditTarget::Account, &account_id);
+
+    let member = match run(&pool, move |conn| get_account(conn, &account_id)).await {
+        Ok(Some(account)) if account.organization_id == tenant.organization_id => account,
+        Ok(_) => return Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Account not found"
+        }))),
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            return Ok(internal_error());
+        }
+    };
+
+    match run(&pool, move |conn| disable(conn, &member.id)).await {
+        Ok(_) => {
+            audit.record_with(&pool, None, Some(json!({ "mfa_enabled": false }))).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
+
+pub async fn require_mfa_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    requirement: web::Json<MfaRequirement>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let required = requirement.into_inner().required;
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "organization.require_mfa", AuditTarget::Organization, &tenant.organization_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| set_required(conn, &tenant.organization_id, required)).await {
+        Ok(_) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({ "required": required })))
+        }
+        Err(err) => Ok(mfa_error_response(err)),
+    }
+}
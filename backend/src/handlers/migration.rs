@@ -0,0 +1,32 @@
+use actix_web::{web, HttpResponse, Error};
+use serde_json::json;
+use log::error;
+use crate::migrations::{MigrationError, current_version, migration_status};
+use crate::database::{DbPool, run};
+
+// Schema version and pending migrations, guarded by the system:read permission on /admin
+pub async fn get_migrations_handler(pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    let result = run(&pool, move |conn| {
+        let version = current_version(conn)?;
+        let migrations = migration_status(conn)?;
+        Ok::<_, MigrationError>((version, migrations))
+    }).await;
+
+    match result {
+        Ok((version, migrations)) => {
+            let pending = migrations.iter().filter(|m| m.applied_at.is_none()).count();
+            Ok(HttpResponse::Ok().json(json!({
+                "current_version": version,
+                "pending": pending,
+                "migrations": migrations
+            })))
+        }
+        Err(err) => {
+            error!("Internal server error: {:?}", err);
+            Ok(HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            })))
+        }
+    }
+}
@@ -7,6 +7,18 @@ mod host;
 mod log;
 mod rule;
 mod cases;
+mod migration;
+mod retention;
+mod archive;
+mod integrity;
+mod role;
+mod organization;
+mod api_token;
+mod mfa;
+mod session;
+mod oidc;
+mod password;
+mod audit;
 
 pub use account::*;
 pub use agent::*;
@@ -16,4 +28,16 @@ pub use csrf::*;
 pub use host::*;
 pub use log::*;
 pub use rule::*;
-pub use cases::*;
\ No newline at end of file
+pub use cases::*;
+pub use migration::*;
+pub use retention::*;
+pub use archive::*;
+pub use integrity::*;
+pub use role::*;
+pub use organization::*;
+pub use api_token::*;
+pub use mfa::*;
+pub use session::*;
+pub use oidc::*;
+pub use password::*;
+pub use audit::*;
\ No newline at end of file
@@ -0,0 +1,207 @@
+use actix_web::{web, http::header::LOCATION, HttpResponse, HttpRequest, Error};
+use actix_session::Session;
+use serde::Deserialize;
+use serde_json::json;
+use log::{error, warn};
+use crate::oidc::{OidcConfig, OidcError, PendingLogin, begin_login, complete_login, OIDC_PROVIDER};
+use crate::account::{AccountError, provision_external_account, start_session};
+use crate::mfa::login_state;
+use crate::login_guard::record_success;
+use crate::database::{DbPool, run};
+
+#[derive(Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+fn oidc_error_response(err: OidcError) -> HttpResponse {
+    match err {
+        OidcError::NotConfigured(msg) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        OidcError::ProviderError(msg) => {
+            error!("OIDC provider error: {}", msg);
+            HttpResponse::ServiceUnavailable().json(json!({
+                "status": "error",
+                "message": "Identity provider unavailable"
+            }))
+        }
+        OidcError::ValidationError(msg) => HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        OidcError::AccountError(AccountError::ValidationError(msg)) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        OidcError::AccountError(AccountError::InvalidRole) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "The role mapped from your claims does not exist"
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// Start single sign-on: remember the state, nonce and PKCE verifier in the session and send the
+// browser to the provider
+pub async fn oidc_login_handler(session: Session) -> Result<HttpResponse, Error> {
+    let result = async {
+        let config = OidcConfig::from_env()?;
+        begin_login(&config).await
+    }.await;
+
+    match result {
+        Ok((url, login)) => {
+            session.insert("oidc_login", login)
+                .map_err(|_| actix_web::error::ErrorInternalServerError("Failed to update session"))?;
+            Ok(HttpResponse::Found().insert_header((LOCATION, url)).finish())
+        }
+        Err(err) => Ok(oidc_error_response(err)),
+    }
+}
+
+// The provider redirects back here with an authorization code. A valid ID token signs the user
+// in with an ordinary session, creating their account on first login. The account is the one
+// bound to the token's issuer and subject, whatever name the provider sends
+pub async fn oidc_callback_handler(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    callback: web::Query<OidcCallback>
+) -> Result<HttpResponse, Error> {
+    let callback = callback.into_inner();
+    // A pending login is only good for one callback
+    let login = session.remove_as::<PendingLogin>("oidc_login").and_then(Result::ok);
+
+    let config = match OidcConfig::from_env() {
+        Ok(config) => config,
+        Err(err) => return Ok(oidc_error_response(err)),
+    };
+    let Some(login) = login else {
+        return Ok(oidc_error_response(OidcError::ValidationError("No single sign-on login in progress".to_string())));
+    };
+    if let Some(error) = callback.error {
+        warn!("OIDC provider returned {}: {}", error, callback.error_description.unwrap_or_default());
+        return Ok(oidc_error_response(OidcError::ValidationError(format!("The provider refused the login: {}", error))));
+    }
+    let (Some(code), Some(state)) = (callback.code, callback.state) else {
+        return Ok(oidc_error_response(OidcError::ValidationError("Missing code or state".to_string())));
+    };
+
+    let identity = match complete_login(&config, &login, &code, &state).await {
+        Ok(identity) => identity,
+        Err(err) => return Ok(oidc_error_response(err)),
+    };
+
+    let organization_id = config.organization_id.clone();
+    let signed_in = run(&pool, move |conn| {
+        let account = provision_external_account(
+            conn,
+            &organization_id,
+            &identity.issuer,
+            &identity.subject,
+            &identity.name,
+            &identity.role,
+            OIDC_PROVIDER,
+        )?;
+        record_success(conn, &account.name)?;
+        let mfa_state = login_state(conn, &account.id, &account.organization_id)?;
+        Ok::<_, AccountError>((account, mfa_state))
+    }).await
+        .and_then(|(account, mfa_state)| start_session(&session, &account, mfa_state, &req));
+
+    match signed_in {
+        // The frontend picks up any pending MFA step from `/account/mfa`, as after a password login
+        Ok(()) => Ok(HttpResponse::Found().insert_header((LOCATION, config.post_login_url)).finish()),
+        Err(err) => Ok(oidc_error_response(err.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::SessionMiddleware;
+    use actix_web::{test, App};
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::StatusCode;
+    use reqwest::Url;
+    use std::collections::HashMap;
+    use std::env;
+    use crate::session_store::SqliteSessionStore;
+    use crate::test_support::{AUTH_CODE, KEY_A, MockIssuer, OIDC_CLIENT, organization, test_pool};
+
+    fn session_cookie(response: &ServiceResponse) -> Cookie<'static> {
+        response.response().cookies().next().expect("session cookie").into_owned()
+    }
+
+    fn location(response: &ServiceResponse) -> String {
+        response.headers().get(LOCATION).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn the_callback_signs_in_once_per_login_and_checks_the_state() {
+        let pool = test_pool();
+        let organization_id = organization(&pool.get().unwrap(), "acme").0;
+        let issuer = MockIssuer::start(&[&KEY_A]);
+        // No other test reads the single sign-on settings
+        env::set_var("AUTH_PROVIDERS", "local,oidc");
+        env::set_var("OIDC_ISSUER", &issuer.url);
+        env::set_var("OIDC_CLIENT_ID", OIDC_CLIENT);
+        env::set_var("OIDC_REDIRECT_URI", "https://siem.example.com/backend/account/oidc/callback");
+        env::set_var("OIDC_CLAIM_ROLES", "soc=>Analyst");
+        env::set_var("OIDC_ORGANIZATION_ID", &organization_id);
+        env::set_var("OIDC_POST_LOGIN_URL", "/dashboard");
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .wrap(SessionMiddleware::new(SqliteSessionStore::new(pool.clone()), Key::generate()))
+                .route("/oidc/login", web::get().to(oidc_login_handler))
+                .route("/oidc/callback", web::get().to(oidc_callback_handler))
+        ).await;
+
+        // Start a login and have the provider expect it, as the browser's visit would
+        let start = test::call_service(&app, test::TestRequest::get().uri("/oidc/login").to_request()).await;
+        assert_eq!(start.status(), StatusCode::FOUND);
+        let params: HashMap<String, String> = Url::parse(&location(&start)).unwrap().query_pairs().into_owned().collect();
+        issuer.expect_login(&params["code_challenge"], KEY_A.sign(&issuer.claims(&params["nonce"])));
+        let pending = session_cookie(&start);
+
+        let callback = |state: &str| format!("/oidc/callback?code={}&state={}", AUTH_CODE, state);
+        let req = test::TestRequest::get().uri(&callback("forged")).cookie(pending.clone()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        // The failed callback used up the pending login
+        let req = test::TestRequest::get().uri(&callback(&params["state"])).cookie(pending).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let start = test::call_service(&app, test::TestRequest::get().uri("/oidc/login").to_request()).await;
+        let params: HashMap<String, String> = Url::parse(&location(&start)).unwrap().query_pairs().into_owned().collect();
+        issuer.expect_login(&params["code_challenge"], KEY_A.sign(&issuer.claims(&params["nonce"])));
+        let req = test::TestRequest::get().uri(&callback(&params["state"])).cookie(session_cookie(&start)).to_request();
+        let signed_in = test::call_service(&app, req).await;
+        assert_eq!(signed_in.status(), StatusCode::FOUND);
+        assert_eq!(location(&signed_in), "/dashboard");
+
+        let (name, role, subject): (String, String, String) = pool.get().unwrap().query_row(
+            "SELECT name, role, external_subject FROM accounts WHERE organization_id = ?1 AND auth_provider = 'oidc'",
+            [&organization_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        assert_eq!((name.as_str(), role.as_str(), subject.as_str()), ("alice", "Analyst", "sub-1"));
+
+        // Replaying the callback finds no login in progress
+        let req = test::TestRequest::get().uri(&callback(&params["state"])).cookie(session_cookie(&signed_in)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,152 @@
+use actix_web::{web, HttpResponse, HttpRequest, Error};
+use serde::Deserialize;
+use serde_json::json;
+use log::error;
+use crate::organization::{OrganizationError, get_organization, list_members, create_invitation,
+    list_invitations, revoke_invitation, accept_invitation};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+#[derive(Deserialize)]
+pub struct InvitationRequest {
+    pub role: String,
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct JoinRequest {
+    pub token: String,
+    pub name: String,
+    pub password: String,
+}
+
+fn organization_error_response(err: OrganizationError) -> HttpResponse {
+    match err {
+        OrganizationError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+pub async fn get_organization_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    match run(&pool, move |conn| get_organization(conn, &tenant.organization_id)).await {
+        Ok(Some(organization)) => Ok(HttpResponse::Ok().json(organization)),
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Organization not found"
+        }))),
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
+
+pub async fn get_members_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    match run(&pool, move |conn| list_members(conn, &tenant.organization_id)).await {
+        Ok(members) => Ok(HttpResponse::Ok().json(members)),
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
+
+pub async fn create_invitation_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    invitation: web::Json<InvitationRequest>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let invitation = invitation.into_inner();
+    let actor = AuditActor::new(&tenant, &req);
+    let result = run(&pool, move |conn| create_invitation(
+        conn,
+        &tenant.organization_id,
+        &tenant.account_id,
+        &invitation.role,
+        invitation.expires_in_hours,
+    )).await;
+
+    match result {
+        Ok((invitation, token)) => {
+            Audit::new(actor, "invitation.create", AuditTarget::Invitation, &invitation.id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "invitation": invitation,
+                "token": token
+            })))
+        }
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
+
+pub async fn get_invitations_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    match run(&pool, move |conn| list_invitations(conn, &tenant.organization_id)).await {
+        Ok(invitations) => Ok(HttpResponse::Ok().json(invitations)),
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
+
+pub async fn revoke_invitation_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    invitation_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let invitation_id = invitation_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "invitation.revoke", AuditTarget::Invitation, &invitation_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| revoke_invitation(conn, &tenant.organization_id, &invitation_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Invitation not found"
+        }))),
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
+
+// Public: the invitation token is the credential
+pub async fn accept_invitation_handler(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    join: web::Json<JoinRequest>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let join = join.into_inner();
+    let result = run(&pool, move |conn| {
+        let account_id = accept_invitation(conn, &join.token, join.name, join.password)?;
+        let organization_id: String = conn.query_row(
+            "SELECT organization_id FROM accounts WHERE id = ?1",
+            [&account_id],
+            |row| row.get(0),
+        )?;
+        Ok::<_, OrganizationError>((account_id, organization_id))
+    }).await;
+
+    match result {
+        Ok((account_id, organization_id)) => {
+            // The new member is the actor: nobody else is signed in
+            let actor = AuditActor::account(&organization_id, &account_id, &req);
+            Audit::new(actor, "invitation.accept", AuditTarget::Account, &account_id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "account_id": account_id
+            })))
+        }
+        Err(err) => Ok(organization_error_response(err)),
+    }
+}
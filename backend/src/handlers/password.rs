@@ -0,0 +1,179 @@
+use actix_web::{web, error::ErrorUnauthorized, http::header::RETRY_AFTER, HttpResponse, HttpRequest, Error};
+use actix_session::Session;
+use serde::Deserialize;
+use serde_json::json;
+use log::error;
+use crate::account::{AccountError, get_account};
+use crate::password::{change_password, issue_password_reset, redeem_password_reset};
+use crate::session_store::revoke_sessions;
+use crate::login_guard::{LoginBlock, reserve_login, release_attempt, record_failure, unlock_account};
+use crate::auth_session::{Tenant, client_ip, verify_session_allowing_password_change};
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetRedemption {
+    pub token: String,
+    pub new_password: String,
+}
+
+enum ChangeOutcome {
+    Changed,
+    WrongPassword,
+    Blocked(LoginBlock),
+}
+
+fn password_error_response(err: AccountError) -> HttpResponse {
+    match err {
+        AccountError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+// The signed-in user replaces their password, which signs out their other sessions. Works for
+// sessions that were told to change their password and nothing else yet. A wrong current
+// password counts as a failed login for the account and the address
+pub async fn change_password_handler(
+    req: HttpRequest,
+    session: Session,
+    pool: web::Data<DbPool>,
+    change: web::Json<PasswordChange>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let account_id = verify_session_allowing_password_change(&session, &req)?;
+    let current = session.get::<String>("session_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?;
+    let organization_id = session.get::<String>("organization_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .ok_or_else(|| ErrorUnauthorized("Unauthorized"))?;
+    let audit = Audit::new(AuditActor::account(&organization_id, &account_id, &req), "account.password_change", AuditTarget::Account, &account_id);
+    let before = audit.snapshot(&pool).await;
+
+    let change = change.into_inner();
+    let ip = client_ip(&req);
+    let result = run(&pool, move |conn| {
+        let Some(account) = get_account(conn, &account_id)? else {
+            return Ok(ChangeOutcome::WrongPassword);
+        };
+        if let Some(block) = reserve_login(conn, &account.name, &ip)? {
+            return Ok(ChangeOutcome::Blocked(block));
+        }
+        match change_password(conn, &account_id, &change.current_password, &change.new_password) {
+            Ok(true) => release_attempt(conn, &account.name, &ip)?,
+            Ok(false) => {
+                record_failure(conn, &account.name, &ip)?;
+                return Ok(ChangeOutcome::WrongPassword);
+            }
+            Err(err) => {
+                release_attempt(conn, &account.name, &ip)?;
+                return Err(err);
+            }
+        }
+        revoke_sessions(conn, &account_id, None, current.as_deref())?;
+        Ok::<_, AccountError>(ChangeOutcome::Changed)
+    }).await;
+
+    match result {
+        Ok(ChangeOutcome::Changed) => {
+            audit.record(&pool, before).await;
+            session.remove("password_change_required");
+            session.renew();
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Password changed"
+            })))
+        }
+        Ok(ChangeOutcome::WrongPassword) => Ok(HttpResponse::Unauthorized().json(json!({
+            "status": "error",
+            "message": "Current password is incorrect"
+        }))),
+        Ok(ChangeOutcome::Blocked(block)) => Ok(HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, block.retry_after.to_string()))
+            .json(json!({
+                "status": "error",
+                "message": "Too many wrong passwords, try again later",
+                "retry_after": block.retry_after
+            }))),
+        Err(err) => Ok(password_error_response(err)),
+    }
+}
+
+// Admin reset for a member who forgot their password. The one-time token is returned once and
+// is handed to the member out of band
+pub async fn issue_password_reset_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let account_id = account_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "account.password_reset_issue", AuditTarget::Account, &account_id);
+    match run(&pool, move |conn| issue_password_reset(conn, &tenant.organization_id, &account_id, &tenant.account_id)).await {
+        Ok(Some(reset)) => {
+            audit.record_with(&pool, None, Some(json!({ "expires_at": reset.expires_at }))).await;
+            Ok(HttpResponse::Ok().json(reset))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Account not found"
+        }))),
+        Err(err) => Ok(password_error_response(err)),
+    }
+}
+
+// Set a new password with a reset token. Every session of the account is signed out and a
+// lockout from failed logins is lifted
+pub async fn redeem_password_reset_handler(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    redemption: web::Json<PasswordResetRedemption>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let redemption = redemption.into_inner();
+    let result = run(&pool, move |conn| {
+        let Some(account) = redeem_password_reset(conn, &redemption.token, &redemption.new_password)? else {
+            return Ok(None);
+        };
+        revoke_sessions(conn, &account.id, None, None)?;
+        unlock_account(conn, &account.name)?;
+        Ok::<_, AccountError>(Some(account))
+    }).await;
+
+    match result {
+        Ok(Some(account)) => {
+            // The token stood in for a sign-in, so the owner is the actor
+            let actor = AuditActor::account(&account.organization_id, &account.id, &req);
+            Audit::new(actor, "account.password_reset", AuditTarget::Account, &account.id)
+                .record_with(&pool, None, Some(json!({ "password": "changed" }))).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Password changed, you can now log in"
+            })))
+        }
+        Ok(None) => Ok(HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": "Invalid or expired reset token"
+        }))),
+        Err(err) => Ok(password_error_response(err)),
+    }
+}
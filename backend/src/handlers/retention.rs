@@ -0,0 +1,186 @@
+use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
+use serde_json::json;
+use log::error;
+use crate::retention::{RetentionPolicy, RetentionError, create_policy, get_policy, list_policies,
+    update_policy, delete_policy, list_runs, purge_account, incremental_vacuum};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+fn retention_error_response(err: RetentionError) -> HttpResponse {
+    match err {
+        RetentionError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+pub async fn create_retention_policy_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    policy: web::Json<RetentionPolicy>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    tenant.authorize(&account_id)?;
+
+    let actor = AuditActor::new(&tenant, &req);
+    let mut policy = policy.into_inner();
+    policy.account_id = account_id.into_inner();
+    match run(&pool, move |conn| create_policy(conn, &policy)).await {
+        Ok(policy) => {
+            Audit::new(actor, "retention_policy.create", AuditTarget::RetentionPolicy, &policy.id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(policy))
+        }
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
+
+pub async fn get_retention_policy_handler(tenant: Tenant, pool: web::Data<DbPool>, policy_id: web::Path<String>) -> impl Responder {
+    let policy_id = policy_id.into_inner();
+    match run(&pool, move |conn| get_policy(conn, &tenant.organization_id, &policy_id)).await {
+        Ok(Some(policy)) => HttpResponse::Ok().json(policy),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Retention policy not found"
+        })),
+        Err(err) => retention_error_response(err),
+    }
+}
+
+pub async fn get_retention_policies_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_policies(conn, &account_id)).await {
+        Ok(policies) => Ok(HttpResponse::Ok().json(policies)),
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
+
+pub async fn edit_retention_policy_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    policy_id: web::Path<String>,
+    policy: web::Json<RetentionPolicy>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let policy_id = policy_id.into_inner();
+    let policy = policy.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "retention_policy.update", AuditTarget::RetentionPolicy, &policy_id);
+    let before = audit.snapshot(&pool).await;
+    let result = run(&pool, move |conn| {
+        match get_policy(conn, &tenant.organization_id, &policy_id)? {
+            Some(existing) => update_policy(conn, &RetentionPolicy {
+                id: existing.id,
+                account_id: existing.account_id,
+                created_at: existing.created_at,
+                ..policy
+            }),
+            None => Ok(false),
+        }
+    }).await;
+
+    match result {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Retention policy updated successfully"
+            })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Retention policy not found"
+        }))),
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
+
+pub async fn delete_retention_policy_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    policy_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let policy_id = policy_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "retention_policy.delete", AuditTarget::RetentionPolicy, &policy_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_policy(conn, &tenant.organization_id, &policy_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(json!({
+                "status": "success",
+                "message": "Retention policy deleted successfully"
+            })))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Retention policy not found"
+        }))),
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
+
+// Purge an account now instead of waiting for the scheduled job
+pub async fn run_retention_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "retention.run", AuditTarget::Organization, &tenant.organization_id);
+    let result = run(&pool, move |conn| {
+        let report = purge_account(conn, &account_id)?;
+        if report.logs_deleted + report.logs_archived > 0 {
+            incremental_vacuum(conn)?;
+        }
+        Ok::<_, RetentionError>(report)
+    }).await;
+
+    match result {
+        Ok(report) => {
+            audit.record_with(&pool, None, serde_json::to_value(&report).ok()).await;
+            Ok(HttpResponse::Ok().json(report))
+        }
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
+
+pub async fn get_retention_runs_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_runs(conn, &account_id)).await {
+        Ok(runs) => Ok(HttpResponse::Ok().json(runs)),
+        Err(err) => Ok(retention_error_response(err)),
+    }
+}
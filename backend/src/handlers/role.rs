@@ -0,0 +1,104 @@
+use actix_web::{web, HttpResponse, HttpRequest, Error};
+use serde_json::json;
+use log::error;
+use crate::rbac::{Role, RbacError, ALL_PERMISSIONS, list_roles, create_role, update_role, delete_role};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+fn rbac_error_response(err: RbacError) -> HttpResponse {
+    match err {
+        RbacError::ValidationError(msg) => HttpResponse::BadRequest().json(json!({
+            "status": "error",
+            "message": msg
+        })),
+        _ => {
+            error!("Internal server error: {:?}", err);
+            HttpResponse::InternalServerError().json(json!({
+                "status": "error",
+                "message": "An internal error occurred"
+            }))
+        }
+    }
+}
+
+pub async fn get_roles_handler(tenant: Tenant, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    match run(&pool, move |conn| list_roles(conn, &tenant.organization_id)).await {
+        Ok(roles) => Ok(HttpResponse::Ok().json(roles)),
+        Err(err) => Ok(rbac_error_response(err)),
+    }
+}
+
+pub async fn get_permissions_handler() -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ALL_PERMISSIONS))
+}
+
+pub async fn create_role_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    role: web::Json<Role>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let role = role.into_inner();
+    let actor = AuditActor::new(&tenant, &req);
+    match run(&pool, move |conn| create_role(conn, &tenant.organization_id, &role)).await {
+        Ok(role) => {
+            Audit::new(actor, "role.create", AuditTarget::Role, &role.name).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(role))
+        }
+        Err(err) => Ok(rbac_error_response(err)),
+    }
+}
+
+pub async fn edit_role_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    name: web::Path<String>,
+    role: web::Json<Role>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let name = name.into_inner();
+    let role = role.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "role.update", AuditTarget::Role, &name);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| update_role(conn, &tenant.organization_id, &name, &role)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Role not found"
+        }))),
+        Err(err) => Ok(rbac_error_response(err)),
+    }
+}
+
+pub async fn delete_role_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    name: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let name = name.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "role.delete", AuditTarget::Role, &name);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_role(conn, &tenant.organization_id, &name)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Role not found"
+        }))),
+        Err(err) => Ok(rbac_error_response(err)),
+    }
+}
@@ -2,20 +2,32 @@ use actix_web::{web, HttpResponse, HttpRequest, Responder, Error};
 use serde_json::json;
 use log::info;
 use crate::rules::{Rule, create_rule, get_rule, list_rules, update_rule, delete_rule};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
 use crate::csrf::{CsrfMiddleware, csrf_validator};
 
 pub async fn create_rule_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>,
     rule: web::Json<Rule>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
+    tenant.authorize(&account_id)?;
     info!("create_rule_handler()");
     info!("Rule date: {:?}", rule.date);
     info!("created date: {:?}", rule.created_at);
     info!("updated date: {:?}", rule.updated_at);
-    match create_rule(&rule) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let actor = AuditActor::new(&tenant, &req);
+    let rule = Rule { account_id: tenant.organization_id, ..rule.into_inner() };
+    match run(&pool, move |conn| create_rule(conn, &rule)).await {
+        Ok(rule_id) => {
+            Audit::new(actor, "rule.create", AuditTarget::Rule, rule_id).record(&pool, None).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -23,9 +35,14 @@ pub async fn create_rule_handler(
     }
 }
 
-pub async fn get_rule_handler(rule_id: web::Path<String>) -> impl Responder {
-    match get_rule(&rule_id.to_string()) {
-        Ok(rule) => HttpResponse::Ok().json(rule),
+pub async fn get_rule_handler(tenant: Tenant, pool: web::Data<DbPool>, rule_id: web::Path<String>) -> impl Responder {
+    let rule_id = rule_id.into_inner();
+    match run(&pool, move |conn| get_rule(conn, &tenant.organization_id, &rule_id)).await {
+        Ok(Some(rule)) => HttpResponse::Ok().json(rule),
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Rule not found"
+        })),
         Err(err) => HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -33,24 +50,42 @@ pub async fn get_rule_handler(rule_id: web::Path<String>) -> impl Responder {
     }
 }
 
-pub async fn get_all_rules_handler(account_id: web::Path<String>) -> impl Responder {
-    match list_rules(&account_id.to_string()) {
-        Ok(rules) => HttpResponse::Ok().json(rules),
-        Err(err) => HttpResponse::InternalServerError().json(json!({
+pub async fn get_all_rules_handler(
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
+    account_id: web::Path<String>
+) -> Result<HttpResponse, Error> {
+    let account_id = account_id.into_inner();
+    tenant.authorize(&account_id)?;
+    match run(&pool, move |conn| list_rules(conn, &account_id)).await {
+        Ok(rules) => Ok(HttpResponse::Ok().json(rules)),
+        Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
-        }))
+        })))
     }
 }
 
 pub async fn edit_rule_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     rule: web::Json<Rule>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match update_rule(&rule) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "rule.update", AuditTarget::Rule, &rule.id);
+    let before = audit.snapshot(&pool).await;
+    let rule = Rule { account_id: tenant.organization_id, ..rule.into_inner() };
+    match run(&pool, move |conn| update_rule(conn, &rule)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Rule not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -60,12 +95,24 @@ pub async fn edit_rule_handler(
 
 pub async fn delete_rule_handler(
     req: HttpRequest,
+    tenant: Tenant,
+    pool: web::Data<DbPool>,
     rule_id: web::Path<String>,
     csrf: web::Data<CsrfMiddleware>
 ) -> Result<HttpResponse, Error> {
     csrf_validator(&req, &csrf).await?;
-    match delete_rule(&rule_id.to_string()) {
-        Ok(ok) => Ok(HttpResponse::Ok().json(ok)),
+    let rule_id = rule_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "rule.delete", AuditTarget::Rule, &rule_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| delete_rule(conn, &tenant.organization_id, &rule_id)).await {
+        Ok(true) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Rule not found"
+        }))),
         Err(err) => Ok(HttpResponse::InternalServerError().json(json!({
             "status": "error",
             "message": err.to_string()
@@ -0,0 +1,86 @@
+use actix_web::{web, error::{ErrorForbidden, ErrorUnauthorized}, HttpResponse, HttpRequest, Error};
+use actix_session::Session;
+use serde_json::json;
+use log::error;
+use crate::session_store::{list_sessions, revoke_sessions};
+use crate::auth_session::Tenant;
+use crate::audit::{Audit, AuditActor, AuditTarget};
+use crate::database::{DbPool, run};
+use crate::csrf::{CsrfMiddleware, csrf_validator};
+
+// The sign-in making the request. Sessions are managed from a signed-in browser, not with API tokens
+fn current_session(tenant: &Tenant, session: &Session) -> Result<String, Error> {
+    if tenant.token_id.is_some() {
+        return Err(ErrorForbidden("API tokens cannot manage sessions"));
+    }
+    session.get::<String>("session_id")
+        .map_err(|_| ErrorUnauthorized("Session error"))?
+        .ok_or_else(|| ErrorUnauthorized("Unauthorized"))
+}
+
+fn internal_error(err: rusqlite::Error) -> HttpResponse {
+    error!("Internal server error: {:?}", err);
+    HttpResponse::InternalServerError().json(json!({
+        "status": "error",
+        "message": "An internal error occurred"
+    }))
+}
+
+pub async fn get_sessions_handler(tenant: Tenant, session: Session, pool: web::Data<DbPool>) -> Result<HttpResponse, Error> {
+    let current = current_session(&tenant, &session)?;
+    match run(&pool, move |conn| list_sessions(conn, &tenant.account_id)).await {
+        Ok(mut sessions) => {
+            for info in sessions.iter_mut() {
+                info.current = info.id == current;
+            }
+            Ok(HttpResponse::Ok().json(sessions))
+        }
+        Err(err) => Ok(internal_error(err)),
+    }
+}
+
+pub async fn revoke_session_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    session: Session,
+    pool: web::Data<DbPool>,
+    session_id: web::Path<String>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    current_session(&tenant, &session)?;
+    let session_id = session_id.into_inner();
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "session.revoke", AuditTarget::Session, &session_id);
+    let before = audit.snapshot(&pool).await;
+    match run(&pool, move |conn| revoke_sessions(conn, &tenant.account_id, Some(&session_id), None)).await {
+        Ok(0) => Ok(HttpResponse::NotFound().json(json!({
+            "status": "error",
+            "message": "Session not found"
+        }))),
+        Ok(_) => {
+            audit.record(&pool, before).await;
+            Ok(HttpResponse::Ok().json(()))
+        }
+        Err(err) => Ok(internal_error(err)),
+    }
+}
+
+// Sign out everywhere else; the current session stays signed in
+pub async fn revoke_other_sessions_handler(
+    req: HttpRequest,
+    tenant: Tenant,
+    session: Session,
+    pool: web::Data<DbPool>,
+    csrf: web::Data<CsrfMiddleware>
+) -> Result<HttpResponse, Error> {
+    csrf_validator(&req, &csrf).await?;
+    let current = current_session(&tenant, &session)?;
+    let audit = Audit::new(AuditActor::new(&tenant, &req), "session.revoke_others", AuditTarget::Account, &tenant.account_id);
+    match run(&pool, move |conn| revoke_sessions(conn, &tenant.account_id, None, Some(&current))).await {
+        Ok(revoked) => {
+            audit.record_with(&pool, None, Some(json!({ "revoked": revoked }))).await;
+            Ok(HttpResponse::Ok().json(json!({ "revoked": revoked })))
+        }
+        Err(err) => Ok(internal_error(err)),
+    }
+}
@@ -1,5 +1,4 @@
-use rusqlite::{Error as SqliteError, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, params};
 use std::{fmt, net::IpAddr, str::FromStr};
 use serde::{Serialize, Deserialize};
 use rusqlite::OptionalExtension;
@@ -50,14 +49,14 @@ impl Host {
     }
 }
 
-pub fn create_host(host: &Host, account_id: &String) -> Result<(), HostError> {
+// Returns the new host's ID
+pub fn create_host(conn: &Connection, host: &Host, account_id: &String) -> Result<String, HostError> {
     host.validate()?;
 
-    let conn = establish_connection()?;
     let id = Uuid::new_v4().to_string();
 
     if let Some(ref hostname) = host.hostname {
-        if hostname_exists(account_id, hostname)? {
+        if hostname_exists(conn, account_id, hostname)? {
             return Err(HostError::ValidationError(
                 format!("A host with the hostname '{}' already exists.", hostname)
             ));
@@ -81,20 +80,22 @@ pub fn create_host(host: &Host, account_id: &String) -> Result<(), HostError> {
         ],
     )?;
     
-    Ok(())
+    Ok(new_host.id)
 }
 
-pub fn get_host(host_id: &String) -> Result<Option<Host>, HostError> {
+pub fn get_host(conn: &Connection, account_id: &str, host_id: &String) -> Result<Option<Host>, HostError> {
+    if account_id.is_empty() {
+        return Err(HostError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if host_id.is_empty() {
         return Err(HostError::ValidationError("Host ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
-        "SELECT id, account_id, ip_address, hostname FROM hosts WHERE id = ?1"
+        "SELECT id, account_id, ip_address, hostname FROM hosts WHERE id = ?1 AND account_id = ?2"
     )?;
 
-    let host = stmt.query_row(params![host_id], |row| {
+    let host = stmt.query_row(params![host_id, account_id], |row| {
         Ok(Host {
             id: row.get(0)?,
             account_id: row.get(1)?,
@@ -106,12 +107,11 @@ pub fn get_host(host_id: &String) -> Result<Option<Host>, HostError> {
     Ok(host)
 }
 
-pub fn get_all_hosts(account_id: &String) -> Result<Vec<Host>, HostError> {
+pub fn get_all_hosts(conn: &Connection, account_id: &String) -> Result<Vec<Host>, HostError> {
     if account_id.is_empty() {
         return Err(HostError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
         "SELECT id, account_id, ip_address, hostname FROM hosts WHERE account_id = ?1"
     )?;
@@ -129,12 +129,12 @@ pub fn get_all_hosts(account_id: &String) -> Result<Vec<Host>, HostError> {
     Ok(hosts?)
 }
 
-pub fn update_host(host: &Host) -> Result<(), HostError> {
+// Hosts never move between accounts, so account_id only scopes the update
+pub fn update_host(conn: &Connection, host: &Host) -> Result<bool, HostError> {
     host.validate()?;
 
-    let conn = establish_connection()?;
-    conn.execute(
-        "UPDATE hosts SET account_id = ?1, ip_address = ?2, hostname = ?3 WHERE id = ?4",
+    let affected_rows = conn.execute(
+        "UPDATE hosts SET ip_address = ?2, hostname = ?3 WHERE id = ?4 AND account_id = ?1",
         params![
             host.account_id,
             host.ip_address,
@@ -143,29 +143,30 @@ pub fn update_host(host: &Host) -> Result<(), HostError> {
         ],
     )?;
 
-    Ok(())
+    Ok(affected_rows > 0)
 }
 
-pub fn delete_host(host_id: &String) -> Result<bool, HostError> {
+pub fn delete_host(conn: &Connection, account_id: &str, host_id: &String) -> Result<bool, HostError> {
+    if account_id.is_empty() {
+        return Err(HostError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if host_id.is_empty() {
         return Err(HostError::ValidationError("Host ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let affected_rows = conn.execute(
-        "DELETE FROM hosts WHERE id = ?1",
-        params![host_id],
+        "DELETE FROM hosts WHERE id = ?1 AND account_id = ?2",
+        params![host_id, account_id],
     )?;
 
     Ok(affected_rows > 0)
 }
 
-fn hostname_exists(account_id: &String, hostname: &String) -> Result<bool, HostError> {
+fn hostname_exists(conn: &Connection, account_id: &String, hostname: &String) -> Result<bool, HostError> {
     if account_id.is_empty() || hostname.is_empty() {
         return Err(HostError::ValidationError("Account ID and/or hostname cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
         "SELECT COUNT(*) FROM hosts WHERE account_id = ?1 AND hostname = ?2"
     )?;
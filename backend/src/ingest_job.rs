@@ -0,0 +1,204 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+use uuid::Uuid;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum JobError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            JobError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for JobError {
+    fn from(err: SqliteError) -> Self {
+        JobError::DatabaseError(err)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IngestJob {
+    pub id: String,
+    pub account_id: String,
+    pub host_id: String,
+    pub status: String,
+    pub lines_read: i64,
+    pub lines_parsed: i64,
+    pub duplicates: i64,
+    pub failures: i64,
+    pub alerts_raised: i64,
+    pub batches_total: i64,
+    pub batches_done: i64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+// Counters produced by processing a single batch
+#[derive(Debug, Default, Clone)]
+pub struct BatchStats {
+    pub lines_parsed: i64,
+    pub duplicates: i64,
+    pub failures: i64,
+    pub alerts_raised: i64,
+}
+
+pub fn create_job(conn: &Connection, account_id: &str, host_id: &str, lines_read: i64, batches_total: i64) -> Result<IngestJob, JobError> {
+    if account_id.is_empty() {
+        return Err(JobError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if host_id.is_empty() {
+        return Err(JobError::ValidationError("Host ID cannot be empty".to_string()));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let job = IngestJob {
+        id: Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        host_id: host_id.to_string(),
+        status: if batches_total == 0 { "completed" } else { "queued" }.to_string(),
+        lines_read,
+        lines_parsed: 0,
+        duplicates: 0,
+        failures: 0,
+        alerts_raised: 0,
+        batches_total,
+        batches_done: 0,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+
+    conn.execute(
+        "INSERT INTO ingest_jobs (id, account_id, host_id, status, lines_read, batches_total, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            job.id,
+            job.account_id,
+            job.host_id,
+            job.status,
+            job.lines_read,
+            job.batches_total,
+            job.created_at,
+            job.updated_at,
+        ],
+    )?;
+
+    Ok(job)
+}
+
+pub fn get_job(conn: &Connection, account_id: &str, job_id: &str) -> Result<Option<IngestJob>, JobError> {
+    if account_id.is_empty() {
+        return Err(JobError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if job_id.is_empty() {
+        return Err(JobError::ValidationError("Job ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, host_id, status, lines_read, lines_parsed, duplicates, failures,
+         alerts_raised, batches_total, batches_done, created_at, updated_at
+         FROM ingest_jobs WHERE id = ?1 AND account_id = ?2"
+    )?;
+
+    let job = stmt.query_row(params![job_id, account_id], |row| {
+        Ok(IngestJob {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            host_id: row.get(2)?,
+            status: row.get(3)?,
+            lines_read: row.get(4)?,
+            lines_parsed: row.get(5)?,
+            duplicates: row.get(6)?,
+            failures: row.get(7)?,
+            alerts_raised: row.get(8)?,
+            batches_total: row.get(9)?,
+            batches_done: row.get(10)?,
+            created_at: row.get(11)?,
+            updated_at: row.get(12)?,
+        })
+    }).optional()?;
+
+    Ok(job)
+}
+
+// Add a finished batch's counters to its job, completing the job on its last batch
+pub fn record_batch(conn: &Connection, job_id: &str, stats: &BatchStats) -> Result<(), JobError> {
+    conn.execute(
+        "UPDATE ingest_jobs SET
+         lines_parsed = lines_parsed + ?2,
+         duplicates = duplicates + ?3,
+         failures = failures + ?4,
+         alerts_raised = alerts_raised + ?5,
+         batches_done = batches_done + 1,
+         status = CASE WHEN batches_done + 1 >= batches_total THEN 'completed' ELSE 'processing' END,
+         updated_at = ?6
+         WHERE id = ?1",
+        params![
+            job_id,
+            stats.lines_parsed,
+            stats.duplicates,
+            stats.failures,
+            stats.alerts_raised,
+            Utc::now().to_rfc3339(),
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{host, organization, test_pool};
+
+    fn stats(lines_parsed: i64, duplicates: i64, failures: i64, alerts_raised: i64) -> BatchStats {
+        BatchStats { lines_parsed, duplicates, failures, alerts_raised }
+    }
+
+    #[test]
+    fn jobs_need_an_account_and_host_and_stay_in_their_account() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (acme, _) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+        let host_id = host(&conn, &acme, "web");
+
+        assert!(matches!(create_job(&conn, "", &host_id, 1, 1), Err(JobError::ValidationError(_))));
+        assert!(matches!(create_job(&conn, &acme, "", 1, 1), Err(JobError::ValidationError(_))));
+
+        let job = create_job(&conn, &acme, &host_id, 10, 2).unwrap();
+        assert_eq!(job.status, "queued");
+        assert_eq!(get_job(&conn, &acme, &job.id).unwrap().unwrap().lines_read, 10);
+        assert!(get_job(&conn, &globex, &job.id).unwrap().is_none());
+        assert!(matches!(get_job(&conn, &acme, ""), Err(JobError::ValidationError(_))));
+
+        // An empty upload has nothing to wait for
+        assert_eq!(create_job(&conn, &acme, &host_id, 0, 0).unwrap().status, "completed");
+    }
+
+    #[test]
+    fn batches_add_up_until_the_job_completes() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let job = create_job(&conn, &organization_id, &host_id, 7, 2).unwrap();
+
+        record_batch(&conn, &job.id, &stats(3, 1, 1, 2)).unwrap();
+        let halfway = get_job(&conn, &organization_id, &job.id).unwrap().unwrap();
+        assert_eq!((halfway.status.as_str(), halfway.batches_done), ("processing", 1));
+
+        record_batch(&conn, &job.id, &stats(2, 0, 1, 0)).unwrap();
+        let done = get_job(&conn, &organization_id, &job.id).unwrap().unwrap();
+        assert_eq!(done.status, "completed");
+        assert_eq!((done.lines_parsed, done.duplicates, done.failures, done.alerts_raised), (5, 1, 2, 2));
+        assert_eq!((done.batches_done, done.batches_total), (2, 2));
+    }
+}
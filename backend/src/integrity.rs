@@ -0,0 +1,415 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use hmac::{Hmac, Mac};
+use std::collections::HashMap;
+use std::time::Duration;
+use actix_web::rt;
+use chrono::Utc;
+use log::{error, info, warn};
+use crate::database::{DbPool, run};
+use crate::log::Log;
+use std::env;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const DIGEST_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+    SigningKeyMissing,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            IntegrityError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            IntegrityError::SigningKeyMissing => write!(f, "DIGEST_SIGNING_KEY is not set"),
+        }
+    }
+}
+
+impl From<SqliteError> for IntegrityError {
+    fn from(err: SqliteError) -> Self {
+        IntegrityError::DatabaseError(err)
+    }
+}
+
+// Signed summary of an account's chain at the end of a UTC day
+#[derive(Debug, Serialize)]
+pub struct LogDigest {
+    pub account_id: String,
+    pub day: String,
+    pub last_seq: i64,
+    pub chain_hash: String,
+    pub log_count: i64,
+    pub signature: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct VerificationReport {
+    pub entries_checked: i64,
+    pub purged: i64,
+    pub chain_breaks: Vec<i64>,
+    pub modified_logs: Vec<String>,
+    pub missing_logs: Vec<String>,
+    pub digests_checked: i64,
+    pub digest_failures: Vec<String>,
+    pub valid: bool,
+}
+
+// Hash of the fields fixed at ingest. Columns updated later (restored_at) are excluded
+pub fn record_hash(log: &Log) -> String {
+    let fields = serde_json::json!([log.id, log.account_id, log.host_id, log.timestamp, log.log_data]);
+    let mut hasher = Sha256::new();
+    hasher.update(fields.to_string().as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn chain_hash(prev_hash: &str, log_id: &str, log_hash: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(log_id.as_bytes());
+    hasher.update(log_hash.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn chain_head(conn: &Connection, account_id: &str) -> Result<String, SqliteError> {
+    let head = conn.query_row(
+        "SELECT chain_hash FROM log_chain WHERE account_id = ?1 ORDER BY seq DESC LIMIT 1",
+        params![account_id],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(head.unwrap_or_else(|| GENESIS_HASH.to_string()))
+}
+
+// Link newly stored logs onto their account's chain. Runs in the insert transaction,
+// which must be IMMEDIATE so two writers never extend the same head
+pub fn append_to_chain(conn: &Connection, logs: &[Log]) -> Result<(), SqliteError> {
+    let mut heads: HashMap<String, String> = HashMap::new();
+    let mut stmt = conn.prepare(
+        "INSERT INTO log_chain (account_id, log_id, log_hash, prev_hash, chain_hash, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+    )?;
+
+    let now = Utc::now().to_rfc3339();
+    for log in logs {
+        let prev_hash = match heads.get(&log.account_id) {
+            Some(head) => head.clone(),
+            None => chain_head(conn, &log.account_id)?,
+        };
+        let log_hash = record_hash(log);
+        let next_hash = chain_hash(&prev_hash, &log.id, &log_hash);
+
+        stmt.execute(params![log.account_id, log.id, log_hash, prev_hash, next_hash, now])?;
+        heads.insert(log.account_id.clone(), next_hash);
+    }
+    Ok(())
+}
+
+// Retention removes logs on purpose; their chain entries stay, marked as purged
+pub fn mark_purged(conn: &Connection, log_ids: &[String]) -> Result<(), SqliteError> {
+    let now = Utc::now().to_rfc3339();
+    let mut stmt = conn.prepare("UPDATE log_chain SET purged_at = ?1 WHERE log_id = ?2 AND purged_at IS NULL")?;
+    for id in log_ids {
+        stmt.execute(params![now, id])?;
+    }
+    Ok(())
+}
+
+fn signing_key() -> Option<Vec<u8>> {
+    env::var("DIGEST_SIGNING_KEY").ok().filter(|key| !key.is_empty()).map(String::into_bytes)
+}
+
+fn sign_digest(key: &[u8], account_id: &str, day: &str, last_seq: i64, chain_hash: &str, log_count: i64) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}|{}|{}|{}|{}", account_id, day, last_seq, chain_hash, log_count).as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+// Sign a digest for every finished UTC day that doesn't have one yet
+pub fn create_pending_digests(conn: &Connection) -> Result<usize, IntegrityError> {
+    let key = signing_key().ok_or(IntegrityError::SigningKeyMissing)?;
+    let today = Utc::now().format("%Y-%m-%d").to_string();
+
+    let mut stmt = conn.prepare(
+        "SELECT c.account_id, substr(c.created_at, 1, 10) AS day, MAX(c.seq), COUNT(*)
+         FROM log_chain c
+         WHERE substr(c.created_at, 1, 10) < ?1
+           AND NOT EXISTS (
+               SELECT 1 FROM log_digests d
+               WHERE d.account_id = c.account_id AND d.day = substr(c.created_at, 1, 10)
+           )
+         GROUP BY c.account_id, day"
+    )?;
+    let pending: Result<Vec<(String, String, i64, i64)>, SqliteError> = stmt.query_map(params![today], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })?.collect();
+
+    let mut created = 0;
+    for (account_id, day, last_seq, log_count) in pending? {
+        let hash: String = conn.query_row(
+            "SELECT chain_hash FROM log_chain WHERE seq = ?1",
+            params![last_seq],
+            |row| row.get(0),
+        )?;
+        let signature = sign_digest(&key, &account_id, &day, last_seq, &hash, log_count);
+
+        conn.execute(
+            "INSERT INTO log_digests (account_id, day, last_seq, chain_hash, log_count, signature, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![account_id, day, last_seq, hash, log_count, signature, Utc::now().to_rfc3339()],
+        )?;
+        created += 1;
+    }
+    Ok(created)
+}
+
+pub fn list_digests(conn: &Connection, account_id: &str) -> Result<Vec<LogDigest>, IntegrityError> {
+    if account_id.is_empty() {
+        return Err(IntegrityError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT account_id, day, last_seq, chain_hash, log_count, signature, created_at
+         FROM log_digests WHERE account_id = ?1
+         ORDER BY day"
+    )?;
+
+    let digests_iter = stmt.query_map(params![account_id], |row| {
+        Ok(LogDigest {
+            account_id: row.get(0)?,
+            day: row.get(1)?,
+            last_seq: row.get(2)?,
+            chain_hash: row.get(3)?,
+            log_count: row.get(4)?,
+            signature: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    })?;
+
+    let digests: Result<Vec<LogDigest>, SqliteError> = digests_iter.collect();
+    Ok(digests?)
+}
+
+// Walk the account's chain, recomputing every link and every stored log's hash,
+// then check each daily digest's signature against the chain
+pub fn verify_chain(conn: &Connection, account_id: &str) -> Result<VerificationReport, IntegrityError> {
+    if account_id.is_empty() {
+        return Err(IntegrityError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let mut report = VerificationReport::default();
+    let mut chain_hashes: HashMap<i64, String> = HashMap::new();
+
+    let mut stmt = conn.prepare(
+        "SELECT c.seq, c.log_id, c.log_hash, c.prev_hash, c.chain_hash, c.purged_at IS NOT NULL,
+                l.id, l.hash, l.account_id, l.host_id, l.timestamp, l.log_data
+         FROM log_chain c
+         LEFT JOIN logs l ON l.id = c.log_id
+         WHERE c.account_id = ?1
+         ORDER BY c.seq"
+    )?;
+    let mut rows = stmt.query(params![account_id])?;
+
+    let mut expected_prev = GENESIS_HASH.to_string();
+    while let Some(row) = rows.next()? {
+        let seq: i64 = row.get(0)?;
+        let log_id: String = row.get(1)?;
+        let log_hash: String = row.get(2)?;
+        let prev_hash: String = row.get(3)?;
+        let stored_hash: String = row.get(4)?;
+        let purged: bool = row.get(5)?;
+        report.entries_checked += 1;
+
+        if prev_hash != expected_prev || chain_hash(&prev_hash, &log_id, &log_hash) != stored_hash {
+            report.chain_breaks.push(seq);
+        }
+
+        match row.get::<_, Option<String>>(6)? {
+            Some(id) => {
+                let log = Log {
+                    id,
+                    hash: row.get(7)?,
+                    account_id: row.get(8)?,
+                    host_id: row.get(9)?,
+                    timestamp: row.get(10)?,
+                    log_data: row.get(11)?,
+                    occurrence_count: 1,
+                    last_seen: None,
+                };
+                if record_hash(&log) != log_hash {
+                    report.modified_logs.push(log_id);
+                }
+            }
+            None if purged => report.purged += 1,
+            None => report.missing_logs.push(log_id),
+        }
+
+        chain_hashes.insert(seq, stored_hash.clone());
+        expected_prev = stored_hash;
+    }
+
+    let key = signing_key();
+    for digest in list_digests(conn, account_id)? {
+        report.digests_checked += 1;
+        let signature_ok = match &key {
+            Some(key) => sign_digest(key, &digest.account_id, &digest.day, digest.last_seq, &digest.chain_hash, digest.log_count) == digest.signature,
+            None => false,
+        };
+        let chain_ok = chain_hashes.get(&digest.last_seq) == Some(&digest.chain_hash);
+        if !signature_ok || !chain_ok {
+            report.digest_failures.push(digest.day);
+        }
+    }
+
+    report.valid = report.chain_breaks.is_empty()
+        && report.modified_logs.is_empty()
+        && report.missing_logs.is_empty()
+        && report.digest_failures.is_empty();
+    Ok(report)
+}
+
+// Sign daily digests in the background. Without DIGEST_SIGNING_KEY the chain is
+// still kept, but no digests are produced
+pub fn spawn_digest_job(pool: DbPool) {
+    if signing_key().is_none() {
+        warn!("DIGEST_SIGNING_KEY is not set, daily log digests are disabled");
+        return;
+    }
+
+    rt::spawn(async move {
+        loop {
+            match run(&pool, |conn| create_pending_digests(conn)).await {
+                Ok(0) => {},
+                Ok(created) => info!("Signed {} daily log digests", created),
+                Err(e) => error!("Failed to create log digests: {}", e),
+            }
+            rt::time::sleep(DIGEST_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+    use crate::archive::archive_logs;
+    use crate::test_support::{archive_dir, host, organization, store_logs, test_pool};
+
+    // Digests are signed with one fixed key for every test
+    fn signing_key_set() {
+        static SET: Once = Once::new();
+        SET.call_once(|| env::set_var("DIGEST_SIGNING_KEY", "test-digest-key"));
+    }
+
+    // Put the account's chain entries on a day that is over, so it can be digested
+    fn finish_day(conn: &Connection, account_id: &str) {
+        conn.execute(
+            "UPDATE log_chain SET created_at = '2024-01-01T12:00:00+00:00' WHERE account_id = ?1",
+            params![account_id],
+        ).unwrap();
+    }
+
+    #[test]
+    fn batches_extend_one_chain_that_verifies() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        store_logs(&mut conn, &organization_id, &host_id, &["one", "two"]);
+        store_logs(&mut conn, &organization_id, &host_id, &["three"]);
+
+        let links: Vec<(String, String)> = conn.prepare("SELECT prev_hash, chain_hash FROM log_chain ORDER BY seq").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(links.len(), 3);
+        assert_eq!(links[0].0, GENESIS_HASH);
+        assert_eq!(links[2].0, links[1].1);
+
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!(report.entries_checked, 3);
+        assert!(report.valid);
+    }
+
+    #[test]
+    fn edited_and_deleted_logs_are_reported() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let logs = store_logs(&mut conn, &organization_id, &host_id, &["one", "two", "three"]);
+
+        conn.execute("UPDATE logs SET log_data = '{\"raw\":\"nothing happened\"}' WHERE id = ?1", params![logs[0].id]).unwrap();
+        conn.execute("DELETE FROM logs WHERE id = ?1", params![logs[1].id]).unwrap();
+
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!(report.modified_logs, vec![logs[0].id.clone()]);
+        assert_eq!(report.missing_logs, vec![logs[1].id.clone()]);
+        assert!(report.chain_breaks.is_empty());
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn purged_logs_keep_the_chain_valid() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let logs = store_logs(&mut conn, &organization_id, &host_id, &["one", "two"]);
+        archive_logs(&mut conn, &organization_id, &[logs[0].id.clone()]).unwrap();
+
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!((report.entries_checked, report.purged), (2, 1));
+        assert!(report.valid);
+    }
+
+    #[test]
+    fn a_rewritten_link_breaks_the_chain() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        store_logs(&mut conn, &organization_id, &host_id, &["one", "two", "three"]);
+        let second: i64 = conn.query_row("SELECT seq FROM log_chain ORDER BY seq LIMIT 1 OFFSET 1", [], |row| row.get(0)).unwrap();
+        conn.execute("UPDATE log_chain SET prev_hash = ?1 WHERE seq = ?2", params![GENESIS_HASH, second]).unwrap();
+
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!(report.chain_breaks, vec![second]);
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn finished_days_are_digested_once_and_checked() {
+        signing_key_set();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        store_logs(&mut conn, &organization_id, &host_id, &["one", "two"]);
+
+        // Today isn't over yet
+        assert_eq!(create_pending_digests(&conn).unwrap(), 0);
+        finish_day(&conn, &organization_id);
+        assert_eq!(create_pending_digests(&conn).unwrap(), 1);
+        assert_eq!(create_pending_digests(&conn).unwrap(), 0);
+
+        let digests = list_digests(&conn, &organization_id).unwrap();
+        assert_eq!((digests[0].day.as_str(), digests[0].log_count), ("2024-01-01", 2));
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!(report.digests_checked, 1);
+        assert!(report.valid);
+
+        conn.execute("UPDATE log_digests SET log_count = 3 WHERE account_id = ?1", params![organization_id]).unwrap();
+        let report = verify_chain(&conn, &organization_id).unwrap();
+        assert_eq!(report.digest_failures, vec!["2024-01-01".to_string()]);
+        assert!(!report.valid);
+    }
+}
@@ -0,0 +1,369 @@
+use ldap3::{LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry, dn_escape, ldap_escape};
+use std::time::Duration;
+use log::{error, warn};
+use crate::account::{Account, AccountError, AuthProvider, Grant, optional_env, required_env, role_mappings};
+
+pub const LDAP_PROVIDER: &str = "ldap";
+
+// LDAP result code for a failed bind
+const INVALID_CREDENTIALS: u32 = 49;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Settings from the environment:
+// - `LDAP_URL`: `ldap://` or `ldaps://` address of the directory
+// - `LDAP_USER_DN`: DN to bind as, with `{username}` for the escaped login name. A UPN such as
+//   `{username}@corp.example.com` works for Active Directory
+// - `LDAP_SEARCH_BASE`, `LDAP_USER_FILTER`: where to find the user's entry after binding, default
+//   the bound DN itself. The filter defaults to `(uid={username})`, use `(sAMAccountName={username})` for AD
+// - `LDAP_GROUP_ATTRIBUTE`: attribute on the user entry listing group DNs, default `memberOf`
+// - `LDAP_GROUP_SEARCH_BASE`, `LDAP_GROUP_FILTER`: optional group search for directories without
+//   `memberOf`, the filter defaults to `(|(member={dn})(uniqueMember={dn}))`
+// - `LDAP_GROUP_ROLES`: `group DN=>Role` pairs separated by `;`, the first match wins
+// - `LDAP_DEFAULT_ROLE`: role for users in none of the mapped groups, otherwise they can't log in
+// - `LDAP_ORGANIZATION_ID`: organization that directory users join
+// - `LDAP_STARTTLS`: `true` to upgrade an `ldap://` connection
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    pub url: String,
+    pub user_dn: String,
+    pub search_base: Option<String>,
+    pub user_filter: String,
+    pub group_attribute: String,
+    pub group_search_base: Option<String>,
+    pub group_filter: String,
+    pub group_roles: Vec<(String, String)>,
+    pub default_role: Option<String>,
+    pub organization_id: String,
+    pub starttls: bool,
+}
+
+impl LdapConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let user_dn = required_env("LDAP_USER_DN")?;
+        if !user_dn.contains("{username}") {
+            return Err("LDAP_USER_DN must contain {username}".to_string());
+        }
+
+        Ok(LdapConfig {
+            url: required_env("LDAP_URL")?,
+            user_dn,
+            search_base: optional_env("LDAP_SEARCH_BASE"),
+            user_filter: optional_env("LDAP_USER_FILTER").unwrap_or_else(|| "(uid={username})".to_string()),
+            group_attribute: optional_env("LDAP_GROUP_ATTRIBUTE").unwrap_or_else(|| "memberOf".to_string()),
+            group_search_base: optional_env("LDAP_GROUP_SEARCH_BASE"),
+            group_filter: optional_env("LDAP_GROUP_FILTER").unwrap_or_else(|| "(|(member={dn})(uniqueMember={dn}))".to_string()),
+            group_roles: role_mappings("LDAP_GROUP_ROLES")?,
+            default_role: optional_env("LDAP_DEFAULT_ROLE"),
+            organization_id: required_env("LDAP_ORGANIZATION_ID")?,
+            starttls: optional_env("LDAP_STARTTLS").is_some_and(|v| v == "true"),
+        })
+    }
+
+    // Role for the first mapped group the user is in. DNs compare case-insensitively
+    pub fn role_for(&self, groups: &[String]) -> Option<String> {
+        self.group_roles.iter()
+            .find(|(group, _)| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+            .map(|(_, role)| role.clone())
+            .or_else(|| self.default_role.clone())
+    }
+}
+
+// Bind as the user and read their groups. None when the directory rejects the credentials
+pub fn directory_groups(config: &LdapConfig, username: &str, password: &str) -> Result<Option<Vec<String>>, LdapError> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(CONNECT_TIMEOUT)
+        .set_starttls(config.starttls);
+    let mut ldap = LdapConn::with_settings(settings, &config.url)?;
+
+    let bind_dn = config.user_dn.replace("{username}", &dn_escape(username));
+    let bind = ldap.simple_bind(&bind_dn, password)?;
+    if bind.rc == INVALID_CREDENTIALS {
+        let _ = ldap.unbind();
+        return Ok(None);
+    }
+    bind.success()?;
+
+    let (base, scope, filter) = match &config.search_base {
+        Some(base) => (base.clone(), Scope::Subtree, config.user_filter.replace("{username}", &ldap_escape(username))),
+        None => (bind_dn.clone(), Scope::Base, "(objectClass=*)".to_string()),
+    };
+    let (entries, _) = ldap.search(&base, scope, &filter, vec![config.group_attribute.as_str()])?.success()?;
+    let Some(entry) = entries.into_iter().next().map(SearchEntry::construct) else {
+        let _ = ldap.unbind();
+        return Ok(None);
+    };
+
+    let mut groups = entry.attrs.iter()
+        .find(|(attr, _)| attr.eq_ignore_ascii_case(&config.group_attribute))
+        .map(|(_, values)| values.clone())
+        .unwrap_or_default();
+
+    if let Some(group_base) = &config.group_search_base {
+        let filter = config.group_filter.replace("{dn}", &ldap_escape(&entry.dn));
+        let (entries, _) = ldap.search(group_base, Scope::Subtree, &filter, vec!["1.1"])?.success()?;
+        groups.extend(entries.into_iter().map(|e| SearchEntry::construct(e).dn));
+    }
+
+    let _ = ldap.unbind();
+    Ok(Some(groups))
+}
+
+// Binds to a directory with the user's own credentials. Accounts are created in the configured
+// organization on first login and get their role from group membership on every login
+pub struct LdapProvider {
+    config: LdapConfig,
+}
+
+impl LdapProvider {
+    pub fn from_env() -> Result<Self, String> {
+        Ok(LdapProvider { config: LdapConfig::from_env()? })
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn name(&self) -> &'static str {
+        LDAP_PROVIDER
+    }
+
+    fn provisions_accounts(&self) -> bool {
+        true
+    }
+
+    fn verify(&self, _account: Option<&Account>, name: &str, password: &str) -> Result<Option<Grant>, AccountError> {
+        // An empty password is an unauthenticated bind, which many directories accept
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        let groups = match directory_groups(&self.config, name, password) {
+            Ok(Some(groups)) => groups,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                error!("LDAP authentication failed for {}: {}", name, err);
+                return Err(AccountError::ProviderError("Directory server unavailable".to_string()));
+            }
+        };
+
+        let Some(role) = self.config.role_for(&groups) else {
+            warn!("LDAP user {} is in none of the mapped groups", name);
+            return Ok(None);
+        };
+        Ok(Some(Grant::Provisioned { organization_id: self.config.organization_id.clone(), role }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{grant_login, get_account};
+    use crate::test_support::{organization, test_pool};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    const GROUP_BASE: &str = "ou=groups,dc=example,dc=org";
+    const ADMINS: &str = "cn=SIEM-Admins,ou=groups,dc=example,dc=org";
+    const ANALYSTS: &str = "cn=analysts,ou=groups,dc=example,dc=org";
+
+    // Bind DN, password and `memberOf` values of the mock directory's users
+    const USERS: &[(&str, &str, &[&str])] = &[
+        ("uid=alice,ou=people,dc=example,dc=org", "alice-pw", &["cn=siem-admins,ou=groups,dc=example,dc=org"]),
+        ("uid=erin,ou=people,dc=example,dc=org", "erin-pw", &[]),
+    ];
+    // Group DN and member, for directories without `memberOf`
+    const GROUPS: &[(&str, &str)] = &[(ANALYSTS, "uid=erin,ou=people,dc=example,dc=org")];
+
+    // One BER element off the front of `buf`: tag, value and what follows
+    fn split_tlv(buf: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (len, start) = if buf[1] & 0x80 == 0 {
+            (buf[1] as usize, 2)
+        } else {
+            let n = (buf[1] & 0x7f) as usize;
+            (buf[2..2 + n].iter().fold(0, |len, b| (len << 8) | *b as usize), 2 + n)
+        };
+        (buf[0], &buf[start..start + len], &buf[start + len..])
+    }
+
+    fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if value.len() < 128 {
+            out.push(value.len() as u8);
+        } else {
+            let len = (value.len() as u32).to_be_bytes();
+            let skip = len.iter().take_while(|b| **b == 0).count();
+            out.push(0x80 | (len.len() - skip) as u8);
+            out.extend_from_slice(&len[skip..]);
+        }
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).ok()?;
+        let len = if head[1] & 0x80 == 0 {
+            head[1] as usize
+        } else {
+            let mut len = vec![0u8; (head[1] & 0x7f) as usize];
+            stream.read_exact(&mut len).ok()?;
+            len.iter().fold(0, |len, b| (len << 8) | *b as usize)
+        };
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).ok()?;
+        Some(body)
+    }
+
+    fn reply(stream: &mut TcpStream, id: &[u8], op: Vec<u8>) {
+        stream.write_all(&tlv(0x30, &[tlv(0x02, id), op].concat())).unwrap();
+    }
+
+    fn ldap_result(tag: u8, code: u8) -> Vec<u8> {
+        tlv(tag, &[tlv(0x0a, &[code]), tlv(0x04, b""), tlv(0x04, b"")].concat())
+    }
+
+    // Simple binds against `USERS`, base searches on the bound user's own entry and searches
+    // under `GROUP_BASE` for the groups listing them. Filters are not evaluated
+    fn serve(mut stream: TcpStream) {
+        let mut bound: Option<&(&str, &str, &[&str])> = None;
+        while let Some(message) = read_message(&mut stream) {
+            let (_, id, rest) = split_tlv(&message);
+            let (op, request, _) = split_tlv(rest);
+            match op {
+                // BindRequest: version, name, simple password
+                0x60 => {
+                    let (_, _, rest) = split_tlv(request);
+                    let (_, dn, rest) = split_tlv(rest);
+                    let (_, password, _) = split_tlv(rest);
+                    bound = USERS.iter().find(|(d, p, _)| d.as_bytes() == dn && p.as_bytes() == password);
+                    reply(&mut stream, id, ldap_result(0x61, if bound.is_some() { 0 } else { INVALID_CREDENTIALS as u8 }));
+                }
+                // SearchRequest, starting with the base DN
+                0x63 => {
+                    let (_, base, _) = split_tlv(request);
+                    let entries: Vec<(&str, &[&str])> = match bound {
+                        Some((dn, _, groups)) if dn.as_bytes() == base => vec![(*dn, *groups)],
+                        Some((dn, _, _)) if base == GROUP_BASE.as_bytes() => GROUPS.iter()
+                            .filter(|(_, member)| member == dn)
+                            .map(|(group, _)| (*group, &[][..]))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    for (dn, groups) in entries {
+                        let values: Vec<u8> = groups.iter().flat_map(|g| tlv(0x04, g.as_bytes())).collect();
+                        let attribute = tlv(0x30, &[tlv(0x04, b"memberOf"), tlv(0x31, &values)].concat());
+                        reply(&mut stream, id, tlv(0x64, &[tlv(0x04, dn.as_bytes()), tlv(0x30, &attribute)].concat()));
+                    }
+                    reply(&mut stream, id, ldap_result(0x65, 0));
+                }
+                // UnbindRequest or anything the mock doesn't speak
+                _ => return,
+            }
+        }
+    }
+
+    fn mock_directory() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || serve(stream));
+            }
+        });
+        url
+    }
+
+    fn config(url: String) -> LdapConfig {
+        LdapConfig {
+            url,
+            user_dn: "uid={username},ou=people,dc=example,dc=org".to_string(),
+            search_base: None,
+            user_filter: "(uid={username})".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_search_base: None,
+            group_filter: "(|(member={dn})(uniqueMember={dn}))".to_string(),
+            group_roles: vec![(ADMINS.to_string(), "Admin".to_string()), (ANALYSTS.to_string(), "Analyst".to_string())],
+            default_role: None,
+            organization_id: "org".to_string(),
+            starttls: false,
+        }
+    }
+
+    fn role_granted(provider: &LdapProvider, name: &str, password: &str) -> Option<String> {
+        provider.verify(None, name, password).unwrap().map(|grant| match grant {
+            Grant::Provisioned { role, .. } => role,
+            other => panic!("unexpected grant {:?}", other),
+        })
+    }
+
+    #[test]
+    fn wrong_password_is_refused() {
+        let config = config(mock_directory());
+        assert_eq!(directory_groups(&config, "alice", "wrong").unwrap(), None);
+        assert_eq!(directory_groups(&config, "nobody", "alice-pw").unwrap(), None);
+    }
+
+    #[test]
+    fn empty_password_never_binds() {
+        let provider = LdapProvider { config: config(mock_directory()) };
+        assert!(provider.verify(None, "alice", "").unwrap().is_none());
+    }
+
+    #[test]
+    fn member_of_maps_to_role_ignoring_case() {
+        let provider = LdapProvider { config: config(mock_directory()) };
+        assert_eq!(role_granted(&provider, "alice", "alice-pw").as_deref(), Some("Admin"));
+    }
+
+    #[test]
+    fn group_search_finds_groups_without_member_of() {
+        let mut config = config(mock_directory());
+        config.group_search_base = Some(GROUP_BASE.to_string());
+        let provider = LdapProvider { config };
+        assert_eq!(role_granted(&provider, "erin", "erin-pw").as_deref(), Some("Analyst"));
+    }
+
+    #[test]
+    fn unmapped_users_need_a_default_role() {
+        let mut config = config(mock_directory());
+        assert!(LdapProvider { config: config.clone() }.verify(None, "erin", "erin-pw").unwrap().is_none());
+
+        config.default_role = Some("Analyst".to_string());
+        let provider = LdapProvider { config };
+        assert_eq!(role_granted(&provider, "erin", "erin-pw").as_deref(), Some("Analyst"));
+    }
+
+    #[test]
+    fn login_name_is_escaped_in_the_bind_dn() {
+        let config = config(mock_directory());
+        assert_eq!(directory_groups(&config, "alice,ou=people,dc=example,dc=org", "alice-pw").unwrap(), None);
+    }
+
+    #[test]
+    fn unreachable_directory_is_a_provider_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let provider = LdapProvider { config: config(url) };
+        assert!(matches!(provider.verify(None, "alice", "alice-pw"), Err(AccountError::ProviderError(_))));
+    }
+
+    #[test]
+    fn grants_provision_and_refresh_directory_accounts() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "bob");
+        let grant = |role: &str| Grant::Provisioned { organization_id: organization_id.clone(), role: role.to_string() };
+
+        let created = grant_login(&conn, "alice", LDAP_PROVIDER, grant("Admin")).unwrap().unwrap();
+        assert_eq!(created.role, "Admin");
+        let refreshed = grant_login(&conn, "alice", LDAP_PROVIDER, grant("Analyst")).unwrap().unwrap();
+        assert_eq!(refreshed.id, created.id);
+        assert_eq!(get_account(&conn, &created.id).unwrap().unwrap().role, "Analyst");
+
+        // A directory user with a local account's name never takes it over
+        assert!(matches!(
+            grant_login(&conn, "bob", LDAP_PROVIDER, grant("Admin")),
+            Err(AccountError::ValidationError(_))
+        ));
+    }
+}
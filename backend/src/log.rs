@@ -1,12 +1,15 @@
 use crate::eql::EqlError;
-use rusqlite::{Error as SqliteError, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, params};
+use crate::integrity::append_to_chain;
 use serde::{Serialize, Deserialize};
 use crate::eql::QueryExecutor;
 use sha2::{Sha256, Digest};
 use uuid::Uuid;
+use std::env;
 use std::fmt;
 
+const DEFAULT_DEDUP_WINDOW_SECS: i64 = 300;
+
 #[derive(Debug)]
 pub enum LogError {
     DatabaseError(SqliteError),
@@ -48,6 +51,11 @@ pub struct Log {
     pub host_id: String,
     pub timestamp: Option<String>,
     pub log_data: String,
+    // Repeats of the same line from the same host within the dedup window
+    #[serde(default)]
+    pub occurrence_count: i64,
+    #[serde(default)]
+    pub last_seen: Option<String>,
 }
 
 impl Log {
@@ -61,75 +69,118 @@ impl Log {
         Ok(())
     }
 
+    // Dedup key: the raw line with whitespace collapsed, scoped to the account and host
     pub fn calculate_hash(&self) -> String {
+        let raw = serde_json::from_str::<serde_json::Value>(&self.log_data)
+            .ok()
+            .and_then(|data| data.get("raw").and_then(|raw| raw.as_str()).map(String::from))
+            .unwrap_or_else(|| self.log_data.clone());
+        let normalized = raw.split_whitespace().collect::<Vec<&str>>().join(" ");
+
         let mut hasher = Sha256::new();
-        hasher.update(self.log_data.as_bytes());
+        hasher.update(self.account_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(self.host_id.as_bytes());
+        hasher.update([0]);
+        hasher.update(normalized.as_bytes());
         format!("{:x}", hasher.finalize())
     }
 }
 
-pub fn create_log(log: &Log) -> Result<Option<Log>, LogError> {
-    log.validate()?;
-    let hash = log.calculate_hash();
-    let conn = establish_connection()?;
+// Per-batch outcome of a bulk insert
+#[derive(Debug, Default)]
+pub struct BatchInsertResult {
+    pub inserted: Vec<Log>,
+    pub duplicates: usize,
+}
 
-    // Check for existing log with the same hash
-    let mut stmt = conn.prepare("SELECT COUNT(*) FROM logs WHERE hash = ?1")?;
-    let count: i64 = stmt.query_row(params![&hash], |row| row.get(0))?;
+// Seconds within which a repeated line is folded into the stored log (`DEDUP_WINDOW_SECS`,
+// default 300). Zero stores every line
+fn dedup_window_secs() -> i64 {
+    env::var("DEDUP_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+        .unwrap_or(DEFAULT_DEDUP_WINDOW_SECS)
+}
 
-    if count > 0 {
-        return Ok(None); // Duplicate log
+// Insert a whole batch. A line already stored for the same account and host within the
+// dedup window bumps that log's occurrence count instead. Callers run this inside an IMMEDIATE
+// transaction, so concurrent batches for the same account extend the hash chain in turn and a
+// failed batch leaves neither logs nor bumped counts behind
+pub fn create_logs(tx: &Connection, logs: &[Log]) -> Result<BatchInsertResult, LogError> {
+    for log in logs {
+        log.validate()?;
     }
+    let window_secs = dedup_window_secs();
+    let window = format!("-{} seconds", window_secs);
 
-    let mut log_uuid = Uuid::new_v4().to_string();
-    // Check if this UUID already exists
-    let mut id_check = conn.prepare("SELECT COUNT(*) FROM logs WHERE id = ?1")?;
-    let id_count: i64 = id_check.query_row(params![&log_uuid], |row| row.get(0))?;
-    if id_count > 0 {
-        log_uuid = Uuid::new_v4().to_string();
-    }
+    let mut result = BatchInsertResult::default();
 
-    let new_log = Log {
-        id: log_uuid,
-        hash,
-        account_id: log.account_id.clone(),
-        host_id: log.host_id.clone(),
-        timestamp: log.timestamp.clone(),
-        log_data: log.log_data.clone(),
-    };
-
-    conn.execute(
-        "INSERT INTO logs (id, hash, account_id, host_id, timestamp, log_data) 
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![
-            &new_log.id,
-            &new_log.hash,
-            &new_log.account_id,
-            &new_log.host_id,
-            &new_log.timestamp,
-            &new_log.log_data,
-        ],
-    )?;
+    {
+        let mut repeat = tx.prepare(
+            "UPDATE logs SET occurrence_count = occurrence_count + 1, last_seen = CURRENT_TIMESTAMP
+             WHERE id = (
+                 SELECT id FROM logs
+                 WHERE account_id = ?1 AND host_id = ?2 AND hash = ?3
+                   AND created_at >= datetime('now', ?4)
+                 ORDER BY created_at DESC LIMIT 1
+             )"
+        )?;
+        let mut insert = tx.prepare(
+            "INSERT INTO logs (id, hash, account_id, host_id, timestamp, log_data, occurrence_count, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, 1, CURRENT_TIMESTAMP)"
+        )?;
+
+        for log in logs {
+            let hash = log.calculate_hash();
+            if window_secs > 0 && repeat.execute(params![&log.account_id, &log.host_id, &hash, &window])? > 0 {
+                result.duplicates += 1;
+                continue;
+            }
+
+            let new_log = Log {
+                id: Uuid::new_v4().to_string(),
+                hash,
+                account_id: log.account_id.clone(),
+                host_id: log.host_id.clone(),
+                timestamp: log.timestamp.clone(),
+                log_data: log.log_data.clone(),
+                occurrence_count: 1,
+                last_seen: None,
+            };
 
-    Ok(Some(new_log))
+            insert.execute(params![
+                &new_log.id,
+                &new_log.hash,
+                &new_log.account_id,
+                &new_log.host_id,
+                &new_log.timestamp,
+                &new_log.log_data,
+            ])?;
+            result.inserted.push(new_log);
+        }
+    }
+
+    append_to_chain(tx, &result.inserted)?;
+    Ok(result)
 }
 
-pub fn get_query_logs(account_id: &str, eql_query: &str, start_time: Option<String>, end_time: Option<String>,
+pub fn get_query_logs(conn: &Connection, account_id: &str, eql_query: &str, start_time: Option<String>, end_time: Option<String>,
 ) -> Result<Vec<Log>, LogError> {
     let start_time = start_time.unwrap_or("1970-01-01".to_string());
     let end_time = end_time.unwrap_or("9999-12-31".to_string());
-    let logs = QueryExecutor::execute_query(account_id, &start_time, &end_time, eql_query)?;
+    let logs = QueryExecutor::execute_query(conn, account_id, &start_time, &end_time, eql_query)?;
     Ok(logs)
 }
 
-pub fn get_all_logs(account_id: &String) -> Result<Vec<Log>, LogError> {
+pub fn get_all_logs(conn: &Connection, account_id: &String) -> Result<Vec<Log>, LogError> {
     if account_id.is_empty() {
         return Err(LogError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare(
-        "SELECT id, hash, account_id, host_id, timestamp, log_data 
+        "SELECT id, hash, account_id, host_id, timestamp, log_data, occurrence_count, last_seen
          FROM logs WHERE account_id = ?1"
     )?;
 
@@ -141,9 +192,91 @@ pub fn get_all_logs(account_id: &String) -> Result<Vec<Log>, LogError> {
             host_id: row.get(3)?,
             timestamp: row.get(4)?,
             log_data: row.get(5)?,
+            occurrence_count: row.get(6)?,
+            last_seen: row.get(7)?,
         })
     })?;
 
     let logs: Result<Vec<Log>, SqliteError> = logs_iter.collect();
     Ok(logs?)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{age_logs, host, organization, store_logs, test_pool};
+
+    // The occurrence count of every stored log with this raw line
+    fn occurrences(conn: &Connection, organization_id: &str, raw: &str) -> Vec<i64> {
+        get_all_logs(conn, &organization_id.to_string()).unwrap().into_iter()
+            .filter(|log| log.log_data.contains(raw))
+            .map(|log| log.occurrence_count)
+            .collect()
+    }
+
+    #[test]
+    fn repeats_within_the_window_bump_one_log() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+
+        assert_eq!(store_logs(&mut conn, &organization_id, &host_id, &["disk full", "disk full"]).len(), 1);
+        // Whitespace doesn't make a line new
+        assert!(store_logs(&mut conn, &organization_id, &host_id, &["disk   full "]).is_empty());
+        assert_eq!(occurrences(&conn, &organization_id, "disk"), vec![3]);
+    }
+
+    #[test]
+    fn repeats_are_scoped_to_the_host_and_account() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (acme, _) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+        let web = host(&conn, &acme, "web");
+        let db = host(&conn, &acme, "db");
+        let other = host(&conn, &globex, "web");
+
+        for (organization_id, host_id) in [(&acme, &web), (&acme, &db), (&globex, &other)] {
+            assert_eq!(store_logs(&mut conn, organization_id, host_id, &["disk full"]).len(), 1);
+        }
+        assert_eq!(occurrences(&conn, &acme, "disk full"), vec![1, 1]);
+        assert_eq!(occurrences(&conn, &globex, "disk full"), vec![1]);
+    }
+
+    #[test]
+    fn a_repeat_after_the_window_is_stored_again() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let first = store_logs(&mut conn, &organization_id, &host_id, &["disk full"]);
+        age_logs(&conn, &first, 1);
+
+        let second = store_logs(&mut conn, &organization_id, &host_id, &["disk full"]);
+        assert_eq!(second.len(), 1);
+        assert_eq!(first[0].hash, second[0].hash);
+        assert_eq!(occurrences(&conn, &organization_id, "disk full"), vec![1, 1]);
+    }
+
+    #[test]
+    fn a_failed_batch_leaves_no_counts_behind() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let stored = store_logs(&mut conn, &organization_id, &host_id, &["disk full"]);
+
+        conn.execute_batch(
+            "CREATE TRIGGER refuse_boom BEFORE INSERT ON logs WHEN json_extract(new.log_data, '$.raw') = 'boom'
+             BEGIN SELECT RAISE(ABORT, 'refused'); END;"
+        ).unwrap();
+
+        // The repeat is counted before the second line fails
+        let boom = Log { log_data: r#"{"raw":"boom"}"#.to_string(), ..stored[0].clone() };
+        let tx = conn.transaction().unwrap();
+        assert!(matches!(create_logs(&tx, &[stored[0].clone(), boom]), Err(LogError::DatabaseError(_))));
+        drop(tx);
+        assert_eq!(occurrences(&conn, &organization_id, "disk full"), vec![1]);
+    }
+}
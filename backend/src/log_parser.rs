@@ -24,6 +24,12 @@ impl std::fmt::Display for ParseLogError {
 
 impl std::error::Error for ParseLogError {}
 
+impl From<rusqlite::Error> for ParseLogError {
+    fn from(err: rusqlite::Error) -> Self {
+        ParseLogError::DatabaseError(err.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NormalizedLog {
     pub timestamp: Option<String>,
@@ -55,9 +61,9 @@ fn detect_format(log: &str) -> LogFormat {
     let cleaned = clean_log(log);
     if cleaned.starts_with("CEF:") {
         LogFormat::Cef
-    } else if cleaned.starts_with('<') && cleaned.contains('>') && cleaned[1..].chars().next().unwrap().is_digit(10) {
+    } else if cleaned.starts_with('<') && cleaned.contains('>') && cleaned[1..].chars().next().unwrap().is_ascii_digit() {
         // Check for <priority> followed by timestamp-like pattern
-        let after_priority = cleaned.splitn(2, '>').nth(1).unwrap_or("");
+        let after_priority = cleaned.split_once('>').map_or("", |(_, rest)| rest);
         if after_priority.len() > 11 && after_priority[3..6].contains(" ") && after_priority[6..11].contains(":") {
             LogFormat::Syslog
         } else {
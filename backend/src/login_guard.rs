@@ -0,0 +1,487 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, TransactionBehavior, params};
+use serde::Serialize;
+use std::collections::HashMap;
+use chrono::Utc;
+use std::env;
+use log::warn;
+use crate::security_event::{SecurityEventError, record_security_event};
+use std::fmt;
+
+// Failures allowed before backoff starts
+const FREE_ATTEMPTS: i64 = 3;
+const MAX_BACKOFF_SECS: i64 = 300;
+const ACCOUNT_LOCKOUT_THRESHOLD: i64 = 10;
+// Higher than the account threshold so a shared office address isn't locked out by one user
+const IP_LOCKOUT_THRESHOLD: i64 = 50;
+// A six digit code falls to guessing long before the password limits would stop it
+const MFA_LOCKOUT_THRESHOLD: i64 = 5;
+const DEFAULT_LOCKOUT_SECS: i64 = 900;
+// Failures older than this are forgotten
+const FAILURE_WINDOW_SECS: i64 = 3600;
+
+#[derive(Debug)]
+pub enum LoginGuardError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for LoginGuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginGuardError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            LoginGuardError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for LoginGuardError {
+    fn from(err: SqliteError) -> Self {
+        LoginGuardError::DatabaseError(err)
+    }
+}
+
+impl From<SecurityEventError> for LoginGuardError {
+    fn from(err: SecurityEventError) -> Self {
+        match err {
+            SecurityEventError::DatabaseError(err) => LoginGuardError::DatabaseError(err),
+            SecurityEventError::ValidationError(msg) => LoginGuardError::ValidationError(msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AttemptScope {
+    Account,
+    Ip,
+    // Wrong second-factor codes, per account ID
+    Mfa,
+}
+
+impl AttemptScope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AttemptScope::Account => "account",
+            AttemptScope::Ip => "ip",
+            AttemptScope::Mfa => "mfa",
+        }
+    }
+
+    fn lockout_threshold(&self) -> i64 {
+        match self {
+            AttemptScope::Account => ACCOUNT_LOCKOUT_THRESHOLD,
+            AttemptScope::Ip => IP_LOCKOUT_THRESHOLD,
+            AttemptScope::Mfa => MFA_LOCKOUT_THRESHOLD,
+        }
+    }
+}
+
+// Why a login is refused before the password is even checked
+#[derive(Debug, Serialize)]
+pub struct LoginBlock {
+    pub retry_after: i64,
+    // A lockout, rather than a short backoff delay
+    pub locked: bool,
+}
+
+#[derive(Debug)]
+struct FailureCount {
+    failures: i64,
+    locked: bool,
+}
+
+// Lockout length in seconds (`LOGIN_LOCKOUT_SECS`, default 15 minutes)
+fn lockout_secs() -> i64 {
+    env::var("LOGIN_LOCKOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_LOCKOUT_SECS)
+}
+
+// Delay after a failure: none for the first few, then doubling from one second
+fn backoff_secs(failures: i64) -> i64 {
+    if failures <= FREE_ATTEMPTS {
+        return 0;
+    }
+    let exponent = (failures - FREE_ATTEMPTS - 1).min(16) as u32;
+    (1i64 << exponent).min(MAX_BACKOFF_SECS)
+}
+
+fn blocked_for(conn: &Connection, scope: AttemptScope, subject: &str, now: i64) -> Result<Option<LoginBlock>, LoginGuardError> {
+    let row: Option<(Option<i64>, bool)> = conn.query_row(
+        "SELECT blocked_until, locked FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![scope.as_str(), subject],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    Ok(match row {
+        Some((Some(until), locked)) if until > now => Some(LoginBlock { retry_after: until - now, locked }),
+        _ => None,
+    })
+}
+
+fn count_failure(conn: &Connection, scope: AttemptScope, subject: &str, now: i64) -> Result<FailureCount, LoginGuardError> {
+    let previous: Option<(i64, i64)> = conn.query_row(
+        "SELECT failures, last_failure_at FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![scope.as_str(), subject],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    let failures = match previous {
+        Some((failures, last_failure_at)) if now - last_failure_at <= FAILURE_WINDOW_SECS => failures + 1,
+        _ => 1,
+    };
+    // Past the threshold every further failure locks again, since attempts are refused while locked
+    let locked = failures >= scope.lockout_threshold();
+    let delay = if locked { lockout_secs() } else { backoff_secs(failures) };
+    let blocked_until = (delay > 0).then_some(now + delay);
+
+    conn.execute(
+        "INSERT INTO login_attempts (scope, subject, failures, last_failure_at, blocked_until, locked)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT(scope, subject) DO UPDATE SET failures = excluded.failures, last_failure_at = excluded.last_failure_at,
+            blocked_until = excluded.blocked_until, locked = excluded.locked",
+        params![scope.as_str(), subject, failures, now, blocked_until, locked],
+    )?;
+
+    Ok(FailureCount { failures, locked })
+}
+
+// Take back a failure counted for an attempt that turned out not to be one. The wait is worked
+// out again from the remaining count
+fn uncount_failure(conn: &Connection, scope: AttemptScope, subject: &str) -> Result<(), LoginGuardError> {
+    let previous: Option<(i64, i64)> = conn.query_row(
+        "SELECT failures, last_failure_at FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![scope.as_str(), subject],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    let Some((failures, last_failure_at)) = previous else {
+        return Ok(());
+    };
+
+    let failures = failures - 1;
+    if failures <= 0 {
+        conn.execute(
+            "DELETE FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+            params![scope.as_str(), subject],
+        )?;
+        return Ok(());
+    }
+    let locked = failures >= scope.lockout_threshold();
+    let delay = if locked { lockout_secs() } else { backoff_secs(failures) };
+    let blocked_until = (delay > 0).then_some(last_failure_at + delay);
+
+    conn.execute(
+        "UPDATE login_attempts SET failures = ?3, blocked_until = ?4, locked = ?5 WHERE scope = ?1 AND subject = ?2",
+        params![scope.as_str(), subject, failures, blocked_until, locked],
+    )?;
+    Ok(())
+}
+
+fn failure_count(conn: &Connection, scope: AttemptScope, subject: &str) -> Result<FailureCount, LoginGuardError> {
+    let count = conn.query_row(
+        "SELECT failures, locked FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![scope.as_str(), subject],
+        |row| Ok(FailureCount { failures: row.get(0)?, locked: row.get(1)? }),
+    ).optional()?;
+    Ok(count.unwrap_or(FailureCount { failures: 0, locked: false }))
+}
+
+fn organization_for(conn: &Connection, name: &str) -> Result<Option<String>, LoginGuardError> {
+    let organization_id = conn.query_row(
+        "SELECT organization_id FROM accounts WHERE name = ?1",
+        params![name],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(organization_id)
+}
+
+// Refuse a login while the account name or the client address is backing off or locked out.
+// The longer of the two waits wins
+pub fn check_login(conn: &Connection, name: &str, ip: &str) -> Result<Option<LoginBlock>, LoginGuardError> {
+    let now = Utc::now().timestamp();
+    let account = blocked_for(conn, AttemptScope::Account, name, now)?;
+    let address = blocked_for(conn, AttemptScope::Ip, ip, now)?;
+
+    Ok(match (account, address) {
+        (Some(a), Some(b)) => Some(if a.retry_after >= b.retry_after { a } else { b }),
+        (a, b) => a.or(b),
+    })
+}
+
+// Start a login attempt. Refused while the name or address is blocked; otherwise the attempt is
+// counted as a failure against both before the password is checked, in one IMMEDIATE transaction,
+// so concurrent guesses can't all slip in under the limit. Settle it with `record_failure`, or
+// `release_attempt` when it didn't fail
+pub fn reserve_login(conn: &mut Connection, name: &str, ip: &str) -> Result<Option<LoginBlock>, LoginGuardError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if let Some(block) = check_login(&tx, name, ip)? {
+        return Ok(Some(block));
+    }
+
+    let now = Utc::now().timestamp();
+    // Drop counters that have run out so guessed names don't pile up
+    tx.execute(
+        "DELETE FROM login_attempts WHERE last_failure_at < ?1 AND (blocked_until IS NULL OR blocked_until < ?2)",
+        params![now - FAILURE_WINDOW_SECS, now],
+    )?;
+    count_failure(&tx, AttemptScope::Account, name, now)?;
+    count_failure(&tx, AttemptScope::Ip, ip, now)?;
+    tx.commit()?;
+    Ok(None)
+}
+
+// Take back the failure `reserve_login` counted, for an attempt that succeeded or couldn't be
+// checked at all
+pub fn release_attempt(conn: &mut Connection, name: &str, ip: &str) -> Result<(), LoginGuardError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    uncount_failure(&tx, AttemptScope::Account, name)?;
+    uncount_failure(&tx, AttemptScope::Ip, ip)?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Settle a reserved attempt as failed. When the name belongs to an account, the failure and
+// any lockout are recorded as security events for its organization
+pub fn record_failure(conn: &Connection, name: &str, ip: &str) -> Result<(), LoginGuardError> {
+    let account = failure_count(conn, AttemptScope::Account, name)?;
+    let address = failure_count(conn, AttemptScope::Ip, ip)?;
+
+    let Some(organization_id) = organization_for(conn, name)? else {
+        warn!("Failed login for unknown account from {} ({} failures from this address)", ip, address.failures);
+        return Ok(());
+    };
+
+    let extensions = HashMap::from([
+        ("account".to_string(), name.to_string()),
+        ("failures".to_string(), account.failures.to_string()),
+        ("ip_failures".to_string(), address.failures.to_string()),
+    ]);
+    record_security_event(
+        conn,
+        &organization_id,
+        "login_failure",
+        Some(ip),
+        format!("Failed login for {} from {}", name, ip),
+        extensions.clone(),
+    )?;
+    if account.locked {
+        warn!("Account {} locked after {} failed logins", name, account.failures);
+        record_security_event(
+            conn,
+            &organization_id,
+            "account_locked",
+            Some(ip),
+            format!("Account {} locked after {} failed logins", name, account.failures),
+            extensions.clone(),
+        )?;
+    }
+    if address.locked {
+        warn!("Address {} locked after {} failed logins", ip, address.failures);
+        record_security_event(
+            conn,
+            &organization_id,
+            "ip_locked",
+            Some(ip),
+            format!("Address {} locked after {} failed logins", ip, address.failures),
+            extensions,
+        )?;
+    }
+    Ok(())
+}
+
+// A successful login clears the account's counter. The address keeps its count so one valid
+// login can't reset a password spraying run
+pub fn record_success(conn: &Connection, name: &str) -> Result<(), LoginGuardError> {
+    conn.execute(
+        "DELETE FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![AttemptScope::Account.as_str(), name],
+    )?;
+    Ok(())
+}
+
+// Start a second-factor check for an account, counted the same way as `reserve_login`. Settle
+// it with `record_mfa_failure`, `record_mfa_success` or `release_mfa`
+pub fn reserve_mfa(conn: &mut Connection, account_id: &str) -> Result<Option<LoginBlock>, LoginGuardError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let now = Utc::now().timestamp();
+    if let Some(block) = blocked_for(&tx, AttemptScope::Mfa, account_id, now)? {
+        return Ok(Some(block));
+    }
+    count_failure(&tx, AttemptScope::Mfa, account_id, now)?;
+    tx.commit()?;
+    Ok(None)
+}
+
+// Take back a reserved second-factor check that couldn't be made
+pub fn release_mfa(conn: &Connection, account_id: &str) -> Result<(), LoginGuardError> {
+    uncount_failure(conn, AttemptScope::Mfa, account_id)
+}
+
+pub fn record_mfa_success(conn: &Connection, account_id: &str) -> Result<(), LoginGuardError> {
+    conn.execute(
+        "DELETE FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![AttemptScope::Mfa.as_str(), account_id],
+    )?;
+    Ok(())
+}
+
+// Settle a reserved second-factor check as failed, recording it as a security event. Returns
+// whether the account is now locked out of MFA verification
+pub fn record_mfa_failure(conn: &Connection, organization_id: &str, account_id: &str, ip: &str) -> Result<bool, LoginGuardError> {
+    let count = failure_count(conn, AttemptScope::Mfa, account_id)?;
+    let extensions = HashMap::from([
+        ("account_id".to_string(), account_id.to_string()),
+        ("failures".to_string(), count.failures.to_string()),
+    ]);
+    record_security_event(
+        conn,
+        organization_id,
+        "mfa_failure",
+        Some(ip),
+        format!("Wrong MFA code for account {} from {}", account_id, ip),
+        extensions.clone(),
+    )?;
+    if count.locked {
+        warn!("MFA verification for account {} locked after {} wrong codes", account_id, count.failures);
+        record_security_event(
+            conn,
+            organization_id,
+            "mfa_locked",
+            Some(ip),
+            format!("MFA verification for account {} locked after {} wrong codes", account_id, count.failures),
+            extensions,
+        )?;
+    }
+    Ok(count.locked)
+}
+
+// Admin unlock: clears the failures, backoff and lockout of an account, for passwords and MFA codes
+pub fn unlock_account(conn: &Connection, name: &str) -> Result<bool, LoginGuardError> {
+    if name.is_empty() {
+        return Err(LoginGuardError::ValidationError("Account name cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "DELETE FROM login_attempts WHERE scope = ?1 AND subject = ?2",
+        params![AttemptScope::Account.as_str(), name],
+    )?;
+    // The second-factor counter is keyed by account ID
+    let mfa_rows = conn.execute(
+        "DELETE FROM login_attempts WHERE scope = ?1 AND subject = (SELECT id FROM accounts WHERE name = ?2)",
+        params![AttemptScope::Mfa.as_str(), name],
+    )?;
+    Ok(affected_rows + mfa_rows > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{organization, test_pool};
+
+    // Let every backoff delay run out, leaving lockouts in place
+    fn wait_out_backoff(conn: &Connection) {
+        conn.execute("UPDATE login_attempts SET blocked_until = NULL WHERE locked = 0", []).unwrap();
+    }
+
+    // A wrong password, the way the login handler settles it
+    fn fail(conn: &mut Connection, name: &str, ip: &str) -> Option<LoginBlock> {
+        if let Some(block) = reserve_login(conn, name, ip).unwrap() {
+            return Some(block);
+        }
+        record_failure(conn, name, ip).unwrap();
+        None
+    }
+
+    fn security_events(conn: &Connection, event_type: &str) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM security_events WHERE event_type = ?1", params![event_type], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_free_attempts() {
+        let delays: Vec<i64> = (1..=7).map(backoff_secs).collect();
+        assert_eq!(delays, vec![0, 0, 0, 1, 2, 4, 8]);
+        assert_eq!(backoff_secs(40), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn failures_back_off_then_lock_the_account() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        organization(&conn, "acme");
+
+        for _ in 0..FREE_ATTEMPTS {
+            assert!(fail(&mut conn, "acme", "10.1.1.1").is_none());
+        }
+        assert!(fail(&mut conn, "acme", "10.1.1.1").is_none());
+        let delay: i64 = conn.query_row(
+            "SELECT blocked_until - last_failure_at FROM login_attempts WHERE scope = 'account' AND subject = 'acme'",
+            [],
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(delay, 1);
+
+        while failure_count(&conn, AttemptScope::Account, "acme").unwrap().failures < ACCOUNT_LOCKOUT_THRESHOLD {
+            wait_out_backoff(&conn);
+            assert!(fail(&mut conn, "acme", "10.1.1.1").is_none());
+        }
+        wait_out_backoff(&conn);
+        let block = fail(&mut conn, "acme", "10.1.1.2").unwrap();
+        assert!(block.locked);
+        assert!(block.retry_after > MAX_BACKOFF_SECS);
+        // Refused attempts aren't counted
+        assert_eq!(failure_count(&conn, AttemptScope::Account, "acme").unwrap().failures, ACCOUNT_LOCKOUT_THRESHOLD);
+        assert_eq!(security_events(&conn, "login_failure"), ACCOUNT_LOCKOUT_THRESHOLD);
+        assert_eq!(security_events(&conn, "account_locked"), 1);
+
+        assert!(unlock_account(&conn, "acme").unwrap());
+        assert!(reserve_login(&mut conn, "acme", "10.1.1.2").unwrap().is_none());
+        assert!(matches!(unlock_account(&conn, ""), Err(LoginGuardError::ValidationError(_))));
+    }
+
+    #[test]
+    fn released_attempts_leave_no_trace() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        assert!(fail(&mut conn, "acme", "10.1.1.1").is_none());
+
+        for _ in 0..10 {
+            assert!(reserve_login(&mut conn, "acme", "10.1.1.1").unwrap().is_none());
+            release_attempt(&mut conn, "acme", "10.1.1.1").unwrap();
+        }
+        assert_eq!(failure_count(&conn, AttemptScope::Account, "acme").unwrap().failures, 1);
+        assert_eq!(failure_count(&conn, AttemptScope::Ip, "10.1.1.1").unwrap().failures, 1);
+
+        // Unknown names are counted too, but nothing is recorded for an organization
+        assert_eq!(security_events(&conn, "login_failure"), 0);
+    }
+
+    #[test]
+    fn a_successful_login_clears_the_account_but_not_the_address() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        for _ in 0..3 {
+            assert!(fail(&mut conn, "acme", "10.1.1.1").is_none());
+        }
+        assert!(reserve_login(&mut conn, "acme", "10.1.1.1").unwrap().is_none());
+        release_attempt(&mut conn, "acme", "10.1.1.1").unwrap();
+        record_success(&conn, "acme").unwrap();
+
+        assert_eq!(failure_count(&conn, AttemptScope::Account, "acme").unwrap().failures, 0);
+        assert_eq!(failure_count(&conn, AttemptScope::Ip, "10.1.1.1").unwrap().failures, 3);
+    }
+
+    #[test]
+    fn one_address_guessing_many_names_is_locked() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        for n in 0..IP_LOCKOUT_THRESHOLD {
+            wait_out_backoff(&conn);
+            assert!(fail(&mut conn, &format!("user{}", n), "10.1.1.1").is_none());
+        }
+
+        let block = reserve_login(&mut conn, "someone-else", "10.1.1.1").unwrap().unwrap();
+        assert!(block.locked);
+        assert!(reserve_login(&mut conn, "someone-else", "10.1.1.2").unwrap().is_none());
+    }
+}
@@ -17,11 +17,43 @@ mod handlers;
 mod cases;
 mod case_comments;
 mod log_parser;
+mod ingest_job;
+mod dead_letter;
+mod migrations;
+mod retention;
+mod archive;
+mod integrity;
+mod rbac;
+mod organization;
+mod api_token;
+mod mfa;
+mod security_event;
+mod login_guard;
+mod session_store;
+mod ldap_auth;
+mod oidc;
+mod password;
+mod audit;
+#[cfg(test)]
+mod test_support;
 
-use crate::collector::LogCollector;
+use crate::collector::{LogCollector, spawn_workers};
+use crate::database::{DbPool, build_pool, create_pool, run};
+use crate::integrity::spawn_digest_job;
+use crate::retention::{enable_incremental_vacuum, spawn_retention_job};
+use crate::agent::spawn_offline_check;
+use crate::migrations::{apply_pending, current_version, pending_migrations};
+use crate::message_queue::MessageQueue;
+use crate::rbac::{Permission, RequirePermission};
 use crate::handlers::{
     index,
     import_log_handler,
+    get_ingest_job_handler,
+    get_dead_letters_handler,
+    reparse_dead_letter_handler,
+    reparse_all_dead_letters_handler,
+    delete_dead_letter_handler,
+    purge_dead_letters_handler,
     get_query_logs_handler,
     get_alert_handler,
     get_all_alerts_handler,
@@ -50,6 +82,9 @@ use crate::handlers::{
     register_agent_handler, 
     agent_upload_handler,
     agent_heartbeat_handler,
+    rotate_agent_key_handler,
+    revoke_agent_handler,
+    get_agents_handler,
     create_case_handler,
     get_case_handler,
     get_cases_by_account_handler,
@@ -61,24 +96,99 @@ use crate::handlers::{
     get_case_comments_handler,
     update_comment_handler,
     delete_comment_handler,
-    get_logs_wt_cases_handler
+    get_logs_wt_cases_handler,
+    get_migrations_handler,
+    create_retention_policy_handler,
+    get_retention_policy_handler,
+    get_retention_policies_handler,
+    edit_retention_policy_handler,
+    delete_retention_policy_handler,
+    run_retention_handler,
+    get_retention_runs_handler,
+    get_archives_handler,
+    restore_archive_handler,
+    verify_log_chain_handler,
+    get_log_digests_handler,
+    get_roles_handler,
+    get_permissions_handler,
+    create_role_handler,
+    edit_role_handler,
+    delete_role_handler,
+    get_organization_handler,
+    get_members_handler,
+    create_invitation_handler,
+    get_invitations_handler,
+    revoke_invitation_handler,
+    accept_invitation_handler,
+    create_api_token_handler,
+    get_api_tokens_handler,
+    revoke_api_token_handler,
+    get_mfa_status_handler,
+    enroll_mfa_handler,
+    confirm_mfa_handler,
+    verify_mfa_handler,
+    disable_mfa_handler,
+    reset_mfa_handler,
+    require_mfa_handler,
+    unlock_account_handler,
+    get_security_events_handler,
+    get_sessions_handler,
+    revoke_session_handler,
+    revoke_other_sessions_handler,
+    oidc_login_handler,
+    oidc_callback_handler,
+    change_password_handler,
+    issue_password_reset_handler,
+    redeem_password_reset_handler,
+    get_audit_log_handler,
+    export_audit_log_handler
 };
 use crate::csrf::CsrfMiddleware;
-use actix_session::{SessionMiddleware, storage::CookieSessionStore, config::PersistentSession};
+use actix_session::{SessionMiddleware, config::PersistentSession};
+use session_store::SqliteSessionStore;
 use actix_web::{web, cookie::time::Duration, cookie::Key, App, HttpServer};
 use actix_multipart::form::tempfile::TempFileConfig;
 use actix_cors::Cors;
 use dotenvy::dotenv;
-use env_logger;
 use std::env;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let collector = web::Data::new(LogCollector::new());
     dotenv().ok();
 
     env_logger::init();
 
+    // `backend migrate [status|up]` manages the schema without starting the server
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        return migrate_command(args.get(2).map(String::as_str).unwrap_or("status"));
+    }
+
+    let pool: DbPool = create_pool().expect("Failed to create database pool");
+    let queue = MessageQueue::new();
+    let collector = web::Data::new(LogCollector::new());
+
+    // Replay batches that were in flight when the server last stopped
+    let replay_queue = queue.clone();
+    if let Err(e) = run(&pool, move |conn| replay_queue.replay(conn)).await {
+        ::log::error!("Failed to replay message queue: {}", e);
+    }
+
+    let worker_count = env::var("INGEST_WORKERS")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(4);
+    spawn_workers(pool.clone(), queue.clone(), collector.clone(), worker_count);
+
+    // Purged pages are reclaimed incrementally, the mode applies from the next full VACUUM
+    if let Err(e) = run(&pool, |conn| enable_incremental_vacuum(conn)).await {
+        ::log::error!("Failed to enable incremental vacuum: {}", e);
+    }
+    spawn_retention_job(pool.clone());
+    spawn_digest_job(pool.clone());
+    spawn_offline_check(pool.clone());
+
     let secret_key = env::var("SESSION_SECRET_KEY").expect("SESSION_SECRET_KEY must be set");
     let cookie_key = Key::from(secret_key.as_bytes());
 
@@ -96,11 +206,13 @@ async fn main() -> std::io::Result<()> {
 
     HttpServer::new(move || {
         App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(queue.clone()))
             .app_data(collector.clone())
             .app_data(csrf.clone())
             .app_data(TempFileConfig::default().directory("./tmp"))
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), cookie_key.clone())
+                SessionMiddleware::builder(SqliteSessionStore::new(pool.clone()), cookie_key.clone())
                     .cookie_secure(false) // Set to true in PRODUCTION
                     .cookie_http_only(true)
                     .cookie_same_site(actix_web::cookie::SameSite::Lax)
@@ -123,6 +235,17 @@ async fn main() -> std::io::Result<()> {
                     .route("/", web::get().to(index))
                     .route("/check-auth", web::get().to(verify_session_handler))
                     .route("/logout", web::post().to(logout_handler))
+                    .service(
+                        web::scope("/admin")
+                            .wrap(RequirePermission::new(Permission::ReadSystem, Permission::ReadSystem))
+                            .route("/migrations", web::get().to(get_migrations_handler))
+                    )
+                    .service(
+                        web::scope("/audit")
+                            .wrap(RequirePermission::new(Permission::ReadAudit, Permission::ReadAudit))
+                            .route("", web::get().to(get_audit_log_handler))
+                            .route("/export", web::get().to(export_audit_log_handler))
+                    )
                     .service(
                         web::scope("/csrf")
                             .route("/", web::get().to(get_csrf_handler))
@@ -130,12 +253,21 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         web::scope("/log")
+                            .wrap(RequirePermission::new(Permission::ReadLogs, Permission::IngestLogs))
                             .route("/import", web::post().to(import_log_handler))
                             .route("/all/{account_id}", web::get().to(get_logs_handler))
                             .route("/filter", web::get().to(get_query_logs_handler))
+                            .route("/security-events", web::get().to(get_security_events_handler))
+                            .route("/jobs/{job_id}", web::get().to(get_ingest_job_handler))
+                            .route("/dead-letter/all/{account_id}", web::get().to(get_dead_letters_handler))
+                            .route("/dead-letter/all/{account_id}", web::delete().to(purge_dead_letters_handler))
+                            .route("/dead-letter/reparse/{account_id}", web::post().to(reparse_all_dead_letters_handler))
+                            .route("/dead-letter/{letter_id}/reparse", web::post().to(reparse_dead_letter_handler))
+                            .route("/dead-letter/{letter_id}", web::delete().to(delete_dead_letter_handler))
                     )
                     .service(
                         web::scope("/alert")
+                            .wrap(RequirePermission::new(Permission::ReadAlerts, Permission::ManageAlerts))
                             .route("/{alert_id}", web::get().to(get_alert_handler))
                             .route("/all/{account_id}", web::get().to(get_all_alerts_handler))
                             .route("/{alert_id}", web::delete().to(delete_alert_handler))
@@ -143,6 +275,7 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         web::scope("/host")
+                            .wrap(RequirePermission::new(Permission::ReadHosts, Permission::ManageHosts))
                             .route("/{account_id}", web::post().to(create_host_handler))
                             .route("/{host_id}", web::get().to(get_host_handler))
                             .route("/all/{account_id}", web::get().to(get_all_hosts_handler))
@@ -151,6 +284,7 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         web::scope("/rule")
+                            .wrap(RequirePermission::new(Permission::ReadRules, Permission::ManageRules))
                             .route("/{account_id}", web::post().to(create_rule_handler))
                             .route("/{rule_id}", web::get().to(get_rule_handler))
                             .route("/all/{account_id}", web::get().to(get_all_rules_handler))
@@ -161,18 +295,99 @@ async fn main() -> std::io::Result<()> {
                         web::scope("/account")
                             .route("/", web::post().to(create_account_handler))
                             .route("/login", web::post().to(login_account_handler))
+                            .route("/join", web::post().to(accept_invitation_handler))
+                            .route("/oidc/login", web::get().to(oidc_login_handler))
+                            .route("/oidc/callback", web::get().to(oidc_callback_handler))
+                            .route("/password", web::post().to(change_password_handler))
+                            .route("/password-reset", web::post().to(redeem_password_reset_handler))
+                            .route("/sessions", web::get().to(get_sessions_handler))
+                            .route("/sessions", web::delete().to(revoke_other_sessions_handler))
+                            .route("/sessions/{session_id}", web::delete().to(revoke_session_handler))
+                            .route("/mfa", web::get().to(get_mfa_status_handler))
+                            .route("/mfa/enroll", web::post().to(enroll_mfa_handler))
+                            .route("/mfa/confirm", web::post().to(confirm_mfa_handler))
+                            .route("/mfa/verify", web::post().to(verify_mfa_handler))
+                            .route("/mfa/disable", web::post().to(disable_mfa_handler))
+                            .route("/{account_id}/mfa", web::delete().to(reset_mfa_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
+                            .route("/{account_id}/lockout", web::delete().to(unlock_account_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
+                            .route("/{account_id}/password-reset", web::post().to(issue_password_reset_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
                             .route("/{account_id}", web::get().to(get_account_handler))
-                            .route("/{account_id}", web::put().to(edit_account_handler))
-                            .route("/{account_id}", web::delete().to(delete_account_handler))
+                            .route("/{account_id}", web::put().to(edit_account_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
+                            .route("/{account_id}", web::delete().to(delete_account_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
+                    )
+                    .service(
+                        web::scope("/organization")
+                            .route("/", web::get().to(get_organization_handler))
+                            .route("/members", web::get().to(get_members_handler))
+                            .route("/mfa", web::put().to(require_mfa_handler)
+                                .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers)))
+                            .service(
+                                web::scope("/invitations")
+                                    .wrap(RequirePermission::new(Permission::ManageUsers, Permission::ManageUsers))
+                                    .route("", web::get().to(get_invitations_handler))
+                                    .route("", web::post().to(create_invitation_handler))
+                                    .route("/{invitation_id}", web::delete().to(revoke_invitation_handler))
+                            )
+                    )
+                    .service(
+                        web::scope("/token")
+                            .route("/", web::post().to(create_api_token_handler))
+                            .route("/all", web::get().to(get_api_tokens_handler))
+                            .route("/{token_id}", web::delete().to(revoke_api_token_handler))
+                    )
+                    .service(
+                        web::scope("/role")
+                            .wrap(RequirePermission::new(Permission::ReadSystem, Permission::ManageUsers))
+                            .route("/all", web::get().to(get_roles_handler))
+                            .route("/permissions", web::get().to(get_permissions_handler))
+                            .route("/", web::post().to(create_role_handler))
+                            .route("/{name}", web::put().to(edit_role_handler))
+                            .route("/{name}", web::delete().to(delete_role_handler))
                     )
                     .service(
                         web::scope("/agent")
-                            .route("/register", web::post().to(register_agent_handler))
+                            .route("/register", web::post().to(register_agent_handler)
+                                .wrap(RequirePermission::new(Permission::ManageHosts, Permission::ManageHosts)))
                             .route("/upload", web::post().to(agent_upload_handler))
                             .route("/heartbeat", web::post().to(agent_heartbeat_handler))
+                            .route("/all", web::get().to(get_agents_handler)
+                                .wrap(RequirePermission::new(Permission::ReadHosts, Permission::ReadHosts)))
+                            .route("/{agent_id}/key", web::post().to(rotate_agent_key_handler)
+                                .wrap(RequirePermission::new(Permission::ManageHosts, Permission::ManageHosts)))
+                            .route("/{agent_id}", web::delete().to(revoke_agent_handler)
+                                .wrap(RequirePermission::new(Permission::ManageHosts, Permission::ManageHosts)))
+                    )
+                    .service(
+                        web::scope("/retention")
+                            .wrap(RequirePermission::new(Permission::ReadRetention, Permission::ManageRetention))
+                            .route("/{account_id}", web::post().to(create_retention_policy_handler))
+                            .route("/{policy_id}", web::get().to(get_retention_policy_handler))
+                            .route("/all/{account_id}", web::get().to(get_retention_policies_handler))
+                            .route("/{policy_id}", web::put().to(edit_retention_policy_handler))
+                            .route("/{policy_id}", web::delete().to(delete_retention_policy_handler))
+                            .route("/run/{account_id}", web::post().to(run_retention_handler))
+                            .route("/runs/{account_id}", web::get().to(get_retention_runs_handler))
+                    )
+                    .service(
+                        web::scope("/archive")
+                            .wrap(RequirePermission::new(Permission::ReadRetention, Permission::ManageRetention))
+                            .route("/all/{account_id}", web::get().to(get_archives_handler))
+                            .route("/restore/{account_id}", web::post().to(restore_archive_handler))
+                    )
+                    .service(
+                        web::scope("/integrity")
+                            .wrap(RequirePermission::new(Permission::ReadLogs, Permission::ReadLogs))
+                            .route("/verify/{account_id}", web::get().to(verify_log_chain_handler))
+                            .route("/digests/{account_id}", web::get().to(get_log_digests_handler))
                     )
                     .service(
                         web::scope("/case")
+                            .wrap(RequirePermission::new(Permission::ReadCases, Permission::ManageCases))
                             .route("/{account_id}", web::post().to(create_case_handler))
                             .route("/{case_id}", web::get().to(get_case_handler))
                             .route("/all/{account_id}", web::get().to(get_cases_by_account_handler))
@@ -191,4 +406,32 @@ async fn main() -> std::io::Result<()> {
     .bind(("127.0.0.1", 4200))?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+fn migrate_command(command: &str) -> std::io::Result<()> {
+    let pool = build_pool().map_err(std::io::Error::other)?;
+    let mut conn = pool.get().map_err(std::io::Error::other)?;
+
+    match command {
+        "status" => {
+            let version = current_version(&conn).map_err(|e| std::io::Error::other(e.to_string()))?;
+            let pending = pending_migrations(&conn).map_err(|e| std::io::Error::other(e.to_string()))?;
+            println!("Current schema version: {}", version);
+            if pending.is_empty() {
+                println!("No pending migrations");
+            }
+            for migration in pending {
+                println!("Pending: {} {}", migration.version, migration.description);
+            }
+        }
+        "up" => {
+            let applied = apply_pending(&mut conn).map_err(|e| std::io::Error::other(e.to_string()))?;
+            println!("Applied {} migrations", applied);
+        }
+        other => {
+            eprintln!("Unknown migrate command: {} (expected status or up)", other);
+            std::process::exit(2);
+        }
+    }
+    Ok(())
+}
@@ -1,28 +1,238 @@
-use std::collections::VecDeque;
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
 use crate::batch_maker::Batch;
-use tokio::sync::Mutex;
-use std::sync::Arc;
+use log::{info, warn};
+use std::env;
+use std::fmt;
 
+const DEFAULT_MAX_BATCHES: i64 = 10_000;
+const DEFAULT_MAX_ATTEMPTS: i64 = 5;
+
+#[derive(Debug)]
+pub enum QueueError {
+    DatabaseError(SqliteError),
+    SerializationError(String),
+    QueueFull,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            QueueError::SerializationError(msg) => write!(f, "Serialization error: {}", msg),
+            QueueError::QueueFull => write!(f, "Ingestion queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+impl From<SqliteError> for QueueError {
+    fn from(err: SqliteError) -> Self {
+        QueueError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for QueueError {
+    fn from(err: serde_json::Error) -> Self {
+        QueueError::SerializationError(err.to_string())
+    }
+}
+
+// A batch taken off the queue. It stays in the spool until acked
+pub struct QueuedBatch {
+    pub id: i64,
+    // Times the batch has been taken off the queue, including this one
+    pub attempts: i64,
+    pub batch: Batch,
+}
+
+// Durable queue backed by the message_queue table. Batches are only removed
+// once acked, so anything in flight during a crash is replayed on startup
 #[derive(Clone)]
 pub struct MessageQueue {
-    queue: Arc<Mutex<VecDeque<Batch>>>,
+    max_batches: i64,
+    max_attempts: i64,
+}
+
+fn env_limit(name: &str, default: i64) -> i64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default)
 }
 
 impl MessageQueue {
     pub fn new() -> Self {
         MessageQueue {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
+            max_batches: env_limit("QUEUE_MAX_BATCHES", DEFAULT_MAX_BATCHES),
+            max_attempts: env_limit("QUEUE_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS),
         }
     }
 
-    pub async fn enqueue(&self, batch: Batch) -> Result<(), String> {
-        let mut queue = self.queue.lock().await;
-        queue.push_back(batch);
+    #[cfg(test)]
+    pub fn with_limits(max_batches: i64, max_attempts: i64) -> Self {
+        MessageQueue { max_batches, max_attempts }
+    }
+
+    // Whether a batch that failed this time has used up its attempts and should be given up on
+    pub fn exhausted(&self, queued: &QueuedBatch) -> bool {
+        queued.attempts >= self.max_attempts
+    }
+
+    // Enqueue all batches or none of them, so a rejected upload leaves nothing behind.
+    // Callers run this inside a transaction and wake the workers once it commits
+    pub fn enqueue_all(&self, conn: &Connection, batches: &[Batch]) -> Result<(), QueueError> {
+        if batches.is_empty() {
+            return Ok(());
+        }
+
+        let depth: i64 = conn.query_row("SELECT COUNT(*) FROM message_queue", [], |row| row.get(0))?;
+        if depth + batches.len() as i64 > self.max_batches {
+            warn!("Rejecting {} batches, queue depth {} of {}", batches.len(), depth, self.max_batches);
+            return Err(QueueError::QueueFull);
+        }
+
+        let mut stmt = conn.prepare(
+            "INSERT INTO message_queue (job_id, account_id, host_id, lines) VALUES (?1, ?2, ?3, ?4)"
+        )?;
+        for batch in batches {
+            let lines = serde_json::to_string(&batch.lines)?;
+            stmt.execute(params![batch.job_id, batch.account_id, batch.host_id, lines])?;
+        }
+
         Ok(())
     }
 
-    pub async fn dequeue(&self) -> Result<Batch, String> {
-        let mut queue = self.queue.lock().await;
-        queue.pop_front().ok_or("Queue is empty".to_string())
+    // Take the oldest pending batch and mark it in flight. Batches that already failed go
+    // behind fresh ones, so a failing batch can't hold up the rest of the queue. The attempt is
+    // counted here, so a batch that takes the process down with it is also given up on
+    pub fn dequeue(&self, conn: &Connection) -> Result<Option<QueuedBatch>, QueueError> {
+        let row = conn.query_row(
+            "UPDATE message_queue SET status = 'processing', attempts = attempts + 1
+             WHERE id = (SELECT id FROM message_queue WHERE status = 'pending' ORDER BY attempts, id LIMIT 1)
+             RETURNING id, attempts, job_id, account_id, host_id, lines",
+            [],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                    row.get::<_, String>(5)?,
+                ))
+            },
+        ).optional()?;
+
+        match row {
+            Some((id, attempts, job_id, account_id, host_id, lines)) => {
+                let lines: Vec<String> = serde_json::from_str(&lines)?;
+                Ok(Some(QueuedBatch {
+                    id,
+                    attempts,
+                    batch: Batch { job_id, account_id, host_id, lines },
+                }))
+            }
+            None => Ok(None),
+        }
     }
-}
\ No newline at end of file
+
+    // Remove a batch once all of its logs have been stored
+    pub fn ack(&self, conn: &Connection, id: i64) -> Result<(), QueueError> {
+        conn.execute("DELETE FROM message_queue WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    // Put an in-flight batch back so it is retried
+    pub fn release(&self, conn: &Connection, id: i64) -> Result<(), QueueError> {
+        conn.execute(
+            "UPDATE message_queue SET status = 'pending' WHERE id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    // Return batches left in flight by a previous run to the pending state
+    pub fn replay(&self, conn: &Connection) -> Result<usize, QueueError> {
+        let replayed = conn.execute(
+            "UPDATE message_queue SET status = 'pending' WHERE status = 'processing'",
+            [],
+        )?;
+        if replayed > 0 {
+            info!("Replaying {} unacknowledged batches", replayed);
+        }
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    fn batch(line: &str) -> Batch {
+        Batch {
+            job_id: "job".to_string(),
+            account_id: "org".to_string(),
+            host_id: "host".to_string(),
+            lines: vec![line.to_string()],
+        }
+    }
+
+    fn first_line(queued: &QueuedBatch) -> &str {
+        &queued.batch.lines[0]
+    }
+
+    #[test]
+    fn a_full_queue_rejects_the_whole_upload() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let queue = MessageQueue::with_limits(2, 5);
+
+        queue.enqueue_all(&conn, &[batch("a")]).unwrap();
+        assert!(matches!(queue.enqueue_all(&conn, &[batch("b"), batch("c")]), Err(QueueError::QueueFull)));
+        let depth: i64 = conn.query_row("SELECT COUNT(*) FROM message_queue", [], |row| row.get(0)).unwrap();
+        assert_eq!(depth, 1);
+    }
+
+    #[test]
+    fn batches_stay_queued_until_acked() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let queue = MessageQueue::with_limits(10, 5);
+        queue.enqueue_all(&conn, &[batch("a"), batch("b")]).unwrap();
+
+        let a = queue.dequeue(&conn).unwrap().unwrap();
+        assert_eq!((first_line(&a), a.attempts), ("a", 1));
+        let b = queue.dequeue(&conn).unwrap().unwrap();
+        assert_eq!(first_line(&b), "b");
+        assert!(queue.dequeue(&conn).unwrap().is_none());
+
+        // A restart puts both back; only the acked one is gone for good
+        queue.ack(&conn, a.id).unwrap();
+        assert_eq!(queue.replay(&conn).unwrap(), 1);
+        let b = queue.dequeue(&conn).unwrap().unwrap();
+        assert_eq!((first_line(&b), b.attempts), ("b", 2));
+    }
+
+    #[test]
+    fn failed_batches_go_behind_fresh_ones_until_exhausted() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let queue = MessageQueue::with_limits(10, 2);
+        queue.enqueue_all(&conn, &[batch("poison"), batch("fresh")]).unwrap();
+
+        let poison = queue.dequeue(&conn).unwrap().unwrap();
+        assert!(!queue.exhausted(&poison));
+        queue.release(&conn, poison.id).unwrap();
+
+        let fresh = queue.dequeue(&conn).unwrap().unwrap();
+        assert_eq!(first_line(&fresh), "fresh");
+        queue.ack(&conn, fresh.id).unwrap();
+
+        let poison = queue.dequeue(&conn).unwrap().unwrap();
+        assert_eq!((first_line(&poison), poison.attempts), ("poison", 2));
+        assert!(queue.exhausted(&poison));
+    }
+}
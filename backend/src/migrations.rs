@@ -0,0 +1,680 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::Serialize;
+use chrono::Utc;
+use crate::schema::Schema;
+use crate::account::Account;
+use crate::agent::{AGENT_OFFLINE_EVENT, hash_key, key_prefix, seed_offline_rule};
+use crate::integrity::append_to_chain;
+use crate::log::Log;
+use log::{info, error};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    DatabaseError(SqliteError),
+    ForeignKeyViolation(i64),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            MigrationError::ForeignKeyViolation(version) => write!(f, "Migration {} left foreign key violations", version),
+        }
+    }
+}
+
+impl From<SqliteError> for MigrationError {
+    fn from(err: SqliteError) -> Self {
+        MigrationError::DatabaseError(err)
+    }
+}
+
+// A numbered up-migration. Versions are applied in order and never edited once released
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub up: fn(&Connection) -> Result<(), SqliteError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied_at: Option<String>,
+}
+
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        up: Schema::create_all,
+    },
+    Migration {
+        version: 2,
+        description: "Add case_id to alerts",
+        up: add_alerts_case_id,
+    },
+    Migration {
+        version: 3,
+        description: "Add retention policies and purge reports",
+        up: add_retention_tables,
+    },
+    Migration {
+        version: 4,
+        description: "Add cold storage archive manifest",
+        up: add_archive_manifest,
+    },
+    Migration {
+        version: 5,
+        description: "Add full-text index over log messages",
+        up: add_logs_fts,
+    },
+    Migration {
+        version: 6,
+        description: "Add log hash chain and daily digests",
+        up: add_log_chain,
+    },
+    Migration {
+        version: 7,
+        description: "Scope log dedup to account and host with occurrence counts",
+        up: scope_log_dedup,
+    },
+    Migration {
+        version: 8,
+        description: "Add custom roles",
+        up: Schema::create_roles_table,
+    },
+    Migration {
+        version: 9,
+        description: "Add organizations and invitations, owning resources by organization",
+        up: add_organizations,
+    },
+    Migration {
+        version: 10,
+        description: "Add personal API tokens",
+        up: Schema::create_api_tokens_table,
+    },
+    Migration {
+        version: 11,
+        description: "Add TOTP multi-factor authentication",
+        up: add_mfa,
+    },
+    Migration {
+        version: 12,
+        description: "Add login attempt tracking and security events",
+        up: Schema::create_login_guard_tables,
+    },
+    Migration {
+        version: 13,
+        description: "Add server-side session store",
+        up: Schema::create_session_tables,
+    },
+    Migration {
+        version: 14,
+        description: "Record the authentication provider of each account",
+        up: add_account_auth_provider,
+    },
+    Migration {
+        version: 15,
+        description: "Add password history, admin resets and forced password changes",
+        up: add_password_management,
+    },
+    Migration {
+        version: 16,
+        description: "Add append-only audit log",
+        up: Schema::create_audit_tables,
+    },
+    Migration {
+        version: 17,
+        description: "Hash agent API keys and allow key rotation",
+        up: hash_agent_keys,
+    },
+    Migration {
+        version: 18,
+        description: "Add agent heartbeat details and offline detection",
+        up: add_agent_heartbeat,
+    },
+    Migration {
+        version: 19,
+        description: "Count ingestion attempts per queued batch",
+        up: add_queue_attempts,
+    },
+    Migration {
+        version: 20,
+        description: "Record every archive run as its own manifest segment",
+        up: segment_archive_manifest,
+    },
+    Migration {
+        version: 21,
+        description: "Key single sign-on accounts on the provider's issuer and subject",
+        up: add_account_external_identity,
+    },
+    Migration {
+        version: 22,
+        description: "Give every organization the agent offline rule up front",
+        up: seed_offline_rules,
+    },
+];
+
+fn create_schema_version_table(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64, MigrationError> {
+    create_schema_version_table(conn)?;
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(version)
+}
+
+// Every known migration with the time it was applied, if it has been
+pub fn migration_status(conn: &Connection) -> Result<Vec<MigrationStatus>, MigrationError> {
+    create_schema_version_table(conn)?;
+    let mut stmt = conn.prepare("SELECT applied_at FROM schema_version WHERE version = ?1")?;
+
+    let mut statuses = Vec::with_capacity(MIGRATIONS.len());
+    for migration in MIGRATIONS {
+        let applied_at = stmt.query_row(params![migration.version], |row| row.get::<_, String>(0))
+            .optional()?;
+        statuses.push(MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            applied_at,
+        });
+    }
+    Ok(statuses)
+}
+
+pub fn pending_migrations(conn: &Connection) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let statuses = migration_status(conn)?;
+    Ok(statuses.into_iter().filter(|status| status.applied_at.is_none()).collect())
+}
+
+// Apply every pending migration, each in its own transaction. Foreign keys are switched
+// off while migrating so tables can be rebuilt, and checked before each commit
+pub fn apply_pending(conn: &mut Connection) -> Result<usize, MigrationError> {
+    let version = current_version(conn)?;
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > version).collect();
+    if pending.is_empty() {
+        info!("Database schema is up to date at version {}", version);
+        return Ok(0);
+    }
+
+    conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+    let result = apply_all(conn, &pending);
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    result?;
+
+    Ok(pending.len())
+}
+
+fn apply_all(conn: &mut Connection, pending: &[&Migration]) -> Result<(), MigrationError> {
+    for migration in pending {
+        info!("Applying migration {}: {}", migration.version, migration.description);
+        let tx = conn.transaction()?;
+        let before = foreign_key_violations(&tx)?;
+        (migration.up)(&tx)?;
+
+        // Only fail on violations the migration introduced, not ones already in the data
+        let violations = foreign_key_violations(&tx)?;
+        if violations > before {
+            error!("Migration {} failed foreign key check with {} new violations", migration.version, violations - before);
+            return Err(MigrationError::ForeignKeyViolation(migration.version));
+        }
+
+        tx.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, ?2)",
+            params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn foreign_key_violations(conn: &Connection) -> Result<i64, SqliteError> {
+    conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))
+}
+
+// Databases created before cases existed have an alerts table without case_id
+fn add_alerts_case_id(conn: &Connection) -> Result<(), SqliteError> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('alerts') WHERE name = 'case_id'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute("ALTER TABLE alerts ADD COLUMN case_id TEXT REFERENCES cases(id)", [])?;
+    }
+    Ok(())
+}
+
+fn add_retention_tables(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_retention_policies_table(conn)?;
+    Schema::create_retention_runs_table(conn)
+}
+
+fn add_archive_manifest(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_archive_manifest_table(conn)?;
+    conn.execute("ALTER TABLE logs ADD COLUMN restored_at DATETIME", [])?;
+    conn.execute("ALTER TABLE retention_runs ADD COLUMN logs_archived INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+// Index logs that were stored before the triggers existed
+fn add_logs_fts(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_logs_fts_table(conn)?;
+
+    let mut select = conn.prepare("SELECT id FROM logs WHERE id NOT IN (SELECT log_id FROM logs_fts_rowids)")?;
+    let ids: Result<Vec<String>, SqliteError> = select.query_map([], |row| row.get(0))?.collect();
+
+    let mut index = conn.prepare(
+        "INSERT INTO logs_fts (raw, message, log_id)
+         SELECT COALESCE(json_extract(log_data, '$.raw'), ''),
+                COALESCE(json_extract(log_data, '$.extensions.message'), ''),
+                id
+         FROM logs WHERE id = ?1"
+    )?;
+    let mut map = conn.prepare("INSERT INTO logs_fts_rowids (log_id, fts_rowid) VALUES (?1, last_insert_rowid())")?;
+    for id in ids? {
+        index.execute(params![id])?;
+        map.execute(params![id])?;
+    }
+    Ok(())
+}
+
+// Chain the logs already stored, oldest first
+fn add_log_chain(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_log_chain_table(conn)?;
+    Schema::create_log_digests_table(conn)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT id, hash, account_id, host_id, timestamp, log_data FROM logs
+         WHERE id NOT IN (SELECT log_id FROM log_chain)
+         ORDER BY created_at, rowid"
+    )?;
+    let logs: Result<Vec<Log>, SqliteError> = stmt.query_map([], |row| {
+        Ok(Log {
+            id: row.get(0)?,
+            hash: row.get(1)?,
+            account_id: row.get(2)?,
+            host_id: row.get(3)?,
+            timestamp: row.get(4)?,
+            log_data: row.get(5)?,
+            occurrence_count: 1,
+            last_seen: None,
+        })
+    })?.collect();
+
+    append_to_chain(conn, &logs?)
+}
+
+// Rebuild `logs` without the global unique index on hash, so identical lines from
+// different hosts or accounts are kept, and rehash every row with its new dedup key
+fn scope_log_dedup(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute_batch(
+        "CREATE TABLE logs_new (
+            id TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            account_id TEXT NOT NULL,
+            host_id TEXT NOT NULL,
+            timestamp DATETIME,
+            log_data TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            restored_at DATETIME,
+            occurrence_count INTEGER NOT NULL DEFAULT 1,
+            last_seen DATETIME,
+            FOREIGN KEY(host_id) REFERENCES hosts(id),
+            FOREIGN KEY(account_id) REFERENCES accounts(id)
+        );
+
+        INSERT INTO logs_new (id, hash, account_id, host_id, timestamp, log_data, created_at, restored_at, last_seen)
+        SELECT id, hash, account_id, host_id, timestamp, log_data, created_at, restored_at, created_at FROM logs;
+
+        DROP TABLE logs;
+        ALTER TABLE logs_new RENAME TO logs;
+
+        CREATE INDEX IF NOT EXISTS idx_logs_account_created ON logs (account_id, created_at);
+        CREATE INDEX IF NOT EXISTS idx_logs_dedup ON logs (account_id, host_id, hash, created_at);"
+    )?;
+    // Dropping the old table dropped its full-text triggers
+    Schema::create_logs_fts_table(conn)?;
+
+    let mut stmt = conn.prepare("SELECT id, hash, account_id, host_id, timestamp, log_data FROM logs")?;
+    let logs: Result<Vec<Log>, SqliteError> = stmt.query_map([], |row| {
+        Ok(Log {
+            id: row.get(0)?,
+            hash: row.get(1)?,
+            account_id: row.get(2)?,
+            host_id: row.get(3)?,
+            timestamp: row.get(4)?,
+            log_data: row.get(5)?,
+            occurrence_count: 1,
+            last_seen: None,
+        })
+    })?.collect();
+
+    let mut update = conn.prepare("UPDATE logs SET hash = ?1 WHERE id = ?2")?;
+    for log in logs? {
+        update.execute(params![log.calculate_hash(), log.id])?;
+    }
+    Ok(())
+}
+
+// Tables whose account_id column names the owning tenant, which is now an organization
+const ORGANIZATION_OWNED_TABLES: &[&str] = &[
+    "rules",
+    "hosts",
+    "alerts",
+    "logs",
+    "agents",
+    "cases",
+    "ingest_jobs",
+    "dead_letters",
+    "retention_policies",
+    "retention_runs",
+    "archive_manifest",
+    "log_chain",
+    "log_digests",
+];
+
+// Every existing account becomes an organization with the same ID, so resources keep their
+// account_id values and only the foreign keys move from accounts to organizations
+fn add_organizations(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_organizations_table(conn)?;
+    Schema::create_invitations_table(conn)?;
+
+    conn.execute_batch(
+        "INSERT INTO organizations (id, name, created_at, updated_at)
+        SELECT id, name, created_at, updated_at FROM accounts;
+
+        ALTER TABLE accounts ADD COLUMN organization_id TEXT REFERENCES organizations(id);
+        UPDATE accounts SET organization_id = id;
+        CREATE INDEX IF NOT EXISTS idx_accounts_organization ON accounts (organization_id);
+
+        CREATE TABLE roles_new (
+            organization_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            description TEXT NOT NULL DEFAULT '',
+            permissions TEXT NOT NULL,
+            created_at DATETIME NOT NULL,
+            updated_at DATETIME NOT NULL,
+            PRIMARY KEY(organization_id, name),
+            FOREIGN KEY(organization_id) REFERENCES organizations(id)
+        );
+
+        INSERT INTO roles_new (organization_id, name, description, permissions, created_at, updated_at)
+        SELECT organizations.id, roles.name, roles.description, roles.permissions, roles.created_at, roles.updated_at
+        FROM roles CROSS JOIN organizations;
+
+        DROP TABLE roles;
+        ALTER TABLE roles_new RENAME TO roles;"
+    )?;
+
+    for table in ORGANIZATION_OWNED_TABLES {
+        repoint_account_fk(conn, table)?;
+    }
+    Ok(())
+}
+
+// SQLite can't alter a foreign key, so rebuild the table from its stored definition and
+// restore the indexes and triggers that dropping the old table removed
+fn repoint_account_fk(conn: &Connection, table: &str) -> Result<(), SqliteError> {
+    let sql: String = conn.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |row| row.get(0),
+    )?;
+    let columns = &sql[sql.find('(').unwrap_or(0)..];
+    let create = format!(
+        "CREATE TABLE {}_new {}",
+        table,
+        columns.replace("FOREIGN KEY(account_id) REFERENCES accounts(id)", "FOREIGN KEY(account_id) REFERENCES organizations(id)")
+    );
+
+    let mut stmt = conn.prepare(
+        "SELECT sql FROM sqlite_master WHERE tbl_name = ?1 AND type IN ('index', 'trigger') AND sql IS NOT NULL"
+    )?;
+    let dependents: Result<Vec<String>, SqliteError> = stmt.query_map(params![table], |row| row.get(0))?.collect();
+    let dependents = dependents?;
+
+    conn.execute(&create, [])?;
+    conn.execute(&format!("INSERT INTO {0}_new SELECT * FROM {0}", table), [])?;
+    conn.execute(&format!("DROP TABLE {}", table), [])?;
+    conn.execute(&format!("ALTER TABLE {0}_new RENAME TO {0}", table), [])?;
+    for sql in dependents {
+        conn.execute_batch(&sql)?;
+    }
+    Ok(())
+}
+
+fn add_mfa(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_mfa_tables(conn)?;
+    conn.execute("ALTER TABLE organizations ADD COLUMN require_mfa BOOLEAN NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+fn add_account_auth_provider(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute("ALTER TABLE accounts ADD COLUMN auth_provider TEXT NOT NULL DEFAULT 'local'", [])?;
+    Ok(())
+}
+
+fn add_password_management(conn: &Connection) -> Result<(), SqliteError> {
+    Schema::create_password_tables(conn)?;
+    conn.execute("ALTER TABLE accounts ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0", [])?;
+    conn.execute("ALTER TABLE accounts ADD COLUMN password_changed_at DATETIME", [])?;
+
+    // Account edits used to store the new password as given. Hash those and have their owners
+    // choose a new one, since it sat in the database in clear text
+    let mut stmt = conn.prepare("SELECT id, password FROM accounts WHERE password NOT LIKE '$argon2%'")?;
+    let plaintext = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for (id, password) in plaintext {
+        let hashed_password = Account::hash_password(&password)
+            .map_err(|e| SqliteError::ToSqlConversionFailure(e.to_string().into()))?;
+        conn.execute(
+            "UPDATE accounts SET password = ?1, must_change_password = 1 WHERE id = ?2",
+            params![hashed_password, id],
+        )?;
+    }
+    Ok(())
+}
+
+// Agent keys were stored in clear. Rebuild `agents` with a lookup prefix and a hash of each
+// key instead, plus room for the key a rotation replaced. Existing keys keep working
+fn hash_agent_keys(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute_batch(
+        "CREATE TABLE agents_new (
+            id TEXT PRIMARY KEY,
+            key_prefix TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            previous_key_prefix TEXT,
+            previous_key_hash TEXT,
+            previous_key_expires_at DATETIME,
+            host_id TEXT NOT NULL,
+            account_id TEXT NOT NULL,
+            ip_address TEXT,
+            hostname TEXT,
+            status TEXT NOT NULL,
+            last_seen DATETIME,
+            FOREIGN KEY(host_id) REFERENCES hosts(id),
+            FOREIGN KEY(account_id) REFERENCES organizations(id)
+        );"
+    )?;
+
+    let mut stmt = conn.prepare("SELECT id, api_key FROM agents")?;
+    let keys = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut insert = conn.prepare(
+        "INSERT INTO agents_new (id, key_prefix, key_hash, host_id, account_id, ip_address, hostname, status, last_seen)
+        SELECT id, ?2, ?3, host_id, account_id, ip_address, hostname, status, last_seen FROM agents WHERE id = ?1"
+    )?;
+    for (id, api_key) in keys {
+        insert.execute(params![id, key_prefix(&api_key), hash_key(&api_key)])?;
+    }
+
+    conn.execute_batch(
+        "DROP TABLE agents;
+        ALTER TABLE agents_new RENAME TO agents;
+        CREATE INDEX IF NOT EXISTS idx_agents_key_prefix ON agents (key_prefix);
+        CREATE INDEX IF NOT EXISTS idx_agents_previous_key_prefix ON agents (previous_key_prefix);"
+    )?;
+    Ok(())
+}
+
+fn add_agent_heartbeat(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute_batch(
+        "ALTER TABLE agents ADD COLUMN version TEXT;
+        ALTER TABLE agents ADD COLUMN os TEXT;
+        ALTER TABLE agents ADD COLUMN queue_depth INTEGER;
+        ALTER TABLE agents ADD COLUMN dropped_events INTEGER;
+        ALTER TABLE agents ADD COLUMN revoked_at DATETIME;"
+    )?;
+    // Until now nothing but revocation marked an agent inactive
+    conn.execute(
+        "UPDATE agents SET revoked_at = ?1 WHERE status = 'Inactive'",
+        params![Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+fn add_queue_attempts(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute("ALTER TABLE message_queue ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+// A day can now have one manifest entry per file. Files written before keep their entry
+fn segment_archive_manifest(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute_batch(
+        "CREATE TABLE archive_manifest_new (
+            account_id TEXT NOT NULL,
+            day TEXT NOT NULL,
+            path TEXT NOT NULL,
+            log_count INTEGER NOT NULL DEFAULT 0,
+            size_bytes INTEGER NOT NULL DEFAULT 0,
+            sha256 TEXT NOT NULL,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY(account_id, day, path),
+            FOREIGN KEY(account_id) REFERENCES organizations(id)
+        );
+        INSERT INTO archive_manifest_new (account_id, day, path, log_count, size_bytes, sha256, updated_at)
+        SELECT account_id, day, path, log_count, size_bytes, sha256, updated_at FROM archive_manifest;
+        DROP TABLE archive_manifest;
+        ALTER TABLE archive_manifest_new RENAME TO archive_manifest;"
+    )?;
+    Ok(())
+}
+
+// Accounts from LDAP and earlier SSO logins have no subject. SSO accounts get theirs on the next login
+fn add_account_external_identity(conn: &Connection) -> Result<(), SqliteError> {
+    conn.execute_batch(
+        "ALTER TABLE accounts ADD COLUMN external_issuer TEXT;
+        ALTER TABLE accounts ADD COLUMN external_subject TEXT;
+        CREATE UNIQUE INDEX IF NOT EXISTS idx_accounts_external_identity
+            ON accounts (external_issuer, external_subject) WHERE external_subject IS NOT NULL;"
+    )?;
+    Ok(())
+}
+
+// The offline check used to add this rule the first time an agent went silent, and again after
+// it was deleted. Organizations without it get it now, unless they have deleted it before
+fn seed_offline_rules(conn: &Connection) -> Result<(), SqliteError> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM organizations o
+        WHERE NOT EXISTS (
+            SELECT 1 FROM rules WHERE account_id = o.id AND json_extract(detection, '$.selection.event_type') = ?1
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM audit_log WHERE organization_id = o.id AND action = 'rule.delete'
+                AND json_extract(json_extract(before_state, '$.detection'), '$.selection.event_type') = ?1
+        )"
+    )?;
+    let organizations = stmt.query_map(params![AGENT_OFFLINE_EVENT], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for organization_id in organizations {
+        seed_offline_rule(conn, &organization_id)
+            .map_err(|e| SqliteError::ToSqlConversionFailure(e.to_string().into()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latest() -> i64 {
+        MIGRATIONS.last().unwrap().version
+    }
+
+    fn table_exists(conn: &Connection, name: &str) -> bool {
+        conn.query_row("SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1", params![name], |row| row.get(0)).unwrap()
+    }
+
+    fn orphan_host(conn: &Connection) -> Result<(), SqliteError> {
+        conn.execute("INSERT INTO hosts (id, account_id, ip_address, hostname) VALUES ('h1', 'missing', '10.0.0.1', 'web')", [])?;
+        Ok(())
+    }
+
+    fn half_done(conn: &Connection) -> Result<(), SqliteError> {
+        conn.execute("CREATE TABLE half_done (id INTEGER)", [])?;
+        conn.execute("INSERT INTO no_such_table VALUES (1)", [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn versions_are_numbered_in_order() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration.description);
+        }
+    }
+
+    #[test]
+    fn a_new_database_gets_every_migration_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(pending_migrations(&conn).unwrap().len(), MIGRATIONS.len());
+
+        assert_eq!(apply_pending(&mut conn).unwrap(), MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest());
+        assert!(migration_status(&conn).unwrap().iter().all(|status| status.applied_at.is_some()));
+
+        assert_eq!(apply_pending(&mut conn).unwrap(), 0);
+        assert!(pending_migrations(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_migration_that_breaks_foreign_keys_is_rolled_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply_pending(&mut conn).unwrap();
+        let bad = Migration { version: latest() + 1, description: "Orphan a host", up: orphan_host };
+        // As in `apply_pending`, so the violation is only caught by the check before commit
+        conn.execute_batch("PRAGMA foreign_keys = OFF;").unwrap();
+
+        assert!(matches!(apply_all(&mut conn, &[&bad]), Err(MigrationError::ForeignKeyViolation(v)) if v == bad.version));
+        let hosts: i64 = conn.query_row("SELECT COUNT(*) FROM hosts", [], |row| row.get(0)).unwrap();
+        assert_eq!(hosts, 0);
+        assert_eq!(current_version(&conn).unwrap(), latest());
+    }
+
+    #[test]
+    fn a_failing_migration_leaves_nothing_behind() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        apply_pending(&mut conn).unwrap();
+        let bad = Migration { version: latest() + 1, description: "Fail half way", up: half_done };
+
+        assert!(matches!(apply_all(&mut conn, &[&bad]), Err(MigrationError::DatabaseError(_))));
+        assert!(!table_exists(&conn, "half_done"));
+        assert_eq!(current_version(&conn).unwrap(), latest());
+    }
+}
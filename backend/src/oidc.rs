@@ -0,0 +1,484 @@
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use reqwest::{Client, Url};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::{Sha256, Digest};
+use data_encoding::BASE64URL_NOPAD;
+use rand::RngCore;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::account::{AccountError, optional_env, provider_names, required_env, role_mappings};
+use std::fmt;
+
+pub const OIDC_PROVIDER: &str = "oidc";
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Unknown key IDs refetch the JWKS, at most this often, to pick up key rotation
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+// How long a started login may take at the provider
+const LOGIN_TIMEOUT_SECS: i64 = 600;
+
+lazy_static! {
+    // Discovery documents by issuer and key sets by JWKS URI, fetched on first use
+    static ref METADATA: RwLock<HashMap<String, ProviderMetadata>> = RwLock::new(HashMap::new());
+    static ref KEY_SETS: RwLock<HashMap<String, (JwkSet, Instant)>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    // The provider is not configured or not enabled
+    NotConfigured(String),
+    // The provider could not be reached or sent something unusable
+    ProviderError(String),
+    // The callback or ID token was rejected
+    ValidationError(String),
+    AccountError(AccountError),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::NotConfigured(msg) => write!(f, "OIDC not configured: {}", msg),
+            OidcError::ProviderError(msg) => write!(f, "Provider error: {}", msg),
+            OidcError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            OidcError::AccountError(err) => write!(f, "Account error: {}", err),
+        }
+    }
+}
+
+impl From<AccountError> for OidcError {
+    fn from(err: AccountError) -> Self {
+        OidcError::AccountError(err)
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(err: reqwest::Error) -> Self {
+        OidcError::ProviderError(err.to_string())
+    }
+}
+
+// Settings from the environment:
+// - `OIDC_ISSUER`: issuer URL, `/.well-known/openid-configuration` is read from it
+// - `OIDC_CLIENT_ID`, `OIDC_CLIENT_SECRET`: the client registered for the SIEM. The secret is
+//   optional for public clients, which rely on PKCE alone
+// - `OIDC_REDIRECT_URI`: this server's `/backend/account/oidc/callback` as the provider sees it
+// - `OIDC_SCOPES`: default `openid profile email`
+// - `OIDC_USERNAME_CLAIM`: claim used as the account's display name, default `preferred_username`.
+//   Accounts are matched on the token's `iss` and `sub`, never on this claim
+// - `OIDC_ROLE_CLAIM`: claim holding the user's groups or roles, a string or a list, default `groups`
+// - `OIDC_CLAIM_ROLES`: `claim value=>Role` pairs separated by `;`, the first match wins
+// - `OIDC_DEFAULT_ROLE`: role for users with none of the mapped values, otherwise they can't log in
+// - `OIDC_ORGANIZATION_ID`: organization that SSO users join
+// - `OIDC_POST_LOGIN_URL`: where the browser goes after signing in, default `/`
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub role_claim: String,
+    pub claim_roles: Vec<(String, String)>,
+    pub default_role: Option<String>,
+    pub organization_id: String,
+    pub post_login_url: String,
+}
+
+impl OidcConfig {
+    // The configuration when `oidc` is one of the `AUTH_PROVIDERS`
+    pub fn from_env() -> Result<Self, OidcError> {
+        if !provider_names().iter().any(|name| name == OIDC_PROVIDER) {
+            return Err(OidcError::NotConfigured("Single sign-on is not enabled".to_string()));
+        }
+        Self::read_env().map_err(OidcError::NotConfigured)
+    }
+
+    fn read_env() -> Result<Self, String> {
+        Ok(OidcConfig {
+            issuer: required_env("OIDC_ISSUER")?.trim_end_matches('/').to_string(),
+            client_id: required_env("OIDC_CLIENT_ID")?,
+            client_secret: optional_env("OIDC_CLIENT_SECRET"),
+            redirect_uri: required_env("OIDC_REDIRECT_URI")?,
+            scopes: optional_env("OIDC_SCOPES").unwrap_or_else(|| "openid profile email".to_string()),
+            username_claim: optional_env("OIDC_USERNAME_CLAIM").unwrap_or_else(|| "preferred_username".to_string()),
+            role_claim: optional_env("OIDC_ROLE_CLAIM").unwrap_or_else(|| "groups".to_string()),
+            claim_roles: role_mappings("OIDC_CLAIM_ROLES")?,
+            default_role: optional_env("OIDC_DEFAULT_ROLE"),
+            organization_id: required_env("OIDC_ORGANIZATION_ID")?,
+            post_login_url: optional_env("OIDC_POST_LOGIN_URL").unwrap_or_else(|| "/".to_string()),
+        })
+    }
+
+    // Role for the first mapped value the user has
+    pub fn role_for(&self, values: &[String]) -> Option<String> {
+        self.claim_roles.iter()
+            .find(|(value, _)| values.contains(value))
+            .map(|(_, role)| role.clone())
+            .or_else(|| self.default_role.clone())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+// A login waiting for the provider's callback, kept in the session under `oidc_login`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub started_at: i64,
+}
+
+// Who the provider says signed in, and the role the claims map to. `issuer` and `subject`
+// identify the user; `name` is only what they are called at the provider today
+#[derive(Debug)]
+pub struct Identity {
+    pub issuer: String,
+    pub subject: String,
+    pub name: String,
+    pub role: String,
+}
+
+fn http_client() -> Result<Client, OidcError> {
+    Ok(Client::builder().timeout(HTTP_TIMEOUT).build()?)
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+// PKCE S256 challenge for a verifier
+fn code_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+async fn provider_metadata(client: &Client, config: &OidcConfig) -> Result<ProviderMetadata, OidcError> {
+    if let Some(metadata) = METADATA.read().unwrap().get(&config.issuer) {
+        return Ok(metadata.clone());
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", config.issuer);
+    let metadata: ProviderMetadata = client.get(&url).send().await?.error_for_status()?.json().await?;
+    if metadata.issuer.trim_end_matches('/') != config.issuer {
+        return Err(OidcError::ProviderError(format!("Discovery document is for issuer {}", metadata.issuer)));
+    }
+    METADATA.write().unwrap().insert(config.issuer.clone(), metadata.clone());
+    Ok(metadata)
+}
+
+// The key an ID token was signed with. A key ID that isn't in the cached set refetches it,
+// since providers rotate keys
+async fn signing_key(client: &Client, jwks_uri: &str, kid: Option<&str>) -> Result<DecodingKey, OidcError> {
+    let find = |keys: &JwkSet| match kid {
+        Some(kid) => keys.find(kid).cloned(),
+        // Without a key ID the set must hold a single key
+        None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+        None => None,
+    };
+
+    let cached = KEY_SETS.read().unwrap().get(jwks_uri).cloned();
+    let stale = match &cached {
+        Some((keys, fetched_at)) => match find(keys) {
+            Some(jwk) => return DecodingKey::from_jwk(&jwk).map_err(|e| OidcError::ProviderError(e.to_string())),
+            None => fetched_at.elapsed() >= JWKS_REFRESH_INTERVAL,
+        },
+        None => true,
+    };
+    if !stale {
+        return Err(OidcError::ValidationError("ID token is signed with an unknown key".to_string()));
+    }
+
+    let keys: JwkSet = client.get(jwks_uri).send().await?.error_for_status()?.json().await?;
+    let jwk = find(&keys);
+    KEY_SETS.write().unwrap().insert(jwks_uri.to_string(), (keys, Instant::now()));
+    match jwk {
+        Some(jwk) => DecodingKey::from_jwk(&jwk).map_err(|e| OidcError::ProviderError(e.to_string())),
+        None => Err(OidcError::ValidationError("ID token is signed with an unknown key".to_string())),
+    }
+}
+
+// Start a login: the provider URL to send the browser to, and what to keep in the session
+// until the callback
+pub async fn begin_login(config: &OidcConfig) -> Result<(String, PendingLogin), OidcError> {
+    let metadata = provider_metadata(&http_client()?, config).await?;
+    let login = PendingLogin {
+        state: random_token(),
+        nonce: random_token(),
+        code_verifier: random_token(),
+        started_at: chrono::Utc::now().timestamp(),
+    };
+
+    let url = Url::parse_with_params(&metadata.authorization_endpoint, &[
+        ("response_type", "code"),
+        ("client_id", config.client_id.as_str()),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("scope", config.scopes.as_str()),
+        ("state", login.state.as_str()),
+        ("nonce", login.nonce.as_str()),
+        ("code_challenge", code_challenge(&login.code_verifier).as_str()),
+        ("code_challenge_method", "S256"),
+    ]).map_err(|e| OidcError::ProviderError(format!("Invalid authorization endpoint: {}", e)))?;
+    Ok((url.to_string(), login))
+}
+
+fn claim_strings(claims: &HashMap<String, Value>, name: &str) -> Vec<String> {
+    match claims.get(name) {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values.iter().filter_map(|v| v.as_str().map(String::from)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Validate an ID token's signature against the provider's JWKS, then its issuer, audience,
+// expiry and nonce
+async fn validate_id_token(
+    client: &Client,
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+    nonce: &str
+) -> Result<HashMap<String, Value>, OidcError> {
+    let header = decode_header(id_token)
+        .map_err(|e| OidcError::ValidationError(format!("Malformed ID token: {}", e)))?;
+    // Only asymmetric algorithms, so a token can't be signed with a public key used as an HMAC secret
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(OidcError::ValidationError("ID token must be signed with the provider's key".to_string()));
+    }
+    let key = signing_key(client, &metadata.jwks_uri, header.kid.as_deref()).await?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    let claims = decode::<HashMap<String, Value>>(id_token, &key, &validation)
+        .map_err(|e| OidcError::ValidationError(format!("Invalid ID token: {}", e)))?
+        .claims;
+
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        return Err(OidcError::ValidationError("ID token nonce does not match".to_string()));
+    }
+    // A token issued to several clients must name this one as the authorized party
+    if let Some(azp) = claims.get("azp").and_then(Value::as_str) {
+        if azp != config.client_id {
+            return Err(OidcError::ValidationError("ID token was issued to another client".to_string()));
+        }
+    }
+    Ok(claims)
+}
+
+// Finish a login: check the callback's state, redeem the code with the PKCE verifier and map
+// the validated ID token to the user's identity and role
+pub async fn complete_login(config: &OidcConfig, login: &PendingLogin, code: &str, state: &str) -> Result<Identity, OidcError> {
+    if state != login.state {
+        return Err(OidcError::ValidationError("Login state does not match".to_string()));
+    }
+    if chrono::Utc::now().timestamp() - login.started_at > LOGIN_TIMEOUT_SECS {
+        return Err(OidcError::ValidationError("Login has expired, please start again".to_string()));
+    }
+
+    let client = http_client()?;
+    let metadata = provider_metadata(&client, config).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", login.code_verifier.as_str()),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+    let response = client.post(&metadata.token_endpoint).form(&form).send().await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        warn!("OIDC token request failed with {}: {}", status, response.text().await.unwrap_or_default());
+        return Err(OidcError::ValidationError("The provider rejected the authorization code".to_string()));
+    }
+    let id_token = response.json::<TokenResponse>().await?.id_token
+        .ok_or_else(|| OidcError::ProviderError("Token response has no ID token".to_string()))?;
+
+    let claims = validate_id_token(&client, config, &metadata, &id_token, &login.nonce).await?;
+    // Both are required claims and the issuer was checked against the discovery document
+    let issuer = claims.get("iss").and_then(Value::as_str).unwrap_or_default().to_string();
+    let subject = claims.get("sub").and_then(Value::as_str)
+        .filter(|sub| !sub.is_empty())
+        .ok_or_else(|| OidcError::ValidationError("ID token has no sub claim".to_string()))?
+        .to_string();
+    let name = claims.get(&config.username_claim).and_then(Value::as_str)
+        .ok_or_else(|| OidcError::ValidationError(format!("ID token has no {} claim", config.username_claim)))?
+        .to_string();
+    let Some(role) = config.role_for(&claim_strings(&claims, &config.role_claim)) else {
+        warn!("OIDC user {} has none of the mapped {} values", name, config.role_claim);
+        return Err(OidcError::ValidationError("Your account has no access to the SIEM".to_string()));
+    };
+    Ok(Identity { issuer, subject, name, role })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+    use crate::test_support::{AUTH_CODE, KEY_A, KEY_B, MockIssuer, OIDC_CLIENT};
+
+    fn config(issuer: &MockIssuer) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.url.clone(),
+            client_id: OIDC_CLIENT.to_string(),
+            client_secret: None,
+            redirect_uri: "https://siem.example.com/backend/account/oidc/callback".to_string(),
+            scopes: "openid profile email".to_string(),
+            username_claim: "preferred_username".to_string(),
+            role_claim: "groups".to_string(),
+            claim_roles: vec![("soc".to_string(), "Analyst".to_string())],
+            default_role: None,
+            organization_id: "acme".to_string(),
+            post_login_url: "/".to_string(),
+        }
+    }
+
+    async fn validate(issuer: &MockIssuer, id_token: &str, nonce: &str) -> Result<HashMap<String, Value>, OidcError> {
+        let client = http_client()?;
+        let config = config(issuer);
+        let metadata = provider_metadata(&client, &config).await?;
+        validate_id_token(&client, &config, &metadata, id_token, nonce).await
+    }
+
+    // Claims with some of them replaced
+    fn with(mut claims: Value, changes: Value) -> Value {
+        for (name, value) in changes.as_object().unwrap() {
+            claims[name] = value.clone();
+        }
+        claims
+    }
+
+    fn rejected(result: Result<HashMap<String, Value>, OidcError>) -> String {
+        match result {
+            Err(OidcError::ValidationError(msg)) => msg,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn id_tokens_are_checked_claim_by_claim() {
+        let issuer = MockIssuer::start(&[&KEY_A]);
+        let claims = issuer.claims("nonce");
+
+        let valid = validate(&issuer, &KEY_A.sign(&claims), "nonce").await.unwrap();
+        assert_eq!(valid["sub"], "sub-1");
+
+        let expired = chrono::Utc::now().timestamp() - 600;
+        let cases = [
+            (with(claims.clone(), json!({ "aud": "another-client" })), "nonce"),
+            (with(claims.clone(), json!({ "iss": "https://evil.example.com" })), "nonce"),
+            (with(claims.clone(), json!({ "exp": expired })), "nonce"),
+            (with(claims.clone(), json!({ "azp": "another-client" })), "nonce"),
+            (claims.clone(), "another-nonce"),
+        ];
+        for (claims, nonce) in cases {
+            rejected(validate(&issuer, &KEY_A.sign(&claims), nonce).await);
+        }
+    }
+
+    #[actix_web::test]
+    async fn tampered_and_symmetric_tokens_are_refused() {
+        let issuer = MockIssuer::start(&[&KEY_A]);
+        let claims = issuer.claims("nonce");
+
+        // Another subject's claims under the original signature
+        let signed = KEY_A.sign(&claims);
+        let parts: Vec<&str> = signed.split('.').collect();
+        let forged = BASE64URL_NOPAD.encode(with(claims.clone(), json!({ "sub": "admin" })).to_string().as_bytes());
+        rejected(validate(&issuer, &format!("{}.{}.{}", parts[0], forged, parts[2]), "nonce").await);
+
+        // Signed with the published key used as an HMAC secret
+        let header = Header { kid: Some(KEY_A.kid.to_string()), ..Header::new(Algorithm::HS256) };
+        let hmac = encode(&header, &claims, &EncodingKey::from_secret(KEY_A.x.as_bytes())).unwrap();
+        assert_eq!(rejected(validate(&issuer, &hmac, "nonce").await), "ID token must be signed with the provider's key");
+
+        // Signed by a key the provider never published
+        rejected(validate(&issuer, &KEY_B.sign(&claims), "nonce").await);
+    }
+
+    #[actix_web::test]
+    async fn an_unknown_key_id_refetches_the_key_set_at_most_once_a_minute() {
+        let issuer = MockIssuer::start(&[&KEY_A]);
+        let claims = issuer.claims("nonce");
+        validate(&issuer, &KEY_A.sign(&claims), "nonce").await.unwrap();
+        assert_eq!(issuer.jwks_fetches(), 1);
+
+        // The provider rotates, but the cached set was only just fetched
+        issuer.serve_keys(&[&KEY_A, &KEY_B]);
+        rejected(validate(&issuer, &KEY_B.sign(&claims), "nonce").await);
+        assert_eq!(issuer.jwks_fetches(), 1);
+
+        let jwks_uri = format!("{}/jwks", issuer.url);
+        if let Some((_, fetched_at)) = KEY_SETS.write().unwrap().get_mut(&jwks_uri) {
+            *fetched_at = Instant::now().checked_sub(JWKS_REFRESH_INTERVAL).unwrap();
+        }
+        validate(&issuer, &KEY_B.sign(&claims), "nonce").await.unwrap();
+        validate(&issuer, &KEY_A.sign(&claims), "nonce").await.unwrap();
+        assert_eq!(issuer.jwks_fetches(), 2);
+    }
+
+    #[actix_web::test]
+    async fn a_login_round_trips_state_nonce_and_the_pkce_verifier() {
+        let issuer = MockIssuer::start(&[&KEY_A]);
+        let config = config(&issuer);
+        let (url, login) = begin_login(&config).await.unwrap();
+
+        let url = Url::parse(&url).unwrap();
+        assert_eq!(url.as_str().split('?').next(), Some(format!("{}/authorize", issuer.url).as_str()));
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["client_id"], OIDC_CLIENT);
+        assert_eq!(params["state"], login.state);
+        assert_eq!(params["nonce"], login.nonce);
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["code_challenge"], code_challenge(&login.code_verifier));
+        issuer.expect_login(&params["code_challenge"], KEY_A.sign(&issuer.claims(&login.nonce)));
+
+        let refused = |result: Result<Identity, OidcError>| matches!(result, Err(OidcError::ValidationError(_)));
+        assert!(refused(complete_login(&config, &login, AUTH_CODE, "another-state").await));
+        let expired = PendingLogin { started_at: login.started_at - LOGIN_TIMEOUT_SECS - 1, ..clone_login(&login) };
+        assert!(refused(complete_login(&config, &expired, AUTH_CODE, &login.state).await));
+        let wrong_verifier = PendingLogin { code_verifier: random_token(), ..clone_login(&login) };
+        assert!(refused(complete_login(&config, &wrong_verifier, AUTH_CODE, &login.state).await));
+
+        let identity = complete_login(&config, &login, AUTH_CODE, &login.state).await.unwrap();
+        assert_eq!((identity.issuer.as_str(), identity.subject.as_str()), (issuer.url.as_str(), "sub-1"));
+        assert_eq!((identity.name.as_str(), identity.role.as_str()), ("alice", "Analyst"));
+
+        // Users with none of the mapped groups are turned away
+        let outsider = with(issuer.claims(&login.nonce), json!({ "groups": ["sales"] }));
+        issuer.expect_login(&params["code_challenge"], KEY_A.sign(&outsider));
+        assert!(refused(complete_login(&config, &login, AUTH_CODE, &login.state).await));
+    }
+
+    fn clone_login(login: &PendingLogin) -> PendingLogin {
+        PendingLogin {
+            state: login.state.clone(),
+            nonce: login.nonce.clone(),
+            code_verifier: login.code_verifier.clone(),
+            started_at: login.started_at,
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use uuid::Uuid;
+use crate::account::{AccountError, create_account};
+use crate::rbac::{RbacError, role_exists};
+use crate::agent::{AgentError, seed_offline_rule};
+use std::fmt;
+
+const DEFAULT_INVITATION_HOURS: i64 = 72;
+const MAX_INVITATION_HOURS: i64 = 30 * 24;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
+pub enum OrganizationError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+    AccountError(AccountError),
+}
+
+impl fmt::Display for OrganizationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrganizationError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            OrganizationError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            OrganizationError::AccountError(err) => write!(f, "Account error: {}", err),
+        }
+    }
+}
+
+impl From<SqliteError> for OrganizationError {
+    fn from(err: SqliteError) -> Self {
+        OrganizationError::DatabaseError(err)
+    }
+}
+
+impl From<RbacError> for OrganizationError {
+    fn from(err: RbacError) -> Self {
+        match err {
+            RbacError::DatabaseError(err) => OrganizationError::DatabaseError(err),
+            RbacError::ValidationError(msg) => OrganizationError::ValidationError(msg),
+        }
+    }
+}
+
+impl From<AgentError> for OrganizationError {
+    fn from(err: AgentError) -> Self {
+        match err {
+            AgentError::DatabaseError(err) => OrganizationError::DatabaseError(err),
+            AgentError::ValidationError(msg) => OrganizationError::ValidationError(msg),
+        }
+    }
+}
+
+// Account errors caused by the request are validation errors, the rest stay internal
+impl From<AccountError> for OrganizationError {
+    fn from(err: AccountError) -> Self {
+        match err {
+            AccountError::DatabaseError(err) => OrganizationError::DatabaseError(err),
+            AccountError::ValidationError(msg) => OrganizationError::ValidationError(msg),
+            AccountError::InvalidRole | AccountError::ExpectedField(_) => OrganizationError::ValidationError(err.to_string()),
+            _ => OrganizationError::AccountError(err),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub require_mfa: bool,
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+// An account as seen by the other members of its organization
+#[derive(Debug, Serialize)]
+pub struct Member {
+    pub id: String,
+    pub name: String,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: String,
+    pub organization_id: String,
+    pub role: String,
+    pub invited_by: String,
+    pub expires_at: String,
+    pub accepted_at: Option<String>,
+    pub accepted_by: Option<String>,
+    pub created_at: String,
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn create_organization(conn: &Connection, name: &str) -> Result<Organization, OrganizationError> {
+    let name = name.trim();
+    if name.len() < 3 || name.len() > 100 {
+        return Err(OrganizationError::ValidationError("Organization name must be between 3 and 100 characters".to_string()));
+    }
+
+    let exists: i64 = conn.query_row("SELECT COUNT(*) FROM organizations WHERE name = ?1", params![name], |row| row.get(0))?;
+    if exists > 0 {
+        return Err(OrganizationError::ValidationError(format!("Organization '{}' already exists", name)));
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO organizations (id, name, created_at, updated_at) VALUES (?1, ?2, ?3, ?4)",
+        params![id, name, now, now],
+    )?;
+    seed_offline_rule(conn, &id)?;
+
+    Ok(Organization {
+        id,
+        name: name.to_string(),
+        require_mfa: false,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+    })
+}
+
+pub fn get_organization(conn: &Connection, id: &str) -> Result<Option<Organization>, OrganizationError> {
+    if id.is_empty() {
+        return Err(OrganizationError::ValidationError("Organization ID cannot be empty".to_string()));
+    }
+
+    let organization = conn.query_row(
+        "SELECT id, name, require_mfa, created_at, updated_at FROM organizations WHERE id = ?1",
+        params![id],
+        |row| Ok(Organization {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            require_mfa: row.get(2)?,
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
+        }),
+    ).optional()?;
+    Ok(organization)
+}
+
+pub fn list_members(conn: &Connection, organization_id: &str) -> Result<Vec<Member>, OrganizationError> {
+    let mut stmt = conn.prepare("SELECT id, name, role FROM accounts WHERE organization_id = ?1 ORDER BY name")?;
+    let members = stmt.query_map(params![organization_id], |row| {
+        Ok(Member {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            role: row.get(2)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(members)
+}
+
+// The first account of an installation gets a new organization named after it and the Admin role
+pub fn bootstrap_organization(conn: &mut Connection, name: String, password: String) -> Result<String, OrganizationError> {
+    let tx = conn.transaction()?;
+    let organization = create_organization(&tx, &name)?;
+    let account_id = create_account(&tx, &organization.id, name, password, "Admin".to_string())?;
+    tx.commit()?;
+    Ok(account_id)
+}
+
+// Returns the invitation and its token. Only the token's hash is stored, so it can't be shown again
+pub fn create_invitation(
+    conn: &Connection,
+    organization_id: &str,
+    invited_by: &str,
+    role: &str,
+    expires_in_hours: Option<i64>,
+) -> Result<(Invitation, String), OrganizationError> {
+    if !role_exists(conn, organization_id, role)? {
+        return Err(OrganizationError::ValidationError("Invalid role provided".to_string()));
+    }
+    let hours = expires_in_hours.unwrap_or(DEFAULT_INVITATION_HOURS);
+    if !(1..=MAX_INVITATION_HOURS).contains(&hours) {
+        return Err(OrganizationError::ValidationError(format!("Invitations must expire within 1 to {} hours", MAX_INVITATION_HOURS)));
+    }
+
+    let token = generate_token();
+    let now = Utc::now();
+    let invitation = Invitation {
+        id: Uuid::new_v4().to_string(),
+        organization_id: organization_id.to_string(),
+        role: role.to_string(),
+        invited_by: invited_by.to_string(),
+        expires_at: (now + Duration::hours(hours)).to_rfc3339(),
+        accepted_at: None,
+        accepted_by: None,
+        created_at: now.to_rfc3339(),
+    };
+
+    conn.execute(
+        "INSERT INTO invitations (id, organization_id, token_hash, role, invited_by, expires_at, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            invitation.id,
+            invitation.organization_id,
+            hash_token(&token),
+            invitation.role,
+            invitation.invited_by,
+            invitation.expires_at,
+            invitation.created_at,
+        ],
+    )?;
+
+    Ok((invitation, token))
+}
+
+pub fn list_invitations(conn: &Connection, organization_id: &str) -> Result<Vec<Invitation>, OrganizationError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, organization_id, role, invited_by, expires_at, accepted_at, accepted_by, created_at
+        FROM invitations WHERE organization_id = ?1 ORDER BY created_at DESC"
+    )?;
+    let invitations = stmt.query_map(params![organization_id], |row| {
+        Ok(Invitation {
+            id: row.get(0)?,
+            organization_id: row.get(1)?,
+            role: row.get(2)?,
+            invited_by: row.get(3)?,
+            expires_at: row.get(4)?,
+            accepted_at: row.get(5)?,
+            accepted_by: row.get(6)?,
+            created_at: row.get(7)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(invitations)
+}
+
+// Accepted invitations are kept as a record of who joined
+pub fn revoke_invitation(conn: &Connection, organization_id: &str, id: &str) -> Result<bool, OrganizationError> {
+    if id.is_empty() {
+        return Err(OrganizationError::ValidationError("Invitation ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "DELETE FROM invitations WHERE id = ?1 AND organization_id = ?2 AND accepted_at IS NULL",
+        params![id, organization_id],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+// Create an account in the inviting organization with the invited role. Expired, revoked
+// and already used tokens are all reported the same way
+pub fn accept_invitation(conn: &mut Connection, token: &str, name: String, password: String) -> Result<String, OrganizationError> {
+    let tx = conn.transaction()?;
+
+    let invitation: Option<(String, String, String)> = tx.query_row(
+        "SELECT id, organization_id, role FROM invitations
+        WHERE token_hash = ?1 AND accepted_at IS NULL AND expires_at > ?2",
+        params![hash_token(token), Utc::now().to_rfc3339()],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ).optional()?;
+    let (invitation_id, organization_id, role) = invitation
+        .ok_or_else(|| OrganizationError::ValidationError("Invitation is invalid or has expired".to_string()))?;
+
+    let account_id = create_account(&tx, &organization_id, name, password, role)?;
+    tx.execute(
+        "UPDATE invitations SET accepted_at = ?1, accepted_by = ?2 WHERE id = ?3",
+        params![Utc::now().to_rfc3339(), account_id, invitation_id],
+    )?;
+    tx.commit()?;
+
+    Ok(account_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::get_account;
+    use crate::test_support::{organization, test_pool};
+
+    const PASSWORD: &str = "Invited-Password-0";
+
+    fn accept(conn: &mut Connection, token: &str, name: &str) -> Result<String, OrganizationError> {
+        accept_invitation(conn, token, name.to_string(), PASSWORD.to_string())
+    }
+
+    #[test]
+    fn an_invitation_joins_the_inviting_organization_once() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, admin) = organization(&conn, "acme");
+
+        assert!(matches!(create_invitation(&conn, &organization_id, &admin, "Overlord", None), Err(OrganizationError::ValidationError(_))));
+        assert!(matches!(create_invitation(&conn, &organization_id, &admin, "Analyst", Some(MAX_INVITATION_HOURS + 1)), Err(OrganizationError::ValidationError(_))));
+
+        let (invitation, token) = create_invitation(&conn, &organization_id, &admin, "Analyst", None).unwrap();
+        let stored: String = conn.query_row("SELECT token_hash FROM invitations WHERE id = ?1", params![invitation.id], |row| row.get(0)).unwrap();
+        assert_ne!(stored, token);
+
+        let account_id = accept(&mut conn, &token, "alice").unwrap();
+        let account = get_account(&conn, &account_id).unwrap().unwrap();
+        assert_eq!((account.organization_id.as_str(), account.role.as_str()), (organization_id.as_str(), "Analyst"));
+        assert_eq!(list_members(&conn, &organization_id).unwrap().len(), 2);
+
+        assert!(matches!(accept(&mut conn, &token, "mallory"), Err(OrganizationError::ValidationError(_))));
+        let invitations = list_invitations(&conn, &organization_id).unwrap();
+        assert_eq!(invitations[0].accepted_by.as_deref(), Some(account_id.as_str()));
+        // Accepted invitations stay as the record of who joined
+        assert!(!revoke_invitation(&conn, &organization_id, &invitation.id).unwrap());
+    }
+
+    #[test]
+    fn revoked_expired_and_foreign_invitations_are_handled() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (acme, admin) = organization(&conn, "acme");
+        let (globex, _) = organization(&conn, "globex");
+
+        let (revoked, token) = create_invitation(&conn, &acme, &admin, "Analyst", None).unwrap();
+        assert!(!revoke_invitation(&conn, &globex, &revoked.id).unwrap());
+        assert!(revoke_invitation(&conn, &acme, &revoked.id).unwrap());
+        assert!(matches!(accept(&mut conn, &token, "alice"), Err(OrganizationError::ValidationError(_))));
+
+        let (expired, token) = create_invitation(&conn, &acme, &admin, "Analyst", Some(1)).unwrap();
+        conn.execute(
+            "UPDATE invitations SET expires_at = ?1 WHERE id = ?2",
+            params![(Utc::now() - Duration::minutes(1)).to_rfc3339(), expired.id],
+        ).unwrap();
+        assert!(matches!(accept(&mut conn, &token, "alice"), Err(OrganizationError::ValidationError(_))));
+
+        assert_eq!(list_members(&conn, &acme).unwrap().len(), 1);
+        assert!(list_invitations(&conn, &globex).unwrap().is_empty());
+    }
+}
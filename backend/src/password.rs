@@ -0,0 +1,297 @@
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use data_encoding::BASE64URL_NOPAD;
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use uuid::Uuid;
+use crate::account::{Account, AccountError, LOCAL_PROVIDER, get_account};
+
+// Earlier passwords kept per account that can't be chosen again, besides the current one
+const PASSWORD_HISTORY: i64 = 5;
+const RESET_TOKEN_TTL_HOURS: i64 = 24;
+
+// An admin-issued reset, returned once to be handed to the account owner
+#[derive(Debug, Serialize)]
+pub struct PasswordReset {
+    pub account_id: String,
+    pub token: String,
+    pub expires_at: String,
+}
+
+fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+// The stored hash of a local account. Accounts of other providers have no password to change
+fn local_password(conn: &Connection, account_id: &str) -> Result<Option<String>, AccountError> {
+    let row: Option<(String, String)> = conn.query_row(
+        "SELECT password, auth_provider FROM accounts WHERE id = ?1",
+        params![account_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    match row {
+        Some((_, provider)) if provider != LOCAL_PROVIDER => Err(AccountError::ValidationError(
+            "The password of this account is managed by its identity provider".to_string()
+        )),
+        Some((hash, _)) => Ok(Some(hash)),
+        None => Ok(None),
+    }
+}
+
+fn reused(conn: &Connection, account_id: &str, current_hash: &str, password: &str) -> Result<bool, AccountError> {
+    if Account::password_matches(current_hash, password) {
+        return Ok(true);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT password FROM password_history WHERE account_id = ?1 ORDER BY created_at DESC LIMIT ?2"
+    )?;
+    let previous = stmt.query_map(params![account_id, PASSWORD_HISTORY], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(previous.iter().any(|hash| Account::password_matches(hash, password)))
+}
+
+// Replace an account's password, moving the old hash into its history
+fn store_password(conn: &Connection, account_id: &str, current_hash: &str, password: &str, must_change: bool) -> Result<(), AccountError> {
+    let hashed_password = Account::hash_password(password)?;
+    let now = Utc::now().to_rfc3339();
+
+    conn.execute(
+        "INSERT INTO password_history (id, account_id, password, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![Uuid::new_v4().to_string(), account_id, current_hash, now],
+    )?;
+    conn.execute(
+        "DELETE FROM password_history WHERE account_id = ?1 AND id NOT IN (
+            SELECT id FROM password_history WHERE account_id = ?1 ORDER BY created_at DESC LIMIT ?2
+        )",
+        params![account_id, PASSWORD_HISTORY],
+    )?;
+    conn.execute(
+        "UPDATE accounts SET password = ?1, must_change_password = ?2, password_changed_at = ?3, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?4",
+        params![hashed_password, must_change, now, account_id],
+    )?;
+    Ok(())
+}
+
+// The owner changes their password. False when the current password is wrong
+pub fn change_password(conn: &mut Connection, account_id: &str, current_password: &str, new_password: &str) -> Result<bool, AccountError> {
+    // IMMEDIATE so the hash that was checked is still the current one when it moves into the history
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let Some(current_hash) = local_password(&tx, account_id)? else {
+        return Ok(false);
+    };
+    if !Account::password_matches(&current_hash, current_password) {
+        return Ok(false);
+    }
+    Account::validate_password(new_password)?;
+    if reused(&tx, account_id, &current_hash, new_password)? {
+        return Err(AccountError::ValidationError(format!(
+            "Password must differ from the current one and the last {} passwords", PASSWORD_HISTORY
+        )));
+    }
+    store_password(&tx, account_id, &current_hash, new_password, false)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+// Whether the account has to pick a new password before its session works
+pub fn password_change_required(conn: &Connection, account_id: &str) -> Result<bool, AccountError> {
+    let required: Option<bool> = conn.query_row(
+        "SELECT must_change_password FROM accounts WHERE id = ?1",
+        params![account_id],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(required.unwrap_or(false))
+}
+
+pub fn require_password_change(conn: &Connection, account_id: &str) -> Result<(), AccountError> {
+    conn.execute("UPDATE accounts SET must_change_password = 1 WHERE id = ?1", params![account_id])?;
+    Ok(())
+}
+
+// Admin reset for an account in the organization. Only the token's hash is stored and any
+// earlier unused reset for the account stops working. None when the account isn't found
+pub fn issue_password_reset(conn: &Connection, organization_id: &str, account_id: &str, created_by: &str) -> Result<Option<PasswordReset>, AccountError> {
+    if account_id.is_empty() {
+        return Err(AccountError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    let in_organization: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM accounts WHERE id = ?1 AND organization_id = ?2)",
+        params![account_id, organization_id],
+        |row| row.get(0),
+    )?;
+    if !in_organization || local_password(conn, account_id)?.is_none() {
+        return Ok(None);
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = format!("pwreset_{}", BASE64URL_NOPAD.encode(&bytes));
+    let now = Utc::now();
+    let expires_at = (now + Duration::hours(RESET_TOKEN_TTL_HOURS)).to_rfc3339();
+
+    conn.execute(
+        "DELETE FROM password_resets WHERE account_id = ?1 AND used_at IS NULL",
+        params![account_id],
+    )?;
+    conn.execute(
+        "INSERT INTO password_resets (id, account_id, token_hash, created_by, expires_at, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![Uuid::new_v4().to_string(), account_id, hash_token(&token), created_by, expires_at, now.to_rfc3339()],
+    )?;
+
+    Ok(Some(PasswordReset { account_id: account_id.to_string(), token, expires_at }))
+}
+
+// Set a new password with a reset token, which works once. Returns the account, or None when
+// the token is unknown, used or expired. Everything happens in one IMMEDIATE transaction and the
+// token is only marked used if nothing else got to it first, so concurrent redemptions can't
+// both succeed
+pub fn redeem_password_reset(conn: &mut Connection, token: &str, new_password: &str) -> Result<Option<Account>, AccountError> {
+    if token.is_empty() {
+        return Err(AccountError::ValidationError("Reset token cannot be empty".to_string()));
+    }
+    Account::validate_password(new_password)?;
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let now = Utc::now().to_rfc3339();
+    let reset: Option<(String, String)> = tx.query_row(
+        "SELECT id, account_id FROM password_resets WHERE token_hash = ?1 AND used_at IS NULL AND expires_at > ?2",
+        params![hash_token(token), now],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+    let Some((reset_id, account_id)) = reset else {
+        return Ok(None);
+    };
+    let Some(current_hash) = local_password(&tx, &account_id)? else {
+        return Ok(None);
+    };
+    if reused(&tx, &account_id, &current_hash, new_password)? {
+        return Err(AccountError::ValidationError(format!(
+            "Password must differ from the current one and the last {} passwords", PASSWORD_HISTORY
+        )));
+    }
+
+    let claimed = tx.execute(
+        "UPDATE password_resets SET used_at = ?1 WHERE id = ?2 AND used_at IS NULL",
+        params![now, reset_id],
+    )?;
+    if claimed != 1 {
+        return Ok(None);
+    }
+    store_password(&tx, &account_id, &current_hash, new_password, false)?;
+    let account = get_account(&tx, &account_id)?;
+    tx.commit()?;
+    Ok(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::create_account;
+    use crate::test_support::{organization, test_pool};
+
+    const INITIAL: &str = "Initial-Password-0";
+
+    fn password(n: usize) -> String {
+        format!("Rotated-Password-{}", n)
+    }
+
+    // A local Analyst in a new organization: the organization and account IDs
+    fn alice(conn: &Connection) -> (String, String) {
+        let (organization_id, _) = organization(conn, "acme");
+        let account_id = create_account(conn, &organization_id, "alice".to_string(), INITIAL.to_string(), "Analyst".to_string()).unwrap();
+        (organization_id, account_id)
+    }
+
+    fn signs_in_with(conn: &Connection, account_id: &str, password: &str) -> bool {
+        let hash = local_password(conn, account_id).unwrap().unwrap();
+        Account::password_matches(&hash, password)
+    }
+
+    #[test]
+    fn changing_needs_the_current_password_and_a_strong_new_one() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (_, account_id) = alice(&conn);
+        require_password_change(&conn, &account_id).unwrap();
+
+        assert!(!change_password(&mut conn, &account_id, "Wrong-Password-00", &password(1)).unwrap());
+        assert!(matches!(change_password(&mut conn, &account_id, INITIAL, "short"), Err(AccountError::ValidationError(_))));
+        assert!(matches!(change_password(&mut conn, &account_id, INITIAL, INITIAL), Err(AccountError::ValidationError(_))));
+        assert!(signs_in_with(&conn, &account_id, INITIAL));
+        assert!(password_change_required(&conn, &account_id).unwrap());
+
+        assert!(change_password(&mut conn, &account_id, INITIAL, &password(1)).unwrap());
+        assert!(signs_in_with(&conn, &account_id, &password(1)));
+        assert!(!password_change_required(&conn, &account_id).unwrap());
+    }
+
+    #[test]
+    fn the_last_passwords_cannot_be_reused() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (_, account_id) = alice(&conn);
+
+        let mut current = INITIAL.to_string();
+        for n in 1..=PASSWORD_HISTORY as usize {
+            assert!(change_password(&mut conn, &account_id, &current, &password(n)).unwrap());
+            current = password(n);
+        }
+        assert!(matches!(change_password(&mut conn, &account_id, &current, INITIAL), Err(AccountError::ValidationError(_))));
+
+        // One more change pushes the first password out of the history
+        let next = password(PASSWORD_HISTORY as usize + 1);
+        assert!(change_password(&mut conn, &account_id, &current, &next).unwrap());
+        assert!(change_password(&mut conn, &account_id, &next, INITIAL).unwrap());
+        let kept: i64 = conn.query_row("SELECT COUNT(*) FROM password_history WHERE account_id = ?1", params![account_id], |row| row.get(0)).unwrap();
+        assert_eq!(kept, PASSWORD_HISTORY);
+    }
+
+    #[test]
+    fn a_reset_token_works_once_and_only_the_latest() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, account_id) = alice(&conn);
+        let (other_organization, admin) = organization(&conn, "globex");
+
+        assert!(issue_password_reset(&conn, &other_organization, &account_id, &admin).unwrap().is_none());
+        let replaced = issue_password_reset(&conn, &organization_id, &account_id, &admin).unwrap().unwrap();
+        let reset = issue_password_reset(&conn, &organization_id, &account_id, &admin).unwrap().unwrap();
+        assert!(redeem_password_reset(&mut conn, &replaced.token, &password(1)).unwrap().is_none());
+
+        // A refused password leaves the token usable
+        assert!(matches!(redeem_password_reset(&mut conn, &reset.token, INITIAL), Err(AccountError::ValidationError(_))));
+        let account = redeem_password_reset(&mut conn, &reset.token, &password(1)).unwrap().unwrap();
+        assert_eq!(account.id, account_id);
+        assert!(signs_in_with(&conn, &account_id, &password(1)));
+        assert!(redeem_password_reset(&mut conn, &reset.token, &password(2)).unwrap().is_none());
+    }
+
+    #[test]
+    fn an_expired_reset_token_is_refused() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, account_id) = alice(&conn);
+        let reset = issue_password_reset(&conn, &organization_id, &account_id, &account_id).unwrap().unwrap();
+        conn.execute("UPDATE password_resets SET expires_at = '2000-01-01T00:00:00+00:00'", []).unwrap();
+
+        assert!(redeem_password_reset(&mut conn, &reset.token, &password(1)).unwrap().is_none());
+        assert!(signs_in_with(&conn, &account_id, INITIAL));
+    }
+
+    #[test]
+    fn provider_accounts_have_no_password_here() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, account_id) = alice(&conn);
+        conn.execute("UPDATE accounts SET auth_provider = 'ldap' WHERE id = ?1", params![account_id]).unwrap();
+
+        assert!(matches!(change_password(&mut conn, &account_id, INITIAL, &password(1)), Err(AccountError::ValidationError(_))));
+        assert!(matches!(issue_password_reset(&conn, &organization_id, &account_id, &account_id), Err(AccountError::ValidationError(_))));
+    }
+}
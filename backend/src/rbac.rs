@@ -0,0 +1,474 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError};
+use actix_web::http::Method;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
+use chrono::Utc;
+use log::error;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use crate::auth_session::{Tenant, request_tenant};
+use crate::database::{DbPool, run};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RbacError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for RbacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RbacError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            RbacError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for RbacError {
+    fn from(err: SqliteError) -> Self {
+        RbacError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for RbacError {
+    fn from(err: serde_json::Error) -> Self {
+        RbacError::ValidationError(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "logs:read")]
+    ReadLogs,
+    #[serde(rename = "logs:ingest")]
+    IngestLogs,
+    #[serde(rename = "alerts:read")]
+    ReadAlerts,
+    #[serde(rename = "alerts:manage")]
+    ManageAlerts,
+    #[serde(rename = "rules:read")]
+    ReadRules,
+    #[serde(rename = "rules:manage")]
+    ManageRules,
+    #[serde(rename = "hosts:read")]
+    ReadHosts,
+    #[serde(rename = "hosts:manage")]
+    ManageHosts,
+    #[serde(rename = "cases:read")]
+    ReadCases,
+    #[serde(rename = "cases:manage")]
+    ManageCases,
+    #[serde(rename = "retention:read")]
+    ReadRetention,
+    #[serde(rename = "retention:manage")]
+    ManageRetention,
+    #[serde(rename = "users:manage")]
+    ManageUsers,
+    #[serde(rename = "system:read")]
+    ReadSystem,
+    #[serde(rename = "audit:read")]
+    ReadAudit,
+}
+
+pub const ALL_PERMISSIONS: &[Permission] = &[
+    Permission::ReadLogs,
+    Permission::IngestLogs,
+    Permission::ReadAlerts,
+    Permission::ManageAlerts,
+    Permission::ReadRules,
+    Permission::ManageRules,
+    Permission::ReadHosts,
+    Permission::ManageHosts,
+    Permission::ReadCases,
+    Permission::ManageCases,
+    Permission::ReadRetention,
+    Permission::ManageRetention,
+    Permission::ManageUsers,
+    Permission::ReadSystem,
+    Permission::ReadAudit,
+];
+
+// Built-in roles live in code so they can't be edited or deleted
+const BUILTIN_ROLES: &[(&str, &str, &[Permission])] = &[
+    ("Admin", "Full access, including user and role management", ALL_PERMISSIONS),
+    ("Detection Engineer", "Writes detection rules and ingests logs", &[
+        Permission::ReadLogs,
+        Permission::IngestLogs,
+        Permission::ReadAlerts,
+        Permission::ManageAlerts,
+        Permission::ReadRules,
+        Permission::ManageRules,
+        Permission::ReadHosts,
+        Permission::ReadCases,
+        Permission::ReadRetention,
+    ]),
+    ("Analyst", "Triages alerts and works cases", &[
+        Permission::ReadLogs,
+        Permission::ReadAlerts,
+        Permission::ManageAlerts,
+        Permission::ReadRules,
+        Permission::ReadHosts,
+        Permission::ReadCases,
+        Permission::ManageCases,
+    ]),
+    ("Read-only Auditor", "Reads everything, changes nothing", &[
+        Permission::ReadLogs,
+        Permission::ReadAlerts,
+        Permission::ReadRules,
+        Permission::ReadHosts,
+        Permission::ReadCases,
+        Permission::ReadRetention,
+        Permission::ReadSystem,
+    ]),
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub permissions: Vec<Permission>,
+    #[serde(default)]
+    pub builtin: bool,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+fn builtin_role(name: &str) -> Option<Role> {
+    BUILTIN_ROLES.iter()
+        .find(|(role_name, _, _)| *role_name == name)
+        .map(|(name, description, permissions)| Role {
+            name: name.to_string(),
+            description: description.to_string(),
+            permissions: permissions.to_vec(),
+            builtin: true,
+            created_at: None,
+            updated_at: None,
+        })
+}
+
+fn validate_role(name: &str, permissions: &[Permission]) -> Result<(), RbacError> {
+    let name = name.trim();
+    if name.len() < 3 || name.len() > 50 {
+        return Err(RbacError::ValidationError("Role name must be between 3 and 50 characters".to_string()));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '_' | '-')) {
+        return Err(RbacError::ValidationError("Role name can only contain alphanumeric characters, spaces, underscores, and hyphens".to_string()));
+    }
+    if permissions.is_empty() {
+        return Err(RbacError::ValidationError("A role needs at least one permission".to_string()));
+    }
+    Ok(())
+}
+
+fn custom_role(conn: &Connection, organization_id: &str, name: &str) -> Result<Option<Role>, RbacError> {
+    let row = conn.query_row(
+        "SELECT name, description, permissions, created_at, updated_at FROM roles WHERE organization_id = ?1 AND name = ?2",
+        params![organization_id, name],
+        |row| Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+        )),
+    ).optional()?;
+
+    match row {
+        Some((name, description, permissions, created_at, updated_at)) => Ok(Some(Role {
+            name,
+            description,
+            permissions: serde_json::from_str(&permissions)?,
+            builtin: false,
+            created_at: Some(created_at),
+            updated_at: Some(updated_at),
+        })),
+        None => Ok(None),
+    }
+}
+
+// Built-in roles are shared, custom roles belong to one organization
+pub fn get_role(conn: &Connection, organization_id: &str, name: &str) -> Result<Option<Role>, RbacError> {
+    match builtin_role(name) {
+        Some(role) => Ok(Some(role)),
+        None => custom_role(conn, organization_id, name),
+    }
+}
+
+pub fn role_exists(conn: &Connection, organization_id: &str, name: &str) -> Result<bool, RbacError> {
+    Ok(get_role(conn, organization_id, name)?.is_some())
+}
+
+pub fn list_roles(conn: &Connection, organization_id: &str) -> Result<Vec<Role>, RbacError> {
+    let mut roles: Vec<Role> = BUILTIN_ROLES.iter()
+        .filter_map(|(name, _, _)| builtin_role(name))
+        .collect();
+
+    let mut stmt = conn.prepare("SELECT name FROM roles WHERE organization_id = ?1 ORDER BY name")?;
+    let names: Result<Vec<String>, SqliteError> = stmt.query_map(params![organization_id], |row| row.get(0))?.collect();
+    for name in names? {
+        if let Some(role) = custom_role(conn, organization_id, &name)? {
+            roles.push(role);
+        }
+    }
+    Ok(roles)
+}
+
+pub fn create_role(conn: &Connection, organization_id: &str, role: &Role) -> Result<Role, RbacError> {
+    validate_role(&role.name, &role.permissions)?;
+    let name = role.name.trim();
+    if role_exists(conn, organization_id, name)? {
+        return Err(RbacError::ValidationError(format!("Role '{}' already exists", name)));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO roles (organization_id, name, description, permissions, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![organization_id, name, role.description, serde_json::to_string(&role.permissions)?, now, now],
+    )?;
+
+    Ok(Role {
+        name: name.to_string(),
+        description: role.description.clone(),
+        permissions: role.permissions.clone(),
+        builtin: false,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+    })
+}
+
+// Only the description and permissions of a custom role can change
+pub fn update_role(conn: &Connection, organization_id: &str, name: &str, role: &Role) -> Result<bool, RbacError> {
+    if builtin_role(name).is_some() {
+        return Err(RbacError::ValidationError("Built-in roles cannot be changed".to_string()));
+    }
+    validate_role(name, &role.permissions)?;
+
+    let affected_rows = conn.execute(
+        "UPDATE roles SET description = ?1, permissions = ?2, updated_at = ?3 WHERE organization_id = ?4 AND name = ?5",
+        params![role.description, serde_json::to_string(&role.permissions)?, Utc::now().to_rfc3339(), organization_id, name],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+pub fn delete_role(conn: &Connection, organization_id: &str, name: &str) -> Result<bool, RbacError> {
+    if builtin_role(name).is_some() {
+        return Err(RbacError::ValidationError("Built-in roles cannot be deleted".to_string()));
+    }
+
+    let in_use: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM accounts WHERE organization_id = ?1 AND role = ?2",
+        params![organization_id, name],
+        |row| row.get(0),
+    )?;
+    if in_use > 0 {
+        return Err(RbacError::ValidationError(format!("Role '{}' is assigned to {} accounts", name, in_use)));
+    }
+
+    let affected_rows = conn.execute(
+        "DELETE FROM roles WHERE organization_id = ?1 AND name = ?2",
+        params![organization_id, name],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+// Permissions granted to an account through its role. Unknown accounts and roles get none
+pub fn account_permissions(conn: &Connection, account_id: &str) -> Result<Vec<Permission>, RbacError> {
+    let role: Option<(String, String)> = conn.query_row(
+        "SELECT organization_id, role FROM accounts WHERE id = ?1",
+        params![account_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ).optional()?;
+
+    match role {
+        Some((organization_id, role)) => Ok(get_role(conn, &organization_id, &role)?.map(|role| role.permissions).unwrap_or_default()),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Resolve the caller and check that its account holds `permission`. API tokens only
+// get the permissions that are both in their scopes and still granted by the role
+pub async fn authorize(req: &HttpRequest, permission: Permission) -> Result<Tenant, Error> {
+    let tenant = request_tenant(req).await?;
+    let pool = req.app_data::<web::Data<DbPool>>()
+        .ok_or_else(|| ErrorInternalServerError("An internal error occurred"))?;
+
+    let account_id = tenant.account_id.clone();
+    let permissions = run(pool, move |conn| account_permissions(conn, &account_id)).await
+        .map_err(|e| {
+            error!("Failed to load permissions for {}: {}", tenant.account_id, e);
+            ErrorInternalServerError("An internal error occurred")
+        })?;
+
+    let in_scope = tenant.scopes.as_ref().is_none_or(|scopes| scopes.contains(&permission));
+    if !permissions.contains(&permission) || !in_scope {
+        return Err(ErrorForbidden("Permission denied"));
+    }
+    Ok(tenant)
+}
+
+// Guards a scope or route: GET requests need `read`, every other method needs `write`.
+// The verified tenant is stored on the request for the `Tenant` extractor
+pub struct RequirePermission {
+    read: Permission,
+    write: Permission,
+}
+
+impl RequirePermission {
+    pub fn new(read: Permission, write: Permission) -> Self {
+        RequirePermission { read, write }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            read: self.read,
+            write: self.write,
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    read: Permission,
+    write: Permission,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = if req.method() == Method::GET { self.read } else { self.write };
+
+        Box::pin(async move {
+            let tenant = authorize(req.request(), permission).await?;
+            req.extensions_mut().insert(tenant);
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use crate::test_support::{organization, test_pool};
+
+    fn custom(name: &str, permissions: &[Permission]) -> Role {
+        Role {
+            name: name.to_string(),
+            description: String::new(),
+            permissions: permissions.to_vec(),
+            builtin: false,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn set_role(conn: &Connection, account_id: &str, role: &str) {
+        conn.execute("UPDATE accounts SET role = ?1 WHERE id = ?2", params![role, account_id]).unwrap();
+    }
+
+    // Ask for `permission` on a request the tenant has already been resolved for
+    async fn check(pool: &DbPool, tenant: Tenant, permission: Permission) -> Result<(), StatusCode> {
+        let req = TestRequest::default().app_data(web::Data::new(pool.clone())).to_http_request();
+        req.extensions_mut().insert(tenant);
+        authorize(&req, permission).await
+            .map(|_| ())
+            .map_err(|err| err.as_response_error().status_code())
+    }
+
+    fn session(organization_id: &str, account_id: &str) -> Tenant {
+        Tenant { account_id: account_id.to_string(), organization_id: organization_id.to_string(), token_id: None, scopes: None }
+    }
+
+    #[actix_web::test]
+    async fn built_in_roles_grant_their_permissions() {
+        let pool = test_pool();
+        let (organization_id, account_id) = organization(&pool.get().unwrap(), "acme");
+        set_role(&pool.get().unwrap(), &account_id, "Analyst");
+
+        let tenant = session(&organization_id, &account_id);
+        assert_eq!(check(&pool, tenant.clone(), Permission::ManageCases).await, Ok(()));
+        assert_eq!(check(&pool, tenant.clone(), Permission::ManageRules).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(check(&pool, tenant, Permission::ManageUsers).await, Err(StatusCode::FORBIDDEN));
+    }
+
+    #[actix_web::test]
+    async fn custom_roles_belong_to_their_organization() {
+        let pool = test_pool();
+        let (acme, acme_account, globex_account) = {
+            let conn = pool.get().unwrap();
+            let (acme, acme_account) = organization(&conn, "acme");
+            let (_, globex_account) = organization(&conn, "globex");
+            create_role(&conn, &acme, &custom("Case Clerk", &[Permission::ReadCases])).unwrap();
+            set_role(&conn, &acme_account, "Case Clerk");
+            set_role(&conn, &globex_account, "Case Clerk");
+            (acme, acme_account, globex_account)
+        };
+
+        assert_eq!(check(&pool, session(&acme, &acme_account), Permission::ReadCases).await, Ok(()));
+        assert_eq!(check(&pool, session(&acme, &acme_account), Permission::ReadLogs).await, Err(StatusCode::FORBIDDEN));
+        // The same role name means nothing in another organization
+        assert!(account_permissions(&pool.get().unwrap(), &globex_account).unwrap().is_empty());
+        assert!(account_permissions(&pool.get().unwrap(), "no-such-account").unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn role_changes_apply_to_the_next_request() {
+        let pool = test_pool();
+        let (organization_id, account_id) = organization(&pool.get().unwrap(), "acme");
+        create_role(&pool.get().unwrap(), &organization_id, &custom("Ingest Only", &[Permission::IngestLogs])).unwrap();
+        set_role(&pool.get().unwrap(), &account_id, "Ingest Only");
+        assert_eq!(check(&pool, session(&organization_id, &account_id), Permission::ReadLogs).await, Err(StatusCode::FORBIDDEN));
+
+        update_role(&pool.get().unwrap(), &organization_id, "Ingest Only", &custom("Ingest Only", &[Permission::ReadLogs])).unwrap();
+        assert_eq!(check(&pool, session(&organization_id, &account_id), Permission::ReadLogs).await, Ok(()));
+        assert_eq!(check(&pool, session(&organization_id, &account_id), Permission::IngestLogs).await, Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn built_in_and_assigned_roles_are_kept() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, account_id) = organization(&conn, "acme");
+        create_role(&conn, &organization_id, &custom("Case Clerk", &[Permission::ReadCases])).unwrap();
+        set_role(&conn, &account_id, "Case Clerk");
+
+        assert!(matches!(create_role(&conn, &organization_id, &custom("Analyst", &[Permission::ReadLogs])), Err(RbacError::ValidationError(_))));
+        assert!(matches!(update_role(&conn, &organization_id, "Admin", &custom("Admin", &[Permission::ReadLogs])), Err(RbacError::ValidationError(_))));
+        assert!(matches!(delete_role(&conn, &organization_id, "Admin"), Err(RbacError::ValidationError(_))));
+        assert!(matches!(delete_role(&conn, &organization_id, "Case Clerk"), Err(RbacError::ValidationError(_))));
+
+        set_role(&conn, &account_id, "Admin");
+        assert!(delete_role(&conn, &organization_id, "Case Clerk").unwrap());
+    }
+}
@@ -0,0 +1,556 @@
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use actix_web::rt;
+use chrono::{Duration as ChronoDuration, Utc};
+use uuid::Uuid;
+use log::{error, info};
+use crate::cases::{CaseError, all_logs_with_cases};
+use crate::archive::{ArchiveError, archive_logs};
+use crate::integrity::mark_purged;
+use crate::database::{DbPool, run};
+use std::env;
+use std::fmt;
+
+// Logs are deleted in chunks so a large purge doesn't hold the write lock for long
+const PURGE_CHUNK: usize = 500;
+// Pages released by each incremental vacuum after a purge
+const INCREMENTAL_VACUUM_PAGES: i64 = 2000;
+
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
+pub enum RetentionError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+    CaseError(CaseError),
+    ArchiveError(ArchiveError),
+}
+
+impl fmt::Display for RetentionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetentionError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            RetentionError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            RetentionError::CaseError(err) => write!(f, "Case error: {}", err),
+            RetentionError::ArchiveError(err) => write!(f, "Archive error: {}", err),
+        }
+    }
+}
+
+impl From<SqliteError> for RetentionError {
+    fn from(err: SqliteError) -> Self {
+        RetentionError::DatabaseError(err)
+    }
+}
+
+impl From<ArchiveError> for RetentionError {
+    fn from(err: ArchiveError) -> Self {
+        RetentionError::ArchiveError(err)
+    }
+}
+
+impl From<CaseError> for RetentionError {
+    fn from(err: CaseError) -> Self {
+        RetentionError::CaseError(err)
+    }
+}
+
+// Keep logs for `retention_days` after ingestion, then delete them or move them to
+// cold storage. A policy covers the whole account, or only one host and/or event type.
+// The most specific matching policy wins
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub account_id: String,
+    pub host_id: Option<String>,
+    pub event_type: Option<String>,
+    pub retention_days: i64,
+    pub action: String,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub updated_at: String,
+}
+
+impl RetentionPolicy {
+    pub fn is_valid_action(action: &str) -> bool {
+        matches!(action, "delete" | "archive")
+    }
+}
+
+// Outcome of one purge run for an account
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionRun {
+    pub id: String,
+    pub account_id: String,
+    pub logs_deleted: i64,
+    pub logs_archived: i64,
+    pub logs_kept_for_cases: i64,
+    pub started_at: String,
+    pub finished_at: String,
+}
+
+fn validate_policy(policy: &RetentionPolicy) -> Result<(), RetentionError> {
+    if policy.account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if policy.retention_days < 1 {
+        return Err(RetentionError::ValidationError("Retention days must be at least 1".to_string()));
+    }
+    if !RetentionPolicy::is_valid_action(&policy.action) {
+        return Err(RetentionError::ValidationError(format!("Invalid retention action: {}", policy.action)));
+    }
+    Ok(())
+}
+
+pub fn create_policy(conn: &Connection, policy: &RetentionPolicy) -> Result<RetentionPolicy, RetentionError> {
+    validate_policy(policy)?;
+
+    let now = Utc::now().to_rfc3339();
+    let new_policy = RetentionPolicy {
+        id: Uuid::new_v4().to_string(),
+        account_id: policy.account_id.clone(),
+        host_id: policy.host_id.clone().filter(|h| !h.is_empty()),
+        event_type: policy.event_type.clone().filter(|e| !e.is_empty()),
+        retention_days: policy.retention_days,
+        action: policy.action.clone(),
+        created_at: now.clone(),
+        updated_at: now,
+    };
+
+    conn.execute(
+        "INSERT INTO retention_policies (id, account_id, host_id, event_type, retention_days, action, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            new_policy.id,
+            new_policy.account_id,
+            new_policy.host_id,
+            new_policy.event_type,
+            new_policy.retention_days,
+            new_policy.action,
+            new_policy.created_at,
+            new_policy.updated_at,
+        ],
+    )?;
+
+    Ok(new_policy)
+}
+
+pub fn get_policy(conn: &Connection, account_id: &str, policy_id: &str) -> Result<Option<RetentionPolicy>, RetentionError> {
+    if account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if policy_id.is_empty() {
+        return Err(RetentionError::ValidationError("Policy ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, host_id, event_type, retention_days, action, created_at, updated_at
+         FROM retention_policies WHERE id = ?1 AND account_id = ?2"
+    )?;
+
+    let policy = stmt.query_row(params![policy_id, account_id], |row| {
+        Ok(RetentionPolicy {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            host_id: row.get(2)?,
+            event_type: row.get(3)?,
+            retention_days: row.get(4)?,
+            action: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+        })
+    }).optional()?;
+
+    Ok(policy)
+}
+
+pub fn list_policies(conn: &Connection, account_id: &str) -> Result<Vec<RetentionPolicy>, RetentionError> {
+    if account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, host_id, event_type, retention_days, action, created_at, updated_at
+         FROM retention_policies WHERE account_id = ?1
+         ORDER BY created_at"
+    )?;
+
+    let policies_iter = stmt.query_map(params![account_id], |row| {
+        Ok(RetentionPolicy {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            host_id: row.get(2)?,
+            event_type: row.get(3)?,
+            retention_days: row.get(4)?,
+            action: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+        })
+    })?;
+
+    let policies: Result<Vec<RetentionPolicy>, SqliteError> = policies_iter.collect();
+    Ok(policies?)
+}
+
+pub fn update_policy(conn: &Connection, policy: &RetentionPolicy) -> Result<bool, RetentionError> {
+    if policy.id.is_empty() {
+        return Err(RetentionError::ValidationError("Policy ID cannot be empty".to_string()));
+    }
+    validate_policy(policy)?;
+
+    let affected_rows = conn.execute(
+        "UPDATE retention_policies SET host_id = ?1, event_type = ?2, retention_days = ?3, action = ?4, updated_at = ?5
+         WHERE id = ?6 AND account_id = ?7",
+        params![
+            policy.host_id.as_deref().filter(|h| !h.is_empty()),
+            policy.event_type.as_deref().filter(|e| !e.is_empty()),
+            policy.retention_days,
+            policy.action,
+            Utc::now().to_rfc3339(),
+            policy.id,
+            policy.account_id,
+        ],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+pub fn delete_policy(conn: &Connection, account_id: &str, policy_id: &str) -> Result<bool, RetentionError> {
+    if account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+    if policy_id.is_empty() {
+        return Err(RetentionError::ValidationError("Policy ID cannot be empty".to_string()));
+    }
+
+    let affected_rows = conn.execute(
+        "DELETE FROM retention_policies WHERE id = ?1 AND account_id = ?2",
+        params![policy_id, account_id],
+    )?;
+    Ok(affected_rows > 0)
+}
+
+pub fn list_runs(conn: &Connection, account_id: &str) -> Result<Vec<RetentionRun>, RetentionError> {
+    if account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, logs_deleted, logs_archived, logs_kept_for_cases, started_at, finished_at
+         FROM retention_runs WHERE account_id = ?1
+         ORDER BY started_at DESC LIMIT 100"
+    )?;
+
+    let runs_iter = stmt.query_map(params![account_id], |row| {
+        Ok(RetentionRun {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            logs_deleted: row.get(2)?,
+            logs_archived: row.get(3)?,
+            logs_kept_for_cases: row.get(4)?,
+            started_at: row.get(5)?,
+            finished_at: row.get(6)?,
+        })
+    })?;
+
+    let runs: Result<Vec<RetentionRun>, SqliteError> = runs_iter.collect();
+    Ok(runs?)
+}
+
+// Expired log with the action of its policy. Logs restored from the archive are
+// already in cold storage and are only ever deleted
+struct ExpiredLog {
+    id: String,
+    action: String,
+    restored: bool,
+}
+
+// The account's logs that are past the retention of their most specific policy.
+// Restored logs are left alone for `RESTORE_TTL_DAYS` (default 7) after restoring
+fn expired_logs(conn: &Connection, account_id: &str) -> Result<Vec<ExpiredLog>, RetentionError> {
+    let restore_ttl = env::var("RESTORE_TTL_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v >= 0)
+        .unwrap_or(7);
+    let restored_cutoff = (Utc::now() - ChronoDuration::days(restore_ttl)).to_rfc3339();
+
+    let mut stmt = conn.prepare(
+        "SELECT l.id, p.action, l.restored_at IS NOT NULL FROM logs l
+         JOIN retention_policies p ON p.id = (
+             SELECT p2.id FROM retention_policies p2
+             WHERE p2.account_id = l.account_id
+               AND (p2.host_id IS NULL OR p2.host_id = l.host_id)
+               AND (p2.event_type IS NULL OR p2.event_type = json_extract(l.log_data, '$.event_type'))
+             ORDER BY (p2.host_id IS NOT NULL) + (p2.event_type IS NOT NULL) DESC,
+                      (p2.host_id IS NOT NULL) DESC
+             LIMIT 1
+         )
+         WHERE l.account_id = ?1
+           AND l.created_at < datetime('now', '-' || p.retention_days || ' days')
+           AND (l.restored_at IS NULL OR l.restored_at < ?2)"
+    )?;
+
+    let logs: Result<Vec<ExpiredLog>, SqliteError> = stmt.query_map(params![account_id, restored_cutoff], |row| {
+        Ok(ExpiredLog {
+            id: row.get(0)?,
+            action: row.get(1)?,
+            restored: row.get(2)?,
+        })
+    })?.collect();
+    Ok(logs?)
+}
+
+// Delete or archive expired logs for one account, keeping any log referenced by a case
+pub fn purge_account(conn: &mut Connection, account_id: &str) -> Result<RetentionRun, RetentionError> {
+    if account_id.is_empty() {
+        return Err(RetentionError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let started_at = Utc::now().to_rfc3339();
+    let protected: HashSet<String> = all_logs_with_cases(conn, account_id)?.into_iter().collect();
+
+    let expired = expired_logs(conn, account_id)?;
+    let (kept, expired): (Vec<ExpiredLog>, Vec<ExpiredLog>) = expired.into_iter()
+        .partition(|log| protected.contains(&log.id));
+    let (to_archive, to_delete): (Vec<ExpiredLog>, Vec<ExpiredLog>) = expired.into_iter()
+        .partition(|log| log.action == "archive" && !log.restored);
+
+    let archive_ids: Vec<String> = to_archive.into_iter().map(|log| log.id).collect();
+    let logs_archived = archive_logs(conn, account_id, &archive_ids)?;
+
+    let mut logs_deleted = 0;
+    for chunk in to_delete.chunks(PURGE_CHUNK) {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM logs WHERE id = ?1")?;
+            for log in chunk {
+                logs_deleted += stmt.execute(params![log.id])? as i64;
+            }
+        }
+        let ids: Vec<String> = chunk.iter().map(|log| log.id.clone()).collect();
+        mark_purged(&tx, &ids)?;
+        tx.commit()?;
+    }
+
+    let report = RetentionRun {
+        id: Uuid::new_v4().to_string(),
+        account_id: account_id.to_string(),
+        logs_deleted,
+        logs_archived,
+        logs_kept_for_cases: kept.len() as i64,
+        started_at,
+        finished_at: Utc::now().to_rfc3339(),
+    };
+
+    conn.execute(
+        "INSERT INTO retention_runs (id, account_id, logs_deleted, logs_archived, logs_kept_for_cases, started_at, finished_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            report.id,
+            report.account_id,
+            report.logs_deleted,
+            report.logs_archived,
+            report.logs_kept_for_cases,
+            report.started_at,
+            report.finished_at,
+        ],
+    )?;
+
+    Ok(report)
+}
+
+// Purge every account that has a retention policy
+pub fn purge_all(conn: &mut Connection) -> Result<Vec<RetentionRun>, RetentionError> {
+    let account_ids: Vec<String> = {
+        let mut stmt = conn.prepare("SELECT DISTINCT account_id FROM retention_policies")?;
+        let ids: Result<Vec<String>, SqliteError> = stmt.query_map([], |row| row.get(0))?.collect();
+        ids?
+    };
+
+    let mut runs = Vec::with_capacity(account_ids.len());
+    for account_id in account_ids {
+        let run = purge_account(conn, &account_id)?;
+        info!("Retention purge for account {}: {} deleted, {} archived, {} kept for cases",
+            account_id, run.logs_deleted, run.logs_archived, run.logs_kept_for_cases);
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+// Switch the database to incremental auto-vacuum. It takes effect on the next full VACUUM
+pub fn enable_incremental_vacuum(conn: &Connection) -> Result<(), RetentionError> {
+    conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL;")?;
+    Ok(())
+}
+
+// Release free pages left behind by a purge
+pub fn incremental_vacuum(conn: &Connection) -> Result<(), RetentionError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA incremental_vacuum({})", INCREMENTAL_VACUUM_PAGES))?;
+    let mut rows = stmt.query([])?;
+    while rows.next()?.is_some() {}
+    Ok(())
+}
+
+pub fn full_vacuum(conn: &Connection) -> Result<(), RetentionError> {
+    conn.execute_batch("VACUUM;")?;
+    Ok(())
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    let secs = env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default);
+    Duration::from_secs(secs)
+}
+
+// Run the purge on an interval (`RETENTION_INTERVAL_SECS`, default hourly), follow purges
+// with an incremental vacuum and run a full VACUUM every `VACUUM_INTERVAL_SECS` (default weekly)
+pub fn spawn_retention_job(pool: DbPool) {
+    let interval = env_secs("RETENTION_INTERVAL_SECS", 3600);
+    let vacuum_interval = env_secs("VACUUM_INTERVAL_SECS", 7 * 24 * 3600);
+
+    rt::spawn(async move {
+        let mut last_vacuum = Instant::now();
+        loop {
+            rt::time::sleep(interval).await;
+
+            let full = last_vacuum.elapsed() >= vacuum_interval;
+            let result = run(&pool, move |conn| {
+                let runs = purge_all(conn)?;
+                if full {
+                    info!("Running scheduled VACUUM");
+                    full_vacuum(conn)?;
+                } else if runs.iter().any(|run| run.logs_deleted + run.logs_archived > 0) {
+                    incremental_vacuum(conn)?;
+                }
+                Ok::<_, RetentionError>(())
+            }).await;
+
+            match result {
+                Ok(()) => if full { last_vacuum = Instant::now(); },
+                Err(e) => error!("Retention job failed: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cases::{Observable, add_observable, create_case};
+    use crate::log::Log;
+    use crate::test_support::{age_logs, archive_dir, host, organization, store_logs, test_pool};
+
+    fn policy(organization_id: &str, host_id: Option<&str>, retention_days: i64, action: &str) -> RetentionPolicy {
+        RetentionPolicy {
+            id: String::new(),
+            account_id: organization_id.to_string(),
+            host_id: host_id.map(String::from),
+            event_type: None,
+            retention_days,
+            action: action.to_string(),
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    // How many of the logs are still in the table
+    fn remaining(conn: &Connection, logs: &[Log]) -> usize {
+        logs.iter().filter(|log| {
+            conn.query_row("SELECT COUNT(*) > 0 FROM logs WHERE id = ?1", params![log.id], |row| row.get::<_, bool>(0)).unwrap()
+        }).count()
+    }
+
+    #[test]
+    fn expired_logs_are_deleted_and_fresh_ones_kept() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let old = store_logs(&mut conn, &organization_id, &host_id, &["old one", "old two"]);
+        let fresh = store_logs(&mut conn, &organization_id, &host_id, &["fresh"]);
+        age_logs(&conn, &old, 40);
+        create_policy(&conn, &policy(&organization_id, None, 30, "delete")).unwrap();
+
+        let run = purge_account(&mut conn, &organization_id).unwrap();
+        assert_eq!((run.logs_deleted, run.logs_archived, run.logs_kept_for_cases), (2, 0, 0));
+        assert_eq!((remaining(&conn, &old), remaining(&conn, &fresh)), (0, 1));
+        assert_eq!(list_runs(&conn, &organization_id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn logs_in_a_case_outlive_their_retention() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let old = store_logs(&mut conn, &organization_id, &host_id, &["evidence", "noise"]);
+        age_logs(&conn, &old, 40);
+        let case = create_case(&conn, &organization_id).unwrap();
+        // Log observables hold the whole log as the analyst saw it
+        add_observable(&conn, &organization_id, &case.id, Observable {
+            observable_type: "log".to_string(),
+            value: serde_json::to_string(&old[0]).unwrap(),
+        }).unwrap();
+        create_policy(&conn, &policy(&organization_id, None, 30, "delete")).unwrap();
+
+        let run = purge_account(&mut conn, &organization_id).unwrap();
+        assert_eq!((run.logs_deleted, run.logs_kept_for_cases), (1, 1));
+        assert_eq!(remaining(&conn, &old[..1]), 1);
+    }
+
+    #[test]
+    fn the_most_specific_policy_wins() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let web = host(&conn, &organization_id, "web");
+        let db = host(&conn, &organization_id, "db");
+        let web_logs = store_logs(&mut conn, &organization_id, &web, &["web line"]);
+        let db_logs = store_logs(&mut conn, &organization_id, &db, &["db line"]);
+        age_logs(&conn, &web_logs, 40);
+        age_logs(&conn, &db_logs, 40);
+        create_policy(&conn, &policy(&organization_id, None, 30, "delete")).unwrap();
+        create_policy(&conn, &policy(&organization_id, Some(&db), 365, "delete")).unwrap();
+
+        assert_eq!(purge_account(&mut conn, &organization_id).unwrap().logs_deleted, 1);
+        assert_eq!((remaining(&conn, &web_logs), remaining(&conn, &db_logs)), (0, 1));
+    }
+
+    #[test]
+    fn archiving_moves_expired_logs_to_cold_storage() {
+        archive_dir();
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+        let host_id = host(&conn, &organization_id, "web");
+        let old = store_logs(&mut conn, &organization_id, &host_id, &["old one", "old two"]);
+        age_logs(&conn, &old, 40);
+        create_policy(&conn, &policy(&organization_id, None, 30, "archive")).unwrap();
+
+        let run = purge_account(&mut conn, &organization_id).unwrap();
+        assert_eq!((run.logs_deleted, run.logs_archived), (0, 2));
+        assert_eq!(remaining(&conn, &old), 0);
+        let archived: i64 = conn.query_row(
+            "SELECT SUM(log_count) FROM archive_manifest WHERE account_id = ?1", params![organization_id], |row| row.get(0),
+        ).unwrap();
+        assert_eq!(archived, 2);
+    }
+
+    #[test]
+    fn policies_need_a_retention_and_a_known_action() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (organization_id, _) = organization(&conn, "acme");
+
+        assert!(matches!(create_policy(&conn, &policy(&organization_id, None, 0, "delete")), Err(RetentionError::ValidationError(_))));
+        assert!(matches!(create_policy(&conn, &policy(&organization_id, None, 30, "shred")), Err(RetentionError::ValidationError(_))));
+        assert!(list_policies(&conn, &organization_id).unwrap().is_empty());
+    }
+}
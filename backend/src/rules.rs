@@ -1,16 +1,15 @@
-use rusqlite::{Error as SqliteError, params};
-use crate::database::establish_connection;
+use rusqlite::{Connection, Error as SqliteError, params};
 use crate::alert::{create_alert, Alert};
 use crate::log_parser::NormalizedLog;
 use serde::{Serialize, Deserialize};
 use chrono::{Utc, NaiveDateTime};
 use rusqlite::OptionalExtension;
 use uuid::Uuid;
-use serde_json;
 use log::info;
 use std::fmt;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names, reason = "variants share the Error suffix used by every error enum in the crate")]
 pub enum RuleError {
     DatabaseError(SqliteError),
     ValidationError(String),
@@ -57,9 +56,9 @@ pub struct Detection {
 
 impl fmt::Display for Detection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Selection: {{\n")?;
+        writeln!(f, "Selection: {{")?;
         for (key, value) in &self.selection {
-            write!(f, " {}: {},\n", key, value)?;
+            writeln!(f, " {}: {},", key, value)?;
         }
         write!(f, "}}\nCondition: {}", self.condition)
     }
@@ -129,9 +128,9 @@ impl Rule {
     }
 }
 
-pub fn create_rule(rule: &Rule) -> Result<(), RuleError> {
+// Returns the new rule's ID
+pub fn create_rule(conn: &Connection, rule: &Rule) -> Result<String, RuleError> {
     rule.validate()?;
-    let conn = establish_connection()?;
     let now = Utc::now();
     let formatted_date = rule.format_sigma_date()?;
 
@@ -179,18 +178,20 @@ pub fn create_rule(rule: &Rule) -> Result<(), RuleError> {
         ],
     )?;
 
-    Ok(())
+    Ok(new_rule.id)
 }
 
-pub fn get_rule(id: &String) -> Result<Option<Rule>, RuleError> {
+pub fn get_rule(conn: &Connection, account_id: &str, id: &String) -> Result<Option<Rule>, RuleError> {
+    if account_id.is_empty() {
+        return Err(RuleError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if id.is_empty() {
         return Err(RuleError::ValidationError("Rule ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
-    let mut stmt = conn.prepare("SELECT * FROM rules WHERE id = ?1")?;
+    let mut stmt = conn.prepare("SELECT * FROM rules WHERE id = ?1 AND account_id = ?2")?;
 
-    let rule = stmt.query_row(params![id], |row| {
+    let rule = stmt.query_row(params![id, account_id], |row| {
         Ok(Rule {
             id: row.get(0)?,
             account_id: row.get(1)?,
@@ -215,17 +216,17 @@ pub fn get_rule(id: &String) -> Result<Option<Rule>, RuleError> {
     Ok(rule)
 }
 
-pub fn update_rule(rule: &Rule) -> Result<(), RuleError> {
+// Rules never move between accounts, so account_id only scopes the update
+pub fn update_rule(conn: &Connection, rule: &Rule) -> Result<bool, RuleError> {
     rule.validate()?;
-    let conn = establish_connection()?;
 
-    conn.execute(
+    let affected_rows = conn.execute(
         "UPDATE rules SET 
-         account_id = ?1, title = ?2, status = ?3, description = ?4, ref_list = ?5,
+         title = ?2, status = ?3, description = ?4, ref_list = ?5,
          tags = ?6, author = ?7, date = ?8, logsource = ?9, detection = ?10, 
          fields = ?11, falsepositives = ?12, level = ?13, enabled = ?14,
          updated_at = ?15 
-         WHERE id = ?16",
+         WHERE id = ?16 AND account_id = ?1",
         params![
             rule.account_id,
             rule.title,
@@ -246,26 +247,27 @@ pub fn update_rule(rule: &Rule) -> Result<(), RuleError> {
         ],
     )?;
 
-    Ok(())
+    Ok(affected_rows > 0)
 }
 
-pub fn delete_rule(id: &String) -> Result<(), RuleError> {
+pub fn delete_rule(conn: &Connection, account_id: &str, id: &String) -> Result<bool, RuleError> {
+    if account_id.is_empty() {
+        return Err(RuleError::ValidationError("Account ID cannot be empty".to_string()));
+    }
     if id.is_empty() {
         return Err(RuleError::ValidationError("Rule ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
-    conn.execute("DELETE FROM rules WHERE id = ?1", params![id])?;
+    let affected_rows = conn.execute("DELETE FROM rules WHERE id = ?1 AND account_id = ?2", params![id, account_id])?;
 
-    Ok(())
+    Ok(affected_rows > 0)
 }
 
-pub fn list_rules(account_id: &String) -> Result<Vec<Rule>, RuleError> {
+pub fn list_rules(conn: &Connection, account_id: &String) -> Result<Vec<Rule>, RuleError> {
     if account_id.is_empty() {
         return Err(RuleError::ValidationError("Account ID cannot be empty".to_string()));
     }
 
-    let conn = establish_connection()?;
     let mut stmt = conn.prepare("SELECT * FROM rules WHERE account_id = ?1")?;
 
     let rules_iter = stmt.query_map(params![account_id], |row| {
@@ -294,8 +296,8 @@ pub fn list_rules(account_id: &String) -> Result<Vec<Rule>, RuleError> {
     Ok(rules?)
 }
 
-pub async fn evaluate_log_against_rules(log: &NormalizedLog, account_id: &String) -> Result<Vec<Alert>, RuleError> {
-    let rules = list_rules(account_id)?;
+pub fn evaluate_log_against_rules(conn: &Connection, log: &NormalizedLog, account_id: &String) -> Result<Vec<Alert>, RuleError> {
+    let rules = list_rules(conn, account_id)?;
     let mut triggered_alerts = Vec::new();
 
     for rule in rules {
@@ -314,7 +316,7 @@ pub async fn evaluate_log_against_rules(log: &NormalizedLog, account_id: &String
                 case_id: None,
                 created_at: Utc::now().to_rfc3339(),
             };
-            create_alert(&new_alert)
+            create_alert(conn, &new_alert)
                 .map_err(|e| RuleError::AlertCreationError(e.to_string()))?;
             info!("Alert created: {:?}", new_alert.message);
             triggered_alerts.push(new_alert);
@@ -31,6 +31,15 @@ impl Schema {
         info!("Creating case comments table");
         Self::create_case_comments_table(conn)?;
 
+        info!("Creating message queue table");
+        Self::create_message_queue_table(conn)?;
+
+        info!("Creating ingest jobs table");
+        Self::create_ingest_jobs_table(conn)?;
+
+        info!("Creating dead letters table");
+        Self::create_dead_letters_table(conn)?;
+
         info!("All tables created successfully");
         Ok(())
     }
@@ -194,4 +203,388 @@ impl Schema {
         )?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn create_message_queue_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS message_queue (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                job_id TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                lines TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                enqueued_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn create_ingest_jobs_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ingest_jobs (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                status TEXT NOT NULL,
+                lines_read INTEGER NOT NULL DEFAULT 0,
+                lines_parsed INTEGER NOT NULL DEFAULT 0,
+                duplicates INTEGER NOT NULL DEFAULT 0,
+                failures INTEGER NOT NULL DEFAULT 0,
+                alerts_raised INTEGER NOT NULL DEFAULT 0,
+                batches_total INTEGER NOT NULL DEFAULT 0,
+                batches_done INTEGER NOT NULL DEFAULT 0,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn create_dead_letters_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS dead_letters (
+                id TEXT PRIMARY KEY,
+                job_id TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                host_id TEXT NOT NULL,
+                raw TEXT NOT NULL,
+                error TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(host_id) REFERENCES hosts(id),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_retention_policies_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS retention_policies (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                host_id TEXT,
+                event_type TEXT,
+                retention_days INTEGER NOT NULL,
+                action TEXT NOT NULL DEFAULT 'delete',
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                FOREIGN KEY(host_id) REFERENCES hosts(id),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_retention_runs_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS retention_runs (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                logs_deleted INTEGER NOT NULL DEFAULT 0,
+                logs_kept_for_cases INTEGER NOT NULL DEFAULT 0,
+                started_at DATETIME NOT NULL,
+                finished_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_logs_account_created ON logs (account_id, created_at)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_archive_manifest_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS archive_manifest (
+                account_id TEXT NOT NULL,
+                day TEXT NOT NULL,
+                path TEXT NOT NULL,
+                log_count INTEGER NOT NULL DEFAULT 0,
+                size_bytes INTEGER NOT NULL DEFAULT 0,
+                sha256 TEXT NOT NULL,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(account_id, day),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    // Full-text index over the raw line and the syslog message. FTS rows are mapped to
+    // logs by ID, since VACUUM may renumber the rowids of `logs`
+    pub fn create_logs_fts_table(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS logs_fts USING fts5 (
+                raw,
+                message,
+                log_id UNINDEXED,
+                tokenize = 'unicode61'
+            );
+
+            CREATE TABLE IF NOT EXISTS logs_fts_rowids (
+                log_id TEXT PRIMARY KEY,
+                fts_rowid INTEGER NOT NULL
+            );
+
+            CREATE TRIGGER IF NOT EXISTS logs_fts_insert AFTER INSERT ON logs BEGIN
+                INSERT INTO logs_fts (raw, message, log_id) VALUES (
+                    COALESCE(json_extract(new.log_data, '$.raw'), ''),
+                    COALESCE(json_extract(new.log_data, '$.extensions.message'), ''),
+                    new.id
+                );
+                INSERT INTO logs_fts_rowids (log_id, fts_rowid) VALUES (new.id, last_insert_rowid());
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS logs_fts_delete AFTER DELETE ON logs BEGIN
+                DELETE FROM logs_fts WHERE rowid = (SELECT fts_rowid FROM logs_fts_rowids WHERE log_id = old.id);
+                DELETE FROM logs_fts_rowids WHERE log_id = old.id;
+            END;"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_log_chain_table(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS log_chain (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                account_id TEXT NOT NULL,
+                log_id TEXT NOT NULL,
+                log_hash TEXT NOT NULL,
+                prev_hash TEXT NOT NULL,
+                chain_hash TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                purged_at DATETIME,
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_log_chain_account_seq ON log_chain (account_id, seq);
+            CREATE INDEX IF NOT EXISTS idx_log_chain_log_id ON log_chain (log_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_log_digests_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS log_digests (
+                account_id TEXT NOT NULL,
+                day TEXT NOT NULL,
+                last_seq INTEGER NOT NULL,
+                chain_hash TEXT NOT NULL,
+                log_count INTEGER NOT NULL,
+                signature TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                PRIMARY KEY(account_id, day),
+                FOREIGN KEY(account_id) REFERENCES accounts(id)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_roles_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL DEFAULT '',
+                permissions TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                updated_at DATETIME NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_organizations_table(conn: &Connection) -> Result<()> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS organizations (
+                id TEXT PRIMARY KEY,
+                name TEXT UNIQUE NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn create_invitations_table(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS invitations (
+                id TEXT PRIMARY KEY,
+                organization_id TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                role TEXT NOT NULL,
+                invited_by TEXT NOT NULL,
+                expires_at DATETIME NOT NULL,
+                accepted_at DATETIME,
+                accepted_by TEXT,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(organization_id) REFERENCES organizations(id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_invitations_organization ON invitations (organization_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_api_tokens_table(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS api_tokens (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                organization_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                prefix TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                scopes TEXT NOT NULL,
+                expires_at DATETIME NOT NULL,
+                last_used_at DATETIME,
+                revoked_at DATETIME,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY(organization_id) REFERENCES organizations(id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_api_tokens_account ON api_tokens (account_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_mfa_tables(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS account_mfa (
+                account_id TEXT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                enabled BOOLEAN NOT NULL DEFAULT 0,
+                last_step INTEGER,
+                enabled_at DATETIME,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                used_at DATETIME,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+            CREATE INDEX IF NOT EXISTS idx_mfa_recovery_codes_account ON mfa_recovery_codes (account_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_login_guard_tables(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS login_attempts (
+                scope TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                failures INTEGER NOT NULL DEFAULT 0,
+                last_failure_at INTEGER NOT NULL,
+                blocked_until INTEGER,
+                locked BOOLEAN NOT NULL DEFAULT 0,
+                PRIMARY KEY(scope, subject)
+            );
+            CREATE TABLE IF NOT EXISTS security_events (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                event_type TEXT NOT NULL,
+                src_ip TEXT,
+                log_data TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES organizations(id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_security_events_account ON security_events (account_id, created_at);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_session_tables(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                ip_address TEXT,
+                user_agent TEXT,
+                created_at DATETIME NOT NULL,
+                last_activity DATETIME NOT NULL,
+                expires_at DATETIME NOT NULL,
+                revoked_at DATETIME,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+            CREATE INDEX IF NOT EXISTS idx_sessions_account ON sessions (account_id);
+            CREATE TABLE IF NOT EXISTS session_states (
+                key_hash TEXT PRIMARY KEY,
+                session_id TEXT,
+                state TEXT NOT NULL,
+                expires_at DATETIME NOT NULL,
+                FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            );
+            CREATE INDEX IF NOT EXISTS idx_session_states_session ON session_states (session_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_password_tables(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS password_history (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                password TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE
+            );
+            CREATE INDEX IF NOT EXISTS idx_password_history_account ON password_history (account_id, created_at);
+            CREATE TABLE IF NOT EXISTS password_resets (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                token_hash TEXT UNIQUE NOT NULL,
+                created_by TEXT,
+                expires_at DATETIME NOT NULL,
+                used_at DATETIME,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(account_id) REFERENCES accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY(created_by) REFERENCES accounts(id) ON DELETE SET NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_password_resets_account ON password_resets (account_id);"
+        )?;
+        Ok(())
+    }
+
+    pub fn create_audit_tables(conn: &Connection) -> Result<()> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id TEXT PRIMARY KEY,
+                organization_id TEXT NOT NULL,
+                actor_id TEXT,
+                actor_name TEXT,
+                token_id TEXT,
+                action TEXT NOT NULL,
+                target_type TEXT NOT NULL,
+                target_id TEXT NOT NULL,
+                before_state TEXT,
+                after_state TEXT,
+                ip_address TEXT,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY(organization_id) REFERENCES organizations(id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_audit_log_organization ON audit_log (organization_id, created_at);
+            CREATE INDEX IF NOT EXISTS idx_audit_log_target ON audit_log (organization_id, target_type, target_id);
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+            BEGIN
+                SELECT RAISE(ABORT, 'audit_log is append-only');
+            END;
+            CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+            BEGIN
+                SELECT RAISE(ABORT, 'audit_log is append-only');
+            END;"
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use rusqlite::{Connection, Error as SqliteError, params};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use chrono::Utc;
+use uuid::Uuid;
+use log::warn;
+use crate::log_parser::NormalizedLog;
+use crate::rules::evaluate_log_against_rules;
+use std::fmt;
+
+// Host label for events the SIEM raises about itself
+pub const INTERNAL_HOST: &str = "siem";
+
+#[derive(Debug)]
+pub enum SecurityEventError {
+    DatabaseError(SqliteError),
+    ValidationError(String),
+}
+
+impl fmt::Display for SecurityEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecurityEventError::DatabaseError(err) => write!(f, "Database error: {}", err),
+            SecurityEventError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+        }
+    }
+}
+
+impl From<SqliteError> for SecurityEventError {
+    fn from(err: SqliteError) -> Self {
+        SecurityEventError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for SecurityEventError {
+    fn from(err: serde_json::Error) -> Self {
+        SecurityEventError::ValidationError(err.to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecurityEvent {
+    pub id: String,
+    pub account_id: String,
+    pub event_type: String,
+    pub src_ip: Option<String>,
+    pub log_data: String,
+    pub created_at: String,
+}
+
+// Store an event about the SIEM itself for an organization and run the organization's rules
+// against it, so rules can match on `event_type` and the extension fields like any other log
+pub fn record_security_event(
+    conn: &Connection,
+    organization_id: &str,
+    event_type: &str,
+    src_ip: Option<&str>,
+    message: String,
+    extensions: HashMap<String, String>,
+) -> Result<SecurityEvent, SecurityEventError> {
+    if organization_id.is_empty() {
+        return Err(SecurityEventError::ValidationError("Account ID cannot be empty".to_string()));
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let log = NormalizedLog {
+        timestamp: Some(now.clone()),
+        src_ip: src_ip.map(String::from),
+        dst_ip: None,
+        event_type: Some(event_type.to_string()),
+        host_id: INTERNAL_HOST.to_string(),
+        account_id: organization_id.to_string(),
+        raw: message,
+        extensions,
+    };
+
+    let event = SecurityEvent {
+        id: Uuid::new_v4().to_string(),
+        account_id: organization_id.to_string(),
+        event_type: event_type.to_string(),
+        src_ip: log.src_ip.clone(),
+        log_data: serde_json::to_string(&log)?,
+        created_at: now,
+    };
+    conn.execute(
+        "INSERT INTO security_events (id, account_id, event_type, src_ip, log_data, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![event.id, event.account_id, event.event_type, event.src_ip, event.log_data, event.created_at],
+    )?;
+
+    // A broken rule must not stop the event from being recorded
+    if let Err(err) = evaluate_log_against_rules(conn, &log, &event.account_id) {
+        warn!("Rule evaluation failed for security event {}: {}", event.id, err);
+    }
+    Ok(event)
+}
+
+pub fn list_security_events(conn: &Connection, organization_id: &str, limit: i64) -> Result<Vec<SecurityEvent>, SecurityEventError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, account_id, event_type, src_ip, log_data, created_at
+        FROM security_events WHERE account_id = ?1 ORDER BY created_at DESC LIMIT ?2"
+    )?;
+    let events = stmt.query_map(params![organization_id, limit], |row| {
+        Ok(SecurityEvent {
+            id: row.get(0)?,
+            account_id: row.get(1)?,
+            event_type: row.get(2)?,
+            src_ip: row.get(3)?,
+            log_data: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(events)
+}
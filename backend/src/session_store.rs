@@ -0,0 +1,358 @@
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError, generate_session_key};
+use actix_web::cookie::time::Duration;
+use rusqlite::{Connection, Error as SqliteError, OptionalExtension, params};
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use chrono::Utc;
+use std::collections::HashMap;
+use crate::database::{DbPool, run};
+
+// Session state is a map of JSON-encoded values, as actix-session hands it to the store
+type SessionState = HashMap<String, String>;
+
+// An active sign-in, as shown to its owner
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_activity: String,
+    pub expires_at: String,
+    // Whether this is the session making the request
+    pub current: bool,
+}
+
+// Keeps session state in SQLite so sessions can be listed and revoked. The cookie only carries
+// a random key, stored here as a SHA-256 hash.
+//
+// A sign-in is one `sessions` row. Its key changes on every renew, so the state lives in
+// `session_states` rows that point at it. Revoked sessions stay as tombstones until they
+// expire, so a request that was in flight can't save them again
+#[derive(Clone)]
+pub struct SqliteSessionStore {
+    pool: DbPool,
+}
+
+impl SqliteSessionStore {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn state_value(state: &SessionState, key: &str) -> Option<String> {
+    state.get(key).and_then(|value| serde_json::from_str::<String>(value).ok())
+}
+
+fn expiry(ttl_secs: i64) -> String {
+    (Utc::now() + chrono::Duration::seconds(ttl_secs)).to_rfc3339()
+}
+
+fn is_revoked(conn: &Connection, session_id: &str) -> Result<bool, SqliteError> {
+    let revoked: Option<Option<String>> = conn.query_row(
+        "SELECT revoked_at FROM sessions WHERE id = ?1",
+        params![session_id],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(matches!(revoked, Some(Some(_))))
+}
+
+// Record the sign-in behind a piece of state, keeping its creation time across renews
+fn touch_session(conn: &Connection, state: &SessionState, expires_at: &str) -> Result<Option<String>, SqliteError> {
+    let (Some(session_id), Some(account_id)) = (state_value(state, "session_id"), state_value(state, "account_id")) else {
+        return Ok(None);
+    };
+
+    let now = Utc::now().to_rfc3339();
+    conn.execute(
+        "INSERT INTO sessions (id, account_id, ip_address, user_agent, created_at, last_activity, expires_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6)
+        ON CONFLICT(id) DO UPDATE SET last_activity = excluded.last_activity, expires_at = excluded.expires_at",
+        params![
+            session_id,
+            account_id,
+            state_value(state, "ip_address"),
+            state_value(state, "user_agent"),
+            now,
+            expires_at,
+        ],
+    )?;
+    Ok(Some(session_id))
+}
+
+#[derive(Debug)]
+enum SessionStoreError {
+    DatabaseError(SqliteError),
+    SerializationError(serde_json::Error),
+}
+
+impl From<SqliteError> for SessionStoreError {
+    fn from(err: SqliteError) -> Self {
+        SessionStoreError::DatabaseError(err)
+    }
+}
+
+impl From<serde_json::Error> for SessionStoreError {
+    fn from(err: serde_json::Error) -> Self {
+        SessionStoreError::SerializationError(err)
+    }
+}
+
+impl From<SessionStoreError> for SaveError {
+    fn from(err: SessionStoreError) -> Self {
+        match err {
+            SessionStoreError::DatabaseError(err) => SaveError::Other(err.into()),
+            SessionStoreError::SerializationError(err) => SaveError::Serialization(err.into()),
+        }
+    }
+}
+
+impl From<SessionStoreError> for UpdateError {
+    fn from(err: SessionStoreError) -> Self {
+        match err {
+            SessionStoreError::DatabaseError(err) => UpdateError::Other(err.into()),
+            SessionStoreError::SerializationError(err) => UpdateError::Serialization(err.into()),
+        }
+    }
+}
+
+fn save_state(conn: &Connection, state: &SessionState, ttl_secs: i64) -> Result<SessionKey, SessionStoreError> {
+    let expires_at = expiry(ttl_secs);
+    let now = Utc::now().to_rfc3339();
+    conn.execute("DELETE FROM session_states WHERE expires_at <= ?1", params![now])?;
+    conn.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+
+    let key = generate_session_key();
+    if let Some(session_id) = state_value(state, "session_id") {
+        // The client gets a key that loads nothing, which reads as signed out
+        if is_revoked(conn, &session_id)? {
+            return Ok(key);
+        }
+    }
+
+    let session_id = touch_session(conn, state, &expires_at)?;
+    conn.execute(
+        "INSERT INTO session_states (key_hash, session_id, state, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![hash_key(key.as_ref()), session_id, serde_json::to_string(state)?, expires_at],
+    )?;
+    Ok(key)
+}
+
+// Write new state under an existing key. False when the key is gone or its session was revoked
+fn update_state(conn: &Connection, key_hash: &str, state: &SessionState, ttl_secs: i64) -> Result<bool, SessionStoreError> {
+    if let Some(session_id) = state_value(state, "session_id") {
+        if is_revoked(conn, &session_id)? {
+            return Ok(false);
+        }
+    }
+
+    let expires_at = expiry(ttl_secs);
+    let updated = conn.execute(
+        "UPDATE session_states SET state = ?1, expires_at = ?2 WHERE key_hash = ?3",
+        params![serde_json::to_string(state)?, expires_at, key_hash],
+    )?;
+    if updated == 0 {
+        return Ok(false);
+    }
+    touch_session(conn, state, &expires_at)?;
+    Ok(true)
+}
+
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let key_hash = hash_key(session_key.as_ref());
+        let data: Option<String> = run(&self.pool, move |conn| {
+            conn.query_row(
+                "SELECT st.state FROM session_states st
+                LEFT JOIN sessions s ON s.id = st.session_id
+                WHERE st.key_hash = ?1 AND st.expires_at > ?2 AND s.revoked_at IS NULL",
+                params![key_hash, Utc::now().to_rfc3339()],
+                |row| row.get(0),
+            ).optional()
+        }).await.map_err(|e: SqliteError| LoadError::Other(e.into()))?;
+
+        data.map(|data| serde_json::from_str(&data))
+            .transpose()
+            .map_err(|e| LoadError::Deserialization(e.into()))
+    }
+
+    async fn save(&self, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let ttl_secs = ttl.whole_seconds();
+        let key = run(&self.pool, move |conn| save_state(conn, &session_state, ttl_secs)).await?;
+        Ok(key)
+    }
+
+    async fn update(&self, session_key: SessionKey, session_state: SessionState, ttl: &Duration) -> Result<SessionKey, UpdateError> {
+        let ttl_secs = ttl.whole_seconds();
+        let key_hash = hash_key(session_key.as_ref());
+        let state = session_state.clone();
+        if run(&self.pool, move |conn| update_state(conn, &key_hash, &state, ttl_secs)).await? {
+            return Ok(session_key);
+        }
+
+        // Gone or revoked: start over under a new key, which save refuses for revoked sessions
+        let key = run(&self.pool, move |conn| save_state(conn, &session_state, ttl_secs)).await?;
+        Ok(key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        let expires_at = expiry(ttl.whole_seconds());
+        let key_hash = hash_key(session_key.as_ref());
+        run(&self.pool, move |conn| {
+            conn.execute(
+                "UPDATE session_states SET expires_at = ?1 WHERE key_hash = ?2",
+                params![expires_at, key_hash],
+            )?;
+            conn.execute(
+                "UPDATE sessions SET expires_at = ?1, last_activity = ?2
+                WHERE id = (SELECT session_id FROM session_states WHERE key_hash = ?3)",
+                params![expires_at, Utc::now().to_rfc3339(), key_hash],
+            )?;
+            Ok::<_, SqliteError>(())
+        }).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        let key_hash = hash_key(session_key.as_ref());
+        run(&self.pool, move |conn| {
+            conn.execute("DELETE FROM session_states WHERE key_hash = ?1", params![key_hash])
+        }).await?;
+        Ok(())
+    }
+}
+
+// Sign-ins of an account that still have a live key, most recently used first
+pub fn list_sessions(conn: &Connection, account_id: &str) -> Result<Vec<SessionInfo>, SqliteError> {
+    let mut stmt = conn.prepare(
+        "SELECT id, ip_address, user_agent, created_at, last_activity, expires_at FROM sessions s
+        WHERE account_id = ?1 AND revoked_at IS NULL AND expires_at > ?2
+          AND EXISTS (SELECT 1 FROM session_states st WHERE st.session_id = s.id AND st.expires_at > ?2)
+        ORDER BY last_activity DESC"
+    )?;
+    let sessions = stmt.query_map(params![account_id, Utc::now().to_rfc3339()], |row| {
+        Ok(SessionInfo {
+            id: row.get(0)?,
+            ip_address: row.get(1)?,
+            user_agent: row.get(2)?,
+            created_at: row.get(3)?,
+            last_activity: row.get(4)?,
+            expires_at: row.get(5)?,
+            current: false,
+        })
+    })?.collect::<Result<Vec<_>, _>>()?;
+    Ok(sessions)
+}
+
+// Revoke one of an account's sessions, or all of them except `keep`. Returns how many were revoked
+pub fn revoke_sessions(conn: &Connection, account_id: &str, session_id: Option<&str>, keep: Option<&str>) -> Result<usize, SqliteError> {
+    let now = Utc::now().to_rfc3339();
+    let revoked = conn.execute(
+        "UPDATE sessions SET revoked_at = ?1
+        WHERE account_id = ?2 AND revoked_at IS NULL AND (?3 IS NULL OR id = ?3) AND (?4 IS NULL OR id != ?4)",
+        params![now, account_id, session_id, keep],
+    )?;
+    conn.execute(
+        "DELETE FROM session_states WHERE session_id IN (SELECT id FROM sessions WHERE account_id = ?1 AND revoked_at IS NOT NULL)",
+        params![account_id],
+    )?;
+    Ok(revoked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{organization, test_pool};
+
+    fn state(session_id: &str, account_id: &str) -> SessionState {
+        [("session_id", session_id), ("account_id", account_id), ("ip_address", "10.1.1.1"), ("user_agent", "curl")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), serde_json::to_string(value).unwrap()))
+            .collect()
+    }
+
+    fn listed(pool: &DbPool, account_id: &str) -> Vec<String> {
+        list_sessions(&pool.get().unwrap(), account_id).unwrap().into_iter().map(|session| session.id).collect()
+    }
+
+    #[actix_web::test]
+    async fn sign_ins_are_stored_under_hashed_keys_and_listed() {
+        let pool = test_pool();
+        let (_, account_id) = organization(&pool.get().unwrap(), "acme");
+        let store = SqliteSessionStore::new(pool.clone());
+        let ttl = Duration::minutes(20);
+
+        let key = store.save(state("s1", &account_id), &ttl).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), Some(state("s1", &account_id)));
+        let stored: String = pool.get().unwrap().query_row("SELECT key_hash FROM session_states", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, hash_key(key.as_ref()));
+
+        // Renewing moves the state to a new key but keeps the one sign-in
+        let renewed = store.save(state("s1", &account_id), &ttl).await.unwrap();
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+        assert!(store.load(&renewed).await.unwrap().is_some());
+        let sessions = list_sessions(&pool.get().unwrap(), &account_id).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].ip_address.as_deref(), sessions[0].user_agent.as_deref()), (Some("10.1.1.1"), Some("curl")));
+    }
+
+    #[actix_web::test]
+    async fn a_revoked_session_cannot_be_loaded_or_saved_again() {
+        let pool = test_pool();
+        let (_, account_id) = organization(&pool.get().unwrap(), "acme");
+        let store = SqliteSessionStore::new(pool.clone());
+        let ttl = Duration::minutes(20);
+        let key = store.save(state("s1", &account_id), &ttl).await.unwrap();
+
+        assert_eq!(revoke_sessions(&pool.get().unwrap(), &account_id, Some("s1"), None).unwrap(), 1);
+        assert_eq!(store.load(&key).await.unwrap(), None);
+        assert!(listed(&pool, &account_id).is_empty());
+
+        // A request that was in flight writes back its state, and gets a key that loads nothing
+        let key = store.update(key, state("s1", &account_id), &ttl).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+        let key = store.save(state("s1", &account_id), &ttl).await.unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn other_sessions_are_revoked_and_this_one_kept() {
+        let pool = test_pool();
+        let (_, account_id) = organization(&pool.get().unwrap(), "acme");
+        let (_, other_account) = organization(&pool.get().unwrap(), "globex");
+        let store = SqliteSessionStore::new(pool.clone());
+        let ttl = Duration::minutes(20);
+        for session_id in ["s1", "s2", "s3"] {
+            store.save(state(session_id, &account_id), &ttl).await.unwrap();
+        }
+        store.save(state("s4", &other_account), &ttl).await.unwrap();
+
+        // Another account's session can't be named
+        assert_eq!(revoke_sessions(&pool.get().unwrap(), &account_id, Some("s4"), None).unwrap(), 0);
+        assert_eq!(revoke_sessions(&pool.get().unwrap(), &account_id, None, Some("s2")).unwrap(), 2);
+        assert_eq!(listed(&pool, &account_id), vec!["s2"]);
+        assert_eq!(listed(&pool, &other_account), vec!["s4"]);
+    }
+
+    #[actix_web::test]
+    async fn expired_sessions_are_not_listed_or_loaded() {
+        let pool = test_pool();
+        let (_, account_id) = organization(&pool.get().unwrap(), "acme");
+        let store = SqliteSessionStore::new(pool.clone());
+        let key = store.save(state("s1", &account_id), &Duration::minutes(20)).await.unwrap();
+
+        pool.get().unwrap().execute_batch(
+            "UPDATE session_states SET expires_at = '2000-01-01T00:00:00+00:00';
+             UPDATE sessions SET expires_at = '2000-01-01T00:00:00+00:00';"
+        ).unwrap();
+        assert_eq!(store.load(&key).await.unwrap(), None);
+        assert!(listed(&pool, &account_id).is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 import React, { useState, useEffect } from 'react';
 import { useNavigate } from 'react-router-dom';
-import { getAuthenticationStatus, checkAuth, user } from '../services/authService';
+import { getAuthenticationStatus, checkAuth, organization } from '../services/authService';
 import { getCsrfToken } from '../services/csrfService';
 import Navbar from '../components/Navbar';
 import '../styles/Alerts.css';
@@ -40,7 +40,7 @@ const Alerts = () => {
         try {
             await getCsrfToken(formId);
 
-            const response = await fetch(`http://localhost:4200/backend/alert/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/alert/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -126,7 +126,7 @@ const Alerts = () => {
         }
 
         try {
-            const response = await fetch(`http://localhost:4200/backend/alert/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/alert/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -1,6 +1,6 @@
 import React, { useState, useEffect } from 'react';
 import { useNavigate, Link } from 'react-router-dom';
-import { getAuthenticationStatus, checkAuth, user } from '../services/authService';
+import { getAuthenticationStatus, checkAuth, user, organization } from '../services/authService';
 import { getCsrfToken } from '../services/csrfService';
 import Navbar from '../components/Navbar';
 import '../styles/CasesList.css';
@@ -74,7 +74,7 @@ const CasesList = () => {
         try {
             const csrfToken = await getCsrfToken(formId);
 
-            const response = await fetch(`http://localhost:4200/backend/case/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/case/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -128,7 +128,7 @@ const CasesList = () => {
                 observables: "[]"
             };
 
-            const response = await fetch(`http://localhost:4200/backend/case/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/case/${organization}`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
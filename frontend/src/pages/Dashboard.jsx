@@ -1,7 +1,7 @@
 import React, { useState, useEffect, useRef } from 'react';
 import { useNavigate } from 'react-router-dom';
 import { getCsrfToken } from '../services/csrfService';
-import { getAuthenticationStatus, checkAuth, user } from '../services/authService';
+import { getAuthenticationStatus, checkAuth, organization } from '../services/authService';
 import Navbar from '../components/Navbar';
 import Chart from 'chart.js/auto';
 import '../styles/Dashboard.css';
@@ -64,7 +64,7 @@ const Dashboard = () => {
     const fetchLogs = async () => {
         try {
             await getCsrfToken(formId);
-            const response = await fetch(`http://localhost:4200/backend/log/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/log/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -91,7 +91,7 @@ const Dashboard = () => {
     const fetchAlerts = async () => {
         try {
             await getCsrfToken(formId);
-            const response = await fetch(`http://localhost:4200/backend/alert/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/alert/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -115,7 +115,7 @@ const Dashboard = () => {
     const fetchCases = async () => {
         try {
             await getCsrfToken(formId);
-            const response = await fetch(`http://localhost:4200/backend/case/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/case/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
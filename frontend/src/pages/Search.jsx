@@ -1,6 +1,6 @@
 import React, { useState, useEffect } from 'react';
 import { useNavigate } from 'react-router-dom';
-import { getAuthenticationStatus, checkAuth, user } from '../services/authService';
+import { getAuthenticationStatus, checkAuth, organization } from '../services/authService';
 import { getCsrfToken } from '../services/csrfService';
 import Navbar from '../components/Navbar';
 import '../styles/Search.css';
@@ -70,7 +70,7 @@ const Search = () => {
         try {
             const params = new URLSearchParams({
                 query: query,
-                account_id: user,
+                account_id: organization,
                 start_time: startTime || '',
                 end_time: endTime || ''
             });
@@ -99,7 +99,7 @@ const Search = () => {
 
     const addLogAsEvent = async (log) => {
         try {
-            const response = await fetch(`http://localhost:4200/backend/case/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/case/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -156,7 +156,7 @@ const Search = () => {
 
     const fetchLogsInCases = async () => {
         try {
-            const response = await fetch(`http://localhost:4200/backend/case/logs/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/case/logs/${organization}`, {
                 method: 'GET',
                 headers: {
                     'Content-Type': 'application/json',
@@ -1,6 +1,6 @@
 import React, { useState, useEffect } from 'react';
 import { useNavigate } from 'react-router-dom';
-import { getAuthenticationStatus, checkAuth, user } from '../services/authService';
+import { getAuthenticationStatus, checkAuth, organization } from '../services/authService';
 import { getCsrfToken } from '../services/csrfService';
 import Navbar from '../components/Navbar';
 import '../styles/Settings.css';
@@ -40,7 +40,7 @@ const Settings = () => {
     const populateHostList = async () => {
         try {
             await getCsrfToken(formId);
-            const response = await fetch(`http://localhost:4200/backend/host/all/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/host/all/${organization}`, {
                 method: 'GET',
                 headers: {
                     'X-Form-ID': formId
@@ -90,7 +90,7 @@ const Settings = () => {
         const formData = new FormData();
         formData.append('file', file);
         formData.append('host_id', hostId);
-        formData.append('account_id', user);
+        formData.append('account_id', organization);
 
         try {
             await getCsrfToken(formId);
@@ -129,7 +129,7 @@ const Settings = () => {
 
         try {
             await getCsrfToken(formId);
-            const response = await fetch(`http://localhost:4200/backend/host/${user}`, {
+            const response = await fetch(`http://localhost:4200/backend/host/${organization}`, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
@@ -137,7 +137,7 @@ const Settings = () => {
                 },
                 body: JSON.stringify({
                     id: '0',
-                     account_id: user,
+                     account_id: organization,
                     hostname: hostname,
                     ip_address: ipAddress
                 }),
@@ -190,7 +190,7 @@ const Settings = () => {
 
             const ruleData = {
                 id: '0',
-                account_id: user,
+                account_id: organization,
                 title: parsedRule.title,
                 status: parsedRule.status,
                 description: parsedRule.description,
@@ -253,12 +253,12 @@ const Settings = () => {
             <main>
                 <section className="agent-info">
                     <div className="info-group">
-                        <h3>Account ID</h3>
+                        <h3>Organization ID</h3>
                         <div className="copy-container">
-                            <span>{user}</span>
+                            <span>{organization}</span>
                             <button 
                                 className="primary-btn" 
-                                onClick={() => handleCopyId(user)}
+                                onClick={() => handleCopyId(organization)}
                             >
                                 Copy
                             </button>
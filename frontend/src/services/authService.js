@@ -1,5 +1,6 @@
 let isAuthenticated = false;
 let user = null;
+let organization = null;
 
 export function getAuthenticationStatus() {
     return isAuthenticated;
@@ -18,14 +19,17 @@ export async function checkAuth() {
             const data = await response.json();
             setAuthenticationStatus(true);
             user = data.account_id;
+            organization = data.organization_id;
         } else {
             setAuthenticationStatus(false);
             user = null;
+            organization = null;
         }
     } catch (error) {
         console.error('Error checking auth:', error);
         setAuthenticationStatus(false);
         user = null;
+        organization = null;
     }
 }
 
@@ -69,6 +73,7 @@ export async function logout() {
         if (response.ok) {
             setAuthenticationStatus(false);
             user = null;
+            organization = null;
             return { success: true };
         }
         return { success: false, message: "Logout didn't work" };
@@ -77,4 +82,4 @@ export async function logout() {
     }
 }
 
-export { user };
\ No newline at end of file
+export { user, organization };
\ No newline at end of file